@@ -0,0 +1,49 @@
+/*
+ * C interface of the GameRoy emulator core, built with the `ffi` feature of `gameroy-core`. See
+ * `core/src/ffi.rs` for the documentation of each function.
+ */
+#ifndef GAMEROY_H
+#define GAMEROY_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* The size in bytes of the RGBA buffer passed to `gameroy_run_frame`: 160x144 pixels. */
+#define GAMEROY_FRAME_BUFFER_SIZE (160 * 144 * 4)
+
+/* The bits of `gameroy_set_buttons`. A set bit means pressed. */
+#define GAMEROY_BUTTON_RIGHT (1 << 0)
+#define GAMEROY_BUTTON_LEFT (1 << 1)
+#define GAMEROY_BUTTON_UP (1 << 2)
+#define GAMEROY_BUTTON_DOWN (1 << 3)
+#define GAMEROY_BUTTON_A (1 << 4)
+#define GAMEROY_BUTTON_B (1 << 5)
+#define GAMEROY_BUTTON_SELECT (1 << 6)
+#define GAMEROY_BUTTON_START (1 << 7)
+
+typedef struct Gameroy Gameroy;
+
+/* Return NULL if the ROM is invalid. The ROM is copied. A `sample_rate` of 0 disables the sound. */
+Gameroy *gameroy_new(const uint8_t *rom, size_t len, uint32_t sample_rate);
+
+/* Does nothing if `gameroy` is NULL. */
+void gameroy_free(Gameroy *gameroy);
+
+bool gameroy_set_buttons(Gameroy *gameroy, uint8_t buttons);
+
+/* Return false if `len` is smaller than `GAMEROY_FRAME_BUFFER_SIZE`, or on a internal error. */
+bool gameroy_run_frame(Gameroy *gameroy, uint8_t *rgba, size_t len);
+
+/* Interleaved stereo samples, from 0.0 to 1.0. Return the number of floats written. */
+size_t gameroy_drain_audio(Gameroy *gameroy, float *out, size_t len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* GAMEROY_H */
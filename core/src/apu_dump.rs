@@ -0,0 +1,97 @@
+//! Dump the APU registers over time, for analyzing how a game's sound engine programs the chip.
+
+use std::io::{self, Write};
+
+use crate::{consts::FRAME_CYCLES, gameboy::GameBoy, interpreter::Interpreter};
+
+/// The registers in the dump, in column order, with their address in the 0xFF00 page.
+pub const REGISTERS: [(u8, &str); 21] = [
+    (0x10, "NR10"),
+    (0x11, "NR11"),
+    (0x12, "NR12"),
+    (0x13, "NR13"),
+    (0x14, "NR14"),
+    (0x16, "NR21"),
+    (0x17, "NR22"),
+    (0x18, "NR23"),
+    (0x19, "NR24"),
+    (0x1A, "NR30"),
+    (0x1B, "NR31"),
+    (0x1C, "NR32"),
+    (0x1D, "NR33"),
+    (0x1E, "NR34"),
+    (0x20, "NR41"),
+    (0x21, "NR42"),
+    (0x22, "NR43"),
+    (0x23, "NR44"),
+    (0x24, "NR50"),
+    (0x25, "NR51"),
+    (0x26, "NR52"),
+];
+
+/// Run the GameBoy for `frames` frames with the interpreter, writing a CSV with a snapshot of the
+/// APU registers at the end of each frame.
+///
+/// The first line is the header `frame,clock_count,NR10,...,NR52`. The registers are read through
+/// `SoundController::read`, so they have the same value that a CPU read would return, and the
+/// lower bits of NR52 contain the enabled state of each channel.
+pub fn dump_apu_registers(gb: &mut GameBoy, frames: u64, w: &mut impl Write) -> io::Result<()> {
+    write!(w, "frame,clock_count")?;
+    for (_, name) in REGISTERS {
+        write!(w, ",{}", name)?;
+    }
+    writeln!(w)?;
+
+    for frame in 0..frames {
+        let target = gb.clock_count + FRAME_CYCLES;
+        while gb.clock_count < target {
+            Interpreter(gb).interpret_op();
+        }
+
+        write!(w, "{},{}", frame, gb.clock_count)?;
+        let sound = gb.sound.get_mut();
+        for (address, _) in REGISTERS {
+            write!(w, ",{:02x}", sound.read(gb.clock_count, address))?;
+        }
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::cartridge::Cartridge;
+
+    #[test]
+    fn dump_nr10() {
+        let cartridge = Cartridge::with_code(
+            0x100,
+            &[
+                0x3e, 0x80, // LD A, $80
+                0xe0, 0x26, // LDH ($26), A
+                0x3e, 0x35, // LD A, $35
+                0xe0, 0x10, // LDH ($10), A
+                0x18, 0xfe, // JR -2
+            ],
+        );
+        let mut gb = GameBoy::new(None, cartridge);
+
+        let mut out = Vec::new();
+        dump_apu_registers(&mut gb, 2, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<Vec<&str>> = out.lines().map(|x| x.split(',').collect()).collect();
+
+        assert_eq!(lines.len(), 3);
+        assert_eq!(&lines[0][..4], &["frame", "clock_count", "NR10", "NR11"]);
+        assert_eq!(lines[0].len(), 2 + REGISTERS.len());
+
+        for (i, line) in lines[1..].iter().enumerate() {
+            assert_eq!(line[0], i.to_string());
+            // the unused bit 7 reads as 1
+            assert_eq!(line[2], "b5");
+            // the APU is on
+            assert_eq!(&line[22][..1], "f");
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! A ring buffer between the emulation thread and the callback of a real-time audio backend.
+//!
+//! The emulation produces a variable number of samples at each `SoundController::get_output`,
+//! while the audio backend pulls buffers of a fixed size from its own thread. [`AudioRing`] is
+//! shared between both: the emulation pushes the samples as they are produced, and the callback
+//! fills its buffer with them.
+
+use std::{collections::VecDeque, sync::Mutex};
+
+/// The maximum value of a sample in the output of the `SoundController`: the 4 channels at volume
+/// 15, with the master volume at 7.
+pub const MAX_SAMPLE: u16 = 4 * 15 * 7;
+
+/// Convert a sample from the output of the `SoundController` to a f32 in the range `0.0..=1.0`.
+pub fn normalize(sample: u16) -> f32 {
+    sample.min(MAX_SAMPLE) as f32 / MAX_SAMPLE as f32
+}
+
+/// A bounded queue of interleaved stereo samples, that can be shared between threads (in a `Arc`,
+/// for example).
+pub struct AudioRing {
+    buffer: Mutex<VecDeque<f32>>,
+    capacity: usize,
+}
+
+impl AudioRing {
+    /// Create a ring that holds at most `capacity` samples. Because the samples are interleaved,
+    /// `capacity` is rounded down to a even number.
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity & !1;
+        Self {
+            buffer: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    /// The maximum number of samples in the ring.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The number of samples currently in the ring.
+    pub fn len(&self) -> usize {
+        self.buffer.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Push the samples returned by `SoundController::get_output`. If the ring overruns, the
+    /// oldest samples are dropped, in pairs to keep the left and right channels in place.
+    pub fn push(&self, samples: &[u16]) {
+        let mut buffer = self.buffer.lock().unwrap();
+        let samples = &samples[samples.len().saturating_sub(self.capacity)..];
+        let overrun = (buffer.len() + samples.len()).saturating_sub(self.capacity);
+        let overrun = ((overrun + 1) & !1).min(buffer.len());
+        buffer.drain(..overrun);
+        buffer.extend(samples.iter().map(|&x| normalize(x)));
+    }
+
+    /// Fill `out` with the oldest samples in the ring. If the ring underruns, the rest of `out` is
+    /// filled with silence. Return the number of samples that came from the ring.
+    pub fn fill(&self, out: &mut [f32]) -> usize {
+        let mut buffer = self.buffer.lock().unwrap();
+        let len = buffer.len().min(out.len());
+        for (a, b) in out.iter_mut().zip(buffer.drain(..len)) {
+            *a = b;
+        }
+        out[len..].fill(0.0);
+        len
+    }
+
+    /// Remove all the samples in the ring.
+    pub fn clear(&self) {
+        self.buffer.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fill_in_chunks() {
+        let ring = AudioRing::new(1024);
+        let samples: Vec<u16> = (0..600).map(|x| x % (MAX_SAMPLE + 1)).collect();
+        for chunk in samples.chunks(70) {
+            ring.push(chunk);
+        }
+        assert_eq!(ring.len(), 600);
+
+        let mut out = Vec::new();
+        let mut buffer = [0.0; 64];
+        while !ring.is_empty() {
+            let len = ring.fill(&mut buffer);
+            out.extend_from_slice(&buffer[..len]);
+        }
+        let expected: Vec<f32> = samples.iter().map(|&x| normalize(x)).collect();
+        assert_eq!(out, expected);
+        assert_eq!(normalize(0), 0.0);
+        assert_eq!(normalize(MAX_SAMPLE), 1.0);
+    }
+
+    #[test]
+    fn underrun() {
+        let ring = AudioRing::new(16);
+
+        let mut buffer = [1.0; 8];
+        assert_eq!(ring.fill(&mut buffer), 0);
+        assert_eq!(buffer, [0.0; 8]);
+
+        ring.push(&[MAX_SAMPLE; 2]);
+        let mut buffer = [0.5; 8];
+        assert_eq!(ring.fill(&mut buffer), 2);
+        assert_eq!(buffer, [1.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn overrun() {
+        let ring = AudioRing::new(8);
+        ring.push(&[1, 2, 3, 4, 5, 6]);
+        ring.push(&[7, 8, 9, 10]);
+        assert_eq!(ring.len(), 8);
+
+        let mut buffer = [0.0; 8];
+        ring.fill(&mut buffer);
+        assert_eq!(buffer, [3, 4, 5, 6, 7, 8, 9, 10].map(normalize));
+
+        // a push larger than the ring keeps only the last samples
+        ring.push(&(0..20).collect::<Vec<u16>>());
+        ring.fill(&mut buffer);
+        assert_eq!(buffer, [12, 13, 14, 15, 16, 17, 18, 19].map(normalize));
+    }
+}
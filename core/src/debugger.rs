@@ -1,13 +1,55 @@
 use std::collections::{BTreeMap, BTreeSet, HashSet};
 
+use crate::log::Logger;
 use crate::save_state::SaveStateContext;
-use crate::{gameboy::GameBoy, interpreter::Interpreter, save_state::SaveState};
+use crate::{
+    disassembler::{Address, Trace},
+    gameboy::GameBoy,
+    interpreter::Interpreter,
+    save_state::SaveState,
+};
 
 pub mod break_flags {
     pub const WRITE: u8 = 1 << 0;
     pub const READ: u8 = 1 << 1;
     pub const EXECUTE: u8 = 1 << 2;
     pub const JUMP: u8 = 1 << 3;
+
+    /// Format the flags as a string like "wr-x", in the order write, read, jump and execute, with
+    /// a '-' for each absent flag.
+    pub fn to_string(flags: u8) -> String {
+        let check = |c, flag| if flags & flag != 0 { c } else { '-' };
+        [
+            check('w', WRITE),
+            check('r', READ),
+            check('j', JUMP),
+            check('x', EXECUTE),
+        ]
+        .iter()
+        .collect()
+    }
+
+    /// Parse flags in the format of `to_string`, or any combination of the characters 'w', 'r',
+    /// 'j' and 'x', in any order.
+    pub fn parse(text: &str) -> Result<u8, String> {
+        let mut flags = 0;
+        for c in text.chars() {
+            flags |= match c {
+                'w' => WRITE,
+                'r' => READ,
+                'j' => JUMP,
+                'x' => EXECUTE,
+                '-' => 0,
+                c => {
+                    return Err(format!(
+                        "'{}' is not a valid break flag. Valid ones are 'r', 'w', 'x' and 'j'.",
+                        c
+                    ))
+                }
+            };
+        }
+        Ok(flags)
+    }
 }
 
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
@@ -41,6 +83,8 @@ pub struct Debugger {
     /// Break if a interrupt is flagged and enabled.
     interrupt_breakpoint: bool,
     breakpoints: BTreeMap<u16, u8>,
+    /// Breakpoints in ROM that only trigger when their bank is mapped.
+    bank_breakpoints: BTreeMap<Address, u8>,
     watchs: BTreeSet<u16>,
     /// Address to stop at
     pub target_address: Option<u16>,
@@ -50,6 +94,9 @@ pub struct Debugger {
     pub last_op_clock: Option<u64>,
     /// Callback called when self is mutated
     pub callback: Option<DebuggerCallback>,
+    /// Where the debugger actions, and the output of commands like 'echo', are logged to. It has
+    /// no sink by default, so that output is discarded until one is installed.
+    pub logger: Logger,
 
     /// Used by StepBack, to ignore breakpoints between the last frame and the target clock.
     pub skip_breakpoints_until_target_clock: bool,
@@ -64,6 +111,13 @@ impl Debugger {
             }
             a.callback = callback;
         };
+        crate::log!(
+            self.logger,
+            Debug,
+            "debugger",
+            "command: {}",
+            args.join(" ")
+        );
         self.target_address = None;
         self.target_clock = None;
         match args[0] {
@@ -140,21 +194,13 @@ impl Debugger {
                     ));
                 }
 
-                let flags = args[1].as_bytes();
-                if let Some(x) = flags.iter().find(|x| !b"wrxj".contains(x)) {
-                    return Err(format!(
-                        "'{}' is not a valid break flag. Valid ones are 'r', 'w', 'x' and 'j'.",
-                        *x as char
-                    ));
-                }
-
-                let write = args[1].contains('w') as u8;
-                let read = args[1].contains('r') as u8;
-                let execute = args[1].contains('x') as u8;
-                let jump = args[1].contains('j') as u8;
+                let flags = break_flags::parse(args[1])?;
 
-                use break_flags::*;
-                let flags = (write * WRITE) | (read * READ) | (execute * EXECUTE) | (jump * JUMP);
+                if args[2].contains(':') {
+                    let address = parse_bank_address(args[2])?;
+                    self.add_bank_break(flags, address);
+                    return Ok(());
+                }
 
                 let address = match u16::from_str_radix(args[2], 16) {
                     Ok(x) => x,
@@ -188,7 +234,7 @@ impl Debugger {
 
                 self.add_watch(address);
             }
-            "echo" => println!("{}", args[1..].join(" ")),
+            "echo" => crate::log!(self.logger, Info, "debugger", "{}", args[1..].join(" ")),
             // write the currently dissasembly to a file
             "dump" => {
                 if args.len() != 2 {
@@ -272,6 +318,81 @@ impl Debugger {
         &self.breakpoints
     }
 
+    /// List the breakpoints, with their flags and the name of the label at their address, if the
+    /// `Trace` has one. These breakpoints are not bank specific, so their addresses are resolved in
+    /// the given ROM banks, like the ones from `Cartridge::curr_bank`.
+    pub fn breakpoints_with_labels(
+        &self,
+        banks: (u16, u16),
+        trace: &Trace,
+    ) -> Vec<(u16, u8, Option<String>)> {
+        self.breakpoints
+            .iter()
+            .map(|(&address, &flags)| {
+                let label = Address::from_pc(banks, address)
+                    .and_then(|x| trace.labels.get(&x))
+                    .map(|x| x.name.clone());
+                (address, flags, label)
+            })
+            .collect()
+    }
+
+    /// The breakpoints that only trigger when their ROM bank is mapped.
+    pub fn bank_breakpoints(&self) -> &BTreeMap<Address, u8> {
+        &self.bank_breakpoints
+    }
+
+    /// Write all breakpoints to a text, one per line, in the format "wr-x 0150", the same one
+    /// displayed in the debugger UI. The breakpoints of a ROM bank are in the format
+    /// "wr-x 03:4abc", with the bank and the address in the CPU address space.
+    pub fn export_breakpoints(&self) -> String {
+        let mut text = String::new();
+        for (address, &flags) in &self.breakpoints {
+            text += &format!("{} {:04x}\n", break_flags::to_string(flags), address);
+        }
+        for (address, &flags) in &self.bank_breakpoints {
+            text += &format!(
+                "{} {:02x}:{:04x}\n",
+                break_flags::to_string(flags),
+                address.bank,
+                address.to_pc()
+            );
+        }
+        text
+    }
+
+    /// Add the breakpoints in a text in the format of `export_breakpoints`. Empty lines are
+    /// ignored.
+    pub fn import_breakpoints(&mut self, text: &str) -> Result<(), String> {
+        let mut breakpoints = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let err = |msg: String| format!("line {}: {}", i + 1, msg);
+            let (flags, address) = line
+                .split_once(' ')
+                .ok_or_else(|| err(format!("expected 'flags address', found '{}'", line)))?;
+            let flags = break_flags::parse(flags).map_err(err)?;
+            let address = address.trim();
+            let address = if address.contains(':') {
+                Err(parse_bank_address(address).map_err(err)?)
+            } else {
+                Ok(u16::from_str_radix(address, 16)
+                    .map_err(|_| err(format!("'{}' is not a valid address", address)))?)
+            };
+            breakpoints.push((flags, address));
+        }
+        for (flags, address) in breakpoints {
+            match address {
+                Ok(address) => self.add_break(flags, address),
+                Err(address) => self.add_bank_break(flags, address),
+            }
+        }
+        Ok(())
+    }
+
     pub fn remove_break(&mut self, address: u16) {
         let address = &address;
         self.breakpoints.remove(address);
@@ -309,6 +430,26 @@ impl Debugger {
         self.callback = take;
     }
 
+    /// Add a breakpoint that only triggers while `address.bank` is mapped.
+    pub fn add_bank_break(&mut self, flags: u8, address: Address) {
+        debug_assert!(flags & 0xF0 == 0);
+        *self.bank_breakpoints.entry(address).or_default() |= flags;
+        let mut take = self.callback.take();
+        if let Some(x) = take.as_mut() {
+            x(self, DebuggerEvent::BreakpointsUpdate)
+        }
+        self.callback = take;
+    }
+
+    pub fn remove_bank_break(&mut self, address: Address) {
+        self.bank_breakpoints.remove(&address);
+        let mut take = self.callback.take();
+        if let Some(x) = take.as_mut() {
+            x(self, DebuggerEvent::BreakpointsUpdate)
+        }
+        self.callback = take;
+    }
+
     pub fn watchs(&self) -> &BTreeSet<u16> {
         &self.watchs
     }
@@ -332,24 +473,34 @@ impl Debugger {
     }
 
     pub fn check_break(&self, inter: &mut Interpreter) -> bool {
+        // this runs for every instruction, so the banks are only read if they are needed
+        let banks = (!self.bank_breakpoints.is_empty()).then(|| inter.0.cartridge.curr_bank());
+        let bank_break = |address: u16, flag: u8| {
+            banks
+                .and_then(|banks| Address::from_pc(banks, address))
+                .and_then(|x| self.bank_breakpoints.get(&x))
+                .is_some_and(|&flags| flags & flag != 0)
+        };
+
         let writes = inter.will_write_to();
-        for w in &writes.1[..writes.0 as usize] {
-            if self.write_breakpoints.contains(w) {
+        for &w in &writes.1[..writes.0 as usize] {
+            if self.write_breakpoints.contains(&w) || bank_break(w, break_flags::WRITE) {
                 return true;
             }
         }
         let reads = inter.will_read_from();
-        for r in &reads.1[..reads.0 as usize] {
-            if self.read_breakpoints.contains(r) {
+        for &r in &reads.1[..reads.0 as usize] {
+            if self.read_breakpoints.contains(&r) || bank_break(r, break_flags::READ) {
                 return true;
             }
         }
         if let Some(jump) = inter.will_jump_to() {
-            if self.jump_breakpoints.contains(&jump) {
+            if self.jump_breakpoints.contains(&jump) || bank_break(jump, break_flags::JUMP) {
                 return true;
             }
         }
-        if self.execute_breakpoints.contains(&inter.0.cpu.pc) {
+        let pc = inter.0.cpu.pc;
+        if self.execute_breakpoints.contains(&pc) || bank_break(pc, break_flags::EXECUTE) {
             return true;
         }
         false
@@ -418,3 +569,133 @@ impl Debugger {
         result
     }
 }
+
+/// Parse a ROM address in the format "03:4abc", with the bank and the address in the CPU address
+/// space.
+fn parse_bank_address(text: &str) -> Result<Address, String> {
+    let invalid = || format!("'{}' is not a valid bank:address", text);
+    let (bank, pc) = text.split_once(':').ok_or_else(invalid)?;
+    let bank = u16::from_str_radix(bank, 16).map_err(|_| invalid())?;
+    let pc = u16::from_str_radix(pc, 16).map_err(|_| invalid())?;
+    Address::from_pc((bank, bank), pc)
+        .filter(|x| x.to_pc() == pc)
+        .ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn breakpoints_round_trip() {
+        use break_flags::*;
+
+        let mut debugger = Debugger::default();
+        debugger.add_break(WRITE, 0xc000);
+        debugger.add_break(READ | JUMP, 0xff40);
+        debugger.add_break(EXECUTE, 0x0150);
+        debugger.add_break(WRITE | READ | JUMP | EXECUTE, 0x4abc);
+
+        let text = debugger.export_breakpoints();
+        assert_eq!(text, "---x 0150\nwrjx 4abc\nw--- c000\n-rj- ff40\n");
+
+        let mut imported = Debugger::default();
+        imported.import_breakpoints(&text).unwrap();
+        assert_eq!(imported.breakpoints(), debugger.breakpoints());
+        assert_eq!(imported.export_breakpoints(), text);
+
+        assert!(imported.import_breakpoints("wq-- 0150").is_err());
+        assert!(imported.import_breakpoints("\nw--- zzzz").is_err());
+        assert_eq!(imported.breakpoints(), debugger.breakpoints());
+    }
+
+    #[test]
+    fn bank_breakpoints_round_trip() {
+        use break_flags::*;
+
+        let mut debugger = Debugger::default();
+        debugger.add_break(WRITE, 0xc000);
+        debugger.add_bank_break(EXECUTE, Address::new(0, 0x0150));
+        debugger.add_bank_break(READ | JUMP, Address::new(3, 0x0abc));
+        debugger.add_bank_break(EXECUTE, Address::new(0x1f, 0x0abc));
+
+        let text = debugger.export_breakpoints();
+        assert_eq!(
+            text,
+            "w--- c000\n---x 00:0150\n-rj- 03:4abc\n---x 1f:4abc\n"
+        );
+
+        let mut imported = Debugger::default();
+        imported.import_breakpoints(&text).unwrap();
+        assert_eq!(imported.breakpoints(), debugger.breakpoints());
+        assert_eq!(imported.bank_breakpoints(), debugger.bank_breakpoints());
+        assert_eq!(imported.export_breakpoints(), text);
+
+        // out of ROM, or the bank 0 in the upper half
+        assert!(imported.import_breakpoints("w--- 00:c000").is_err());
+        assert!(imported.import_breakpoints("w--- 00:4abc").is_err());
+        assert!(imported.import_breakpoints("w--- 03:zzzz").is_err());
+        assert_eq!(imported.bank_breakpoints(), debugger.bank_breakpoints());
+    }
+
+    #[test]
+    fn bank_breakpoint_trigger() {
+        use crate::gameboy::cartridge::Cartridge;
+
+        let mut rom = vec![0x00; 4 * 0x4000];
+        rom[0x147] = 0x01; // MBC1
+        rom[0x148] = 0x01; // 64 KiB
+        rom[0x150..0x155].copy_from_slice(&[
+            0x3e, 0x02, // LD A, $02
+            0xea, 0x00, 0x20, // LD ($2000), A
+        ]);
+        rom[0x155..0x158].copy_from_slice(&[0xc3, 0x00, 0x40]); // JP $4000
+                                                                // bank 1 and 2 jump back
+        for bank in [1, 2] {
+            rom[bank * 0x4000..bank * 0x4000 + 3].copy_from_slice(&[0xc3, 0x50, 0x01]);
+        }
+        let cartridge = Cartridge::new(rom).unwrap();
+
+        for (bank, hits) in [(1, false), (2, true)] {
+            let mut gb = GameBoy::new(None, cartridge.clone());
+            gb.cpu.pc = 0x150;
+            let mut debugger = Debugger::default();
+            debugger.add_bank_break(break_flags::EXECUTE, Address::new(bank, 0));
+            let result = debugger.run_for(&mut gb, 200);
+            assert_eq!(result == RunResult::ReachBreakpoint, hits, "bank {}", bank);
+            if hits {
+                assert_eq!(gb.cpu.pc, 0x4000);
+            }
+        }
+    }
+
+    #[test]
+    fn breakpoints_with_labels() {
+        use crate::disassembler::Label;
+        use break_flags::*;
+
+        let mut trace = Trace::new();
+        for address in [Address::new(0, 0x0150), Address::new(3, 0x0abc)] {
+            let name = format!("label_{:04x}", address.address);
+            trace.labels.insert(address, Label { address, name });
+        }
+
+        let mut debugger = Debugger::default();
+        debugger.add_break(EXECUTE, 0x0150);
+        debugger.add_break(JUMP, 0x0200);
+        debugger.add_break(READ, 0x4abc);
+        debugger.add_break(WRITE, 0xc000);
+
+        assert_eq!(
+            debugger.breakpoints_with_labels((0, 3), &trace),
+            [
+                (0x0150, EXECUTE, Some("label_0150".to_string())),
+                (0x0200, JUMP, None),
+                (0x4abc, READ, Some("label_0abc".to_string())),
+                (0xc000, WRITE, None),
+            ]
+        );
+        // the label is in a bank that is not mapped
+        assert_eq!(debugger.breakpoints_with_labels((0, 1), &trace)[2].2, None);
+    }
+}
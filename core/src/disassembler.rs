@@ -8,6 +8,7 @@ use std::{
 use crate::{
     consts,
     gameboy::{cartridge::Cartridge, GameBoy},
+    interpreter::{Condition, Reg, Reg16},
 };
 
 struct ReallySigned(i8);
@@ -56,7 +57,7 @@ impl Address {
         }
     }
 
-    fn to_pc(self) -> u16 {
+    pub fn to_pc(self) -> u16 {
         if self.bank == 0 {
             self.address
         } else {
@@ -64,7 +65,7 @@ impl Address {
         }
     }
 
-    fn as_cursor(&self, cart: &Cartridge) -> Cursor {
+    pub(crate) fn as_cursor(&self, cart: &Cartridge) -> Cursor {
         let pc = self.to_pc();
         Cursor {
             bank0: cart.bank0_from_bank(self.bank),
@@ -97,6 +98,19 @@ impl Label {
     }
 }
 
+/// A external source of labels and comments for the disassembly, like a symbol file or a
+/// automatic analysis, that is used in addition to the labels of the `Trace`.
+pub trait LabelProvider {
+    /// The name of the label at the given address, if any. Takes precedence over the labels of the
+    /// `Trace`.
+    fn label(&self, address: Address) -> Option<String>;
+
+    /// A comment to be shown after the instruction at the given address, if any.
+    fn comment(&self, _address: Address) -> Option<String> {
+        None
+    }
+}
+
 pub struct Cursor {
     /// The currently active bank in the 0 to 3FFF range.
     pub bank0: u16,
@@ -113,7 +127,7 @@ impl Cursor {
         let bank = self.bank.unwrap_or(self.bank0);
 
         let read_pc = |of| {
-            if self.pc.wrapping_add(of) < 0x7FFF {
+            if self.pc.wrapping_add(of) <= 0x7FFF {
                 // read ROM
                 rom.cartridge.read_at_bank(bank, self.pc.wrapping_add(of))
             } else {
@@ -139,6 +153,15 @@ pub struct Directive {
     pub op: [u8; 3],
 }
 
+/// A function found by `Trace::functions`.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Function {
+    /// The entry point of the function, the target of a CALL or RST.
+    pub entry: Address,
+    /// The ranges of code of the function, sorted and non overlapping.
+    pub ranges: Vec<Range<Address>>,
+}
+
 pub struct Trace {
     /// Disassembled directives
     pub directives: BTreeSet<Directive>,
@@ -148,6 +171,12 @@ pub struct Trace {
     pub labels: BTreeMap<Address, Label>,
     /// Map from a opcode (like jp or call) to another address
     pub jumps: BTreeMap<Address, Address>,
+    /// The reverse of `jumps`. Map from a address to all opcodes that jump to it.
+    references: BTreeMap<Address, BTreeSet<Address>>,
+    /// The opcodes executed from RAM (8000-FFFF), with their length. Because `Address` only
+    /// refers to the ROM, these are keyed by the memory address instead, and are only recorded as
+    /// they are executed, not traced.
+    ram_code: BTreeMap<u16, (u8, [u8; 3])>,
 }
 impl Default for Trace {
     fn default() -> Self {
@@ -161,7 +190,116 @@ impl Trace {
             code_ranges: Vec::new(),
             labels: Default::default(),
             jumps: Default::default(),
+            references: Default::default(),
+            ram_code: Default::default(),
+        }
+    }
+
+    /// Record that the opcode `op` was executed from RAM at the given `address`. If a different
+    /// opcode was executed there before, like in self-modifying code, it is replaced.
+    pub fn add_ram_opcode(&mut self, address: u16, op: &[u8]) {
+        debug_assert!(address >= 0x8000);
+        let mut op_array = [0; 3];
+        op_array[0..op.len()].copy_from_slice(op);
+        let entry = (op.len() as u8, op_array);
+        if self.ram_code.get(&address) != Some(&entry) {
+            self.ram_code.insert(address, entry);
+        }
+    }
+
+    /// The opcodes that were executed from RAM, as (address, length, opcode), in order of address.
+    /// Only the last opcode executed at each address is kept.
+    pub fn ram_code_regions(&self) -> Vec<(u16, u8, [u8; 3])> {
+        self.ram_code
+            .iter()
+            .map(|(&address, &(len, op))| (address, len, op))
+            .collect()
+    }
+
+    /// Return true if the byte at the given RAM address is part of a opcode that was executed.
+    pub fn is_ram_code(&self, address: u16) -> bool {
+        self.ram_code
+            .range(..=address)
+            .next_back()
+            .is_some_and(|(&start, &(len, _))| (address as u32) < start as u32 + len as u32)
+    }
+
+    /// Return the address of all traced opcodes (like jp or call) that jump to the given address.
+    pub fn references_to(&self, address: Address) -> Vec<Address> {
+        self.references
+            .get(&address)
+            .map_or_else(Vec::new, |x| x.iter().copied().collect())
+    }
+
+    /// Return the addresses that the opcode at the given address jumps to, if any.
+    pub fn references_from(&self, address: Address) -> Vec<Address> {
+        self.jumps.get(&address).copied().into_iter().collect()
+    }
+
+    /// Group the traced code into functions. Each target of a CALL or RST is a function entry,
+    /// and the function extends to all code reachable from it, until a RET, RETI or unconditional
+    /// jump, without passing through another entry. So a jump to another function is considered a
+    /// tail call, and is not part of the function.
+    pub fn functions(&self) -> Vec<Function> {
+        let directives: BTreeMap<Address, &Directive> =
+            self.directives.iter().map(|x| (x.address, x)).collect();
+
+        let is_call = |op: u8| op == 0xCD || op & 0b11100111 == 0b11000100;
+        let is_rst = |op: u8| op & 0b11000111 == 0b11000111;
+        let entries: BTreeSet<Address> = self
+            .directives
+            .iter()
+            .filter(|x| is_call(x.op[0]) || is_rst(x.op[0]))
+            .filter_map(|x| self.jumps.get(&x.address).copied())
+            .collect();
+
+        let mut functions = Vec::new();
+        for &entry in &entries {
+            let mut visited = BTreeSet::new();
+            let mut queue = vec![entry];
+            while let Some(address) = queue.pop() {
+                let Some(directive) = directives.get(&address) else {
+                    continue;
+                };
+                if !visited.insert(address) {
+                    continue;
+                }
+                let op = directive.op[0];
+
+                // JP, JP (HL), JR, RET and RETI don't continue to the next opcode.
+                let ends = matches!(op, 0xC3 | 0xE9 | 0x18 | 0xC9 | 0xD9);
+                let next = address.address + directive.len;
+                if !ends && next <= 0x3FFF {
+                    let next = Address::new(address.bank, next);
+                    if !entries.contains(&next) {
+                        queue.push(next);
+                    }
+                }
+
+                if !is_call(op) && !is_rst(op) {
+                    if let Some(&to) = self.jumps.get(&address) {
+                        if !entries.contains(&to) {
+                            queue.push(to);
+                        }
+                    }
+                }
+            }
+
+            let mut ranges: Vec<Range<Address>> = Vec::new();
+            for address in visited {
+                let len = directives[&address].len;
+                let end = Address {
+                    bank: address.bank,
+                    address: address.address + len,
+                };
+                match ranges.last_mut() {
+                    Some(last) if last.end == address => last.end = end,
+                    _ => ranges.push(address..end),
+                }
+            }
+            functions.push(Function { entry, ranges });
         }
+        functions
     }
 
     /// Disassembly some opcodes above and below, respecting `code_ranges`
@@ -342,7 +480,12 @@ impl Trace {
     fn add_jump(&mut self, from: Address, to: Address) {
         if let Some(x) = self.add_label(to) {
             let to = x.address;
-            self.jumps.insert(from, to);
+            if let Some(old) = self.jumps.insert(from, to) {
+                if let Some(refs) = self.references.get_mut(&old) {
+                    refs.remove(&from);
+                }
+            }
+            self.references.entry(to).or_default().insert(from);
         }
     }
 
@@ -913,6 +1056,271 @@ pub fn disassembly_opcode(
     }
 }
 
+/// Decode the single instruction at the start of `bytes`, located at `pc`, returning its mnemonic
+/// and its length. Only the first `consts::LEN` bytes are used, and missing bytes are read as 0.
+/// Addresses are not labeled, and illegal opcodes are decoded as `DB   $xx`.
+pub fn decode_one(bytes: &[u8], pc: u16) -> (String, u8) {
+    let op = bytes.first().copied().unwrap_or(0);
+    let len = consts::LEN[op as usize];
+    let mut padded = [0; 3];
+    for (a, &b) in padded.iter_mut().zip(bytes) {
+        *a = b;
+    }
+
+    let mut mnemonic = String::new();
+    disassembly_opcode(
+        pc,
+        &padded[..len as usize],
+        |x| format!("${:04x}", x),
+        &mut mnemonic,
+    )
+    .unwrap();
+    mnemonic.truncate(mnemonic.trim_end().len());
+    if mnemonic.is_empty() {
+        mnemonic = format!("DB   ${:02x}", op);
+    }
+    (mnemonic, len)
+}
+
+/// A 8-bit operand of a decoded `Instruction`.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Operand {
+    /// A register, or the memory pointed by a register pair, with the same meaning as in the
+    /// interpreter: `Reg::HL` is `(HL)`, `Reg::HLI` is `(HL+)` and `Reg::HLD` is `(HL-)`. Never
+    /// `Reg::Im8` or `Reg::Im16`.
+    Reg(Reg),
+    /// A immediate value, `d8`.
+    Imm8(u8),
+    /// The memory at a immediate address, `(a16)`.
+    Addr(u16),
+    /// The memory at `$FF00 + a8`, used by `LDH`.
+    HighAddr(u8),
+    /// The memory at `$FF00 + C`.
+    HighC,
+}
+
+/// The operation of the `ADD`, `ADC`, `SUB`, `SBC`, `AND`, `XOR`, `OR` and `CP` instructions.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum AluOp {
+    Add,
+    Adc,
+    Sub,
+    Sbc,
+    And,
+    Xor,
+    Or,
+    Cp,
+}
+
+/// The rotate and shift operations of the CB prefixed instructions.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum RotOp {
+    Rlc,
+    Rrc,
+    Rl,
+    Rr,
+    Sla,
+    Sra,
+    Swap,
+    Srl,
+}
+
+/// A decoded instruction, returned by `decode_instruction`. The targets of relative jumps are
+/// already resolved to absolute addresses. `Condition::None` is used by the unconditional jumps,
+/// calls and returns.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Instruction {
+    Nop,
+    Stop,
+    Halt,
+    Di,
+    Ei,
+    Daa,
+    Cpl,
+    Scf,
+    Ccf,
+    Rlca,
+    Rrca,
+    Rla,
+    Rra,
+    /// A 8-bit load, including `LDH`.
+    Ld {
+        dst: Operand,
+        src: Operand,
+    },
+    /// `LD rr, d16`
+    Ld16(Reg16, u16),
+    /// `LD (a16), SP`
+    LdAddrSp(u16),
+    /// `LD SP, HL`
+    LdSpHl,
+    /// `LD HL, SP+r8`
+    LdHlSp(i8),
+    /// `ADD SP, r8`
+    AddSp(i8),
+    /// `ADD HL, rr`
+    AddHl(Reg16),
+    Inc(Operand),
+    Dec(Operand),
+    Inc16(Reg16),
+    Dec16(Reg16),
+    /// A operation between A and the operand, with the result stored in A (except for `CP`).
+    Alu(AluOp, Operand),
+    Push(Reg16),
+    Pop(Reg16),
+    Jr(Condition, u16),
+    Jp(Condition, u16),
+    /// `JP HL`
+    JpHl,
+    Call(Condition, u16),
+    Ret(Condition),
+    Reti,
+    /// `RST`, with the address of the call.
+    Rst(u8),
+    Rot(RotOp, Operand),
+    /// `BIT`, with the number of the tested bit.
+    Bit(u8, Operand),
+    Res(u8, Operand),
+    Set(u8, Operand),
+    /// One of the illegal opcodes, that lock up the CPU.
+    Invalid(u8),
+}
+
+/// Decode the single instruction at the start of `bytes`, located at `pc`. Return None if `bytes`
+/// is shorter than the instruction.
+pub fn decode_instruction(bytes: &[u8], pc: u16) -> Option<Instruction> {
+    use Instruction::*;
+
+    let op = *bytes.first()?;
+    let len = consts::LEN[op as usize] as usize;
+    if bytes.len() < len {
+        return None;
+    }
+    let n = bytes.get(1).copied().unwrap_or(0);
+    let nn = u16::from_le_bytes([n, bytes.get(2).copied().unwrap_or(0)]);
+
+    let r = |i: u8| {
+        Operand::Reg(
+            [
+                Reg::B,
+                Reg::C,
+                Reg::D,
+                Reg::E,
+                Reg::H,
+                Reg::L,
+                Reg::HL,
+                Reg::A,
+            ][i as usize],
+        )
+    };
+    let rp = |i: u8| [Reg16::BC, Reg16::DE, Reg16::HL, Reg16::SP][i as usize];
+    let rp2 = |i: u8| [Reg16::BC, Reg16::DE, Reg16::HL, Reg16::AF][i as usize];
+    let cc = |i: u8| [Condition::NZ, Condition::Z, Condition::NC, Condition::C][i as usize];
+    let alu = |i: u8| {
+        use AluOp::*;
+        [Add, Adc, Sub, Sbc, And, Xor, Or, Cp][i as usize]
+    };
+    let a = Operand::Reg(Reg::A);
+
+    let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+    let (p, q) = (y >> 1, y & 1);
+    let instruction = match (x, z) {
+        (0, 0) => match y {
+            0 => Nop,
+            1 => LdAddrSp(nn),
+            2 => Stop,
+            _ => {
+                let target = pc.wrapping_add(2).wrapping_add(n as i8 as u16);
+                Jr(if y == 3 { Condition::None } else { cc(y - 4) }, target)
+            }
+        },
+        (0, 1) if q == 0 => Ld16(rp(p), nn),
+        (0, 1) => AddHl(rp(p)),
+        (0, 2) => {
+            let mem = Operand::Reg([Reg::BC, Reg::DE, Reg::HLI, Reg::HLD][p as usize]);
+            if q == 0 {
+                Ld { dst: mem, src: a }
+            } else {
+                Ld { dst: a, src: mem }
+            }
+        }
+        (0, 3) if q == 0 => Inc16(rp(p)),
+        (0, 3) => Dec16(rp(p)),
+        (0, 4) => Inc(r(y)),
+        (0, 5) => Dec(r(y)),
+        (0, 6) => Ld {
+            dst: r(y),
+            src: Operand::Imm8(n),
+        },
+        (0, _) => [Rlca, Rrca, Rla, Rra, Daa, Cpl, Scf, Ccf][y as usize],
+        (1, _) if op == 0x76 => Halt,
+        (1, _) => Ld {
+            dst: r(y),
+            src: r(z),
+        },
+        (2, _) => Alu(alu(y), r(z)),
+        (_, 0) => match y {
+            0..=3 => Ret(cc(y)),
+            4 => Ld {
+                dst: Operand::HighAddr(n),
+                src: a,
+            },
+            5 => AddSp(n as i8),
+            6 => Ld {
+                dst: a,
+                src: Operand::HighAddr(n),
+            },
+            _ => LdHlSp(n as i8),
+        },
+        (_, 1) if q == 0 => Pop(rp2(p)),
+        (_, 1) => [Ret(Condition::None), Reti, JpHl, LdSpHl][p as usize],
+        (_, 2) => match y {
+            0..=3 => Jp(cc(y), nn),
+            4 => Ld {
+                dst: Operand::HighC,
+                src: a,
+            },
+            5 => Ld {
+                dst: Operand::Addr(nn),
+                src: a,
+            },
+            6 => Ld {
+                dst: a,
+                src: Operand::HighC,
+            },
+            _ => Ld {
+                dst: a,
+                src: Operand::Addr(nn),
+            },
+        },
+        (_, 3) => match y {
+            0 => Jp(Condition::None, nn),
+            1 => {
+                let (x, y, z) = (n >> 6, (n >> 3) & 7, n & 7);
+                match x {
+                    0 => {
+                        use RotOp::*;
+                        Rot([Rlc, Rrc, Rl, Rr, Sla, Sra, Swap, Srl][y as usize], r(z))
+                    }
+                    1 => Bit(y, r(z)),
+                    2 => Res(y, r(z)),
+                    _ => Set(y, r(z)),
+                }
+            }
+            6 => Di,
+            7 => Ei,
+            _ => Invalid(op),
+        },
+        (_, 4) if y < 4 => Call(cc(y), nn),
+        (_, 5) if q == 0 => Push(rp2(p)),
+        (_, 5) if p == 0 => Call(Condition::None, nn),
+        (_, 4) | (_, 5) => Invalid(op),
+        (_, 6) => Alu(alu(y), Operand::Imm8(n)),
+        (_, _) => Rst(y * 8),
+    };
+    Some(instruction)
+}
+
 fn disassembly_opcode_cr(op: u8, w: &mut impl Write) -> fmt::Result {
     match op {
         0x00 => write!(w, "RLC  B "),
@@ -1173,3 +1581,223 @@ fn disassembly_opcode_cr(op: u8, w: &mut impl Write) -> fmt::Result {
         0xff => write!(w, "SET  7,A "),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::gameboy::{cartridge::Cartridge, GameBoy};
+
+    use super::*;
+
+    #[test]
+    fn references_to() {
+        let mut rom = Cartridge::nop_rom();
+        rom[0x150..0x159].copy_from_slice(&[
+            0xcd, 0x00, 0x02, // CALL $0200
+            0xcd, 0x00, 0x02, // CALL $0200
+            0xc3, 0x50, 0x01, // JP $0150
+        ]);
+        rom[0x200] = 0xc9; // RET
+        let gb = GameBoy::new(None, Cartridge::new(rom).unwrap());
+
+        let mut trace = Trace::new();
+        trace.trace_starting_at(&gb, (0, 1), 0x150, None);
+
+        assert_eq!(
+            trace.references_to(Address::new(0, 0x200)),
+            [Address::new(0, 0x150), Address::new(0, 0x153)]
+        );
+        assert_eq!(
+            trace.references_to(Address::new(0, 0x150)),
+            [Address::new(0, 0x156)]
+        );
+        assert_eq!(
+            trace.references_from(Address::new(0, 0x153)),
+            [Address::new(0, 0x200)]
+        );
+        assert!(trace.references_from(Address::new(0, 0x200)).is_empty());
+    }
+
+    #[test]
+    fn functions() {
+        let mut rom = Cartridge::nop_rom();
+        rom[0x150..0x159].copy_from_slice(&[
+            0xcd, 0x00, 0x02, // CALL $0200
+            0xcd, 0x00, 0x03, // CALL $0300
+            0xc3, 0x50, 0x01, // JP $0150
+        ]);
+        // a function with two returns
+        rom[0x200..0x207].copy_from_slice(&[
+            0x78, // LD A, B
+            0xa7, // AND A
+            0x28, 0x01, // JR Z, $0205
+            0xc9, // RET
+            0x3c, // INC A
+            0xc9, // RET
+        ]);
+        // a function that tail calls the first one
+        rom[0x300..0x304].copy_from_slice(&[
+            0x04, // INC B
+            0xc3, 0x00, 0x02, // JP $0200
+        ]);
+        let gb = GameBoy::new(None, Cartridge::new(rom).unwrap());
+
+        let mut trace = Trace::new();
+        trace.trace_starting_at(&gb, (0, 1), 0x150, None);
+
+        let at = |x| Address::new(0, x);
+        assert_eq!(
+            trace.functions(),
+            [
+                Function {
+                    entry: at(0x200),
+                    ranges: vec![at(0x200)..at(0x207)],
+                },
+                Function {
+                    entry: at(0x300),
+                    ranges: vec![at(0x300)..at(0x304)],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn decode_one() {
+        use super::decode_one;
+        assert_eq!(decode_one(&[0x00], 0x100), ("NOP".to_string(), 1));
+        assert_eq!(
+            decode_one(&[0x3e, 0x12, 0xff], 0),
+            ("LD   A, $12".to_string(), 2)
+        );
+        assert_eq!(
+            decode_one(&[0xc3, 0x50, 0x01], 0x100),
+            ("JP   $0150".to_string(), 3)
+        );
+        assert_eq!(
+            decode_one(&[0x18, 0xfe], 0x150),
+            ("JR   $0150".to_string(), 2)
+        );
+        assert_eq!(decode_one(&[0xcb, 0x7c], 0), ("BIT  7,H".to_string(), 2));
+        assert_eq!(decode_one(&[0xcb, 0x37], 0), ("SWAP A".to_string(), 2));
+        // illegal opcodes
+        assert_eq!(
+            decode_one(&[0xd3, 0x01, 0x02], 0),
+            ("DB   $d3".to_string(), 1)
+        );
+        assert_eq!(decode_one(&[0xfd], 0), ("DB   $fd".to_string(), 1));
+        // missing bytes are read as 0
+        assert_eq!(decode_one(&[0xc3], 0), ("JP   $0000".to_string(), 3));
+        assert_eq!(decode_one(&[], 0), ("NOP".to_string(), 1));
+    }
+
+    #[test]
+    fn decode_instruction() {
+        use super::decode_instruction as decode;
+        use crate::interpreter::Reg as R;
+        use Instruction::*;
+        use Operand::*;
+
+        assert_eq!(decode(&[0x00], 0), Some(Nop));
+        assert_eq!(
+            decode(&[0x3e, 0x12, 0xff], 0),
+            Some(Ld {
+                dst: Reg(R::A),
+                src: Imm8(0x12)
+            })
+        );
+        assert_eq!(
+            decode(&[0x22], 0),
+            Some(Ld {
+                dst: Reg(R::HLI),
+                src: Reg(R::A)
+            })
+        );
+        assert_eq!(
+            decode(&[0xfa, 0x34, 0x12], 0),
+            Some(Ld {
+                dst: Reg(R::A),
+                src: Addr(0x1234)
+            })
+        );
+        assert_eq!(
+            decode(&[0xe0, 0x40], 0),
+            Some(Ld {
+                dst: HighAddr(0x40),
+                src: Reg(R::A)
+            })
+        );
+        assert_eq!(
+            decode(&[0x31, 0xfe, 0xff], 0),
+            Some(Ld16(Reg16::SP, 0xfffe))
+        );
+        assert_eq!(decode(&[0x86], 0), Some(Alu(AluOp::Add, Reg(R::HL))));
+        assert_eq!(decode(&[0xfe, 0x90], 0), Some(Alu(AluOp::Cp, Imm8(0x90))));
+        assert_eq!(
+            decode(&[0xc2, 0x50, 0x01], 0),
+            Some(Jp(Condition::NZ, 0x0150))
+        );
+        assert_eq!(
+            decode(&[0x18, 0xfe], 0x150),
+            Some(Jr(Condition::None, 0x150))
+        );
+        assert_eq!(decode(&[0x38, 0x10], 0x150), Some(Jr(Condition::C, 0x162)));
+        assert_eq!(
+            decode(&[0xcd, 0x00, 0x40], 0),
+            Some(Call(Condition::None, 0x4000))
+        );
+        assert_eq!(decode(&[0xc8], 0), Some(Ret(Condition::Z)));
+        assert_eq!(decode(&[0xc9], 0), Some(Ret(Condition::None)));
+        assert_eq!(decode(&[0xf1], 0), Some(Pop(Reg16::AF)));
+        assert_eq!(decode(&[0xef], 0), Some(Rst(0x28)));
+        assert_eq!(decode(&[0xf8, 0xff], 0), Some(LdHlSp(-1)));
+        assert_eq!(decode(&[0x76], 0), Some(Halt));
+        assert_eq!(decode(&[0xcb, 0x7c], 0), Some(Bit(7, Reg(R::H))));
+        assert_eq!(decode(&[0xcb, 0x37], 0), Some(Rot(RotOp::Swap, Reg(R::A))));
+        assert_eq!(decode(&[0xcb, 0xc6], 0), Some(Set(0, Reg(R::HL))));
+        assert_eq!(decode(&[0xd3, 0x01], 0), Some(Invalid(0xd3)));
+        // missing bytes
+        assert_eq!(decode(&[0xc3, 0x00], 0), None);
+        assert_eq!(decode(&[], 0), None);
+
+        // the illegal opcodes are the ones that `decode_one` can't disassembly
+        for op in 0..=0xffu8 {
+            let bytes = [op, 0, 0];
+            let invalid = decode(&bytes, 0) == Some(Invalid(op));
+            let mnemonic = super::decode_one(&bytes, 0).0;
+            assert_eq!(invalid, mnemonic.starts_with("DB"), "{:02x}", op);
+        }
+    }
+
+    #[test]
+    fn op_crossing_bank_boundary() {
+        // a MBC1 rom with 4 banks, with a `LD HL, $xx34` at 0x3FFE, where the last byte is the
+        // number of the bank at 0x4000, and a `LD HL` at 0x7FFE, whose low byte depends on the bank.
+        let mut rom = vec![0x00; 4 * 0x4000];
+        rom[0x147] = 0x01;
+        rom[0x148] = 0x01;
+        rom[0x3FFE..0x4000].copy_from_slice(&[0x21, 0x34]);
+        for bank in 1..4 {
+            rom[bank * 0x4000] = bank as u8;
+            rom[bank * 0x4000 + 0x3FFE..][..2].copy_from_slice(&[0x21, 0x50 + bank as u8]);
+        }
+        let mut gb = GameBoy::new(None, Cartridge::new(rom).unwrap());
+
+        // select bank 2
+        gb.write(0x2000, 2);
+
+        gb.cpu.pc = 0x3FFE;
+        crate::interpreter::Interpreter(&mut gb).interpret_op();
+        assert_eq!((gb.cpu.h, gb.cpu.l), (2, 0x34));
+
+        let cursor = |bank, pc| Cursor {
+            bank0: 0,
+            bank: Some(bank),
+            pc,
+            reg_a: None,
+        };
+        assert_eq!(cursor(2, 0x3FFE).get_op(&gb), ([0x21, 0x34, 2], 3));
+        assert_eq!(cursor(3, 0x3FFE).get_op(&gb), ([0x21, 0x34, 3], 3));
+
+        // the last byte of the ROM is read from the cursor's bank, not the currently mapped one.
+        assert_eq!(cursor(3, 0x7FFE).get_op(&gb).0[..2], [0x21, 0x53]);
+    }
+}
@@ -0,0 +1,234 @@
+//! A C ABI for embedding the emulator in non-Rust hosts, enabled by the `ffi` feature. The
+//! declarations are in `include/gameroy.h`.
+//!
+//! The emulator is behind an opaque pointer, created by [`gameroy_new`] and destroyed by
+//! [`gameroy_free`]. Panics are caught at the boundary, and reported as a failure of the function.
+//! After a panic, the emulator may be left in a inconsistent state, and should only be freed.
+//!
+//! The crate is a `rlib`, so the host must build it as a `cdylib` or `staticlib`, with `cargo
+//! rustc -p gameroy-core --features ffi --crate-type cdylib`, for example.
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use crate::{
+    audio_ring::normalize,
+    consts::{FRAME_CYCLES, SCREEN_HEIGHT, SCREEN_WIDTH},
+    gameboy::{cartridge::Cartridge, CaptureTrigger, GameBoy},
+    interpreter::Interpreter,
+};
+
+/// The size in bytes of the RGBA buffer passed to `gameroy_run_frame`.
+pub const FRAME_BUFFER_SIZE: usize = SCREEN_WIDTH * SCREEN_HEIGHT * 4;
+
+/// The RGBA colors of the 4 shades of the screen, from lightest to darkest.
+const PALETTE: [[u8; 4]; 4] = [
+    [0xFF, 0xFF, 0xFF, 0xFF],
+    [0xAA, 0xAA, 0xAA, 0xFF],
+    [0x55, 0x55, 0x55, 0xFF],
+    [0x00, 0x00, 0x00, 0xFF],
+];
+
+/// The emulator state behind the opaque pointer.
+pub struct Gameroy {
+    gb: GameBoy,
+    /// The last complete frame, as a `Screen::packed`.
+    frame: Vec<u8>,
+    /// Samples that did not fit in the buffer of the last `gameroy_drain_audio`.
+    audio: Vec<u16>,
+}
+
+/// Create a emulator, running the ROM in `rom[..len]`, with the sound sampled at `sample_rate`
+/// Hz (0 disables the sound). The ROM is copied. Return null if the ROM is invalid.
+///
+/// # Safety
+///
+/// `rom` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn gameroy_new(rom: *const u8, len: usize, sample_rate: u32) -> *mut Gameroy {
+    if rom.is_null() {
+        return std::ptr::null_mut();
+    }
+    let rom = std::slice::from_raw_parts(rom, len).to_vec();
+    catch_unwind(|| {
+        let Ok(cartridge) = Cartridge::new(rom) else {
+            return std::ptr::null_mut();
+        };
+        let mut gb = GameBoy::new(None, cartridge);
+        gb.sound.get_mut().sample_frequency = sample_rate as u64;
+        let frame = gb.ppu.get_mut().screen().packed().to_vec();
+        Box::into_raw(Box::new(Gameroy {
+            gb,
+            frame,
+            audio: Vec::new(),
+        }))
+    })
+    .unwrap_or(std::ptr::null_mut())
+}
+
+/// Destroy a emulator created by `gameroy_new`. Does nothing if `gameroy` is null.
+///
+/// # Safety
+///
+/// `gameroy` must be null or a pointer returned by `gameroy_new`, not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn gameroy_free(gameroy: *mut Gameroy) {
+    if gameroy.is_null() {
+        return;
+    }
+    let gameroy = Box::from_raw(gameroy);
+    let _ = catch_unwind(AssertUnwindSafe(|| drop(gameroy)));
+}
+
+/// Set the pressed buttons. A 1 bit means pressed, and from bit 7 to 0 the order is: Start,
+/// Select, B, A, Down, Up, Left, Right.
+///
+/// # Safety
+///
+/// `gameroy` must be a valid pointer returned by `gameroy_new`.
+#[no_mangle]
+pub unsafe extern "C" fn gameroy_set_buttons(gameroy: *mut Gameroy, buttons: u8) -> bool {
+    let Some(gameroy) = gameroy.as_mut() else {
+        return false;
+    };
+    catch_unwind(AssertUnwindSafe(|| gameroy.gb.set_joypad(!buttons))).is_ok()
+}
+
+/// Run the emulation for a frame, and write the last complete frame to `rgba`, in RGBA, row by
+/// row. Return false if `rgba` is smaller than `FRAME_BUFFER_SIZE`, or if the emulation panicked.
+///
+/// While the LCD is off, the last frame before it was turned off is kept.
+///
+/// # Safety
+///
+/// `gameroy` must be a valid pointer returned by `gameroy_new`, and `rgba` must point to `len`
+/// writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn gameroy_run_frame(
+    gameroy: *mut Gameroy,
+    rgba: *mut u8,
+    len: usize,
+) -> bool {
+    let Some(gameroy) = gameroy.as_mut() else {
+        return false;
+    };
+    if rgba.is_null() || len < FRAME_BUFFER_SIZE {
+        return false;
+    }
+    let rgba = std::slice::from_raw_parts_mut(rgba, FRAME_BUFFER_SIZE);
+    catch_unwind(AssertUnwindSafe(|| {
+        let gb = &mut gameroy.gb;
+        gb.capture_on(CaptureTrigger::Frame(1));
+        let target = gb.clock_count + FRAME_CYCLES;
+        while gb.clock_count < target {
+            Interpreter(gb).interpret_op();
+        }
+        if let Some(frame) = gb.take_capture() {
+            gameroy.frame = frame;
+        }
+
+        let samples = gb.sound.get_mut().get_output(gb.clock_count);
+        gameroy.audio.extend(samples);
+
+        for (pixel, &shade) in rgba.chunks_exact_mut(4).zip(&gameroy.frame) {
+            pixel.copy_from_slice(&PALETTE[shade as usize & 0b11]);
+        }
+    }))
+    .is_ok()
+}
+
+/// Move the audio generated by the last frames to `out`, as interleaved stereo samples from 0.0 to
+/// 1.0. Return the number of values written, which is at most `len`. The values that don't fit
+/// are kept for the next call.
+///
+/// # Safety
+///
+/// `gameroy` must be a valid pointer returned by `gameroy_new`, and `out` must point to `len`
+/// writable floats.
+#[no_mangle]
+pub unsafe extern "C" fn gameroy_drain_audio(
+    gameroy: *mut Gameroy,
+    out: *mut f32,
+    len: usize,
+) -> usize {
+    let Some(gameroy) = gameroy.as_mut() else {
+        return 0;
+    };
+    if out.is_null() {
+        return 0;
+    }
+    let out = std::slice::from_raw_parts_mut(out, len);
+    catch_unwind(AssertUnwindSafe(|| {
+        // keep the left and right channels together
+        let n = gameroy.audio.len().min(len & !1);
+        for (a, b) in out.iter_mut().zip(gameroy.audio.drain(..n)) {
+            *a = normalize(b);
+        }
+        n
+    }))
+    .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_through_ffi() {
+        let mut rom = Cartridge::nop_rom();
+        rom[0x100..0x10e].copy_from_slice(&[
+            0x3e, 0x80, // LD A, $80
+            0xe0, 0x26, // LDH ($26), A
+            0x3e, 0x77, // LD A, $77
+            0xe0, 0x24, // LDH ($24), A
+            0x3e, 0xff, // LD A, $FF
+            0xe0, 0x47, // LDH ($47), A
+            0x18, 0xfe, // JR -2
+        ]);
+
+        let new: unsafe extern "C" fn(*const u8, usize, u32) -> *mut Gameroy = gameroy_new;
+        let run_frame: unsafe extern "C" fn(*mut Gameroy, *mut u8, usize) -> bool =
+            gameroy_run_frame;
+        let drain_audio: unsafe extern "C" fn(*mut Gameroy, *mut f32, usize) -> usize =
+            gameroy_drain_audio;
+
+        unsafe {
+            assert!(new(std::ptr::null(), 0, 0).is_null());
+            assert!(new([0u8; 16].as_ptr(), 16, 0).is_null());
+
+            let gameroy = new(rom.as_ptr(), rom.len(), 48000);
+            assert!(!gameroy.is_null());
+            let start = (*gameroy).gb.clock_count;
+            assert!(gameroy_set_buttons(gameroy, 0x81));
+            assert_eq!((*gameroy).gb.joypad, 0x7E);
+
+            let mut frame = vec![0x12u8; FRAME_BUFFER_SIZE];
+            assert!(!run_frame(gameroy, frame.as_mut_ptr(), frame.len() - 1));
+            assert!(frame.iter().all(|&x| x == 0x12));
+            for _ in 0..3 {
+                assert!(run_frame(gameroy, frame.as_mut_ptr(), frame.len()));
+            }
+            // BGP is $FF, so the background is the darkest shade
+            assert!(frame.chunks(4).all(|x| x == [0x00, 0x00, 0x00, 0xFF]));
+
+            // 3 frames at 48000 Hz, in small chunks
+            let mut audio = Vec::new();
+            let mut buffer = [-1.0f32; 301];
+            loop {
+                let n = drain_audio(gameroy, buffer.as_mut_ptr(), buffer.len());
+                assert!(n <= 300 && n & 1 == 0);
+                if n == 0 {
+                    break;
+                }
+                audio.extend_from_slice(&buffer[..n]);
+            }
+            let gb = &(*gameroy).gb;
+            let expected = gb.sound.borrow().estimate_samples(start, gb.clock_count);
+            assert!(expected > 0);
+            assert_eq!(audio.len(), expected);
+            assert!(audio.iter().all(|x| (0.0..=1.0).contains(x)));
+
+            gameroy_free(gameroy);
+            gameroy_free(std::ptr::null_mut());
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! A resumable way of running the emulation, for hosts that drive it from their own loop.
+//!
+//! Instead of blocking until a frame is complete, like `GameBoy::run_frames`, the host gives the
+//! [`FrameStepper`] the time that has passed, and polls it. Each poll runs the emulation for at
+//! most that time, and returns as soon as a frame is completed, so the host gets back control at
+//! each frame boundary, and never waits for more than the time it gave.
+
+use crate::{
+    consts,
+    gameboy::{CaptureTrigger, GameBoy},
+    interpreter::Interpreter,
+};
+
+/// The result of `FrameStepper::poll`.
+#[derive(PartialEq, Eq, Debug)]
+pub enum StepResult<'a> {
+    /// A frame was completed, as a `Screen::packed`. Poll again to continue to the next one.
+    Frame(&'a [u8]),
+    /// All the given time was run without completing a frame. Call `FrameStepper::add_time`
+    /// before polling again.
+    NeedMoreTime,
+}
+
+/// Run a `GameBoy` incrementally, yielding at the start of each VBlank.
+///
+/// The stepper uses the screen capture of the `GameBoy` (see `GameBoy::capture_on`), so no other
+/// capture should be requested while it is in use. Like in `GameBoy::run_frames`, while the LCD
+/// is off, a frame ends after `consts::FRAME_CYCLES` clocks, and repeats the current screen.
+pub struct FrameStepper {
+    /// The clock_count up to which the emulation may run.
+    target_clock: u64,
+    /// The clock_count at which the current frame ends if no VBlank happens before it, or None if
+    /// the current frame was not started yet.
+    frame_timeout: Option<u64>,
+    /// The last completed frame.
+    frame: Vec<u8>,
+}
+
+impl FrameStepper {
+    /// Create a stepper for `gb`, with no time to run yet.
+    pub fn new(gb: &GameBoy) -> Self {
+        Self {
+            target_clock: gb.clock_count,
+            frame_timeout: None,
+            frame: Vec::new(),
+        }
+    }
+
+    /// Give `clocks` more clock cycles for the emulation to run. Use `consts::CLOCK_SPEED` to
+    /// convert from real time.
+    pub fn add_time(&mut self, clocks: u64) {
+        self.target_clock += clocks;
+    }
+
+    /// The clock cycles that were given, but not run yet.
+    pub fn remaining_time(&self, gb: &GameBoy) -> u64 {
+        self.target_clock.saturating_sub(gb.clock_count)
+    }
+
+    /// Run `gb` until a frame completes, or until the given time runs out. The last instruction
+    /// may run a few clocks past the given time, which are discounted from the next call to
+    /// `add_time`.
+    pub fn poll(&mut self, gb: &mut GameBoy) -> StepResult<'_> {
+        let timeout = *self.frame_timeout.get_or_insert_with(|| {
+            gb.capture_on(CaptureTrigger::Frame(1));
+            gb.clock_count + consts::FRAME_CYCLES
+        });
+        while gb.clock_count < self.target_clock {
+            Interpreter(gb).interpret_op();
+            let frame = match gb.take_capture() {
+                Some(frame) => frame,
+                None if gb.clock_count >= timeout => {
+                    gb.capture_trigger = None;
+                    gb.update_ppu();
+                    gb.ppu.get_mut().screen().packed().to_vec()
+                }
+                None => continue,
+            };
+            self.frame = frame;
+            self.frame_timeout = None;
+            return StepResult::Frame(&self.frame);
+        }
+        StepResult::NeedMoreTime
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{consts::FRAME_CYCLES, gameboy::cartridge::Cartridge};
+
+    #[test]
+    fn one_frame_per_vblank() {
+        // JR -2 at the entry point
+        let mut gb = GameBoy::new(None, Cartridge::with_code(0x100, &[0x18, 0xFE]));
+        let mut stepper = FrameStepper::new(&gb);
+        assert_eq!(stepper.poll(&mut gb), StepResult::NeedMoreTime);
+
+        // Return the clock_count of each frame, driving the stepper in small increments.
+        let mut run = |gb: &mut GameBoy, frames: u64| {
+            let mut clocks = Vec::new();
+            let end = gb.clock_count + frames * FRAME_CYCLES;
+            while gb.clock_count < end {
+                stepper.add_time(1000);
+                while let StepResult::Frame(frame) = stepper.poll(gb) {
+                    assert_eq!(frame, &gb.ppu.get_mut().screen().packed()[..]);
+                    clocks.push(gb.clock_count);
+                }
+                assert_eq!(stepper.remaining_time(gb), 0);
+            }
+            clocks
+        };
+
+        let clocks = run(&mut gb, 10);
+        assert_eq!(clocks.len(), 10);
+        // each one at a VBlank, a frame apart
+        for w in clocks.windows(2) {
+            assert!(w[1] - w[0] <= FRAME_CYCLES + 8, "{:?}", w);
+            assert!(w[1] - w[0] >= FRAME_CYCLES - 8, "{:?}", w);
+        }
+
+        // with the LCD off, the frames still end a frame apart
+        gb.write(0xFF40, 0x00);
+        let clocks = run(&mut gb, 3);
+        assert_eq!(clocks.len(), 3);
+        for w in clocks.windows(2) {
+            assert!(w[1] - w[0] <= FRAME_CYCLES + 8, "{:?}", w);
+        }
+    }
+}
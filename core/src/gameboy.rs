@@ -1,13 +1,16 @@
 use std::cell::{Cell, RefCell};
 
 use crate::{
-    disassembler::Trace,
-    save_state::{LoadStateError, SaveState, SaveStateContext, SaveStateHeader},
+    consts,
+    disassembler::{decode_one, disassembly_opcode, Address, Trace},
+    interpreter::Interpreter,
+    save_state::{LoadStateError, SaveState, SaveStateContext, SaveStateHeader, StateLayout},
 };
 
 pub mod cartridge;
 pub mod cpu;
 pub mod ppu;
+pub mod render_threads;
 pub mod serial_transfer;
 pub mod sound_controller;
 pub mod timer;
@@ -22,15 +25,191 @@ type VBlankCallback = Box<dyn FnMut(&mut GameBoy) + Send>;
 #[cfg(target_arch = "wasm32")]
 type VBlankCallback = Box<dyn FnMut(&mut GameBoy)>;
 
+#[cfg(not(target_arch = "wasm32"))]
+type StatModeCallback = Box<dyn FnMut(u8, u8, u64) + Send>;
+#[cfg(target_arch = "wasm32")]
+type StatModeCallback = Box<dyn FnMut(u8, u8, u64)>;
+
+#[cfg(not(target_arch = "wasm32"))]
+type JoypadCallback = Box<dyn FnMut(u8) + Send>;
+#[cfg(target_arch = "wasm32")]
+type JoypadCallback = Box<dyn FnMut(u8)>;
+
+#[cfg(not(target_arch = "wasm32"))]
+type RomWriteCallback = Box<dyn FnMut(u16, u8) + Send>;
+#[cfg(target_arch = "wasm32")]
+type RomWriteCallback = Box<dyn FnMut(u16, u8)>;
+
+#[cfg(not(target_arch = "wasm32"))]
+type PpuWriteCallback = Box<dyn FnMut(u16, u8, u64) + Send>;
+#[cfg(target_arch = "wasm32")]
+type PpuWriteCallback = Box<dyn FnMut(u16, u8, u64)>;
+
+#[cfg(not(target_arch = "wasm32"))]
+type CgbRegisterCallback = Box<dyn FnMut(u16, u8) + Send>;
+#[cfg(target_arch = "wasm32")]
+type CgbRegisterCallback = Box<dyn FnMut(u16, u8)>;
+
+#[cfg(not(target_arch = "wasm32"))]
+type CpuTraceWriter = Box<dyn std::io::Write + Send>;
+#[cfg(target_arch = "wasm32")]
+type CpuTraceWriter = Box<dyn std::io::Write>;
+
+#[cfg(not(target_arch = "wasm32"))]
+type InfraredCallback = Box<dyn FnMut(bool) + Send>;
+#[cfg(target_arch = "wasm32")]
+type InfraredCallback = Box<dyn FnMut(bool)>;
+
+/// The hardware model being emulated. This only affects behaviors that are documented to differ
+/// between models, and the CGB WRAM and VRAM banks. The CGB colors and double speed are not
+/// emulated.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum Model {
+    #[default]
+    Dmg,
+    Cgb,
+}
+impl SaveState for Model {
+    fn save_state(
+        &self,
+        ctx: &mut SaveStateContext,
+        data: &mut impl std::io::Write,
+    ) -> Result<(), std::io::Error> {
+        (*self as u8).save_state(ctx, data)
+    }
+
+    fn load_state(
+        &mut self,
+        ctx: &mut SaveStateContext,
+        data: &mut impl std::io::Read,
+    ) -> Result<(), LoadStateError> {
+        let mut value = 0u8;
+        value.load_state(ctx, data)?;
+        *self = match value {
+            0 => Self::Dmg,
+            1 => Self::Cgb,
+            x => return Err(LoadStateError::InvalidModel(x)),
+        };
+        Ok(())
+    }
+}
+
+/// Information about a single step of the interpreter, returned by `GameBoy::step_instruction`.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct StepInfo {
+    /// The value of the program counter before the step.
+    pub pc: u16,
+    /// The disassembly of the executed instruction, like `LD   A, $12`. Empty if no instruction
+    /// was executed.
+    pub mnemonic: String,
+    /// The bytes of the executed instruction. Empty if no instruction was executed.
+    pub bytes: Vec<u8>,
+    /// The number of clock cycles that the step took.
+    pub cycles: u64,
+    /// True if a interrupt was serviced, instead of executing a instruction. The CPU is now at the
+    /// start of the interrupt handler.
+    pub interrupt: bool,
+}
+
+/// A component that differs between two `GameBoy`s, returned by `GameBoy::diff`.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum ComponentDiff {
+    Cpu,
+    Cartridge,
+    Timer,
+    Sound,
+    Ppu,
+    Serial,
+    /// The other state stored directly in the `GameBoy`, like the clock count, the joypad, the
+    /// boot ROM and the IF, IE and SVBK registers.
+    Registers,
+    /// A range of addresses of the Work RAM or High RAM that differ. `bank` is the WRAM bank of
+    /// the range, for D000-DFFF, or 0.
+    Memory {
+        bank: u8,
+        range: std::ops::Range<u16>,
+    },
+}
+
+/// When to capture the screen, see `GameBoy::capture_on`.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum CaptureTrigger {
+    /// At the start of the VBlank of the n-th frame completed from now, with the screen containing
+    /// that frame. `Frame(0)` captures the current screen immediately.
+    Frame(u64),
+    /// When the CPU is about to execute the instruction at this address. This is only checked by
+    /// the interpreter, so it is missed while a block compiled by the JIT is running.
+    Pc(u16),
+}
+
+/// A snapshot of the pixel pipeline of the PPU, returned by `GameBoy::step_ppu_dot`.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct PpuDotInfo {
+    pub ly: u8,
+    /// The mode in the STAT register.
+    pub mode: u8,
+    /// The x position of the next pixel to be draw to the screen.
+    pub screen_x: u8,
+    /// The x position in the scanline, including the discarded pixels. See `Ppu::scanline_x`.
+    pub scanline_x: u8,
+    /// The current step of the background pixel fetcher.
+    pub fetcher_step: u8,
+    /// The pixels in the background FIFO, from first to last to be popped.
+    pub background_fifo: Vec<u8>,
+    /// The pixels in the sprite FIFO, from first to last to be popped.
+    pub sprite_fifo: Vec<u8>,
+    /// True if a pixel was output to the screen in this dot.
+    pub pixel_output: bool,
+}
+
+/// The time spent in each part of the emulation, in nanoseconds, accumulated since the GameBoy
+/// was created or `reset_timing_profile` was called. Only measured with the `timing_profile`
+/// feature, see `GameBoy::timing_profile`.
+///
+/// The interpreter and the JIT times include the updates of the other components that happened
+/// inside them, so the parts don't add up to the total.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub struct TimingProfile {
+    /// Time spent in `Interpreter::interpret_op`.
+    pub interpreter: u64,
+    /// Time spent running blocks compiled by the JIT.
+    pub jit: u64,
+    /// Time spent in `Ppu::update`, emulating the pixel FIFO, excluding `ppu_draw_scan_line`.
+    pub ppu_fifo: u64,
+    /// Time spent drawing whole scanlines in the PPU fast path (`draw_scan_line`).
+    pub ppu_draw_scan_line: u64,
+    /// Time spent in `SoundController::update`.
+    pub sound: u64,
+    /// The number of frames completed, for computing the times per frame.
+    pub frames: u64,
+}
+
+/// The version of the save state format of `after_boot/sound.sav`. It was saved with the version 3,
+/// so it must be loaded as such, even after the format changes.
+const AFTER_BOOT_SOUND_VERSION: u32 = 3;
+
+/// The maximum number of clocks that `GameBoy::fast_boot` runs. The DMG boot ROM takes about 340
+/// frames, so a boot that takes twice that is assumed to have locked up.
+pub const FAST_BOOT_TIMEOUT: u64 = 680 * consts::FRAME_CYCLES;
+
 pub struct GameBoy {
+    pub model: Model,
     pub trace: RefCell<Trace>,
     pub cpu: Cpu,
     pub cartridge: Cartridge,
-    /// C000-DFFF: Work RAM
-    pub wram: [u8; 0x2000],
+    /// C000-DFFF: Work RAM. There are 8 banks of 4 KiB: C000-CFFF is always the bank 0, and
+    /// D000-DFFF is the bank selected by `wram_bank`. The DMG only has the banks 0 and 1.
+    pub wram: [u8; 0x8000],
+    /// FF70: WRAM Bank (SVBK), only in CGB. Only the bits 0-2 are stored, and the bank 0 selects
+    /// the bank 1.
+    pub wram_bank: u8,
     /// FF80-FFFE: High RAM
     pub hram: [u8; 0x7F],
     pub boot_rom: Option<[u8; 0x100]>,
+    /// The CGB boot ROM, used instead of `boot_rom` when `model` is CGB, see `new_cgb`. It is
+    /// mapped at 0000-00FF and 0200-08FF, with the cartridge header visible at 0100-01FF in
+    /// between, so the bytes at 0100-01FF of it are never read.
+    pub cgb_boot_rom: Option<Box<[u8; 0x900]>>,
     pub boot_rom_active: bool,
     pub clock_count: u64,
     pub timer: RefCell<Timer>,
@@ -40,8 +219,32 @@ pub struct GameBoy {
     pub joypad_io: u8,
     /// JoyPad state. 0 bit means pressed.
     /// From bit 7 to 0, the order is: Start, Select, B, A, Down, Up, Left, Right
+    ///
+    /// Prefer using `set_joypad`, to properly trigger the joypad interrupt.
     pub joypad: u8,
+    /// A callback called when buttons are released, receiving a mask with the released buttons
+    /// (bit 1 means released), in the same order as `joypad`. This does not exist in hardware,
+    /// and does not affect the emulation.
+    pub joypad_release_callback: Option<JoypadCallback>,
+    /// The joypad as last set by `set_joypad`, before the auto-fire of `turbo_buttons` is applied.
+    held_joypad: u8,
+    /// The buttons with auto-fire, see `set_turbo`.
+    turbo_buttons: u8,
+    /// The number of frames between each toggle of the auto-fire buttons.
+    turbo_period: u64,
+    /// The number of frames since `set_turbo` was called.
+    turbo_frame: u64,
     pub serial: RefCell<Serial>,
+    /// FF56: Infrared Communications Port (RP), only in CGB. Only the writable bits are stored:
+    /// - bit 0: LED on
+    /// - bit 6-7: data read enable (3 = enabled)
+    pub infrared: u8,
+    /// If the infrared receiver is receiving a signal. This is set by the frontend, to simulate
+    /// a incoming signal, and is read in bit 1 of RP, when the reading is enabled.
+    pub infrared_signal: bool,
+    /// A callback called when the infrared LED is turned on or off, by a write to RP. This does not
+    /// affect the emulation.
+    pub infrared_led_callback: Option<InfraredCallback>,
     /// FF0F: Interrupt Flag (IF)
     /// - bit 0: VBlank
     /// - bit 1: STAT
@@ -57,13 +260,78 @@ pub struct GameBoy {
     /// This trigger control if in the next interpret the `v_blank` callback will be called.
     pub v_blank_trigger: Cell<bool>,
     /// A callback that is called after a VBlank. This is called when a vblank interrupt is
-    /// triggered.
+    /// triggered, once per frame, by the interpreter at the next instruction boundary after the
+    /// PPU enters mode 1. At that point LY is 144, and the screen contains the completed frame.
     pub v_blank: Option<VBlankCallback>,
+    /// A callback that is called when the PPU changes its STAT mode, receiving the values of LY,
+    /// the new mode and the clock_count when the change happened. The PPU is updated lazily, so
+    /// this may be called some time after the change.
+    pub stat_mode_callback: RefCell<Option<StatModeCallback>>,
+    /// A callback that is called when the CPU writes to the ROM region (0000-7FFF), at a address
+    /// that is not a register of the cartridge MBC, receiving the address and the written value.
+    /// These writes are ignored by the hardware, and are normally a bug in the game, like
+    /// expecting RAM there. This does not affect the emulation.
+    pub rom_write_callback: Option<RomWriteCallback>,
+    /// A callback that is called on each write to a PPU register (FF40-FF45 and FF47-FF4B),
+    /// receiving the address, the written value and the clock_count of the write, before any of
+    /// the timing adjustments done by the PPU. Writes to the read-only LY are also reported. This
+    /// does not affect the emulation.
+    pub ppu_write_callback: Option<PpuWriteCallback>,
+    /// A callback that is called when, in the DMG model, the CPU writes to a register that only
+    /// exists in the CGB (KEY1, VBK, HDMA1-5, RP, BCPS-OCPD, OPRI and SVBK), receiving the address
+    /// and the written value. A game that needs a CGB (see `CartridgeHeader::is_cgb_only`) often
+    /// writes to them right away, so this tells why it doesn't work. This does not affect the
+    /// emulation.
+    pub cgb_register_callback: Option<CgbRegisterCallback>,
+    /// Where to log each executed instruction, in the format of Gameboy Doctor, like
+    /// `A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02`, one line before
+    /// each instruction is executed by the interpreter. If writing fails, the tracing stops and
+    /// this is set to None.
+    ///
+    /// The instructions executed by the JIT compiler are not logged.
+    pub cpu_trace: Option<CpuTraceWriter>,
+    /// The pending screen capture, see `capture_on`.
+    pub(crate) capture_trigger: Option<CaptureTrigger>,
+    /// The last captured screen, see `take_capture`.
+    captured_screen: Option<Vec<u8>>,
 
     /// Used to toggle the next interrupt prediction, to be able to test its correctness.
     pub predict_interrupt: bool,
     /// Used to toggle the halt optimization, to allow interpreting with more granuallity.
     pub halt_optimization: bool,
+    /// Used to toggle the DMG conflict on writes to the palette registers, where the written value
+    /// is ORed with the old one for a cycle. This never happens on CGB.
+    pub palette_conflict: bool,
+    /// Enable the emulation of subtle and model dependent behaviors, that are not needed by most
+    /// games, like the bus conflicts during OAM DMA.
+    ///
+    /// The memory accesses inlined by the JIT compiler does not take this into account.
+    pub strict_accuracy: bool,
+    /// Do the OAM DMA instantly, when DMA is written, instead of copying one byte per machine cycle
+    /// while blocking the OAM. This is inaccurate, and only meant for speeding up casual play, so
+    /// it is disabled by default.
+    pub instant_dma: bool,
+    /// While the boot ROM is running, read the Nintendo logo in the cartridge header as if it was
+    /// correct, so the boot ROM don't lock up on ROMs without it, like some homebrew.
+    pub bypass_logo_check: bool,
+    /// Record in `trace` each opcode executed from RAM, for the disassembly. This looks up the
+    /// opcode at each instruction executed outside the ROM, so it is disabled by default, and
+    /// meant to be enabled while debugging.
+    pub trace_ram_code: bool,
+    /// Don't render anything, for running only the audio, like in a music player. The PPU still
+    /// runs its LY, STAT and interrupt timing, but the screen is never drawn, so mode 3 is only a
+    /// few cycles long.
+    pub audio_only: bool,
+    /// Compute the region of each frame that changed from the previous one, returned by
+    /// `changed_region`. This keeps a copy of the last frame, and compares all its pixels at each
+    /// VBlank.
+    pub track_screen_changes: bool,
+    /// Hash each frame at VBlank, returned by `frame_hash`. This reads all the pixels of the screen
+    /// once per frame, so it is disabled by default.
+    pub hash_frames: bool,
+    /// Check the consistency of the clocks of the components when saving and loading states, also
+    /// in release builds, returning a error on a mismatch. See `SaveStateContext::strict`.
+    pub strict_save_states: bool,
 
     /// The clock_count when the next interrupt may happen.
     pub next_interrupt: Cell<u64>,
@@ -71,6 +339,10 @@ pub struct GameBoy {
     /// trace of reads and writes. (kind | ((clock_count & !3) >> 1), address, value), kind: 0=read,1=write
     #[cfg(feature = "io_trace")]
     pub io_trace: RefCell<Vec<(u8, u16, u8)>>,
+
+    /// See `timing_profile`. `sound` is kept by the SoundController itself.
+    #[cfg(feature = "timing_profile")]
+    pub profile: RefCell<TimingProfile>,
 }
 
 impl std::fmt::Debug for GameBoy {
@@ -81,8 +353,10 @@ impl std::fmt::Debug for GameBoy {
             .field("cpu", &self.cpu)
             // .field("cartridge", &self.cartridge)
             .field("wram", &self.wram)
+            .field("wram_bank", &self.wram_bank)
             .field("hram", &self.hram)
             .field("boot_rom", &self.boot_rom)
+            .field("cgb_boot_rom", &self.cgb_boot_rom)
             .field("boot_rom_active", &self.boot_rom_active)
             .field("clock_count", &self.clock_count)
             .field("timer", &self.timer)
@@ -101,10 +375,13 @@ impl PartialEq for GameBoy {
     fn eq(&self, other: &Self) -> bool {
         // self.trace == other.trace &&
         self.cpu == other.cpu
+            && self.model == other.model
             && self.cartridge == other.cartridge
             && self.wram == other.wram
+            && self.wram_bank == other.wram_bank
             && self.hram == other.hram
             && self.boot_rom == other.boot_rom
+            && self.cgb_boot_rom == other.cgb_boot_rom
             && self.boot_rom_active == other.boot_rom_active
             && self.clock_count == other.clock_count
             && self.timer == other.timer
@@ -113,17 +390,107 @@ impl PartialEq for GameBoy {
             && self.joypad_io == other.joypad_io
             && self.joypad == other.joypad
             && self.serial == other.serial
+            && self.infrared == other.infrared
+            && self.infrared_signal == other.infrared_signal
             && self.interrupt_flag == other.interrupt_flag
             && self.interrupt_enabled == other.interrupt_enabled
         // && self.v_blank == other.v_blank
     }
 }
+impl GameBoy {
+    /// The components that differ between `self` and `other`, compared with the same `PartialEq`
+    /// as the `GameBoy` itself, so this is empty if they are equal. The Work RAM and High RAM are
+    /// reported as the ranges of addresses that differ, in order. Useful for finding where two
+    /// emulations diverged, like in a failed save state round trip.
+    pub fn diff(&self, other: &GameBoy) -> Vec<ComponentDiff> {
+        use ComponentDiff::*;
+
+        for gb in [self, other] {
+            gb.update_all();
+            gb.ppu.borrow_mut().finish_rendering();
+        }
+
+        let mut diffs = Vec::new();
+        let mut push_if = |differ: bool, diff: ComponentDiff| {
+            if differ {
+                diffs.push(diff);
+            }
+        };
+        push_if(self.cpu != other.cpu, Cpu);
+        push_if(self.cartridge != other.cartridge, Cartridge);
+        push_if(self.timer != other.timer, Timer);
+        push_if(self.sound != other.sound, Sound);
+        push_if(self.ppu != other.ppu, Ppu);
+        push_if(self.serial != other.serial, Serial);
+        push_if(
+            self.model != other.model
+                || self.wram_bank != other.wram_bank
+                || self.boot_rom != other.boot_rom
+                || self.cgb_boot_rom != other.cgb_boot_rom
+                || self.boot_rom_active != other.boot_rom_active
+                || self.clock_count != other.clock_count
+                || self.joypad_io != other.joypad_io
+                || self.joypad != other.joypad
+                || self.infrared != other.infrared
+                || self.infrared_signal != other.infrared_signal
+                || self.interrupt_flag != other.interrupt_flag
+                || self.interrupt_enabled != other.interrupt_enabled,
+            Registers,
+        );
+
+        // the runs of differing bytes in `a` and `b`, as ranges of indices
+        fn differing_ranges(a: &[u8], b: &[u8]) -> Vec<std::ops::Range<usize>> {
+            let mut ranges: Vec<std::ops::Range<usize>> = Vec::new();
+            for i in (0..a.len()).filter(|&i| a[i] != b[i]) {
+                match ranges.last_mut() {
+                    Some(last) if last.end == i => last.end += 1,
+                    _ => ranges.push(i..i + 1),
+                }
+            }
+            ranges
+        }
+        for (bank, (a, b)) in self
+            .wram
+            .chunks(0x1000)
+            .zip(other.wram.chunks(0x1000))
+            .enumerate()
+        {
+            let start = if bank == 0 { 0xC000 } else { 0xD000 };
+            for range in differing_ranges(a, b) {
+                diffs.push(Memory {
+                    bank: bank as u8,
+                    range: start + range.start as u16..start + range.end as u16,
+                });
+            }
+        }
+        for range in differing_ranges(&self.hram, &other.hram) {
+            diffs.push(Memory {
+                bank: 0,
+                range: 0xFF80 + range.start as u16..0xFF80 + range.end as u16,
+            });
+        }
+        diffs
+    }
+}
 crate::save_state!(GameBoy, self, ctx, data {
     SaveStateHeader;
     // self.trace;
     self.cpu;
     self.cartridge;
-    self.wram;
+    if ctx.version >= 5 => {
+        self.wram;
+        self.wram_bank;
+    }
+    if ctx.version < 5 => {
+        // only the DMG banks were saved
+        on_load {
+            let mut wram = [0u8; 0x2000];
+            wram.load_state(ctx, data)?;
+            self.wram = [0; 0x8000];
+            self.wram[..0x2000].copy_from_slice(&wram);
+            self.wram_bank = 0;
+        };
+    }
     self.hram;
     // self.boot_rom;
     self.clock_count;
@@ -135,6 +502,8 @@ crate::save_state!(GameBoy, self, ctx, data {
 
     self.joypad_io;
     self.joypad;
+    // the auto-fire is not saved, so the held buttons are the ones pressed at the save
+    on_load self.held_joypad = self.joypad;
     self.serial.borrow_mut();
     self.interrupt_flag;
     self.dma;
@@ -143,17 +512,34 @@ crate::save_state!(GameBoy, self, ctx, data {
     bitset [self.boot_rom_active, self.v_blank_trigger];
     // self.v_blank;
 
+    if ctx.version >= 4 => {
+        self.infrared;
+        bitset [self.infrared_signal];
+    }
+    if ctx.version < 4 => {
+        on_load self.infrared = 0;
+        on_load self.infrared_signal = false;
+    }
+
+    if ctx.version >= 6 => {
+        self.model;
+    }
+    // older states keep the model of the GameBoy they are loaded into
+
     on_load self.update_next_interrupt();
 });
 impl GameBoy {
     pub fn new(boot_rom: Option<[u8; 0x100]>, cartridge: Cartridge) -> Self {
         let mut this = Self {
+            model: Model::default(),
             trace: RefCell::new(Trace::new()),
             cpu: Cpu::default(),
             cartridge,
-            wram: [0; 0x2000],
+            wram: [0; 0x8000],
+            wram_bank: 0,
             hram: [0; 0x7F],
             boot_rom,
+            cgb_boot_rom: None,
             boot_rom_active: true,
             clock_count: 0,
             timer: Timer::new().into(),
@@ -162,18 +548,45 @@ impl GameBoy {
 
             joypad: 0xFF,
             joypad_io: 0x00,
+            joypad_release_callback: None,
+            held_joypad: 0xFF,
+            turbo_buttons: 0,
+            turbo_period: 1,
+            turbo_frame: 0,
             serial: Serial::new().into(),
+            infrared: 0,
+            infrared_signal: false,
+            infrared_led_callback: None,
             interrupt_flag: 0.into(),
             dma: 0xff,
             interrupt_enabled: 0,
             v_blank_trigger: false.into(),
             v_blank: None,
+            stat_mode_callback: RefCell::new(None),
+            rom_write_callback: None,
+            cgb_register_callback: None,
+            ppu_write_callback: None,
+            cpu_trace: None,
+            capture_trigger: None,
+            captured_screen: None,
             predict_interrupt: true,
             halt_optimization: true,
+            palette_conflict: true,
+            strict_accuracy: false,
+            instant_dma: false,
+            bypass_logo_check: false,
+            trace_ram_code: false,
+            audio_only: false,
+            track_screen_changes: false,
+            hash_frames: false,
+            strict_save_states: false,
             next_interrupt: 0.into(),
 
             #[cfg(feature = "io_trace")]
             io_trace: Vec::new().into(),
+
+            #[cfg(feature = "timing_profile")]
+            profile: TimingProfile::default().into(),
         };
 
         if this.boot_rom.is_none() {
@@ -183,12 +596,131 @@ impl GameBoy {
         this
     }
 
+    /// Create a CGB GameBoy, starting from the given CGB boot ROM, or from the state after the boot
+    /// if there is none. The boot ROM may also be a 0x900 bytes dump, where 0100-01FF is ignored.
+    pub fn new_cgb(boot_rom: Option<Box<[u8; 0x900]>>, cartridge: Cartridge) -> Self {
+        let mut this = Self::new(None, cartridge);
+        this.model = Model::Cgb;
+        this.cgb_boot_rom = boot_rom;
+        this.reset();
+        this
+    }
+
+    /// If there is a boot ROM for the current `model`.
+    fn has_boot_rom(&self) -> bool {
+        match self.model {
+            Model::Dmg => self.boot_rom.is_some(),
+            Model::Cgb => self.cgb_boot_rom.is_some(),
+        }
+    }
+
+    /// Create a GameBoy that only runs the audio, see `audio_only`.
+    pub fn new_audio_only(boot_rom: Option<[u8; 0x100]>, cartridge: Cartridge) -> Self {
+        let mut this = Self::new(boot_rom, cartridge);
+        this.audio_only = true;
+        this
+    }
+
     /// call the `v_blank` callback
+    ///
+    /// If the callback panics, the callback is kept, and the panic is resumed. This also takes a
+    /// pending `CaptureTrigger::Frame` capture, and advances the auto-fire of `set_turbo`, before
+    /// calling the callback.
     pub fn call_v_blank_callback(&mut self) {
+        if self.turbo_buttons != 0 {
+            self.turbo_frame += 1;
+            self.apply_joypad(self.turbo_joypad());
+        }
+
+        if let Some(CaptureTrigger::Frame(frames)) = &mut self.capture_trigger {
+            *frames -= 1;
+            if *frames == 0 {
+                self.capture_screen();
+            }
+        }
+
         if let Some(mut v_blank) = self.v_blank.take() {
-            v_blank(self);
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| v_blank(self)));
             self.v_blank = Some(v_blank);
+            if let Err(panic) = result {
+                std::panic::resume_unwind(panic);
+            }
+        }
+    }
+
+    /// Capture the screen, as a `Screen::packed`, once the trigger fires. The capture can then be
+    /// retrieved with `take_capture`. This replaces any pending capture, and is not part of the
+    /// save state.
+    pub fn capture_on(&mut self, trigger: CaptureTrigger) {
+        self.capture_trigger = Some(trigger);
+        if trigger == CaptureTrigger::Frame(0) {
+            self.capture_screen();
+        }
+    }
+
+    /// Take the screen captured by the last `capture_on`, if its trigger already fired.
+    pub fn take_capture(&mut self) -> Option<Vec<u8>> {
+        self.captured_screen.take()
+    }
+
+    /// Check if a pending `CaptureTrigger::Pc` fires at the current PC.
+    pub(crate) fn check_capture_pc(&mut self) {
+        if self.capture_trigger == Some(CaptureTrigger::Pc(self.cpu.pc)) {
+            self.capture_screen();
+        }
+    }
+
+    /// Write the current state of the CPU to `cpu_trace`.
+    pub(crate) fn write_cpu_trace(&mut self) {
+        let Some(mut writer) = self.cpu_trace.take() else {
+            return;
+        };
+        let cpu = &self.cpu;
+        let pc = cpu.pc;
+        let mem = |i| self.read(pc.wrapping_add(i));
+        let result = writeln!(
+            writer,
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} \
+             PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            cpu.a,
+            cpu.f.0,
+            cpu.b,
+            cpu.c,
+            cpu.d,
+            cpu.e,
+            cpu.h,
+            cpu.l,
+            cpu.sp,
+            pc,
+            mem(0),
+            mem(1),
+            mem(2),
+            mem(3),
+        );
+        if result.is_ok() {
+            self.cpu_trace = Some(writer);
+        }
+    }
+
+    /// Record the opcode at the current PC, in RAM, in `trace`. The opcode is read ignoring the
+    /// bus conflicts, so a running DMA don't change what is recorded.
+    pub(crate) fn trace_ram_opcode(&self) {
+        let pc = self.cpu.pc;
+        let op = self.read_no_conflict(pc);
+        let len = consts::LEN[op as usize].max(1);
+        let mut bytes = [op, 0, 0];
+        for i in 1..len {
+            bytes[i as usize] = self.read_no_conflict(pc.wrapping_add(i as u16));
         }
+        self.trace
+            .borrow_mut()
+            .add_ram_opcode(pc, &bytes[..len as usize]);
+    }
+
+    fn capture_screen(&mut self) {
+        self.capture_trigger = None;
+        self.update_ppu();
+        self.captured_screen = Some(self.ppu.get_mut().screen().packed().to_vec());
     }
 
     /// Saves the current state of the GameBoy.
@@ -201,51 +733,133 @@ impl GameBoy {
         data: &mut W,
     ) -> Result<(), std::io::Error> {
         self.update_all();
+        self.ppu.borrow_mut().finish_rendering();
         let ctx = &mut SaveStateContext::new(timestamp, self.clock_count);
+        ctx.strict = self.strict_save_states;
         SaveState::save_state(self, ctx, data)
     }
 
+    /// The path, offset and length of each field in the data written by `save_state`, in order.
+    /// Useful for debugging corrupted save states, or for migrating them between versions.
+    pub fn dump_state_layout(&self) -> Vec<(String, usize, usize)> {
+        self.update_all();
+        self.ppu.borrow_mut().finish_rendering();
+        StateLayout::of(self, SaveStateContext::new(None, self.clock_count))
+    }
+
+    /// Loads a state saved by `save_state`.
+    ///
+    /// If the data is malformed or truncated, an error is returned, and the GameBoy may be left
+    /// partially loaded, so the caller should restore a previous state, or reset it.
     pub fn load_state<R: std::io::Read>(&mut self, data: &mut R) -> Result<(), LoadStateError> {
         let ctx = &mut SaveStateContext::default();
+        ctx.strict = self.strict_save_states;
         self.update_all();
+        self.ppu.borrow_mut().finish_rendering();
         SaveState::load_state(self, ctx, data)
     }
 
+    /// A hash of the entire state of the GameBoy, computed over the same data that is written by
+    /// `save_state` (without a timestamp). Equal GameBoys have equal hashes, so this can be used to
+    /// cheaply detect when two runs diverge.
+    pub fn state_hash(&self) -> u64 {
+        /// A FNV-1a hasher.
+        struct Hasher(u64);
+        impl std::io::Write for Hasher {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                for &b in buf {
+                    self.0 ^= b as u64;
+                    self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+                }
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut hasher = Hasher(0xcbf2_9ce4_8422_2325);
+        self.save_state(None, &mut hasher)
+            .expect("writing to a hasher never fails");
+        hasher.0
+    }
+
     /// Reset the gameboy to its stating state.
     pub fn reset(&mut self) {
-        if self.boot_rom.is_none() {
+        if !self.has_boot_rom() {
             self.reset_after_boot();
             return;
         }
         // TODO: Maybe I should reset the cartridge
         self.cpu = Cpu::default();
-        self.wram = [0; 0x2000];
-        self.hram = [0; 0x7F];
+        self.clear_ram();
         self.boot_rom_active = true;
         self.clock_count = 0;
         self.timer = Timer::new().into();
         self.sound = RefCell::new(SoundController::default());
-        self.ppu = Ppu::default().into();
+        self.ppu.get_mut().reset();
         self.joypad = 0xFF;
+        self.held_joypad = 0xFF;
         self.joypad_io = 0x00;
 
         self.next_interrupt = 0.into();
         self.update_next_interrupt();
     }
 
+    /// Zero the WRAM and HRAM, in place.
+    fn clear_ram(&mut self) {
+        self.wram.fill(0);
+        self.wram_bank = 0;
+        self.hram.fill(0);
+    }
+
+    /// Run the boot ROM until it is disabled by a write to FF50, as fast as possible. The
+    /// `v_blank` callback is not called and no audio is sampled in the meantime, but otherwise the
+    /// emulation is the same, resulting in the same state as a normal run of the boot ROM.
+    ///
+    /// Does nothing if the boot ROM is not active. If the boot ROM locks up, like it does when the
+    /// cartridge don't have the Nintendo logo, this gives up after `FAST_BOOT_TIMEOUT` clocks, with
+    /// the boot ROM still active.
+    pub fn fast_boot(&mut self) {
+        if !self.has_boot_rom() || !self.boot_rom_active {
+            return;
+        }
+
+        let v_blank = self.v_blank.take();
+        let sampling_disabled =
+            std::mem::replace(&mut self.sound.get_mut().sampling_disabled, true);
+
+        let timeout = self.clock_count + FAST_BOOT_TIMEOUT;
+        while self.boot_rom_active && self.clock_count < timeout {
+            Interpreter(self).interpret_op();
+        }
+
+        self.v_blank = v_blank;
+        // the sound controller is lazily updated, so it need to be updated before sampling again.
+        let sound = self.sound.get_mut();
+        sound.update(self.clock_count);
+        sound.sampling_disabled = sampling_disabled;
+    }
+
     /// Reset the gameboy to its state after disabling the boot.
     pub fn reset_after_boot(&mut self) {
         let ctx = &mut SaveStateContext::default();
 
+        // the registers left by each model's boot ROM
+        let [a, f, b, c, d, e, h, l] = match self.model {
+            Model::Dmg => [0x01, 0xb0, 0x00, 0x13, 0x00, 0xd8, 0x01, 0x4d],
+            Model::Cgb => [0x11, 0x80, 0x00, 0x00, 0xff, 0x56, 0x00, 0x0d],
+        };
         self.cpu = Cpu {
-            a: 0x01,
-            f: cpu::Flags(0xb0),
-            b: 0x00,
-            c: 0x13,
-            d: 0x00,
-            e: 0xd8,
-            h: 0x01,
-            l: 0x4d,
+            a,
+            f: cpu::Flags(f),
+            b,
+            c,
+            d,
+            e,
+            h,
+            l,
             sp: 0xfffe,
             pc: 0x0100,
             ime: cpu::ImeState::Disabled,
@@ -253,8 +867,7 @@ impl GameBoy {
             state: cpu::CpuState::Running,
         };
 
-        self.wram = [0; 0x2000];
-        self.hram = [0; 0x7F];
+        self.clear_ram();
         self.hram[0x7a..=0x7c].copy_from_slice(&[0x39, 0x01, 0x2e]);
 
         self.boot_rom_active = false;
@@ -262,11 +875,14 @@ impl GameBoy {
         self.ppu.get_mut().reset_after_boot();
 
         self.joypad = 0xFF;
+        self.held_joypad = 0xFF;
 
         self.joypad_io = 0xCF;
         self.serial.get_mut().reset();
         self.timer = Timer::after_boot(self.clock_count).into();
         self.interrupt_flag = 1.into();
+        // the fields added to the sound controller after version 3 are set to their default
+        ctx.version = AFTER_BOOT_SOUND_VERSION;
         self.sound
             .get_mut()
             .load_state(ctx, &mut &include_bytes!("../after_boot/sound.sav")[..])
@@ -276,12 +892,33 @@ impl GameBoy {
         self.update_next_interrupt();
     }
 
-    pub fn read(&self, mut address: u16) -> u8 {
-        if self.boot_rom_active && address < 0x100 {
-            let boot_rom = self
-                .boot_rom
-                .expect("the boot rom is only actived when there is one");
-            return boot_rom[address as usize];
+    pub fn read(&self, address: u16) -> u8 {
+        // During a OAM DMA the external and video buses are in use, so the CPU can only reliably
+        // read from the I/O registers and HRAM.
+        if self.strict_accuracy && address < 0xFE00 {
+            if let Some(value) = Ppu::dma_bus_value(self) {
+                return value;
+            }
+        }
+        self.read_no_conflict(address)
+    }
+
+    /// Same as `read`, but ignoring any bus conflicts. Used by the DMA itself.
+    pub(crate) fn read_no_conflict(&self, mut address: u16) -> u8 {
+        if self.boot_rom_active {
+            if let (Model::Cgb, Some(boot_rom)) = (self.model, &self.cgb_boot_rom) {
+                if address < 0x100 || (0x200..0x900).contains(&address) {
+                    return boot_rom[address as usize];
+                }
+            } else if address < 0x100 {
+                let boot_rom = self
+                    .boot_rom
+                    .expect("the boot rom is only actived when there is one");
+                return boot_rom[address as usize];
+            }
+            if self.bypass_logo_check && (0x104..=0x133).contains(&address) {
+                return cartridge::NINTENDO_LOGO[address as usize - 0x104];
+            }
         }
         if (0xE000..=0xFDFF).contains(&address) {
             address -= 0x2000;
@@ -294,13 +931,13 @@ impl GameBoy {
             // Cartridge RAM
             0xA000..=0xBFFF => self.cartridge.read(address),
             // Work RAM
-            0xC000..=0xDFFF => self.wram[address as usize - 0xC000],
+            0xC000..=0xDFFF => self.wram[self.wram_offset(address)],
             // ECHO RAM
             0xE000..=0xFDFF => unreachable!(),
             // Sprite Attribute table
             0xFE00..=0xFE9F => Ppu::read_oam(self, address),
             // Not Usable
-            0xFEA0..=0xFEFF => 0xff,
+            0xFEA0..=0xFEFF => self.read_unusable(address),
             // I/O registers and Hight RAM
             0xFF00..=0xFFFF => self.read_io(address as u8),
         }
@@ -320,13 +957,20 @@ impl GameBoy {
 
         match address {
             // Cartridge ROM
-            0x0000..=0x7FFF => self.cartridge.write(address, value),
+            0x0000..=0x7FFF => {
+                if let Some(callback) = &mut self.rom_write_callback {
+                    if !self.cartridge.is_mbc_register(address) {
+                        callback(address, value);
+                    }
+                }
+                self.cartridge.write(address, value)
+            }
             // Video RAM
             0x8000..=0x9FFF => Ppu::write_vram(self, address, value),
             // Cartridge RAM
             0xA000..=0xBFFF => self.cartridge.write(address, value),
             // Work RAM
-            0xC000..=0xDFFF => self.wram[address as usize - 0xC000] = value,
+            0xC000..=0xDFFF => self.wram[self.wram_offset(address)] = value,
             // ECHO RAM
             0xE000..=0xFDFF => unreachable!(),
             // Sprite Attribute table
@@ -338,11 +982,328 @@ impl GameBoy {
         }
     }
 
+    /// Run the emulation until a newline is sent over serial, or until `timeout_cycles` clock cycles
+    /// have passed. Return all bytes sent over serial in the meantime, including the newline, or
+    /// None if no byte was sent.
+    ///
+    /// While running, `Serial::serial_transfer_callback` is not called.
+    pub fn run_until_serial(&mut self, timeout_cycles: u64) -> Option<Vec<u8>> {
+        use std::sync::{Arc, Mutex};
+
+        let buffer = Arc::new(Mutex::new(Vec::new()));
+        let old_callback = self
+            .serial
+            .get_mut()
+            .serial_transfer_callback
+            .replace(Box::new({
+                let buffer = buffer.clone();
+                move |c| buffer.lock().unwrap().push(c)
+            }));
+
+        let timeout = self.clock_count + timeout_cycles;
+        while self.clock_count < timeout {
+            Interpreter(self).interpret_op();
+            if buffer.lock().unwrap().last() == Some(&b'\n') {
+                break;
+            }
+        }
+
+        self.serial.get_mut().serial_transfer_callback = old_callback;
+
+        let bytes = std::mem::take(&mut *buffer.lock().unwrap());
+        (!bytes.is_empty()).then_some(bytes)
+    }
+
+    /// Run the emulation for `n` frames, for offline rendering, returning each frame, as a
+    /// `Screen::packed`, and all audio generated in the meantime, as returned by
+    /// `SoundController::get_output`: interleaved left and right samples (or a single channel, if
+    /// `SoundController::mono` is set), from 0 to `audio_ring::MAX_SAMPLE`.
+    ///
+    /// Each frame ends at the next VBlank, so the audio covers exactly the clocks of the returned
+    /// frames. While the LCD is off, a frame ends after `consts::FRAME_CYCLES` clocks, and repeats
+    /// the current screen. This replaces any pending `capture_on`.
+    pub fn run_frames(&mut self, n: usize) -> (Vec<Vec<u8>>, Vec<u16>) {
+        let mut frames = Vec::with_capacity(n);
+        let mut audio = Vec::new();
+        for _ in 0..n {
+            let (frame, frame_audio) = self.step_frame_with_audio();
+            frames.push(frame);
+            audio.extend(frame_audio);
+        }
+        (frames, audio)
+    }
+
+    /// Run the emulation for a single frame, like `run_frames(1)`, returning the frame and only
+    /// the audio generated during it, in the same format: interleaved left and right samples, from
+    /// 0 to `audio_ring::MAX_SAMPLE`. So the audio of consecutive calls is contiguous, and each
+    /// part matches its frame.
+    pub fn step_frame_with_audio(&mut self) -> (Vec<u8>, Vec<u16>) {
+        self.capture_on(CaptureTrigger::Frame(1));
+        let timeout = self.clock_count + consts::FRAME_CYCLES;
+        let frame = loop {
+            Interpreter(self).interpret_op();
+            if let Some(frame) = self.take_capture() {
+                break frame;
+            }
+            if self.clock_count >= timeout {
+                self.capture_trigger = None;
+                self.update_ppu();
+                break self.ppu.get_mut().screen().packed().to_vec();
+            }
+        };
+        let audio = self.sound.get_mut().get_output(self.clock_count);
+        (frame, audio)
+    }
+
+    /// Execute a single instruction, or service a single pending interrupt, through the
+    /// interpreter.
+    ///
+    /// If the CPU is halted, and no interrupt wakes it up, no instruction is executed, and the
+    /// returned `bytes` and `mnemonic` are empty.
+    pub fn step_instruction(&mut self) -> StepInfo {
+        let pc = self.cpu.pc;
+        let was_running = self.cpu.state == cpu::CpuState::Running;
+        let bytes: Vec<u8> = {
+            let len = consts::LEN[self.read(pc) as usize] as u16;
+            (0..len).map(|i| self.read(pc.wrapping_add(i))).collect()
+        };
+        let start = self.clock_count;
+
+        let result = Interpreter(self).interpret_op_or_interrupt();
+
+        let interrupt = result.is_break();
+        // a halted CPU only executes a instruction if it was woken up by a interrupt.
+        let executed = !interrupt
+            && (was_running || self.cpu.state == cpu::CpuState::Running || self.cpu.pc != pc);
+        let mut mnemonic = String::new();
+        let bytes = if executed {
+            disassembly_opcode(pc, &bytes, |x| format!("${:04x}", x), &mut mnemonic).unwrap();
+            mnemonic.truncate(mnemonic.trim_end().len());
+            bytes
+        } else {
+            Vec::new()
+        };
+
+        StepInfo {
+            pc,
+            mnemonic,
+            bytes,
+            cycles: self.clock_count - start,
+            interrupt,
+        }
+    }
+
+    /// Decode the instruction at the ROM `address`, without executing it, returning its mnemonic,
+    /// length and bytes. Nothing in the GameBoy is changed: the bytes are read from the given bank,
+    /// and not from the currently mapped ones.
+    pub fn peek_instruction_at(&self, address: Address) -> (String, u8, [u8; 3]) {
+        let cursor = address.as_cursor(&self.cartridge);
+        let (bytes, len) = cursor.get_op(self);
+        let (mnemonic, _) = decode_one(&bytes, cursor.pc);
+        (mnemonic, len, bytes)
+    }
+
+    /// Decode the instruction at the PC, without executing it, the same way `step_instruction`
+    /// reports it. ROM addresses are resolved to the currently mapped banks, and RAM is read like
+    /// the CPU would.
+    pub fn peek_instruction(&self) -> (String, u8, [u8; 3]) {
+        let pc = self.cpu.pc;
+        if let Some(address) = Address::from_pc(self.cartridge.curr_bank(), pc) {
+            // the lower bank may not be 0, but its address would be out of 0..=3FFF
+            if address.to_pc() == pc {
+                return self.peek_instruction_at(address);
+            }
+        }
+        let bytes = [0, 1, 2].map(|i| self.read(pc.wrapping_add(i)));
+        let (mnemonic, len) = decode_one(&bytes, pc);
+        (mnemonic, len, bytes)
+    }
+
+    /// The `depth` bytes at and above SP, as (address, value), in order of address, for displaying
+    /// the stack. The bytes are read ignoring bus conflicts, so any running DMA don't change them,
+    /// and nothing in the GameBoy is changed. Fewer bytes are returned if the stack would go past
+    /// FFFF.
+    pub fn stack_slice(&self, depth: usize) -> Vec<(u16, u8)> {
+        let sp = self.cpu.sp;
+        let len = depth.min(0x10000 - sp as usize);
+        (0..len as u16)
+            .map(|i| (sp + i, self.read_no_conflict(sp + i)))
+            .collect()
+    }
+
     /// Advance the clock by 'count' cycles
     pub fn tick(&mut self, count: u64) {
         self.clock_count += count;
     }
 
+    /// Advance the clock to the absolute `clock` count, without running any instruction, and bring
+    /// the PPU, timer, serial and sound controller up to it. `clock` must not be in the past.
+    pub fn sync_to(&mut self, clock: u64) {
+        assert!(
+            clock >= self.clock_count,
+            "cannot sync back in time, from {} to {}",
+            self.clock_count,
+            clock
+        );
+        self.clock_count = clock;
+        self.update_all();
+        self.sound.get_mut().update(self.clock_count);
+    }
+
+    /// Update the state of the joypad. 0 bit means pressed, in the same order as `joypad`.
+    ///
+    /// This requests a joypad interrupt if any of the currently selected input lines goes from
+    /// high to low, and calls `joypad_release_callback` if any button was released.
+    pub fn set_joypad(&mut self, joypad: u8) {
+        self.held_joypad = joypad;
+        self.apply_joypad(self.turbo_joypad());
+    }
+
+    /// Enable auto-fire on the given `buttons`, with a 1 bit for each button, in the same order as
+    /// `joypad`. While held, these buttons are pressed for `period` frames, and released for
+    /// `period` frames, starting pressed. Each new press requests a joypad interrupt, like a real
+    /// press. Use 0 `buttons` to disable it.
+    ///
+    /// The frames are counted at each VBlank, by `call_v_blank_callback`, so nothing toggles while
+    /// the LCD is off.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `period` is 0.
+    pub fn set_turbo(&mut self, buttons: u8, period: u64) {
+        assert!(period > 0, "the turbo period must be at least 1 frame");
+        self.turbo_buttons = buttons;
+        self.turbo_period = period;
+        self.turbo_frame = 0;
+        self.apply_joypad(self.turbo_joypad());
+    }
+
+    /// The held joypad, with the auto-fire buttons released in the second half of each period.
+    fn turbo_joypad(&self) -> u8 {
+        if (self.turbo_frame / self.turbo_period) % 2 == 1 {
+            self.held_joypad | self.turbo_buttons
+        } else {
+            self.held_joypad
+        }
+    }
+
+    /// Set the effective state of the joypad, see `set_joypad`.
+    fn apply_joypad(&mut self, joypad: u8) {
+        let old_lines = self.joypad_lines();
+        let released = !self.joypad & joypad;
+        self.joypad = joypad;
+        let new_lines = self.joypad_lines();
+
+        if old_lines & !new_lines != 0 {
+            *self.interrupt_flag.get_mut() |= 1 << 4;
+            self.update_next_interrupt();
+        }
+
+        if released != 0 {
+            if let Some(callback) = &mut self.joypad_release_callback {
+                callback(released);
+            }
+        }
+    }
+
+    /// The value of the input lines P10-P13, in the lower nibble of the JOYPAD register.
+    ///
+    /// A low P14 selects the directions, and a low P15 selects the buttons. A pressed button pulls
+    /// its line low, so if both groups are selected the lines are the AND of both, and if none is
+    /// selected all lines are high.
+    fn joypad_lines(&self) -> u8 {
+        let v = self.joypad_io & 0x30;
+        let mut r = 0x0F;
+        if v & 0x10 == 0 {
+            r &= self.joypad & 0x0F;
+        }
+        if v & 0x20 == 0 {
+            r &= self.joypad >> 4;
+        }
+        r
+    }
+
+    /// The number of clock cycles of the last complete frame, measured between the start of the
+    /// last two VBlanks. This is normally `consts::FRAME_CYCLES`, but differs if, for example, the
+    /// LCD was turned off in the middle of the frame. Return None if no frame was completed yet.
+    pub fn last_frame_cycles(&self) -> Option<u64> {
+        self.update_ppu();
+        let cycles = self.ppu.borrow().last_frame_cycles;
+        (cycles != 0).then_some(cycles)
+    }
+
+    /// Advance the emulation by a single clock cycle (a PPU dot) and update the PPU, returning the
+    /// state of its pixel pipeline. Only the PPU is updated, like when the CPU is halted.
+    pub fn step_ppu_dot(&mut self) -> PpuDotInfo {
+        self.update_ppu();
+        let screen_x = self.ppu.borrow().screen_x;
+        self.tick(1);
+        self.update_ppu();
+        let ppu = self.ppu.borrow();
+        PpuDotInfo {
+            ly: ppu.ly,
+            mode: ppu.stat & 0b11,
+            screen_x: ppu.screen_x,
+            scanline_x: ppu.scanline_x(),
+            fetcher_step: ppu.fetcher_step(),
+            background_fifo: ppu.background_fifo_contents(),
+            sprite_fifo: ppu.sprite_fifo_contents(),
+            pixel_output: ppu.screen_x == screen_x + 1,
+        }
+    }
+
+    /// Trace the PPU dot by dot, for a whole frame (`consts::FRAME_CYCLES` dots) starting from
+    /// the current state, like calling `step_ppu_dot` repeatedly. This is slow, and only meant for
+    /// debugging and visualization tools.
+    pub fn trace_ppu_frame(&mut self) -> impl Iterator<Item = PpuDotInfo> + '_ {
+        (0..consts::FRAME_CYCLES).map(|_| self.step_ppu_dot())
+    }
+
+    /// The number of cycles until the next event that may request a interrupt (from the PPU,
+    /// timer or serial), so the emulation can be advanced up to it in a single step. The estimate
+    /// may be a few cycles early, but never late. This is 0 if a interrupt may happen immediately,
+    /// or if `predict_interrupt` is disabled.
+    pub fn cycles_until_next_event(&self) -> u64 {
+        self.next_interrupt.get().saturating_sub(self.clock_count)
+    }
+
+    /// The execution state of the CPU: if it is running, or waiting for a interrupt after a HALT
+    /// or STOP.
+    pub fn cpu_state(&self) -> cpu::CpuState {
+        self.cpu.state
+    }
+
+    /// The internal 16-bit counter of the timer, incremented every clock cycle. DIV (FF04) is its
+    /// upper 8 bits, and writing to DIV resets the entire counter.
+    pub fn div_counter(&self) -> u16 {
+        self.update_timer();
+        self.timer.borrow().div
+    }
+
+    /// A hash of the last complete frame, computed at the start of VBlank. Can be used to cheaply
+    /// detect repeated frames. Return None if `hash_frames` is disabled, or if no frame was
+    /// completed since it was enabled or since the last load of a save state.
+    pub fn frame_hash(&self) -> Option<u64> {
+        self.update_ppu();
+        let hash = self.ppu.borrow().frame_hash;
+        (self.hash_frames && hash != 0).then_some(hash)
+    }
+
+    /// The predicted clock_count at which the next frame starts, or None while the LCD is off.
+    /// See `Ppu::next_frame_clock`.
+    pub fn next_frame_clock(&self) -> Option<u64> {
+        self.update_ppu();
+        self.ppu.borrow().next_frame_clock()
+    }
+
+    /// The bounding box of the pixels of the last complete frame that differ from the frame
+    /// before it, or None if nothing changed. This is the entire screen if `track_screen_changes`
+    /// is disabled, or if there is no previous frame to compare to.
+    pub fn changed_region(&self) -> Option<ppu::Rect> {
+        self.update_ppu();
+        self.ppu.borrow().changed_region
+    }
+
     pub fn update_next_interrupt(&self) {
         if !self.predict_interrupt {
             self.next_interrupt.set(self.clock_count);
@@ -390,8 +1351,47 @@ impl GameBoy {
         self.update_serial();
     }
 
-    fn update_ppu(&self) {
-        let (v_blank_interrupt, stat_interrupt) = Ppu::update(self);
+    /// The time spent in each part of the emulation. Measuring it has a cost, so this is only
+    /// available with the `timing_profile` feature.
+    #[cfg(feature = "timing_profile")]
+    pub fn timing_profile(&self) -> TimingProfile {
+        TimingProfile {
+            sound: self.sound.borrow().update_time,
+            ..*self.profile.borrow()
+        }
+    }
+
+    /// Reset all times in `timing_profile` to 0.
+    #[cfg(feature = "timing_profile")]
+    pub fn reset_timing_profile(&mut self) {
+        *self.profile.get_mut() = TimingProfile::default();
+        self.sound.get_mut().update_time = 0;
+    }
+
+    pub(crate) fn update_ppu(&self) {
+        #[cfg(feature = "timing_profile")]
+        let (start, draw_time) = (
+            std::time::Instant::now(),
+            self.profile.borrow().ppu_draw_scan_line,
+        );
+
+        let mut stat_mode_callback = self.stat_mode_callback.borrow_mut();
+        let mut stat_mode_changes = Vec::new();
+        let (v_blank_interrupt, stat_interrupt) = Ppu::update(
+            self,
+            stat_mode_callback
+                .is_some()
+                .then_some(&mut stat_mode_changes),
+        );
+
+        #[cfg(feature = "timing_profile")]
+        {
+            let profile = &mut *self.profile.borrow_mut();
+            let elapsed = start.elapsed().as_nanos() as u64;
+            let draw_elapsed = profile.ppu_draw_scan_line - draw_time;
+            profile.ppu_fifo += elapsed.saturating_sub(draw_elapsed);
+        }
+
         if stat_interrupt {
             self.interrupt_flag
                 .set(self.interrupt_flag.get() | (1 << 1));
@@ -403,6 +1403,14 @@ impl GameBoy {
         }
 
         self.update_next_interrupt();
+
+        // The callback is only called after the PPU is fully updated and no longer borrowed, so
+        // a panic in it doesn't leave the emulation in a inconsistent state.
+        if let Some(callback) = stat_mode_callback.as_mut() {
+            for (ly, mode, clock_count) in stat_mode_changes {
+                callback(ly, mode, clock_count);
+            }
+        }
     }
 
     fn update_timer(&self) {
@@ -424,11 +1432,51 @@ impl GameBoy {
         self.update_next_interrupt();
     }
 
+    /// The index in `wram` of the given address, in C000-DFFF, in the currently selected bank.
+    fn wram_offset(&self, address: u16) -> usize {
+        let offset = address as usize & 0x0FFF;
+        if address < 0xD000 {
+            return offset;
+        }
+        let bank = match self.model {
+            Model::Dmg => 1,
+            Model::Cgb => self.wram_bank.max(1) as usize,
+        };
+        bank * 0x1000 + offset
+    }
+
+    /// Read from the not usable region, FEA0-FEFF.
+    fn read_unusable(&self, address: u16) -> u8 {
+        match self.model {
+            // Returns 0xFF while OAM is blocked, and 0x00 otherwise.
+            Model::Dmg => {
+                self.update_ppu();
+                if self.ppu.borrow().is_oam_blocked() {
+                    0xff
+                } else {
+                    0x00
+                }
+            }
+            // Returns the high nibble of the lower address byte, twice.
+            Model::Cgb => {
+                let nibble = (address as u8) & 0xF0;
+                nibble | (nibble >> 4)
+            }
+        }
+    }
+
     pub fn read16(&self, address: u16) -> u16 {
         u16::from_le_bytes([self.read(address), self.read(address.wrapping_add(1))])
     }
 
     fn write_io(&mut self, address: u8, value: u8) {
+        if self.model == Model::Dmg {
+            if let Some(callback) = &mut self.cgb_register_callback {
+                if matches!(address, 0x4d | 0x4f | 0x51..=0x56 | 0x68..=0x6c | 0x70) {
+                    callback(0xFF00 | address as u16, value);
+                }
+            }
+        }
         match address {
             0x00 => self.joypad_io = 0b1100_1111 | (value & 0x30), // JOYPAD
             0x01..=0x02 => Serial::write(self, address, value),
@@ -455,14 +1503,37 @@ impl GameBoy {
                 Ppu::start_dma(self, value);
             }
             0x47..=0x4b => Ppu::write(self, address, value),
-            0x4c..=0x4f => {}
+            0x4c..=0x4e => {}
+            0x4f => {
+                if self.model == Model::Cgb {
+                    self.ppu.get_mut().vbk = value & 0x01;
+                }
+            }
             0x50 => {
                 if self.boot_rom_active && value & 0b1 != 0 {
                     self.boot_rom_active = false;
                     self.cpu.pc = 0x100;
                 }
             }
-            0x51..=0x7f => {}
+            0x51..=0x55 => {}
+            0x56 => {
+                if self.model == Model::Cgb {
+                    let led_changed = (self.infrared ^ value) & 0x01 != 0;
+                    self.infrared = value & 0xC1;
+                    if led_changed {
+                        if let Some(callback) = &mut self.infrared_led_callback {
+                            callback(value & 0x01 != 0);
+                        }
+                    }
+                }
+            }
+            0x57..=0x6f => {}
+            0x70 => {
+                if self.model == Model::Cgb {
+                    self.wram_bank = value & 0x07;
+                }
+            }
+            0x71..=0x7f => {}
             0x80..=0xfe => self.hram[address as usize - 0x80] = value,
             0xff => {
                 self.interrupt_enabled = value;
@@ -475,18 +1546,7 @@ impl GameBoy {
         match address {
             0x00 => {
                 // JOYPAD
-                let v = self.joypad_io & 0x30;
-                let mut r = v | 0b1100_0000;
-                if v & 0x10 != 0 {
-                    r |= (self.joypad >> 4) & 0x0F;
-                }
-                if v & 0x20 != 0 {
-                    r |= self.joypad & 0x0F;
-                }
-                if v == 0 {
-                    r |= 0x0F;
-                }
-                r
+                (self.joypad_io & 0x30) | 0b1100_0000 | self.joypad_lines()
             }
             0x01..=0x02 => Serial::read(self, address),
             0x03 => 0xff,
@@ -510,11 +1570,2086 @@ impl GameBoy {
             0x47..=0x4b => Ppu::read(self, address),
             0x4c => 0xff,
             0x4d => 0xff,
-            0x4e..=0x4f => 0xff,
-            0x50 => 0xff,
-            0x51..=0x7F => 0xff,
+            0x4e => 0xff,
+            0x4f => match self.model {
+                Model::Dmg => 0xff,
+                Model::Cgb => 0xFE | self.ppu.borrow().vbk,
+            },
+            // BANK: bit 0 is set once the boot ROM is unmapped
+            0x50 => 0xFE | !self.boot_rom_active as u8,
+            0x51..=0x55 => 0xff,
+            0x56 => match self.model {
+                Model::Dmg => 0xff,
+                Model::Cgb => {
+                    // bit 1 is 0 while receiving a signal, if reading is enabled.
+                    let receiving = self.infrared & 0xC0 == 0xC0 && self.infrared_signal;
+                    0x3C | self.infrared | if receiving { 0x00 } else { 0x02 }
+                }
+            },
+            0x57..=0x6F => 0xff,
+            0x70 => match self.model {
+                Model::Dmg => 0xff,
+                Model::Cgb => 0xF8 | self.wram_bank,
+            },
+            0x71..=0x7F => 0xff,
             0x80..=0xfe => self.hram[address as usize - 0x80],
             0xff => self.interrupt_enabled,
         }
     }
 }
+
+#[cfg(test)]
+impl GameBoy {
+    /// Write each value to its address, 4 clocks apart, like consecutive instructions would.
+    pub(crate) fn write_registers(&mut self, writes: &[(u16, u8)]) {
+        for &(address, value) in writes {
+            self.tick(4);
+            self.write(address, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn echo_ram() {
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+
+        gb.write(0xC000, 0x12);
+        assert_eq!(gb.read(0xE000), 0x12);
+        gb.write(0xF123, 0x34);
+        assert_eq!(gb.read(0xD123), 0x34);
+        gb.write(0xDDFF, 0x56);
+        assert_eq!(gb.read(0xFDFF), 0x56);
+
+        // the unusable region is not echoed.
+        gb.write(0xFEA0, 0x78);
+        assert_ne!(gb.read(0xFEA0), 0x78);
+    }
+
+    #[test]
+    fn capture_on() {
+        // scroll the screen at each VBlank:
+        // LD A, $01; LDH (IE), A
+        // loop: XOR A; LDH (IF), A; HALT; LDH A, (SCX); INC A; LDH (SCX), A; JR loop
+        let cartridge = Cartridge::with_code(
+            0x100,
+            &[
+                0x3E, 0x01, 0xE0, 0xFF, 0xAF, 0xE0, 0x0F, 0x76, 0xF0, 0x43, 0x3C, 0xE0, 0x43, 0x18,
+                0xF5,
+            ],
+        );
+
+        let mut reference = GameBoy::new(None, cartridge.clone());
+        let frames = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let frames_ref = frames.clone();
+        reference.v_blank = Some(Box::new(move |gb| {
+            let screen = gb.ppu.borrow_mut().screen().packed().to_vec();
+            frames_ref.lock().unwrap().push(screen);
+        }));
+
+        let mut gb = GameBoy::new(None, cartridge);
+        gb.capture_on(CaptureTrigger::Frame(2));
+        for gb in [&mut gb, &mut reference] {
+            let target = gb.clock_count + 4 * consts::FRAME_CYCLES;
+            while gb.clock_count < target {
+                Interpreter(gb).interpret_op();
+            }
+        }
+
+        let frames = frames.lock().unwrap();
+        assert!(frames.len() >= 3);
+        assert_ne!(frames[0], frames[1]);
+        assert_ne!(frames[1], frames[2]);
+        assert_eq!(gb.take_capture().as_ref(), Some(&frames[1]));
+        assert_eq!(gb.take_capture(), None);
+
+        // on a PC, right after the HALT ends at the next VBlank
+        gb.capture_on(CaptureTrigger::Pc(0x108));
+        let mut captured = None;
+        while captured.is_none() {
+            Interpreter(&mut gb).interpret_op();
+            captured = gb.take_capture();
+        }
+        // the capture happened before executing the LDH at 0x108
+        assert_eq!(gb.cpu.pc, 0x10A);
+        assert_eq!(gb.ppu.borrow().ly, 144);
+        assert_eq!(
+            captured,
+            Some(gb.ppu.borrow_mut().screen().packed().to_vec())
+        );
+
+        gb.capture_on(CaptureTrigger::Frame(0));
+        assert_eq!(
+            gb.take_capture(),
+            Some(gb.ppu.borrow_mut().screen().packed().to_vec())
+        );
+    }
+
+    #[test]
+    fn div_counter() {
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        let start = gb.div_counter();
+        gb.tick(100);
+        assert_eq!(gb.div_counter(), start.wrapping_add(100));
+        assert_eq!(gb.read(0xFF04), (gb.div_counter() >> 8) as u8);
+
+        gb.tick(4);
+        gb.write(0xFF04, 0x12);
+        assert_eq!(gb.div_counter(), 0);
+        assert_eq!(gb.read(0xFF04), 0);
+
+        // DIV increments every 256 clock cycles, at 16384 Hz
+        gb.tick(255);
+        assert_eq!(gb.div_counter(), 255);
+        assert_eq!(gb.read(0xFF04), 0);
+        gb.tick(1);
+        assert_eq!(gb.read(0xFF04), 1);
+        gb.tick(256 * 9);
+        assert_eq!(gb.read(0xFF04), 10);
+        assert_eq!(gb.div_counter(), 2560);
+    }
+
+    #[test]
+    fn invalid_logo_lock_up() {
+        // a boot ROM that only checks the first 0x18 bytes of the logo, like the DMG one
+        let mut boot_rom = [0x00; 0x100];
+        let code = [
+            0x21, 0x04, 0x01, // LD HL, $0104
+            0x11, 0xa8, 0x00, // LD DE, $00A8
+            0x06, 0x18, // LD B, $18
+            0x1a, // LD A, (DE)
+            0x13, // INC DE
+            0xbe, // CP (HL)
+            0x20, 0xfe, // JR NZ, -2 (lock up)
+            0x23, // INC HL
+            0x05, // DEC B
+            0x20, 0xf7, // JR NZ, -9
+            0xc3, 0xfc, 0x00, // JP $00FC
+        ];
+        boot_rom[..code.len()].copy_from_slice(&code);
+        boot_rom[0xa8..0xa8 + 0x18].copy_from_slice(&cartridge::NINTENDO_LOGO[..0x18]);
+        boot_rom[0xfc..].copy_from_slice(&[
+            0x3e, 0x01, // LD A, $01
+            0xe0, 0x50, // LDH ($50), A
+        ]);
+
+        let mut valid = Cartridge::nop_rom();
+        valid[0x104..0x134].copy_from_slice(&cartridge::NINTENDO_LOGO);
+        let valid = Cartridge::new(valid).unwrap();
+
+        for (cartridge, bypass, boots) in [
+            (Cartridge::halt_filled(), false, false),
+            (Cartridge::halt_filled(), true, true),
+            (valid.clone(), false, true),
+            (valid, true, true),
+        ] {
+            let mut gb = GameBoy::new(Some(boot_rom), cartridge);
+            gb.bypass_logo_check = bypass;
+            gb.fast_boot();
+            assert_eq!(!gb.boot_rom_active, boots, "bypass {}", bypass);
+            if boots {
+                assert_eq!(gb.cpu.pc, 0x100);
+                // the logo is read from the cartridge again
+                assert_eq!(gb.read(0x104) == 0xCE, gb.cartridge.header.check_logo());
+            } else {
+                // stuck in the lock up until the timeout
+                assert_eq!(gb.cpu.pc, 0x0b);
+                assert!(gb.clock_count >= FAST_BOOT_TIMEOUT);
+            }
+        }
+    }
+
+    #[test]
+    fn boot_rom_register() {
+        let mut boot_rom = [0x00; 0x100];
+        boot_rom[0] = 0x31;
+        let mut gb = GameBoy::new(Some(boot_rom), Cartridge::halt_filled());
+        assert_eq!(gb.read(0xFF50), 0xFE);
+        assert_eq!(gb.read(0x0000), 0x31);
+
+        // only bit 0 unmaps the boot ROM
+        gb.write(0xFF50, 0xFE);
+        assert_eq!(gb.read(0xFF50), 0xFE);
+        gb.write(0xFF50, 0x01);
+        assert_eq!(gb.read(0xFF50), 0xFF);
+        assert_eq!(gb.read(0x0000), 0x76);
+
+        // and it cannot be mapped again
+        gb.write(0xFF50, 0x00);
+        assert_eq!(gb.read(0xFF50), 0xFF);
+        assert_eq!(gb.read(0x0000), 0x76);
+
+        let gb = GameBoy::new(None, Cartridge::halt_filled());
+        assert_eq!(gb.read(0xFF50), 0xFF);
+    }
+
+    #[test]
+    fn cgb_boot_rom() {
+        let mut boot_rom = Box::new([0x00; 0x900]);
+        for (i, x) in boot_rom.iter_mut().enumerate() {
+            *x = (i as u8).wrapping_mul(3) | 0x80;
+        }
+        boot_rom[..3].copy_from_slice(&[0xc3, 0x00, 0x02]); // JP $0200
+        boot_rom[0x200..0x207].copy_from_slice(&[
+            0x3e, 0x01, // LD A, $01
+            0xe0, 0x50, // LDH ($50), A
+            0xc3, 0x00, 0x01, // JP $0100
+        ]);
+        let cartridge = Cartridge::with_code(0x100, &[0x18, 0xFE]); // JR -2
+        let rom = cartridge.rom.clone();
+        let mut gb = GameBoy::new_cgb(Some(boot_rom.clone()), cartridge);
+        assert_eq!(gb.model, Model::Cgb);
+        assert!(gb.boot_rom_active);
+        assert_eq!(gb.cpu.pc, 0x0000);
+
+        // the boot ROM is split around the cartridge header
+        for address in [0x0000, 0x00FF, 0x0200, 0x08FF] {
+            assert_eq!(
+                gb.read(address),
+                boot_rom[address as usize],
+                "{:04x}",
+                address
+            );
+        }
+        for address in [0x0100, 0x0101, 0x0134, 0x01FF, 0x0900] {
+            assert_eq!(gb.read(address), rom[address as usize], "{:04x}", address);
+        }
+
+        // it runs from both regions, and is unmapped by FF50
+        for _ in 0..4 {
+            gb.step_instruction();
+        }
+        assert!(!gb.boot_rom_active);
+        assert_eq!(gb.cpu.pc, 0x0100);
+        assert_eq!(gb.read(0x0000), rom[0]);
+        assert_eq!(gb.read(0x0200), rom[0x200]);
+
+        // a reset starts the boot again
+        gb.reset();
+        assert!(gb.boot_rom_active);
+        assert_eq!(gb.read(0x0200), boot_rom[0x200]);
+
+        // without a boot ROM, it starts after the boot
+        let gb = GameBoy::new_cgb(None, Cartridge::with_code(0x100, &[0x18, 0xFE]));
+        assert_eq!(gb.model, Model::Cgb);
+        assert!(!gb.boot_rom_active);
+        assert_eq!(gb.cpu.pc, 0x0100);
+    }
+
+    #[test]
+    fn after_boot_registers() {
+        let cartridge = Cartridge::with_code(0x100, &[0x18, 0xFE]);
+        let dmg = GameBoy::new(None, cartridge.clone());
+        let cgb = GameBoy::new_cgb(None, cartridge.clone());
+        let registers = |gb: &GameBoy| {
+            let cpu = &gb.cpu;
+            (
+                cpu.a, cpu.f.0, cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l, cpu.sp,
+            )
+        };
+        assert_eq!(
+            registers(&dmg),
+            (0x01, 0xb0, 0x00, 0x13, 0x00, 0xd8, 0x01, 0x4d, 0xfffe)
+        );
+        assert_eq!(
+            registers(&cgb),
+            (0x11, 0x80, 0x00, 0x00, 0xff, 0x56, 0x00, 0x0d, 0xfffe)
+        );
+
+        // the model is saved, overwriting the one of the loaded GameBoy
+        assert!(dmg != cgb);
+        let mut state = Vec::new();
+        cgb.save_state(None, &mut state).unwrap();
+        let mut other = GameBoy::new(None, cartridge);
+        other.load_state(&mut &state[..]).unwrap();
+        assert_eq!(other.model, Model::Cgb);
+        assert!(other == cgb);
+    }
+
+    #[test]
+    fn reset_clears_memory() {
+        let mut gb = GameBoy::new(Some([0; 0x100]), Cartridge::halt_filled());
+        gb.ppu.get_mut().set_render_threads(2);
+        for i in 0..3u8 {
+            gb.wram.fill(0x11 + i);
+            gb.hram.fill(0x22 + i);
+            gb.ppu.get_mut().vram.fill(0x33 + i);
+            gb.ppu.get_mut().oam.fill(0x44 + i);
+
+            gb.reset();
+            assert!(gb.wram.iter().all(|&x| x == 0));
+            assert!(gb.hram.iter().all(|&x| x == 0));
+            let ppu = gb.ppu.get_mut();
+            assert!(ppu.vram.iter().all(|&x| x == 0));
+            assert!(ppu.oam.iter().all(|&x| x == 0));
+            assert_eq!(ppu.render_threads(), 2);
+        }
+
+        // without a boot rom, only the values left by the boot ROM remain
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        gb.wram.fill(0x55);
+        gb.hram.fill(0x66);
+        gb.reset();
+        assert!(gb.wram.iter().all(|&x| x == 0));
+        assert_eq!(gb.hram.iter().filter(|&&x| x != 0).count(), 3);
+    }
+
+    #[test]
+    fn sync_to() {
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        let target = gb.clock_count + consts::FRAME_CYCLES + 123;
+        gb.sync_to(target);
+
+        assert_eq!(gb.clock_count, target);
+        assert_eq!(gb.ppu.borrow().last_clock_count, target);
+        assert_eq!(gb.timer.borrow().last_clock_count, target);
+        assert_eq!(gb.sound.borrow().last_clock_count, target);
+
+        // syncing to the current clock does nothing
+        let hash = gb.state_hash();
+        gb.sync_to(target);
+        assert_eq!(gb.state_hash(), hash);
+    }
+
+    #[test]
+    #[should_panic]
+    fn sync_to_the_past() {
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        gb.sync_to(gb.clock_count - 1);
+    }
+
+    #[cfg(feature = "timing_profile")]
+    #[test]
+    fn timing_profile() {
+        // LD A, $80; LDH (NR52), A; INC B; JR -3
+        let cartridge = Cartridge::with_code(0x100, &[0x3E, 0x80, 0xE0, 0x26, 0x04, 0x18, 0xFD]);
+        let mut gb = GameBoy::new(None, cartridge);
+        gb.sound.get_mut().sample_frequency = 44100;
+
+        let target = gb.clock_count + 3 * consts::FRAME_CYCLES;
+        while gb.clock_count < target {
+            Interpreter(&mut gb).interpret_op();
+        }
+        gb.sound.get_mut().get_output(gb.clock_count);
+
+        let profile = gb.timing_profile();
+        assert!(profile.interpreter > 0, "{:?}", profile);
+        assert!(profile.ppu_fifo > 0, "{:?}", profile);
+        assert!(profile.sound > 0, "{:?}", profile);
+        assert_eq!(profile.jit, 0);
+        assert_eq!(profile.frames, 3);
+
+        gb.reset_timing_profile();
+        assert_eq!(gb.timing_profile(), TimingProfile::default());
+    }
+
+    #[test]
+    fn no_cartridge() {
+        let mut gb = GameBoy::new(None, Cartridge::none());
+        for address in [0x0000, 0x0100, 0x4000, 0x7FFF, 0xA000, 0xBFFF] {
+            assert_eq!(gb.read(address), 0xFF);
+        }
+
+        #[rustfmt::skip]
+        let program = [
+            0x3E, 0x42,       // LD   A, $42
+            0xEA, 0x00, 0xC1, // LD   ($C100), A
+            0x04,             // INC  B
+            0x18, 0xFD,       // JR   -3
+        ];
+        gb.wram[..program.len()].copy_from_slice(&program);
+        // JP $C000, from HRAM
+        gb.hram[..3].copy_from_slice(&[0xC3, 0x00, 0xC0]);
+        gb.cpu.pc = 0xFF80;
+        gb.cpu.b = 0;
+
+        for _ in 0..3 + 10 * 2 {
+            Interpreter(&mut gb).interpret_op();
+        }
+        assert_eq!(gb.read(0xC100), 0x42);
+        assert_eq!(gb.cpu.b, 10);
+        assert_eq!(gb.cpu.pc, 0xC005);
+    }
+
+    #[test]
+    fn audio_only() {
+        // Return the clock_count of each VBlank interrupt in 3 frames, the sound output, the
+        // STAT modes seen in the visible lines, and the screen before and after.
+        fn run(audio_only: bool) -> (Vec<u64>, Vec<u16>, u8, [Vec<u8>; 2]) {
+            let cartridge = Cartridge::halt_filled();
+            let mut gb = if audio_only {
+                GameBoy::new_audio_only(None, cartridge)
+            } else {
+                GameBoy::new(None, cartridge)
+            };
+            gb.sound.get_mut().sample_frequency = 44100;
+            // the background is filled with color 3
+            gb.ppu.get_mut().vram[..0x10].fill(0xff);
+            gb.ppu.get_mut().vram[0x1800..0x1C00].fill(0x00);
+            // channel 2 playing, with a constant volume
+            gb.write_registers(&[
+                (0xFF40, 0x91),
+                (0xFF47, 0xe4),
+                (0xFF26, 0x80),
+                (0xFF24, 0x77),
+                (0xFF25, 0xFF),
+                (0xFF16, 0x80),
+                (0xFF17, 0xF0),
+                (0xFF19, 0x87),
+            ]);
+
+            gb.interrupt_flag.set(0);
+            let start_screen = gb.ppu.get_mut().screen().packed().to_vec();
+            let mut v_blanks = Vec::new();
+            let mut modes = 0;
+            let end = gb.clock_count + 3 * crate::consts::FRAME_CYCLES;
+            while gb.clock_count < end {
+                gb.tick(4);
+                gb.update_all();
+                if gb.read(0xFF44) < 144 {
+                    modes |= 1 << (gb.read(0xFF41) & 0x03);
+                }
+                if gb.interrupt_flag.get() & 0x01 != 0 {
+                    v_blanks.push(gb.clock_count);
+                    gb.interrupt_flag.set(gb.interrupt_flag.get() & !0x01);
+                }
+            }
+            let output = gb.sound.get_mut().get_output(gb.clock_count);
+            let screen = gb.ppu.get_mut().screen().packed().to_vec();
+            (v_blanks, output, modes, [start_screen, screen])
+        }
+
+        let (v_blanks, output, modes, [_, screen]) = run(false);
+        assert_eq!(v_blanks.len(), 3);
+        assert_eq!(modes & 0b1101, 0b1101);
+        assert!(screen.iter().all(|&x| x == 3));
+
+        // the audio and interrupts are the same, but nothing is drawn
+        let (audio_v_blanks, audio_output, audio_modes, [start_screen, audio_screen]) = run(true);
+        assert_eq!(audio_v_blanks, v_blanks);
+        // the lines still go through all the STAT modes while the CPU is in them
+        assert_eq!(audio_modes, modes);
+        assert!(audio_output.iter().any(|&x| x != 0));
+        assert_eq!(audio_output, output);
+        assert_eq!(audio_screen, start_screen);
+    }
+
+    #[test]
+    fn diff() {
+        let mut a = GameBoy::new(None, Cartridge::halt_filled());
+        let mut b = GameBoy::new(None, Cartridge::halt_filled());
+        assert_eq!(a.diff(&b), []);
+
+        a.write(0xC123, 0x42);
+        assert_eq!(
+            a.diff(&b),
+            [ComponentDiff::Memory {
+                bank: 0,
+                range: 0xC123..0xC124
+            }]
+        );
+        assert!(a != b);
+
+        // consecutive bytes are reported as a single range
+        a.write(0xD000, 0x01);
+        a.write(0xD001, 0x02);
+        a.write(0xFF80, 0x03);
+        b.write(0xC123, 0x42);
+        assert_eq!(
+            a.diff(&b),
+            [
+                ComponentDiff::Memory {
+                    bank: 1,
+                    range: 0xD000..0xD002
+                },
+                ComponentDiff::Memory {
+                    bank: 0,
+                    range: 0xFF80..0xFF81
+                },
+            ]
+        );
+
+        // running one of them changes more than the memory
+        a.tick(4 * 456);
+        let diffs = a.diff(&b);
+        assert!(diffs.contains(&ComponentDiff::Ppu));
+        assert!(diffs.contains(&ComponentDiff::Registers));
+        assert!(!diffs.contains(&ComponentDiff::Cartridge));
+    }
+
+    #[test]
+    fn state_layout() {
+        fn check(gb: &GameBoy) -> Vec<(String, usize, usize)> {
+            let layout = gb.dump_state_layout();
+            let mut data = Vec::new();
+            gb.save_state(None, &mut data).unwrap();
+
+            // the fields cover the entire save state, in order
+            let mut offset = 0;
+            for (name, start, len) in &layout {
+                assert_eq!(*start, offset, "{}", name);
+                offset += len;
+            }
+            assert_eq!(offset, data.len());
+
+            let (_, start, len) = layout.iter().find(|x| x.0 == "ppu.ly").unwrap();
+            assert_eq!(*len, 1);
+            assert_eq!(data[*start], gb.ppu.borrow().ly);
+            let (_, start, len) = layout.iter().find(|x| x.0 == "cpu.pc").unwrap();
+            assert_eq!(data[*start..][..*len], gb.cpu.pc.to_be_bytes());
+            layout
+        }
+
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        let layout = check(&gb);
+        assert_eq!(layout[0], ("SaveStateHeader".to_string(), 0, 16));
+        assert!(layout
+            .iter()
+            .any(|x| x.0 == "boot_rom_active | v_blank_trigger"));
+
+        // the layout does not depend on the state
+        gb.tick(12345);
+        gb.update_all();
+        assert_eq!(check(&gb), layout);
+        assert_eq!(check(&GameBoy::new(None, Cartridge::halt_filled())), layout);
+    }
+
+    #[test]
+    fn unusable_region() {
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+
+        // disable the LCD, so the OAM is not blocked.
+        gb.tick(4);
+        gb.write(0xFF40, 0x00);
+        for address in [0xFEA0, 0xFEAF, 0xFEC3, 0xFEFF] {
+            assert_eq!(gb.read(address), 0x00);
+        }
+
+        // while a DMA is running, the OAM is blocked.
+        gb.write(0xFF46, 0xC0);
+        gb.tick(16);
+        assert_eq!(gb.read(0xFEA0), 0xFF);
+
+        gb.model = Model::Cgb;
+        assert_eq!(gb.read(0xFEA0), 0xAA);
+        assert_eq!(gb.read(0xFEAF), 0xAA);
+        assert_eq!(gb.read(0xFEB5), 0xBB);
+        assert_eq!(gb.read(0xFEC3), 0xCC);
+        assert_eq!(gb.read(0xFEFF), 0xFF);
+    }
+
+    #[test]
+    fn dma_from_echo_ram() {
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+
+        for i in 0..0xA0 {
+            gb.write(0xC000 + i, i as u8 ^ 0x5a);
+        }
+
+        // DMA Transfer from E000
+        gb.write(0xFF46, 0xE0);
+        gb.tick(8 + 160 * 4);
+        gb.update_all();
+
+        let ppu = gb.ppu.borrow();
+        for i in 0..0xA0 {
+            assert_eq!(ppu.oam[i], i as u8 ^ 0x5a);
+        }
+    }
+
+    #[test]
+    fn dma_from_any_source() {
+        // a ROM with a different byte at each address, but with a zeroed header
+        let mut rom = Cartridge::nop_rom();
+        for (i, x) in rom.iter_mut().enumerate() {
+            *x = (i ^ (i >> 8) ^ 0x42) as u8;
+        }
+        rom[0x100..0x150].fill(0);
+        let mut gb = GameBoy::new(None, Cartridge::new(rom).unwrap());
+        // turn the LCD off, so the PPU does not block the VRAM
+        gb.tick(4);
+        gb.write(0xFF40, 0x00);
+        for i in 0..0x2000 {
+            gb.write(0x8000 + i, (i ^ (i >> 8) ^ 0x17) as u8);
+            gb.write(0xC000 + i, (i ^ (i >> 8) ^ 0x5a) as u8);
+        }
+
+        for source in 0x00..=0xFF {
+            // the WRAM is read at and above E000, including the sources FE and FF
+            let normalized = if source >= 0xE0 {
+                source - 0x20
+            } else {
+                source
+            };
+            let expected: Vec<u8> = (0..0xA0)
+                .map(|i| gb.read(((normalized as u16) << 8) + i))
+                .collect();
+
+            gb.write(0xFF46, source);
+            gb.tick(8 + 160 * 4);
+            gb.update_all();
+            assert_eq!(gb.ppu.borrow().oam[..0xA0], expected[..], "{:02x}", source);
+        }
+
+        // the source E0 is the echo of C000, and FF the one of DF00
+        let oam = |gb: &mut GameBoy, source: u8| {
+            gb.write(0xFF46, source);
+            gb.tick(8 + 160 * 4);
+            gb.update_all();
+            gb.ppu.borrow().oam[..0xA0].to_vec()
+        };
+        let wram = |address: usize| gb.wram[address - 0xC000..][..0xA0].to_vec();
+        let (c000, df00) = (wram(0xC000), wram(0xDF00));
+        assert_eq!(oam(&mut gb, 0xE0), c000);
+        assert_eq!(oam(&mut gb, 0xFF), df00);
+    }
+
+    #[test]
+    fn dma_oam_timing() {
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        // turn the LCD off, so the PPU does not block the OAM
+        gb.tick(4);
+        gb.write(0xFF40, 0x00);
+        for i in 0..0xA0 {
+            gb.write(0xC100 + i, i as u8 ^ 0x5a);
+            gb.write(0xFE00 + i, 0x11);
+        }
+
+        gb.tick(4);
+        gb.write(0xFF46, 0xC1);
+        // the OAM is only blocked after a delay of 8 cycles
+        assert_eq!(gb.read(0xFE00), 0x11);
+        gb.tick(4);
+        assert_eq!(gb.read(0xFE00), 0x11);
+        gb.tick(4);
+        assert_eq!(gb.read(0xFE00), 0xFF);
+        assert_eq!(gb.read(0xFE9F), 0xFF);
+        // and writes are ignored
+        gb.write(0xFE9F, 0x22);
+
+        // the transfer takes 160 machine cycles
+        gb.tick(160 * 4 - 4);
+        assert_eq!(gb.read(0xFE00), 0xFF);
+        gb.tick(4);
+        for i in 0..0xA0 {
+            assert_eq!(gb.read(0xFE00 + i), i as u8 ^ 0x5a);
+        }
+
+        // restarting a running DMA keeps the OAM blocked
+        gb.write(0xFF46, 0xC1);
+        gb.tick(12);
+        assert_eq!(gb.read(0xFE00), 0xFF);
+        gb.write(0xFF46, 0xC1);
+        assert_eq!(gb.read(0xFE00), 0xFF);
+        gb.tick(4);
+        assert_eq!(gb.read(0xFE00), 0xFF);
+        gb.tick(4 + 160 * 4);
+        assert_eq!(gb.read(0xFE00), 0x5a);
+    }
+
+    #[test]
+    fn instant_dma() {
+        for instant_dma in [false, true] {
+            let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+            gb.instant_dma = instant_dma;
+            // turn the LCD off, so the PPU does not block the OAM
+            gb.tick(4);
+            gb.write(0xFF40, 0x00);
+            for i in 0..0xA0 {
+                gb.write(0xC100 + i, i as u8 ^ 0x5a);
+                gb.write(0xFE00 + i, 0x11);
+            }
+
+            gb.tick(4);
+            gb.write(0xFF46, 0xC1);
+            gb.tick(8);
+            if instant_dma {
+                for i in 0..0xA0 {
+                    assert_eq!(gb.read(0xFE00 + i), i as u8 ^ 0x5a);
+                }
+                // the OAM is not blocked
+                gb.write(0xFE9F, 0x22);
+                assert_eq!(gb.read(0xFE9F), 0x22);
+            } else {
+                assert_eq!(gb.read(0xFE00), 0xFF);
+                gb.tick(160 * 4);
+                assert_eq!(gb.read(0xFE00), 0x5a);
+            }
+            assert_eq!(gb.read(0xFF46), 0xC1);
+        }
+    }
+
+    #[test]
+    fn dma_oam_timing_in_instructions() {
+        // The DMA register is written in the last M-cycle of LDH, M-cycle 0. After a M-cycle of
+        // delay, the OAM is blocked from M-cycle 2 to 161, while the 160 bytes are transferred.
+        let mut code = vec![
+            0xaf, // XOR A
+            0xe0, 0x40, // LDH ($40), A
+            0x21, 0x00, 0xfe, // LD HL, $FE00
+            0x36, 0x11, // LD (HL), $11
+            0x3e, 0xc1, // LD A, $C1
+            0xe0, 0x46, // LDH ($46), A
+            0x46, // LD B, (HL) ; read at M-cycle 2
+            0x4e, // LD C, (HL) ; read at M-cycle 4
+        ];
+        code.extend([0x00; 155]); // NOP * 155
+        code.extend([
+            0x56, // LD D, (HL) ; read at M-cycle 161
+            0x5e, // LD E, (HL) ; read at M-cycle 163
+            0x76, // HALT
+        ]);
+        let mut gb = GameBoy::new(None, Cartridge::with_entry_code(&code));
+        for i in 0..0xA0 {
+            gb.write(0xC100 + i, i as u8 ^ 0x5a);
+        }
+        while gb.cpu.state != cpu::CpuState::Halt {
+            Interpreter(&mut gb).interpret_op();
+        }
+        let cpu = &gb.cpu;
+        assert_eq!([cpu.b, cpu.c, cpu.d, cpu.e], [0xFF, 0xFF, 0xFF, 0x5a]);
+        assert_eq!(gb.ppu.borrow().oam[0x9F], 0x9F ^ 0x5a);
+    }
+
+    #[test]
+    fn wram_banks() {
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        gb.model = Model::Cgb;
+
+        gb.write(0xC000, 0x10);
+        for bank in 0..8 {
+            gb.write(0xFF70, bank);
+            gb.write(0xD000, 0x20 + bank);
+            assert_eq!(gb.read(0xFF70), 0xF8 | bank);
+        }
+        gb.write(0xFF70, 2);
+        assert_eq!(gb.read(0xD000), 0x22);
+        gb.write(0xFF70, 3);
+        assert_eq!(gb.read(0xD000), 0x23);
+        assert_eq!(gb.read(0xF000), 0x23);
+        // the bank 0 is always at C000
+        assert_eq!(gb.read(0xC000), 0x10);
+
+        // selecting the bank 0 selects the bank 1
+        gb.write(0xFF70, 0);
+        assert_eq!(gb.read(0xD000), 0x21);
+        gb.write(0xFF70, 1);
+        assert_eq!(gb.read(0xD000), 0x21);
+        gb.write(0xFF70, 0x0A);
+        assert_eq!(gb.read(0xFF70), 0xFA);
+        assert_eq!(gb.read(0xD000), 0x22);
+
+        // all banks are saved
+        let mut state = Vec::new();
+        gb.save_state(None, &mut state).unwrap();
+        let mut other = GameBoy::new(None, Cartridge::halt_filled());
+        other.model = Model::Cgb;
+        other.load_state(&mut state.as_slice()).unwrap();
+        assert_eq!(other.read(0xFF70), 0xFA);
+        other.write(0xFF70, 7);
+        assert_eq!(other.read(0xD000), 0x27);
+
+        // the DMG has a single bank at D000
+        gb.model = Model::Dmg;
+        gb.write(0xFF70, 3);
+        assert_eq!(gb.read(0xFF70), 0xFF);
+        assert_eq!(gb.read(0xD000), 0x21);
+    }
+
+    #[test]
+    fn vram_banks() {
+        let mut gb = GameBoy::new_cgb(None, Cartridge::halt_filled());
+        // turn off the LCD, so the VRAM is never blocked
+        gb.write_registers(&[(0xFF40, 0x00)]);
+
+        gb.write(0x8000, 0x10);
+        gb.write(0xFF4F, 0xFF);
+        assert_eq!(gb.read(0xFF4F), 0xFF);
+        assert_eq!(gb.read(0x8000), 0x00);
+        gb.write(0x8000, 0x11);
+        gb.write(0x9FFF, 0x12);
+        gb.write(0xFF4F, 0x00);
+        assert_eq!(gb.read(0xFF4F), 0xFE);
+        assert_eq!(gb.read(0x8000), 0x10);
+        assert_eq!(gb.read(0x9FFF), 0x00);
+
+        let ppu = gb.ppu.get_mut();
+        assert_eq!(ppu.vram_read(0x8000, 0), Some(0x10));
+        assert_eq!(ppu.vram_read(0x8000, 1), Some(0x11));
+        assert_eq!(ppu.vram_read(0x9FFF, 1), Some(0x12));
+
+        // both banks are saved
+        gb.write(0xFF4F, 0x01);
+        let mut state = Vec::new();
+        gb.save_state(None, &mut state).unwrap();
+        let mut other = GameBoy::new_cgb(None, Cartridge::halt_filled());
+        other.load_state(&mut state.as_slice()).unwrap();
+        assert_eq!(other.read(0xFF4F), 0xFF);
+        assert_eq!(other.read(0x8000), 0x11);
+        other.write(0xFF4F, 0x00);
+        assert_eq!(other.read(0x8000), 0x10);
+
+        // the DMG has a single bank
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        gb.write_registers(&[(0xFF40, 0x00)]);
+        gb.write(0x8000, 0x10);
+        gb.write(0xFF4F, 0x01);
+        assert_eq!(gb.read(0xFF4F), 0xFF);
+        assert_eq!(gb.read(0x8000), 0x10);
+    }
+
+    #[test]
+    fn dma_bus_conflict() {
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        gb.strict_accuracy = true;
+
+        for i in 0..0xA0 {
+            gb.write(0xC100 + i, i as u8 ^ 0x5a);
+        }
+        gb.write(0xD000, 0x77);
+        gb.write(0xFF80, 0x33);
+
+        // DMA Transfer from C100
+        gb.write(0xFF46, 0xC1);
+        gb.tick(8 + 4 * 4);
+
+        // reading during the DMA returns the byte being transferred
+        assert_eq!(gb.read(0xD000), 4 ^ 0x5a);
+        assert_eq!(gb.read(0x0000), 4 ^ 0x5a);
+        // HRAM is not affected
+        assert_eq!(gb.read(0xFF80), 0x33);
+
+        gb.tick(160 * 4);
+        assert_eq!(gb.read(0xD000), 0x77);
+
+        // without strict accuracy, there is no conflict
+        gb.strict_accuracy = false;
+        gb.write(0xFF46, 0xC1);
+        gb.tick(8 + 4 * 4);
+        assert_eq!(gb.read(0xD000), 0x77);
+    }
+
+    #[test]
+    fn step_ppu_dot() {
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        // the background is filled with tile 0, of color 3.
+        gb.ppu.get_mut().vram[..0x10].fill(0xff);
+        gb.tick(4);
+        gb.write(0xFF40, 0x91);
+
+        let mut dot = gb.step_ppu_dot();
+        while !(dot.ly == 1 && dot.mode == 3) {
+            dot = gb.step_ppu_dot();
+        }
+
+        // counting the dot that entered mode 3
+        let mut mode_3_dots = 1;
+        let mut refills = 0;
+        let mut stalls = 0;
+        loop {
+            let next = gb.step_ppu_dot();
+            if next.mode != 3 {
+                dot = next;
+                break;
+            }
+            mode_3_dots += 1;
+            if next.screen_x > 0 {
+                assert!(next.background_fifo.iter().all(|&x| x == 3), "{:?}", next);
+            }
+            assert!(next.sprite_fifo.is_empty());
+
+            let len = dot.background_fifo.len() as i32;
+            let next_len = next.background_fifo.len() as i32;
+            if next_len == 8 && len < 8 {
+                // the fetcher pushed a new tile, after the FIFO was drained
+                assert!(len <= 1, "{:?} -> {:?}", dot, next);
+                refills += 1;
+            } else if next == dot {
+                // the fetcher is still fetching the first tile
+                stalls += 1;
+            } else {
+                // one pixel was popped, being drawn or discarded
+                assert_eq!(next_len, len - 1, "{:?} -> {:?}", dot, next);
+                // in the first pixel, scanline_x still has the value from the previous line.
+                if dot.scanline_x != 160 {
+                    assert_eq!(next.scanline_x, dot.scanline_x.wrapping_add(1));
+                }
+            }
+            assert!(next.screen_x == dot.screen_x || next.screen_x == dot.screen_x + 1);
+            dot = next;
+        }
+
+        assert_eq!(mode_3_dots, 172);
+        assert_eq!(refills, 20);
+        assert_eq!(stalls, 4);
+        assert_eq!((dot.ly, dot.mode, dot.screen_x), (1, 0, 160));
+    }
+
+    #[test]
+    fn trace_ppu_frame() {
+        use crate::consts::{FRAME_CYCLES, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        gb.tick(4);
+        gb.write(0xFF40, 0x91);
+        // start in the middle of a scanline
+        gb.tick(FRAME_CYCLES / 3);
+
+        let start = gb.clock_count;
+        let mut dots = 0;
+        let mut pixels = 0;
+        let mut lines = [0; SCREEN_HEIGHT];
+        for dot in gb.trace_ppu_frame() {
+            dots += 1;
+            if dot.pixel_output {
+                pixels += 1;
+                lines[dot.ly as usize] += 1;
+            }
+        }
+        assert_eq!(dots, FRAME_CYCLES);
+        assert_eq!(gb.clock_count - start, FRAME_CYCLES);
+        assert_eq!(pixels, SCREEN_WIDTH * SCREEN_HEIGHT);
+        assert!(lines.iter().all(|&x| x == SCREEN_WIDTH), "{:?}", lines);
+    }
+
+    #[test]
+    fn stat_mode_callback() {
+        use std::sync::{Arc, Mutex};
+
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        *gb.stat_mode_callback.get_mut() = Some(Box::new({
+            let changes = changes.clone();
+            move |ly, mode, clock| changes.lock().unwrap().push((ly, mode, clock))
+        }));
+
+        gb.tick(3 * crate::consts::FRAME_CYCLES);
+        gb.update_all();
+
+        let changes = changes.lock().unwrap();
+
+        // start at the first full frame
+        let start = changes.iter().position(|&(_, mode, _)| mode == 1).unwrap() + 1;
+        let mut changes = changes[start..].iter().copied();
+
+        // the mode is 0 for a single cycle at the start of line 0
+        let (ly, mode, clock) = changes.next().unwrap();
+        assert_eq!((ly, mode), (0, 0));
+        let mut line_start = clock + 1;
+
+        for line in 0..144 {
+            let (ly, mode, clock) = changes.next().unwrap();
+            assert_eq!((ly, mode), (line, 2));
+            assert_eq!(clock, line_start);
+
+            let (ly, mode, clock) = changes.next().unwrap();
+            assert_eq!((ly, mode), (line, 3));
+            assert_eq!(clock - line_start, 80);
+
+            let (ly, mode, clock) = changes.next().unwrap();
+            assert_eq!((ly, mode), (line, 0));
+            assert!((80 + 172..=80 + 289).contains(&(clock - line_start)));
+
+            line_start += 456;
+        }
+
+        let (ly, mode, clock) = changes.next().unwrap();
+        assert_eq!((ly, mode), (144, 1));
+        assert_eq!(clock, line_start);
+    }
+
+    #[test]
+    fn infrared_port() {
+        use std::sync::{Arc, Mutex};
+
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        gb.tick(4);
+        gb.write(0xFF56, 0xC1);
+        assert_eq!(gb.read(0xFF56), 0xFF);
+
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        gb.model = Model::Cgb;
+        let leds = Arc::new(Mutex::new(Vec::new()));
+        gb.infrared_led_callback = Some(Box::new({
+            let leds = leds.clone();
+            move |on| leds.lock().unwrap().push(on)
+        }));
+        assert_eq!(gb.read(0xFF56), 0x3E);
+
+        // turn the LED on, with reading disabled
+        gb.tick(4);
+        gb.write(0xFF56, 0x01);
+        assert_eq!(gb.read(0xFF56), 0x3F);
+        gb.infrared_signal = true;
+        assert_eq!(gb.read(0xFF56), 0x3F);
+
+        // enable reading
+        gb.tick(4);
+        gb.write(0xFF56, 0xC1);
+        assert_eq!(gb.read(0xFF56), 0xFD);
+        gb.infrared_signal = false;
+        assert_eq!(gb.read(0xFF56), 0xFF);
+
+        // turn the LED off
+        gb.tick(4);
+        gb.write(0xFF56, 0xC0);
+        assert_eq!(gb.read(0xFF56), 0xFE);
+
+        assert_eq!(*leds.lock().unwrap(), [true, false]);
+    }
+
+    #[test]
+    fn rom_write_callback() {
+        use std::sync::{Arc, Mutex};
+
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let callback = {
+            let writes = writes.clone();
+            move || -> RomWriteCallback {
+                let writes = writes.clone();
+                Box::new(move |address, value| writes.lock().unwrap().push((address, value)))
+            }
+        };
+
+        // a ROM only cartridge has no MBC registers
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        gb.rom_write_callback = Some(callback());
+        gb.tick(4);
+        gb.write(0x2000, 0x42);
+        gb.tick(4);
+        gb.write(0x7FFF, 0x01);
+        gb.tick(4);
+        gb.write(0xC000, 0x03);
+        assert_eq!(*writes.lock().unwrap(), [(0x2000, 0x42), (0x7FFF, 0x01)]);
+        assert_eq!(gb.read(0x2000), 0x76);
+
+        // in a MBC5, only 6000-7FFF is unused
+        writes.lock().unwrap().clear();
+        let mut rom = vec![0x00; 4 * 0x4000];
+        rom[0x0147] = 0x19;
+        rom[0x0148] = 0x01;
+        let mut gb = GameBoy::new(None, Cartridge::new(rom).unwrap());
+        gb.rom_write_callback = Some(callback());
+        for address in [0x0000, 0x2000, 0x3000, 0x4000, 0x6000, 0x7123] {
+            gb.tick(4);
+            gb.write(address, 0x01);
+        }
+        assert_eq!(*writes.lock().unwrap(), [(0x6000, 0x01), (0x7123, 0x01)]);
+    }
+
+    #[test]
+    fn cgb_register_callback() {
+        use std::sync::{Arc, Mutex};
+
+        let mut rom = Cartridge::nop_rom();
+        rom[0x143] = 0xC0;
+        #[rustfmt::skip]
+        rom[0x150..0x15c].copy_from_slice(&[
+            0x3e, 0x01, // LD A, $01
+            0xe0, 0x4d, // LDH (KEY1), A
+            0xe0, 0x4f, // LDH (VBK), A
+            0xe0, 0x47, // LDH (BGP), A
+            0xe0, 0x70, // LDH (SVBK), A
+            0x18, 0xfe, // JR -2
+        ]);
+        let cartridge = Cartridge::new(rom).unwrap();
+        assert!(cartridge.header.is_cgb_only());
+        assert!(!Cartridge::halt_filled().header.is_cgb_only());
+
+        let run = |model: Model| {
+            let writes = Arc::new(Mutex::new(Vec::new()));
+            let mut gb = GameBoy::new(None, cartridge.clone());
+            gb.model = model;
+            gb.cgb_register_callback = Some(Box::new({
+                let writes = writes.clone();
+                move |address, value| writes.lock().unwrap().push((address, value))
+            }));
+            gb.cpu.pc = 0x150;
+            for _ in 0..10 {
+                Interpreter(&mut gb).interpret_op();
+            }
+            let writes = writes.lock().unwrap().clone();
+            writes
+        };
+
+        assert_eq!(run(Model::Dmg), [(0xFF4D, 1), (0xFF4F, 1), (0xFF70, 1)]);
+        // a CGB has these registers
+        assert_eq!(run(Model::Cgb), []);
+    }
+
+    #[test]
+    fn ppu_write_callback() {
+        use std::sync::{Arc, Mutex};
+
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        gb.ppu_write_callback = Some(Box::new({
+            let writes = writes.clone();
+            move |address, value, clock| writes.lock().unwrap().push((address, value, clock))
+        }));
+
+        let start = gb.clock_count;
+        let mut expected = Vec::new();
+        for (address, value) in [
+            (0xFF40, 0x91),
+            (0xFF41, 0x40),
+            (0xFF42, 0x12),
+            (0xFF43, 0x34),
+            (0xFF44, 0x00),
+            (0xFF45, 0x56),
+            (0xFF46, 0xC0),
+            (0xFF47, 0xE4),
+            (0xFF48, 0xD2),
+            (0xFF49, 0x1B),
+            (0xFF4A, 0x10),
+            (0xFF4B, 0x07),
+            (0xC000, 0x01),
+        ] {
+            gb.tick(4);
+            if address != 0xFF46 && address != 0xC000 {
+                expected.push((address, value, gb.clock_count));
+            }
+            gb.write(address, value);
+        }
+        assert_eq!(gb.clock_count, start + 13 * 4);
+        assert_eq!(*writes.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn panicking_callbacks() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        gb.tick(4);
+        gb.write(0xFF40, 0x91);
+        *gb.stat_mode_callback.get_mut() = Some(Box::new(|ly, mode, _| {
+            if ly == 144 && mode == 1 {
+                panic!("stat mode callback panicked");
+            }
+        }));
+
+        gb.tick(crate::consts::FRAME_CYCLES);
+        let result = catch_unwind(AssertUnwindSafe(|| gb.update_ppu()));
+        assert!(result.is_err());
+
+        // the PPU was fully updated, and nothing was left borrowed
+        assert!(gb.ppu.try_borrow_mut().is_ok());
+        assert!(gb.stat_mode_callback.try_borrow_mut().is_ok());
+        assert_eq!(gb.interrupt_flag.get() & 0x01, 0x01);
+        *gb.stat_mode_callback.get_mut() = None;
+        gb.tick(100);
+        gb.update_ppu();
+
+        // a panicking v_blank callback is kept
+        gb.v_blank = Some(Box::new(|_| panic!("v_blank callback panicked")));
+        let result = catch_unwind(AssertUnwindSafe(|| gb.call_v_blank_callback()));
+        assert!(result.is_err());
+        assert!(gb.v_blank.is_some());
+    }
+
+    #[test]
+    fn joypad_release_callback() {
+        use std::sync::{Arc, Mutex};
+
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        let released = Arc::new(Mutex::new(Vec::new()));
+        gb.joypad_release_callback = Some(Box::new({
+            let released = released.clone();
+            move |x| released.lock().unwrap().push(x)
+        }));
+
+        // select the action buttons
+        gb.write(0xFF00, 0x10);
+        gb.interrupt_flag.set(0);
+
+        // press A
+        gb.set_joypad(!0x10);
+        assert_eq!(gb.interrupt_flag.get() & (1 << 4), 1 << 4);
+        assert!(released.lock().unwrap().is_empty());
+
+        // release A
+        gb.interrupt_flag.set(0);
+        gb.set_joypad(0xFF);
+        assert_eq!(gb.interrupt_flag.get() & (1 << 4), 0);
+        assert_eq!(*released.lock().unwrap(), [0x10]);
+    }
+
+    #[test]
+    fn turbo() {
+        use std::sync::{Arc, Mutex};
+
+        // JR -2 at the entry point
+        let mut gb = GameBoy::new(None, Cartridge::with_code(0x100, &[0x18, 0xFE]));
+        // select the action buttons
+        gb.write(0xFF00, 0x10);
+        gb.set_turbo(0x10, 2);
+
+        // record if A is pressed, and if the joypad interrupt was requested, at each frame
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        gb.v_blank = Some(Box::new({
+            let frames = frames.clone();
+            move |gb| {
+                let interrupt = gb.interrupt_flag.get() & (1 << 4) != 0;
+                gb.interrupt_flag.set(gb.interrupt_flag.get() & !(1 << 4));
+                frames
+                    .lock()
+                    .unwrap()
+                    .push((gb.joypad & 0x10 == 0, interrupt));
+            }
+        }));
+        let run_frames = |gb: &mut GameBoy, n: usize| {
+            while frames.lock().unwrap().len() < n {
+                Interpreter(gb).interpret_op();
+            }
+            std::mem::take(&mut *frames.lock().unwrap())
+        };
+
+        // the press is immediate
+        gb.interrupt_flag.set(0);
+        gb.set_joypad(!0x10);
+        assert_eq!(gb.interrupt_flag.get() & (1 << 4), 1 << 4);
+        gb.interrupt_flag.set(0);
+
+        let (p, r) = (true, false);
+        assert_eq!(
+            run_frames(&mut gb, 8),
+            [
+                (p, false),
+                (r, false),
+                (r, false),
+                (p, true),
+                (p, false),
+                (r, false),
+                (r, false),
+                (p, true),
+            ]
+        );
+
+        // a released button is not fired, and the other buttons are not affected
+        gb.set_joypad(!0x01);
+        assert!(run_frames(&mut gb, 4).iter().all(|&x| x == (false, false)));
+        assert_eq!(gb.joypad, !0x01);
+
+        // disabling the turbo holds the button again
+        gb.set_joypad(!0x10);
+        gb.set_turbo(0, 1);
+        assert!(run_frames(&mut gb, 4).iter().all(|&(pressed, _)| pressed));
+    }
+
+    #[test]
+    fn joypad_select_lines() {
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        // press A, Right and Down
+        gb.set_joypad(!(0x10 | 0x01 | 0x08));
+
+        for (select, expected) in [
+            (0x00, 0xC6), // both groups
+            (0x10, 0xDE), // buttons
+            (0x20, 0xE6), // directions
+            (0x30, 0xFF), // none
+        ] {
+            gb.write(0xFF00, select | 0xCF);
+            assert_eq!(gb.read(0xFF00), expected, "select {:02x}", select);
+        }
+    }
+
+    #[test]
+    fn last_frame_cycles() {
+        use crate::consts::FRAME_CYCLES;
+
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        assert_eq!(gb.last_frame_cycles(), None);
+
+        for _ in 0..3 {
+            gb.tick(FRAME_CYCLES);
+            gb.update_all();
+        }
+        assert_eq!(gb.last_frame_cycles(), Some(FRAME_CYCLES));
+
+        // find the start of a frame
+        gb.v_blank_trigger.set(false);
+        while !gb.v_blank_trigger.get() {
+            gb.tick(4);
+            gb.update_all();
+        }
+        let frame_start = gb.clock_count;
+
+        // turn the LCD off for 1000 cycles, at the middle of the frame.
+        gb.tick(20_000);
+        gb.write(0xFF40, 0x11);
+        gb.tick(1000);
+        gb.write(0xFF40, 0x91);
+        let lcd_on = gb.clock_count;
+
+        gb.v_blank_trigger.set(false);
+        while !gb.v_blank_trigger.get() {
+            gb.tick(4);
+            gb.update_all();
+        }
+        // After turning on, the LCD takes 144 scanlines to reach VBlank. `frame_start` may be up
+        // to 4 cycles late.
+        let expected = lcd_on - frame_start + 144 * 456;
+        let cycles = gb.last_frame_cycles().unwrap();
+        assert!(
+            (expected - 4..=expected + 4).contains(&cycles),
+            "{} != {}",
+            cycles,
+            expected
+        );
+
+        // the next frame is normal again.
+        gb.tick(FRAME_CYCLES);
+        gb.update_all();
+        assert_eq!(gb.last_frame_cycles(), Some(FRAME_CYCLES));
+    }
+
+    #[test]
+    fn sound_reads_while_off() {
+        // only the DMG, because there is no difference between the models emulated here
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        gb.write_registers(&[(0xFF24, 0x77), (0xFF11, 0x80), (0xFF26, 0x00)]);
+
+        // the wave RAM is still accessible
+        for i in 0..16 {
+            gb.tick(4);
+            gb.write(0xFF30 + i, 0x10 * i as u8 + 0x0F - i as u8);
+        }
+        for i in 0..16 {
+            assert_eq!(gb.read(0xFF30 + i), 0x10 * i as u8 + 0x0F - i as u8);
+        }
+
+        // the registers read as if zeroed, even after writing to the length counters
+        gb.tick(4);
+        gb.write(0xFF11, 0xFF);
+        assert_eq!(gb.read(0xFF11), 0x3F);
+        assert_eq!(gb.read(0xFF24), 0x00);
+        assert_eq!(gb.read(0xFF26), 0x70);
+        assert_eq!(gb.read(0xFF1A), 0x7F);
+    }
+
+    #[test]
+    fn trace_ram_code() {
+        // JP $C000
+        let mut gb = GameBoy::new(None, Cartridge::with_entry_code(&[0xc3, 0x00, 0xc0]));
+        gb.trace_ram_code = true;
+        let routine = [
+            0x3c, // INC A
+            0x06, 0x12, // LD B, $12
+            0xcb, 0x37, // SWAP A
+            0xc3, 0x00, 0xc0, // JP $C000
+        ];
+        for (i, &b) in routine.iter().enumerate() {
+            gb.write(0xC000 + i as u16, b);
+        }
+        for _ in 0..10 {
+            Interpreter(&mut gb).interpret_op();
+        }
+
+        let trace = gb.trace.borrow();
+        assert_eq!(
+            trace.ram_code_regions(),
+            [
+                (0xC000, 1, [0x3c, 0, 0]),
+                (0xC001, 2, [0x06, 0x12, 0]),
+                (0xC003, 2, [0xcb, 0x37, 0]),
+                (0xC005, 3, [0xc3, 0x00, 0xc0]),
+            ]
+        );
+        assert!((0xC000..0xC008).all(|x| trace.is_ram_code(x)));
+        assert!(!trace.is_ram_code(0xC008));
+        assert!(!trace.is_ram_code(0xBFFF));
+        // the ROM code is traced as usual
+        assert!(trace.is_already_traced(crate::disassembler::Address::new(0, 0x150)));
+        drop(trace);
+
+        // self-modifying code replaces the old opcode
+        gb.write(0xC001, 0x04); // INC B
+        gb.write(0xC002, 0x00); // NOP
+        for _ in 0..4 {
+            Interpreter(&mut gb).interpret_op();
+        }
+        let regions = gb.trace.borrow().ram_code_regions();
+        assert_eq!(regions[1], (0xC001, 1, [0x04, 0, 0]));
+        assert_eq!(regions[2], (0xC002, 1, [0x00, 0, 0]));
+
+        // nothing is recorded when disabled
+        gb.trace_ram_code = false;
+        gb.write(0xC001, 0x05); // DEC B
+        for _ in 0..4 {
+            Interpreter(&mut gb).interpret_op();
+        }
+        assert_eq!(gb.trace.borrow().ram_code_regions(), regions);
+    }
+
+    #[test]
+    fn cpu_trace() {
+        use std::sync::{Arc, Mutex};
+
+        struct Log(Arc<Mutex<Vec<u8>>>);
+        impl std::io::Write for Log {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut gb = GameBoy::new(
+            None,
+            Cartridge::with_entry_code(&[
+                0x3e, 0x12, // LD A, $12
+                0x06, 0xab, // LD B, $AB
+                0x31, 0x00, 0xd0, // LD SP, $D000
+                0xaf, // XOR A
+            ]),
+        );
+        let log = Arc::new(Mutex::new(Vec::new()));
+        gb.cpu_trace = Some(Box::new(Log(log.clone())));
+        for _ in 0..6 {
+            Interpreter(&mut gb).interpret_op();
+        }
+
+        let log = String::from_utf8(log.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            log,
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:C3,50,01,00\n\
+             A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0150 PCMEM:3E,12,06,AB\n\
+             A:12 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0152 PCMEM:06,AB,31,00\n\
+             A:12 F:B0 B:AB C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0154 PCMEM:31,00,D0,AF\n\
+             A:12 F:B0 B:AB C:13 D:00 E:D8 H:01 L:4D SP:D000 PC:0157 PCMEM:AF,00,00,00\n\
+             A:00 F:80 B:AB C:13 D:00 E:D8 H:01 L:4D SP:D000 PC:0158 PCMEM:00,00,00,00\n"
+        );
+    }
+
+    #[test]
+    fn run_until_serial() {
+        let mut code = Vec::new();
+        for &c in b"hi\n" {
+            code.extend_from_slice(&[
+                0x3e, c, // LD A, c
+                0xe0, 0x01, // LDH ($01), A
+                0x3e, 0x81, // LD A, $81
+                0xe0, 0x02, // LDH ($02), A
+                0xf0, 0x02, // LDH A, ($02)
+                0xe6, 0x80, // AND $80
+                0x20, 0xfa, // JR NZ, -6
+            ]);
+        }
+        code.extend_from_slice(&[0x18, 0xfe]); // JR -2
+
+        let mut gb = GameBoy::new(None, Cartridge::with_entry_code(&code));
+        assert_eq!(gb.run_until_serial(100_000), Some(b"hi\n".to_vec()));
+
+        // there is no more output
+        let start = gb.clock_count;
+        assert_eq!(gb.run_until_serial(10_000), None);
+        assert!(gb.clock_count >= start + 10_000);
+    }
+
+    #[test]
+    fn stack_slice() {
+        let code = [
+            0x01, 0x34, 0x12, // LD BC, $1234
+            0xc5, // PUSH BC
+            0x11, 0x78, 0x56, // LD DE, $5678
+            0xd5, // PUSH DE
+        ];
+        let mut gb = GameBoy::new(None, Cartridge::with_entry_code(&code));
+        gb.cpu.pc = 0x150;
+        let sp = gb.cpu.sp;
+        for _ in 0..4 {
+            gb.step_instruction();
+        }
+        assert_eq!(gb.cpu.sp, sp - 4);
+
+        let hash = gb.state_hash();
+        assert_eq!(
+            gb.stack_slice(4),
+            [
+                (sp - 4, 0x78),
+                (sp - 3, 0x56),
+                (sp - 2, 0x34),
+                (sp - 1, 0x12)
+            ]
+        );
+        assert_eq!(gb.state_hash(), hash);
+
+        // the slice stops at the top of the memory
+        gb.cpu.sp = 0xFFFD;
+        let slice = gb.stack_slice(10);
+        assert_eq!(
+            slice.iter().map(|x| x.0).collect::<Vec<_>>(),
+            [0xFFFD, 0xFFFE, 0xFFFF]
+        );
+        assert_eq!(slice[2].1, gb.interrupt_enabled);
+        assert_eq!(gb.stack_slice(0), []);
+    }
+
+    #[test]
+    fn peek_instruction() {
+        let code = [
+            0x3e, 0x12, // LD A, $12
+            0x18, 0x02, // JR +2
+            0x00, 0x00, // NOP; NOP
+            0xea, 0x01, 0xc0, // LD ($c001), A
+            0x21, 0x00, 0xc0, // LD HL, $c000
+            0xe9, // JP HL
+        ];
+        let mut cartridge = Cartridge::with_entry_code(&code);
+        cartridge.rom[0x4010..0x4013].copy_from_slice(&[0xc3, 0x34, 0x12]); // JP $1234
+        let mut gb = GameBoy::new(None, cartridge);
+        gb.cpu.pc = 0x150;
+        gb.write(0xc000, 0x04); // INC B
+
+        for _ in 0..6 {
+            let start = gb.clock_count;
+            let hash = gb.state_hash();
+            let (mnemonic, len, bytes) = gb.peek_instruction();
+            assert_eq!(gb.clock_count, start);
+            assert_eq!(gb.state_hash(), hash);
+
+            let info = gb.step_instruction();
+            assert_eq!(mnemonic, info.mnemonic);
+            assert_eq!(&bytes[..len as usize], info.bytes.as_slice());
+        }
+        assert_eq!(gb.cpu.pc, 0xc001);
+
+        let (mnemonic, len, bytes) = gb.peek_instruction_at(Address::new(1, 0x10));
+        assert_eq!(
+            (mnemonic.as_str(), len, bytes),
+            ("JP   $1234", 3, [0xc3, 0x34, 0x12])
+        );
+    }
+
+    #[test]
+    fn run_frames() {
+        use crate::consts::{FRAME_CYCLES, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        gb.sound.get_mut().sample_frequency = 48000;
+
+        // end the first frame at a VBlank
+        gb.run_frames(1);
+        let start = gb.clock_count;
+        let (frames, audio) = gb.run_frames(3);
+        assert_eq!(frames.len(), 3);
+        assert!(frames
+            .iter()
+            .all(|x| x.len() == SCREEN_WIDTH * SCREEN_HEIGHT));
+        assert_eq!(frames[2], gb.ppu.borrow_mut().screen().packed());
+
+        // the frames end at the start of VBlank, after the instruction that reached it
+        let elapsed = gb.clock_count - start;
+        assert!(elapsed.abs_diff(3 * FRAME_CYCLES) < 24, "{}", elapsed);
+        let expected = gb.sound.borrow().estimate_samples(start, gb.clock_count);
+        assert!(expected > 0);
+        assert_eq!(audio.len(), expected);
+
+        // with the LCD off, the frames are still returned
+        gb.tick(4);
+        gb.write(0xFF40, 0x00);
+        let start = gb.clock_count;
+        let (frames, audio) = gb.run_frames(2);
+        assert_eq!(frames.len(), 2);
+        assert!(gb.clock_count - start >= 2 * FRAME_CYCLES);
+        let expected = gb.sound.borrow().estimate_samples(start, gb.clock_count);
+        assert!(expected > 0);
+        assert_eq!(audio.len(), expected);
+    }
+
+    #[test]
+    fn step_frame_with_audio() {
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        gb.sound.get_mut().sample_frequency = 48000;
+        gb.run_frames(1);
+
+        let start = gb.clock_count;
+        let mut total = 0;
+        for _ in 0..30 {
+            let frame_start = gb.clock_count;
+            let (frame, audio) = gb.step_frame_with_audio();
+            assert_eq!(frame, gb.ppu.borrow_mut().screen().packed());
+            // the audio of each frame covers exactly its clocks
+            let sound = gb.sound.borrow();
+            assert!(!audio.is_empty());
+            assert_eq!(audio.len() % 2, 0);
+            assert_eq!(
+                audio.len(),
+                sound.estimate_samples(frame_start, gb.clock_count)
+            );
+            assert!(audio.iter().all(|&x| x <= crate::audio_ring::MAX_SAMPLE));
+            total += audio.len();
+        }
+        let expected = gb.sound.borrow().estimate_samples(start, gb.clock_count);
+        assert_eq!(total, expected);
+    }
+
+    #[test]
+    fn step_instruction() {
+        let code = [
+            0x3e, 0x12, // LD A, $12
+            0x21, 0x00, 0xc0, // LD HL, $c000
+            0xfb, // EI
+            0x00, // NOP
+            0x00, // NOP
+        ];
+        let mut gb = GameBoy::new(None, Cartridge::with_entry_code(&code));
+
+        let step = |gb: &mut GameBoy| {
+            let info = gb.step_instruction();
+            (
+                info.pc,
+                info.mnemonic,
+                info.bytes.len(),
+                info.cycles,
+                info.interrupt,
+            )
+        };
+
+        let expected = [
+            (0x100, "JP   $0150", 3, 16, false),
+            (0x150, "LD   A, $12", 2, 8, false),
+            (0x152, "LD   HL, $c000", 3, 12, false),
+            (0x155, "EI", 1, 4, false),
+            (0x156, "NOP", 1, 4, false),
+        ];
+        gb.interrupt_enabled = 0;
+        for (pc, mnemonic, len, cycles, interrupt) in expected {
+            assert_eq!(
+                step(&mut gb),
+                (pc, mnemonic.to_string(), len, cycles, interrupt)
+            );
+        }
+
+        // request a VBlank interrupt
+        gb.interrupt_enabled = 0x01;
+        gb.interrupt_flag.set(0x01);
+        assert_eq!(step(&mut gb), (0x157, String::new(), 0, 20, true));
+        assert_eq!(gb.cpu.pc, 0x40);
+    }
+
+    #[test]
+    fn interrupt_priority() {
+        // JR -2
+        let mut gb = GameBoy::new(None, Cartridge::with_entry_code(&[0x18, 0xfe]));
+        gb.step_instruction();
+        gb.interrupt_enabled = 0x1F;
+
+        // (pending interrupts, serviced interrupt vector)
+        let cases = [
+            (0b00011, 0x40),
+            (0b01100, 0x50),
+            (0b11111, 0x40),
+            (0b10010, 0x48),
+            (0b11000, 0x58),
+            (0b10000, 0x60),
+        ];
+        for (pending, vector) in cases {
+            gb.cpu.pc = 0x150;
+            gb.cpu.ime = cpu::ImeState::Enabled;
+            gb.interrupt_flag.set(pending);
+            let step = gb.step_instruction();
+            assert!(step.interrupt);
+            assert_eq!(gb.cpu.pc, vector, "pending {:05b}", pending);
+            // only the serviced interrupt is acknowledged
+            assert_eq!(
+                gb.interrupt_flag.get() & 0x1F,
+                pending & (pending - 1),
+                "pending {:05b}",
+                pending
+            );
+        }
+
+        // VBlank and the mode 1 STAT interrupt are requested in the same PPU update
+        gb.cpu.pc = 0x150;
+        gb.interrupt_flag.set(0);
+        gb.interrupt_enabled = 0x03;
+        gb.write(0xFF41, 0x10);
+        gb.cpu.ime = cpu::ImeState::Enabled;
+        while !gb.step_instruction().interrupt {}
+        assert_eq!(gb.cpu.pc, 0x40);
+        assert_eq!(gb.interrupt_flag.get() & 0x1F, 0x02);
+
+        gb.cpu.ime = cpu::ImeState::Enabled;
+        assert!(gb.step_instruction().interrupt);
+        assert_eq!(gb.cpu.pc, 0x48);
+        assert_eq!(gb.interrupt_flag.get() & 0x1F, 0x00);
+    }
+
+    #[test]
+    fn state_hash() {
+        let code = [
+            0x3c, // INC A
+            0xea, 0x00, 0xc0, // LD ($c000), A
+            0x18, 0xfa, // JR -6
+        ];
+        let mut a = GameBoy::new(None, Cartridge::with_entry_code(&code));
+        let mut b = GameBoy::new(None, Cartridge::with_entry_code(&code));
+
+        for _ in 0..100 {
+            a.step_instruction();
+            b.step_instruction();
+            assert_eq!(a.state_hash(), b.state_hash());
+        }
+
+        b.wram[0x100] ^= 1;
+        assert_ne!(a.state_hash(), b.state_hash());
+    }
+
+    #[test]
+    fn load_truncated_state() {
+        let code = [0x3c, 0x18, 0xfd]; // INC A; JR -3
+        let mut gb = GameBoy::new(None, Cartridge::with_entry_code(&code));
+        for _ in 0..1000 {
+            gb.step_instruction();
+        }
+        let mut state = Vec::new();
+        gb.save_state(None, &mut state).unwrap();
+
+        for len in 0..state.len() {
+            let mut other = GameBoy::new(None, Cartridge::with_entry_code(&code));
+            let err = other.load_state(&mut &state[..len]).unwrap_err();
+            match &err {
+                LoadStateError::IoError(err) => {
+                    assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof, "at {}", len)
+                }
+                x => panic!("at {}: {:?}", len, x),
+            }
+            assert_eq!(err.to_string(), "save state is truncated");
+        }
+
+        let mut other = GameBoy::new(None, Cartridge::with_entry_code(&code));
+        other.load_state(&mut &state[..]).unwrap();
+        assert_eq!(other.state_hash(), gb.state_hash());
+    }
+
+    #[test]
+    fn strict_save_states() {
+        let code = [0x3c, 0x18, 0xfd]; // INC A; JR -3
+        let mut gb = GameBoy::new(None, Cartridge::with_entry_code(&code));
+        gb.strict_save_states = true;
+        for _ in 0..1000 {
+            gb.step_instruction();
+        }
+
+        // a consistent state saves and loads normally
+        let mut state = Vec::new();
+        gb.save_state(None, &mut state).unwrap();
+        let mut other = GameBoy::new(None, Cartridge::with_entry_code(&code));
+        other.strict_save_states = true;
+        other.load_state(&mut &state[..]).unwrap();
+        assert_eq!(other.state_hash(), gb.state_hash());
+
+        // saving a PPU that is not at the clock_count of the context is a error
+        let ctx = &mut SaveStateContext::new(None, gb.clock_count + 4);
+        ctx.strict = true;
+        let err = SaveState::save_state(&*gb.ppu.borrow(), ctx, &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("ppu is at clock"), "{}", err);
+
+        // a state where the sound controller is ahead of the GameBoy is not loaded
+        gb.update_all();
+        gb.sound.get_mut().last_clock_count = gb.clock_count + 100;
+        let mut state = Vec::new();
+        let ctx = &mut SaveStateContext::new(None, gb.clock_count);
+        SaveState::save_state(&gb, ctx, &mut state).unwrap();
+        let err = other.load_state(&mut &state[..]).unwrap_err();
+        assert!(
+            matches!(err, LoadStateError::SoundControllerDesync(a, b) if a == b + 100),
+            "{:?}",
+            err
+        );
+
+        // but it loads when not strict
+        other.strict_save_states = false;
+        other.load_state(&mut &state[..]).unwrap();
+    }
+
+    #[test]
+    fn fast_boot() {
+        let mut boot_rom = [0x00; 0x100];
+        #[rustfmt::skip]
+        let code = [
+            0x3e, 0x80, 0xe0, 0x26, // LD A, $80; LDH ($26), A ; turn on the APU
+            0x3e, 0xf3, 0xe0, 0x12, // LD A, $f3; LDH ($12), A
+            0x3e, 0x87, 0xe0, 0x14, // LD A, $87; LDH ($14), A ; trigger channel 1
+            0x3e, 0x77, 0xe0, 0x24, // LD A, $77; LDH ($24), A
+            0x3e, 0x11, 0xe0, 0x25, // LD A, $11; LDH ($25), A
+            0x21, 0x00, 0x04, // LD HL, $0400
+            0x2b, // DEC HL
+            0x7c, // LD A, H
+            0xb5, // OR L
+            0x20, 0xfb, // JR NZ, -5
+            0x3e, 0x91, 0xe0, 0x40, // LD A, $91; LDH ($40), A ; turn on the LCD
+            0x21, 0x00, 0x40, // LD HL, $4000
+            0x2b, // DEC HL
+            0x7c, // LD A, H
+            0xb5, // OR L
+            0x20, 0xfb, // JR NZ, -5
+            0x3e, 0x01, // LD A, $01
+        ];
+        boot_rom[..code.len()].copy_from_slice(&code);
+        // jump to the end of the boot rom, and disable it
+        boot_rom[code.len()..code.len() + 3].copy_from_slice(&[0xc3, 0xfe, 0x00]);
+        boot_rom[0xfe..].copy_from_slice(&[0xe0, 0x50]);
+
+        let cartridge = Cartridge::with_entry_code(&[0x18, 0xfe]);
+        let mut normal = GameBoy::new(Some(boot_rom), cartridge.clone());
+        let mut fast = GameBoy::new(Some(boot_rom), cartridge);
+
+        let frames = std::sync::Arc::new(std::sync::atomic::AtomicU32::new(0));
+        for gb in [&mut normal, &mut fast] {
+            let frames = frames.clone();
+            gb.v_blank = Some(Box::new(move |_| {
+                frames.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            }));
+            gb.sound.get_mut().sample_frequency = 44100;
+        }
+
+        while normal.boot_rom_active {
+            Interpreter(&mut normal).interpret_op();
+        }
+        let normal_frames = frames.swap(0, std::sync::atomic::Ordering::Relaxed);
+        assert!(normal_frames > 0);
+        let normal_output = normal.sound.get_mut().get_output(normal.clock_count);
+        assert!(!normal_output.is_empty());
+
+        fast.fast_boot();
+        assert_eq!(frames.load(std::sync::atomic::Ordering::Relaxed), 0);
+        assert!(fast.sound.get_mut().get_output(fast.clock_count).is_empty());
+        assert!(fast.v_blank.is_some());
+        assert!(!fast.sound.get_mut().sampling_disabled);
+
+        assert!(!fast.boot_rom_active);
+        assert_eq!(fast.cpu.pc, 0x100);
+        assert_eq!(fast.clock_count, normal.clock_count);
+        assert_eq!(fast.state_hash(), normal.state_hash());
+        assert!(fast.ppu.get_mut().screen().packed() == normal.ppu.get_mut().screen().packed());
+
+        // does nothing after the boot
+        let clock_count = fast.clock_count;
+        fast.fast_boot();
+        assert_eq!(fast.clock_count, clock_count);
+
+        // a boot ROM that never ends
+        let mut boot_rom = [0x00; 0x100];
+        boot_rom[..2].copy_from_slice(&[0x18, 0xfe]); // JR -2
+        let mut gb = GameBoy::new(Some(boot_rom), Cartridge::with_entry_code(&[]));
+        gb.fast_boot();
+        assert!(gb.boot_rom_active);
+        assert_eq!(gb.cpu.pc, 0x00);
+        assert!((FAST_BOOT_TIMEOUT..FAST_BOOT_TIMEOUT + 12).contains(&gb.clock_count));
+        assert!(!gb.sound.get_mut().sampling_disabled);
+
+        // a CGB boot ROM, that runs from after the cartridge header
+        let mut boot_rom = Box::new([0x00; 0x900]);
+        boot_rom[..3].copy_from_slice(&[0xc3, 0x00, 0x02]); // JP $0200
+        boot_rom[0x200..0x20b].copy_from_slice(&[
+            0x21, 0x00, 0x04, // LD HL, $0400
+            0x2b, // DEC HL
+            0x7c, // LD A, H
+            0xb5, // OR L
+            0x20, 0xfb, // JR NZ, -5
+            0x3c, // INC A
+            0xe0, 0x50, // LDH ($50), A
+        ]);
+        let cartridge = Cartridge::with_entry_code(&[0x18, 0xfe]);
+        let mut normal = GameBoy::new_cgb(Some(boot_rom.clone()), cartridge.clone());
+        let mut fast = GameBoy::new_cgb(Some(boot_rom), cartridge);
+        while normal.boot_rom_active {
+            Interpreter(&mut normal).interpret_op();
+        }
+        normal.sound.get_mut().update(normal.clock_count);
+        fast.fast_boot();
+        assert!(!fast.boot_rom_active);
+        assert_eq!(fast.cpu.pc, 0x100);
+        assert_eq!(fast.clock_count, normal.clock_count);
+        assert_eq!(fast.state_hash(), normal.state_hash());
+    }
+
+    #[test]
+    fn v_blank_callback() {
+        use crate::consts::FRAME_CYCLES;
+        use std::sync::{Arc, Mutex};
+
+        #[rustfmt::skip]
+        let halt_loop = [
+            0x3e, 0x01, 0xe0, 0xff, // LD A, $01; LDH (IE), A
+            // 0x154:
+            0xaf, 0xe0, 0x0f, // XOR A; LDH (IF), A
+            0x76, // HALT
+            0x18, 0xfa, // JR $0154
+        ];
+        let busy_loop = [0x18, 0xfe]; // JR -2
+
+        for code in [&halt_loop[..], &busy_loop[..]] {
+            for predict_interrupt in [false, true] {
+                let mut gb = GameBoy::new(None, Cartridge::with_entry_code(code));
+                gb.predict_interrupt = predict_interrupt;
+
+                let calls = Arc::new(Mutex::new(Vec::new()));
+                let c = calls.clone();
+                gb.v_blank = Some(Box::new(move |gb| {
+                    let ppu = gb.ppu.borrow();
+                    c.lock()
+                        .unwrap()
+                        .push((ppu.ly, ppu.stat & 0b11, gb.clock_count));
+                }));
+
+                let target = gb.clock_count + 10 * FRAME_CYCLES;
+                while gb.clock_count < target {
+                    Interpreter(&mut gb).interpret_op();
+                }
+
+                let calls = calls.lock().unwrap();
+                assert_eq!(calls.len(), 10, "{:02x?}", code);
+                for &(ly, mode, _) in calls.iter() {
+                    assert_eq!((ly, mode), (144, 1), "{:02x?}", code);
+                }
+                for w in calls.windows(2) {
+                    let frame = w[1].2 - w[0].2;
+                    assert!(frame.abs_diff(FRAME_CYCLES) <= 20, "{}", frame);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn cpu_state() {
+        use cpu::CpuState;
+
+        #[rustfmt::skip]
+        let code = [
+            0xaf, 0xe0, 0x0f, // XOR A; LDH (IF), A
+            0x3e, 0x04, 0xe0, 0xff, // LD A, $04; LDH (IE), A
+            0x3e, 0x05, 0xe0, 0x07, // LD A, $05; LDH (TAC), A
+            0x76, // HALT
+            0x18, 0xfe, // JR -2
+        ];
+        let mut gb = GameBoy::new(None, Cartridge::with_entry_code(&code));
+        assert_eq!(gb.cpu_state(), CpuState::Running);
+
+        while gb.cpu.pc != 0x15c {
+            Interpreter(&mut gb).interpret_op();
+        }
+        assert_eq!(gb.cpu_state(), CpuState::Halt);
+
+        // wait for the timer interrupt
+        while gb.cpu_state() == CpuState::Halt {
+            Interpreter(&mut gb).interpret_op();
+        }
+        assert_eq!(gb.cpu_state(), CpuState::Running);
+        assert_ne!(gb.interrupt_flag.get() & 0b100, 0);
+        assert_eq!(gb.cpu.pc, 0x15c);
+    }
+
+    #[test]
+    fn cycles_until_next_event() {
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        // the LY=LYC interrupt will happen in two lines, before the VBlank
+        let ly = gb.read(0xFF44);
+        let lyc = if ly < 140 { ly + 2 } else { 2 };
+        gb.write_registers(&[(0xFF07, 0x00), (0xFF45, lyc), (0xFF41, 0x40)]);
+        gb.update_all();
+
+        let expected = gb.ppu.borrow().estimate_next_interrupt() - gb.clock_count;
+        let cycles = gb.cycles_until_next_event();
+        assert_eq!(cycles, expected);
+        assert!(cycles > 0);
+
+        // the STAT interrupt is not requested before that
+        gb.interrupt_flag.set(0);
+        gb.tick(cycles - 1);
+        gb.update_all();
+        assert_eq!(gb.interrupt_flag.get() & 0b10, 0);
+        // the prediction may be a few cycles early
+        gb.tick(4);
+        gb.update_all();
+        assert_eq!(gb.interrupt_flag.get() & 0b10, 0b10);
+        assert_eq!(gb.read(0xFF44), lyc);
+
+        gb.predict_interrupt = false;
+        gb.update_next_interrupt();
+        assert_eq!(gb.cycles_until_next_event(), 0);
+    }
+
+    #[test]
+    fn io_unreadable_bits() {
+        // The bits of each I/O register that always read as 1.
+        let mut masks = [0xFFu8; 0x80];
+        for (address, mask) in [
+            (0x00, 0xC0), // P1
+            (0x01, 0x00), // SB
+            (0x02, 0x7E), // SC
+            (0x04, 0x00), // DIV
+            (0x05, 0x00), // TIMA
+            (0x06, 0x00), // TMA
+            (0x07, 0xF8), // TAC
+            (0x0F, 0xE0), // IF
+            (0x10, 0x80), // NR10
+            (0x11, 0x3F), // NR11
+            (0x12, 0x00), // NR12
+            (0x13, 0xFF), // NR13
+            (0x14, 0xBF), // NR14
+            (0x16, 0x3F), // NR21
+            (0x17, 0x00), // NR22
+            (0x18, 0xFF), // NR23
+            (0x19, 0xBF), // NR24
+            (0x1A, 0x7F), // NR30
+            (0x1B, 0xFF), // NR31
+            (0x1C, 0x9F), // NR32
+            (0x1D, 0xFF), // NR33
+            (0x1E, 0xBF), // NR34
+            (0x20, 0xFF), // NR41
+            (0x21, 0x00), // NR42
+            (0x22, 0x00), // NR43
+            (0x23, 0xBF), // NR44
+            (0x24, 0x00), // NR50
+            (0x25, 0x00), // NR51
+            (0x26, 0x70), // NR52
+            (0x40, 0x00), // LCDC
+            (0x41, 0x80), // STAT
+            (0x42, 0x00), // SCY
+            (0x43, 0x00), // SCX
+            (0x44, 0x00), // LY
+            (0x45, 0x00), // LYC
+            (0x46, 0x00), // DMA
+            (0x47, 0x00), // BGP
+            (0x48, 0x00), // OBP0
+            (0x49, 0x00), // OBP1
+            (0x4A, 0x00), // WY
+            (0x4B, 0x00), // WX
+        ] {
+            masks[address] = mask;
+        }
+        // wave RAM
+        masks[0x30..0x40].fill(0x00);
+
+        let check = |gb: &GameBoy| {
+            for (address, &mask) in masks.iter().enumerate() {
+                let value = gb.read(0xFF00 + address as u16);
+                assert_eq!(value & mask, mask, "FF{:02X} = {:02x}", address, value);
+            }
+        };
+
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        check(&gb);
+
+        // clear all registers, except NR52 and the wave RAM, so the APU registers are still
+        // written.
+        for address in (0x00..0x80).filter(|&x| x != 0x26 && !(0x30..0x40).contains(&x)) {
+            gb.tick(4);
+            gb.write(0xFF00 + address, 0x00);
+        }
+        check(&gb);
+
+        // with the APU off
+        gb.tick(4);
+        gb.write(0xFF26, 0x00);
+        check(&gb);
+    }
+}
@@ -1,6 +1,21 @@
 use std::{convert::TryInto, io::Read};
 
-use crate::save_state::{LoadStateError, SaveState, SaveStateContext};
+use crate::save_state::{begin_field, end_field, LoadStateError, SaveState, SaveStateContext};
+
+/// Read from `reader` until `buf` is full or the end of the stream is reached. Return the number of
+/// bytes read.
+fn read_until_full(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(len)
+}
 
 fn mbc_type_name(code: u8) -> &'static str {
     match code {
@@ -36,6 +51,15 @@ fn mbc_type_name(code: u8) -> &'static str {
     }
 }
 
+/// The Nintendo logo, at 0104-0133 of the cartridge header. The boot ROM locks up if the
+/// cartridge don't have it.
+#[rustfmt::skip]
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xCE ,0xED ,0x66 ,0x66 ,0xCC ,0x0D ,0x00 ,0x0B ,0x03 ,0x73 ,0x00 ,0x83 ,0x00 ,0x0C ,0x00 ,0x0D ,
+    0x00 ,0x08 ,0x11 ,0x1F ,0x88 ,0x89 ,0x00 ,0x0E ,0xDC ,0xCC ,0x6E ,0xE6 ,0xDD ,0xDD ,0xD9 ,0x99 ,
+    0xBB ,0xBB ,0x67 ,0x63 ,0x6E ,0x0E ,0xEC ,0xCC ,0xDD ,0xDC ,0x99 ,0x9F ,0xBB ,0xB9 ,0x33 ,0x3E ,
+];
+
 #[derive(PartialEq, Eq, Clone)]
 pub struct CartridgeHeader {
     /// 0104-0133: Logo
@@ -56,7 +80,7 @@ pub struct CartridgeHeader {
     pub version: u8,
     /// 014D: Header Checksum
     pub header_checksum: u8,
-    /// 014E-014F: Global Checksum
+    /// 014E-014F: Global Checksum (big-endian)
     pub global_checksum: u16,
 }
 impl CartridgeHeader {
@@ -75,40 +99,51 @@ impl CartridgeHeader {
             ram_size: bytes[0x0149],
             version: bytes[0x014C],
             header_checksum: bytes[0x014D],
-            global_checksum: u16::from_le_bytes([bytes[0x014E], bytes[0x014F]]),
+            global_checksum: u16::from_be_bytes([bytes[0x014E], bytes[0x014F]]),
         };
 
-        {
-            let check_sum = bytes[0x134..=0x014C]
-                .iter()
-                .fold(0u8, |x, &b| x.wrapping_add(!b));
-            if check_sum != this.header_checksum {
-                return Err((Some(this), "checksum don't match".to_string()));
-            }
+        if Self::compute_header_checksum(bytes) != this.header_checksum {
+            return Err((Some(this), "checksum don't match".to_string()));
         }
 
         Ok(this)
     }
 
+    /// Compute the header checksum of the given rom, over the bytes 0134-014C. `rom` must have at
+    /// least 0x150 bytes.
+    pub fn compute_header_checksum(rom: &[u8]) -> u8 {
+        rom[0x134..=0x014C]
+            .iter()
+            .fold(0u8, |x, &b| x.wrapping_add(!b))
+    }
+
+    /// Compute the global checksum of the given rom: the 16-bit sum of all bytes of the rom,
+    /// except the two checksum bytes themselves. `rom` must have at least 0x150 bytes.
+    pub fn compute_global_checksum(rom: &[u8]) -> u16 {
+        rom.iter()
+            .enumerate()
+            .filter(|&(i, _)| i != 0x014E && i != 0x014F)
+            .fold(0u16, |x, (_, &b)| x.wrapping_add(b as u16))
+    }
+
     /// Return  Err(Some(Self)) if the load was sucessful but the checksum don't match.
     pub fn from_reader(reader: &mut impl Read) -> Result<Self, (Option<Self>, String)> {
         let mut bytes = [0; 0x150];
-        let len = match reader.read(&mut bytes) {
+        let len = match read_until_full(reader, &mut bytes) {
             Ok(x) => x,
             Err(err) => return Err((None, format!("io error: {}", err))),
         };
         Self::from_bytes(&bytes[0..len])
     }
 
+    /// Return true if the CGB flag marks the game as working only on a CGB (C0), and not on a DMG.
+    pub fn is_cgb_only(&self) -> bool {
+        self.cgb_flag == 0xC0
+    }
+
     /// Return true if it has the correct values for the first  0x18  bytes of the Nintendo logo.
     pub fn check_logo(&self) -> bool {
-        #[rustfmt::skip]
-        const NINTENDOO_LOGO: [u8; 48] = [
-            0xCE ,0xED ,0x66 ,0x66 ,0xCC ,0x0D ,0x00 ,0x0B ,0x03 ,0x73 ,0x00 ,0x83 ,0x00 ,0x0C ,0x00 ,0x0D ,
-            0x00 ,0x08 ,0x11 ,0x1F ,0x88 ,0x89 ,0x00 ,0x0E ,0xDC ,0xCC ,0x6E ,0xE6 ,0xDD ,0xDD ,0xD9 ,0x99 ,
-            0xBB ,0xBB ,0x67 ,0x63 ,0x6E ,0x0E ,0xEC ,0xCC ,0xDD ,0xDC ,0x99 ,0x9F ,0xBB ,0xB9 ,0x33 ,0x3E ,
-        ];
-        self.logo[..0x18] == NINTENDOO_LOGO[..0x18]
+        self.logo[..0x18] == NINTENDO_LOGO[..0x18]
     }
 
     pub fn rom_size_in_bytes(&self) -> Result<usize, String> {
@@ -154,6 +189,122 @@ enum Mbc {
     Mbc3(Mbc3),
     Mbc5(Mbc5),
 }
+impl SaveState for Mbc {
+    fn save_state(
+        &self,
+        ctx: &mut SaveStateContext,
+        data: &mut impl std::io::Write,
+    ) -> Result<(), std::io::Error> {
+        match self {
+            Mbc::None(x) => x.save_state(ctx, data),
+            Mbc::Mbc1(x) => x.save_state(ctx, data),
+            Mbc::Mbc1M(x) => x.save_state(ctx, data),
+            Mbc::Mbc2(x) => x.save_state(ctx, data),
+            Mbc::Mbc3(x) => x.save_state(ctx, data),
+            Mbc::Mbc5(x) => x.save_state(ctx, data),
+        }
+    }
+
+    fn load_state(
+        &mut self,
+        ctx: &mut SaveStateContext,
+        data: &mut impl Read,
+    ) -> Result<(), LoadStateError> {
+        match self {
+            Mbc::None(x) => x.load_state(ctx, data),
+            Mbc::Mbc1(x) => x.load_state(ctx, data),
+            Mbc::Mbc1M(x) => x.load_state(ctx, data),
+            Mbc::Mbc2(x) => x.load_state(ctx, data),
+            Mbc::Mbc3(x) => x.load_state(ctx, data),
+            Mbc::Mbc5(x) => x.load_state(ctx, data),
+        }
+    }
+}
+
+/// The kind of memory bank controller (MBC) of a `Cartridge`.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum MbcType {
+    /// No MBC, only 32 KiB of ROM (and optionally up to 8 KiB of RAM).
+    None,
+    Mbc1,
+    /// A MBC1 multicart, with the bank number wired differently.
+    Mbc1M,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+}
+impl MbcType {
+    /// All the MBC types, indexed by their discriminant.
+    const ALL: [MbcType; 6] = [
+        MbcType::None,
+        MbcType::Mbc1,
+        MbcType::Mbc1M,
+        MbcType::Mbc2,
+        MbcType::Mbc3,
+        MbcType::Mbc5,
+    ];
+
+    /// Detect the MBC from the cartridge type in the header. Return None if the type is not
+    /// supported.
+    pub fn detect(header: &CartridgeHeader, rom: &[u8]) -> Option<Self> {
+        Some(match header.cartridge_type {
+            0 | 8 | 9 => Self::None,
+            1 | 2 | 3 => {
+                // Detect if it is a MBC1M card
+                if header.rom_size == 5 {
+                    let mut number_of_games = 0;
+                    for i in 0..4 {
+                        let Some(bytes) = rom.get(i * 0x40000..) else {
+                            continue;
+                        };
+                        let header = match CartridgeHeader::from_bytes(bytes) {
+                            Ok(x) | Err((Some(x), _)) => x,
+                            Err((None, _)) => continue,
+                        };
+                        if header.check_logo() {
+                            number_of_games += 1;
+                        }
+                    }
+                    // multicarts will have, at least, a game selecion screen, and two other games.
+                    if number_of_games >= 3 {
+                        return Some(Self::Mbc1M);
+                    }
+                }
+                Self::Mbc1
+            }
+            5 | 6 => Self::Mbc2,
+            0x0F | 0x10 | 0x11 | 0x12 | 0x13 => Self::Mbc3,
+            0x19 | 0x1A | 0x1B | 0x1C | 0x1D | 0x1E => Self::Mbc5,
+            _ => return None,
+        })
+    }
+
+    fn create(self) -> Mbc {
+        match self {
+            Self::None => Mbc::None(Mbc0 {}),
+            Self::Mbc1 => Mbc::Mbc1(Mbc1::new()),
+            Self::Mbc1M => Mbc::Mbc1M(Mbc1M::new()),
+            Self::Mbc2 => Mbc::Mbc2(Mbc2::new()),
+            Self::Mbc3 => Mbc::Mbc3(Mbc3::new()),
+            Self::Mbc5 => Mbc::Mbc5(Mbc5::new()),
+        }
+    }
+}
+
+/// Options for loading a `Cartridge`.
+#[derive(Clone, Default, Debug)]
+pub struct CartridgeOpts {
+    /// If true, the load fails if the header checksum or the global checksum of the rom don't
+    /// match. Many good dumps have a wrong global checksum, so this is off by default.
+    pub strict_checksum: bool,
+    /// If true, a cartridge with a unknown or unsupported cartridge type (like homebrews with a
+    /// garbage header, or rare mappers) is loaded as a ROM only cartridge, instead of failing. The
+    /// type byte is still reported in `header.cartridge_type`, so the frontend can warn about it.
+    pub unsupported_mbc_as_rom_only: bool,
+    /// If set, this MBC is used instead of the one given by the cartridge type in the header. Can
+    /// be used for ROMs with a wrong header.
+    pub mbc: Option<MbcType>,
+}
 
 #[derive(PartialEq, Eq, Clone)]
 pub struct Cartridge {
@@ -173,15 +324,14 @@ impl SaveState for Cartridge {
         data: &mut impl std::io::Write,
     ) -> Result<(), std::io::Error> {
         // self.rom.save_state(data)?;
+        let field = begin_field(ctx, "ram");
         self.ram.save_state(ctx, data)?;
-        match &self.mbc {
-            Mbc::None(x) => x.save_state(ctx, data),
-            Mbc::Mbc1(x) => x.save_state(ctx, data),
-            Mbc::Mbc1M(x) => x.save_state(ctx, data),
-            Mbc::Mbc2(x) => x.save_state(ctx, data),
-            Mbc::Mbc3(x) => x.save_state(ctx, data),
-            Mbc::Mbc5(x) => x.save_state(ctx, data),
-        }
+        end_field(ctx, field);
+
+        let field = begin_field(ctx, "mbc");
+        self.mbc.save_state(ctx, data)?;
+        end_field(ctx, field);
+        Ok(())
     }
 
     fn load_state(
@@ -191,25 +341,47 @@ impl SaveState for Cartridge {
     ) -> Result<(), LoadStateError> {
         // self.rom.load_state(data)?;
         self.ram.load_state(ctx, data)?;
-        match &mut self.mbc {
-            Mbc::None(x) => x.load_state(ctx, data)?,
-            Mbc::Mbc1(x) => x.load_state(ctx, data)?,
-            Mbc::Mbc1M(x) => x.load_state(ctx, data)?,
-            Mbc::Mbc2(x) => x.load_state(ctx, data)?,
-            Mbc::Mbc3(x) => x.load_state(ctx, data)?,
-            Mbc::Mbc5(x) => x.load_state(ctx, data)?,
-        }
+        self.mbc.load_state(ctx, data)?;
         self.update_banks();
         Ok(())
     }
 }
 impl Cartridge {
     pub fn new(rom: Vec<u8>) -> Result<Self, String> {
+        Self::with_opts(rom, &CartridgeOpts::default())
+    }
+
+    /// Load the rom with the given MBC, ignoring the cartridge type in the header.
+    pub fn new_with_mbc(rom: Vec<u8>, mbc: MbcType) -> Result<Self, String> {
+        Self::with_opts(
+            rom,
+            &CartridgeOpts {
+                mbc: Some(mbc),
+                ..Default::default()
+            },
+        )
+    }
+
+    pub fn with_opts(rom: Vec<u8>, opts: &CartridgeOpts) -> Result<Self, String> {
         let header = match CartridgeHeader::from_bytes(&rom) {
-            Ok(x) | Err((Some(x), _)) => x,
+            Ok(x) => x,
+            Err((Some(_), err)) if opts.strict_checksum => {
+                return Err(format!("header {}", err));
+            }
+            Err((Some(x), _)) => x,
             Err((None, err)) => return Err(err),
         };
 
+        if opts.strict_checksum {
+            let checksum = CartridgeHeader::compute_global_checksum(&rom);
+            if checksum != header.global_checksum {
+                return Err(format!(
+                    "global checksum don't match: the header reports '{:04x}', but the computed one is '{:04x}'",
+                    header.global_checksum, checksum
+                ));
+            }
+        }
+
         let rom_size = header.rom_size_in_bytes()?;
 
         if rom_size != rom.len() {
@@ -222,32 +394,10 @@ impl Cartridge {
 
         // Cartridge Type
         let mbc_kind = header.cartridge_type;
-        let mbc = match mbc_kind {
-            0 | 8 | 9 => Mbc::None(Mbc0 {}),
-            1 | 2 | 3 => 'mbc1: {
-                // Detect if it is a MBC1M card
-                if header.rom_size == 5 {
-                    let mut number_of_games = 0;
-                    for i in 0..4 {
-                        let header = match CartridgeHeader::from_bytes(&rom[i * 0x40000..]) {
-                            Ok(x) | Err((Some(x), _)) => x,
-                            Err((None, _)) => continue,
-                        };
-                        if header.check_logo() {
-                            number_of_games += 1;
-                        }
-                    }
-                    // multicarts will have, at least, a game selecion screen, and two other games.
-                    if number_of_games >= 3 {
-                        break 'mbc1 Mbc::Mbc1M(Mbc1M::new());
-                    }
-                }
-                Mbc::Mbc1(Mbc1::new())
-            }
-            5 | 6 => Mbc::Mbc2(Mbc2::new()),
-            0x0F | 0x10 | 0x11 | 0x12 | 0x13 => Mbc::Mbc3(Mbc3::new()),
-            0x19 | 0x1A | 0x1B | 0x1C | 0x1D | 0x1E => Mbc::Mbc5(Mbc5::new()),
-            _ => {
+        let mbc = match opts.mbc.or_else(|| MbcType::detect(&header, &rom)) {
+            Some(x) => x.create(),
+            None if opts.unsupported_mbc_as_rom_only => Mbc::None(Mbc0 {}),
+            None => {
                 return Err(format!(
                     "MBC type '{}' ({:02x}) is not supported",
                     mbc_type_name(mbc_kind),
@@ -288,6 +438,49 @@ impl Cartridge {
         })
     }
 
+    /// Load a cartridge from a stream, like a file or a network connection. The header is read
+    /// first, to know the size of the rom, and then the rest of the rom is read. Fails if the
+    /// stream ends before the size reported in the header, or if it has more bytes than that.
+    pub fn from_reader(reader: &mut impl Read) -> Result<Self, String> {
+        let mut header = [0; 0x150];
+        let len =
+            read_until_full(reader, &mut header).map_err(|err| format!("io error: {}", err))?;
+        if len < header.len() {
+            return Err(format!(
+                "rom is truncated: the header needs 0x150 bytes, but only {} bytes could be read",
+                len
+            ));
+        }
+
+        let rom_size = match CartridgeHeader::from_bytes(&header) {
+            Ok(x) | Err((Some(x), _)) => x.rom_size_in_bytes()?,
+            Err((None, err)) => return Err(err),
+        };
+
+        let mut rom = vec![0; rom_size];
+        rom[..header.len()].copy_from_slice(&header);
+        let len = read_until_full(reader, &mut rom[header.len()..])
+            .map_err(|err| format!("io error: {}", err))?;
+        if header.len() + len < rom_size {
+            return Err(format!(
+                "rom is truncated: the header reports '{}' bytes, but only '{}' bytes could be read",
+                rom_size,
+                header.len() + len
+            ));
+        }
+
+        let extra =
+            read_until_full(reader, &mut [0]).map_err(|err| format!("io error: {}", err))?;
+        if extra != 0 {
+            return Err(format!(
+                "In the rom header the expected size is '{}' bytes, but the given rom has more bytes",
+                rom_size
+            ));
+        }
+
+        Self::new(rom)
+    }
+
     /// A Cartridge filled with HALT instructions. Used as a test cartridge, when the CPU does not
     /// need to be tested.
     pub fn halt_filled() -> Self {
@@ -303,11 +496,115 @@ impl Cartridge {
         this
     }
 
+    /// A ROM of 2 banks filled with NOP instructions, and with a zeroed header, so it has no MBC
+    /// and no RAM. Used for building test cartridges.
+    pub fn nop_rom() -> Vec<u8> {
+        vec![0x00; 2 * 0x4000]
+    }
+
+    /// A Cartridge of `nop_rom`, with `code` at `address`. Used as a test cartridge.
+    pub fn with_code(address: u16, code: &[u8]) -> Self {
+        let mut rom = Self::nop_rom();
+        rom[address as usize..][..code.len()].copy_from_slice(code);
+        Self::new(rom).unwrap()
+    }
+
+    /// A `with_code` Cartridge with `code` at 0x150, and a jump to it at the entry point, so it
+    /// starts executing `code` after the boot. Used as a test cartridge.
+    pub fn with_entry_code(code: &[u8]) -> Self {
+        let mut this = Self::with_code(0x150, code);
+        this.rom[0x100..0x103].copy_from_slice(&[0xc3, 0x50, 0x01]); // JP $0150
+        this
+    }
+
+    /// A empty cartridge slot, for running code from RAM or only the boot ROM. The whole ROM
+    /// region reads as 0xFF, like the open bus of a missing cartridge, and there is no RAM.
+    pub fn none() -> Self {
+        let mut this = Self::new(Self::nop_rom()).unwrap();
+        this.rom.fill(0xFF);
+        this
+    }
+
+    /// A FNV-1a hash of the bytes of the cartridge header, from the title to the global checksum
+    /// (0134-014F). Can be used to identify the ROM, for example to key its save states.
+    pub fn header_hash(&self) -> u64 {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for &b in &self.rom[0x134..0x150] {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+        hash
+    }
+
     /// The number of banks in this cartridge. A cartridge without bank switching have 2 banks.
     pub fn num_banks(&self) -> u8 {
         (self.rom.len() / 0x4000) as u8
     }
 
+    /// The content of the external RAM, of all banks, like the `.sav` files of battery backed
+    /// cartridges.
+    pub fn export_ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    /// Replace the content of the external RAM, of all banks, by the one exported by `export_ram`.
+    /// The bank selection state of the MBC is kept. Fails if the size of `ram` does not match the
+    /// RAM size of the cartridge.
+    pub fn import_ram(&mut self, ram: Vec<u8>) -> Result<(), String> {
+        if ram.len() != self.ram.len() {
+            return Err(format!(
+                "RAM size mismatch: cartridge has {} bytes of RAM, but got {} bytes",
+                self.ram.len(),
+                ram.len()
+            ));
+        }
+        self.ram = ram;
+        Ok(())
+    }
+
+    /// Save only the state of the registers of the MBC, like the selected ROM and RAM banks and the
+    /// RAM enable, without the content of the RAM. Load it back with `load_mbc_state`.
+    ///
+    /// The state starts with a byte that tags the MBC type, because the layout of the registers is
+    /// different for each one.
+    pub fn save_mbc_state(&self, data: &mut impl std::io::Write) -> Result<(), std::io::Error> {
+        data.write_all(&[self.mbc_type() as u8])?;
+        self.mbc.save_state(&mut SaveStateContext::default(), data)
+    }
+
+    /// Load the state saved by `save_mbc_state`. The cartridge must have the same MBC type, or a
+    /// `LoadStateError::ConstMismatch` is returned.
+    pub fn load_mbc_state(&mut self, data: &mut impl Read) -> Result<(), LoadStateError> {
+        let mut tag = [0];
+        data.read_exact(&mut tag)?;
+        let expected = self.mbc_type();
+        if tag[0] != expected as u8 {
+            let loaded = MbcType::ALL
+                .get(tag[0] as usize)
+                .map_or(format!("{:02x}", tag[0]), |x| format!("{:?}", x));
+            return Err(LoadStateError::ConstMismatch(
+                loaded,
+                format!("{:?}", expected),
+            ));
+        }
+        self.mbc
+            .load_state(&mut SaveStateContext::default(), data)?;
+        self.update_banks();
+        Ok(())
+    }
+
+    /// The MBC of the cartridge.
+    pub fn mbc_type(&self) -> MbcType {
+        match &self.mbc {
+            Mbc::None(_) => MbcType::None,
+            Mbc::Mbc1(_) => MbcType::Mbc1,
+            Mbc::Mbc1M(_) => MbcType::Mbc1M,
+            Mbc::Mbc2(_) => MbcType::Mbc2,
+            Mbc::Mbc3(_) => MbcType::Mbc3,
+            Mbc::Mbc5(_) => MbcType::Mbc5,
+        }
+    }
+
     /// Return a string with the kind of the cartridge.
     pub fn kind_name(&self) -> &str {
         match &self.mbc {
@@ -320,6 +617,17 @@ impl Cartridge {
         }
     }
 
+    /// Return true if a write to the given address in the ROM region (0000-7FFF) is interpreted by
+    /// the MBC as a write to one of its registers. Writes to other ROM addresses are ignored.
+    pub fn is_mbc_register(&self, address: u16) -> bool {
+        match &self.mbc {
+            Mbc::None(_) => false,
+            Mbc::Mbc1(_) | Mbc::Mbc1M(_) | Mbc::Mbc3(_) => address <= 0x7FFF,
+            Mbc::Mbc2(_) => address <= 0x3FFF,
+            Mbc::Mbc5(_) => address <= 0x5FFF,
+        }
+    }
+
     /// The current pair of ROM banks beign mapped to 0..=3FFF and 4000..=7FFF, respectvely.
     pub fn curr_bank(&self) -> (u16, u16) {
         (self.lower_bank, self.upper_bank)
@@ -1035,8 +1343,10 @@ impl Mbc3 {
                         if !self.ram_enabled || ram.is_empty() {
                             return 0xff;
                         }
-                        let start_address = 0x2000 * ((self.selected_bank >> 5) & 0x3) as usize;
-                        ram[address as usize - 0xA000 + start_address]
+                        let start_address = 0x2000 * self.ram_bank as usize;
+                        // mask upper bits if the bank is out of bounds
+                        let ram_address = (address as usize - 0xA000 + start_address) % ram.len();
+                        ram[ram_address]
                     }
                     // RTC registers
                     0x8..=0xC => {
@@ -1119,8 +1429,10 @@ impl Mbc3 {
                         if !self.ram_enabled || ram.is_empty() {
                             return;
                         }
-                        let start_address = 0x2000 * ((self.selected_bank >> 5) & 0x3) as usize;
-                        ram[address as usize - 0xA000 + start_address] = value;
+                        let start_address = 0x2000 * self.ram_bank as usize;
+                        // mask upper bits if the bank is out of bounds
+                        let ram_address = (address as usize - 0xA000 + start_address) % ram.len();
+                        ram[ram_address] = value;
                     }
                     // RTC registers
                     0x8..=0xC => {
@@ -1200,8 +1512,10 @@ impl Mbc5 {
                 if !self.ram_enabled || ram.is_empty() {
                     return 0xff;
                 }
-                let start_address = (self.selected_ram_bank as usize * 0x2000) % ram.len();
-                ram[address as usize - 0xA000 + start_address]
+                let start_address = self.selected_ram_bank as usize * 0x2000;
+                // mask upper bits if the bank is out of bounds
+                let ram_address = (address as usize - 0xA000 + start_address) % ram.len();
+                ram[ram_address]
             }
             _ => unreachable!("read cartridge out of bounds"),
         }
@@ -1232,8 +1546,10 @@ impl Mbc5 {
                 if !self.ram_enabled || ram.is_empty() {
                     return;
                 }
-                let start_address = (self.selected_ram_bank as usize * 0x2000) % ram.len();
-                ram[address as usize - 0xA000 + start_address] = value;
+                let start_address = self.selected_ram_bank as usize * 0x2000;
+                // mask upper bits if the bank is out of bounds
+                let ram_address = (address as usize - 0xA000 + start_address) % ram.len();
+                ram[ram_address] = value;
             }
             _ => unreachable!("write cartridge out of bounds"),
         }
@@ -1252,3 +1568,337 @@ impl Mbc5 {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A rom with a valid header checksum and global checksum.
+    fn valid_rom() -> Vec<u8> {
+        let mut rom = Cartridge::nop_rom();
+        rom[0x134..0x13B].copy_from_slice(b"GAMEROY");
+        rom[0x1000] = 0xAB;
+        rom[0x4321] = 0xCD;
+        rom[0x014D] = CartridgeHeader::compute_header_checksum(&rom);
+        let checksum = CartridgeHeader::compute_global_checksum(&rom);
+        rom[0x014E..=0x014F].copy_from_slice(&checksum.to_be_bytes());
+        rom
+    }
+
+    #[test]
+    fn global_checksum() {
+        let rom = valid_rom();
+        let s: u16 =
+            b"GAMEROY".iter().map(|&x| x as u16).sum::<u16>() + 0xAB + 0xCD + rom[0x014D] as u16;
+        assert_eq!(CartridgeHeader::compute_global_checksum(&rom), s);
+
+        let header = CartridgeHeader::from_bytes(&rom)
+            .unwrap_or_else(|_| panic!("header checksum don't match"));
+        assert_eq!(header.global_checksum, s);
+    }
+
+    #[test]
+    fn strict_checksum() {
+        let strict = CartridgeOpts {
+            strict_checksum: true,
+            ..Default::default()
+        };
+
+        let rom = valid_rom();
+        assert!(Cartridge::with_opts(rom.clone(), &strict).is_ok());
+
+        // corrupt a byte of the rom
+        let mut corrupted = rom.clone();
+        corrupted[0x2000] = 0x01;
+        assert!(Cartridge::with_opts(corrupted.clone(), &strict).is_err());
+        assert!(Cartridge::new(corrupted).is_ok());
+
+        // corrupt the header
+        let mut corrupted = rom;
+        corrupted[0x014D] ^= 0xFF;
+        assert!(Cartridge::with_opts(corrupted.clone(), &strict).is_err());
+        assert!(Cartridge::new(corrupted).is_ok());
+    }
+
+    #[test]
+    fn from_reader() {
+        use std::io::Cursor;
+
+        let rom = valid_rom();
+        let cartridge = Cartridge::from_reader(&mut Cursor::new(&rom)).unwrap();
+        assert!(cartridge == Cartridge::new(rom.clone()).unwrap());
+
+        let err = Cartridge::from_reader(&mut Cursor::new(&rom[..0x5000]))
+            .err()
+            .unwrap();
+        assert!(err.contains("truncated"), "{}", err);
+        assert!(err.contains(&format!("'{}'", 0x5000)), "{}", err);
+
+        let err = Cartridge::from_reader(&mut Cursor::new(&rom[..0x100]))
+            .err()
+            .unwrap();
+        assert!(err.contains("truncated"), "{}", err);
+
+        let mut bigger = rom;
+        bigger.push(0);
+        assert!(Cartridge::from_reader(&mut Cursor::new(&bigger)).is_err());
+    }
+
+    /// A cartridge with the given cartridge type and ram size in the header.
+    fn cartridge_with_ram(cartridge_type: u8, ram_size: u8) -> Cartridge {
+        let mut rom = Cartridge::nop_rom();
+        rom[0x0147] = cartridge_type;
+        rom[0x0149] = ram_size;
+        Cartridge::new(rom).unwrap()
+    }
+
+    #[test]
+    fn cartridge_types() {
+        let kinds = [
+            (0x00, "None"),
+            (0x01, "MBC1"),
+            (0x02, "MBC1"),
+            (0x03, "MBC1"),
+            (0x05, "MBC2"),
+            (0x06, "MBC2"),
+            (0x08, "None"),
+            (0x09, "None"),
+            (0x0F, "MBC3"),
+            (0x10, "MBC3"),
+            (0x11, "MBC3"),
+            (0x12, "MBC3"),
+            (0x13, "MBC3"),
+            (0x19, "MBC5"),
+            (0x1A, "MBC5"),
+            (0x1B, "MBC5"),
+            (0x1C, "MBC5"),
+            (0x1D, "MBC5"),
+            (0x1E, "MBC5"),
+        ];
+        for (cartridge_type, kind) in kinds {
+            assert_eq!(
+                cartridge_with_ram(cartridge_type, 0).kind_name(),
+                kind,
+                "type {:02x}",
+                cartridge_type
+            );
+        }
+
+        let fallback = CartridgeOpts {
+            unsupported_mbc_as_rom_only: true,
+            ..Default::default()
+        };
+        // MMM01, MBC6, MBC7, POCKET CAMERA, HuC1 and a unknown type
+        for cartridge_type in [0x0B, 0x20, 0x22, 0xFC, 0xFF, 0x42] {
+            let mut rom = Cartridge::nop_rom();
+            rom[0x0147] = cartridge_type;
+            let err = Cartridge::new(rom.clone()).err().unwrap();
+            assert!(err.contains("is not supported"), "{}", err);
+
+            let cartridge = Cartridge::with_opts(rom, &fallback).unwrap();
+            assert_eq!(cartridge.kind_name(), "None");
+            assert_eq!(cartridge.header.cartridge_type, cartridge_type);
+        }
+        assert!(Cartridge::new({
+            let mut rom = Cartridge::nop_rom();
+            rom[0x0147] = 0x42;
+            rom
+        })
+        .err()
+        .unwrap()
+        .contains("'Unknown' (42)"));
+    }
+
+    #[test]
+    fn mbc_override() {
+        // a 4 bank rom, with a header saying it is ROM only
+        let mut rom = vec![0x00; 4 * 0x4000];
+        rom[0x0148] = 0x01;
+        for bank in 0..4 {
+            rom[bank * 0x4000] = bank as u8;
+        }
+
+        let mut cartridge = Cartridge::new(rom.clone()).unwrap();
+        assert_eq!(cartridge.mbc_type(), MbcType::None);
+        cartridge.write(0x2000, 2);
+        assert_eq!(cartridge.read(0x4000), 1);
+
+        let mut cartridge = Cartridge::new_with_mbc(rom, MbcType::Mbc1).unwrap();
+        assert_eq!(cartridge.mbc_type(), MbcType::Mbc1);
+        assert_eq!(cartridge.kind_name(), "MBC1");
+        assert_eq!(cartridge.header.cartridge_type, 0x00);
+        assert_eq!(cartridge.read(0x4000), 1);
+        cartridge.write(0x2000, 2);
+        assert_eq!(cartridge.read(0x4000), 2);
+        cartridge.write(0x2000, 3);
+        assert_eq!(cartridge.read(0x4000), 3);
+        assert_eq!(cartridge.read(0x0000), 0);
+    }
+
+    #[test]
+    fn mbc1_large_rom_mode_1() {
+        // a 1 MiB MBC1 cartridge, with the number of each bank in its first byte
+        let mut rom = vec![0x00; 64 * 0x4000];
+        rom[0x0147] = 0x01;
+        rom[0x0148] = 0x05;
+        for bank in 0..64 {
+            rom[bank * 0x4000] = bank as u8;
+        }
+        let mut cartridge = Cartridge::new(rom).unwrap();
+
+        // mode 0: the secondary register only affects the upper region
+        cartridge.write(0x4000, 1);
+        assert_eq!(cartridge.read(0x0000), 0x00);
+        assert_eq!(cartridge.read(0x4000), 0x21);
+
+        // mode 1: the lower region maps to bank 0x00/0x20, with the bank 0x40/0x60 wrapping around
+        cartridge.write(0x6000, 1);
+        for (secondary, lower) in [(0, 0x00), (1, 0x20), (2, 0x00), (3, 0x20)] {
+            cartridge.write(0x4000, secondary);
+            assert_eq!(cartridge.read(0x0000), lower, "secondary {}", secondary);
+            assert_eq!(cartridge.read(0x4000), lower + 1);
+            assert_eq!(cartridge.curr_bank(), (lower as u16, lower as u16 + 1));
+        }
+
+        // back to mode 0
+        cartridge.write(0x4000, 1);
+        cartridge.write(0x6000, 0);
+        assert_eq!(cartridge.read(0x0000), 0x00);
+        assert_eq!(cartridge.read(0x4000), 0x21);
+    }
+
+    #[test]
+    fn mbc5_ram_round_trip() {
+        // a MBC5+RAM+BATTERY cartridge with 128 KiB of RAM
+        let mut cartridge = cartridge_with_ram(0x1B, 0x04);
+        assert_eq!(cartridge.export_ram().len(), 16 * 0x2000);
+
+        let pattern = |bank: u8, i: u16| bank.wrapping_mul(17) ^ (i as u8) ^ (i >> 8) as u8;
+        cartridge.write(0x0000, 0x0A);
+        for bank in 0..16 {
+            cartridge.write(0x4000, bank);
+            for i in 0..0x2000 {
+                cartridge.write(0xA000 + i, pattern(bank, i));
+            }
+        }
+        // leave bank 5 selected
+        cartridge.write(0x4000, 5);
+
+        let ram = cartridge.export_ram().to_vec();
+        assert!(cartridge.import_ram(vec![0; 0x2000]).is_err());
+        cartridge.import_ram(vec![0; ram.len()]).unwrap();
+        assert!(cartridge.export_ram().iter().all(|&x| x == 0));
+        assert_eq!(cartridge.read(0xA000), 0);
+
+        cartridge.import_ram(ram).unwrap();
+        // the selected bank is kept
+        assert_eq!(cartridge.read(0xA123), pattern(5, 0x123));
+        for bank in 0..16 {
+            cartridge.write(0x4000, bank);
+            for i in 0..0x2000 {
+                assert_eq!(
+                    cartridge.read(0xA000 + i),
+                    pattern(bank, i),
+                    "bank {}",
+                    bank
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn mbc_state() {
+        // a MBC1+RAM cartridge with 4 ROM banks
+        let mut rom = vec![0x00; 4 * 0x4000];
+        rom[0x0147] = 0x02;
+        rom[0x0148] = 0x01;
+        rom[0x0149] = 0x02;
+        let mut cartridge = Cartridge::new(rom).unwrap();
+
+        cartridge.write(0x0000, 0x0A);
+        cartridge.write(0x2000, 3);
+        cartridge.write(0xA000, 0x42);
+        assert_eq!(cartridge.curr_bank(), (0, 3));
+        let mut state = Vec::new();
+        cartridge.save_mbc_state(&mut state).unwrap();
+
+        // the RAM is not part of the state
+        cartridge.write(0xA000, 0x43);
+        cartridge.write(0x2000, 1);
+        cartridge.write(0x0000, 0x00);
+        assert_eq!(cartridge.curr_bank(), (0, 1));
+        assert_eq!(cartridge.read(0xA000), 0xFF);
+
+        cartridge.load_mbc_state(&mut state.as_slice()).unwrap();
+        assert_eq!(cartridge.curr_bank(), (0, 3));
+        assert_eq!(cartridge.read(0xA000), 0x43);
+
+        // the state of a MBC1 can't be loaded in a MBC5
+        let mut rom = vec![0x00; 4 * 0x4000];
+        rom[0x0147] = 0x19;
+        rom[0x0148] = 0x01;
+        let mut other = Cartridge::new(rom).unwrap();
+        let error = other.load_mbc_state(&mut state.as_slice()).unwrap_err();
+        assert!(matches!(
+            error,
+            LoadStateError::ConstMismatch(ref loaded, ref expected)
+                if loaded == "Mbc1" && expected == "Mbc5"
+        ));
+        assert_eq!(other.curr_bank(), (0, 1));
+    }
+
+    #[test]
+    fn ram_banking() {
+        // MBC3+RAM+BATTERY and MBC5+RAM+BATTERY, with 32 KiB of RAM.
+        for cartridge_type in [0x13, 0x1B] {
+            let mut cartridge = cartridge_with_ram(cartridge_type, 3);
+            assert_eq!(cartridge.ram.len(), 4 * 0x2000);
+            cartridge.write(0x0000, 0x0A);
+
+            for bank in 0..4 {
+                cartridge.write(0x4000, bank);
+                cartridge.write(0xA000, 0x10 + bank);
+                cartridge.write(0xBFFF, 0x20 + bank);
+            }
+            for bank in 0..4 {
+                cartridge.write(0x4000, bank);
+                assert_eq!(cartridge.read(0xA000), 0x10 + bank);
+                assert_eq!(cartridge.read(0xBFFF), 0x20 + bank);
+                assert_eq!(cartridge.ram[bank as usize * 0x2000], 0x10 + bank);
+            }
+        }
+
+        // MBC5 with 32 KiB of RAM: bank 5 is masked to bank 1.
+        let mut cartridge = cartridge_with_ram(0x1B, 3);
+        cartridge.write(0x0000, 0x0A);
+        cartridge.write(0x4000, 1);
+        cartridge.write(0xA123, 0x56);
+        cartridge.write(0x4000, 5);
+        assert_eq!(cartridge.read(0xA123), 0x56);
+
+        // MBC3 with 8 KiB of RAM: bank 2 is masked to bank 0.
+        let mut cartridge = cartridge_with_ram(0x13, 2);
+        cartridge.write(0x0000, 0x0A);
+        cartridge.write(0x4000, 2);
+        cartridge.write(0xA123, 0x78);
+        cartridge.write(0x4000, 0);
+        assert_eq!(cartridge.read(0xA123), 0x78);
+
+        // MBC5 with 2 KiB of RAM: the RAM repeats across the address space.
+        let mut cartridge = cartridge_with_ram(0x1B, 1);
+        cartridge.write(0x0000, 0x0A);
+        cartridge.write(0xB801, 0x9A);
+        assert_eq!(cartridge.read(0xA001), 0x9A);
+
+        // on MBC3, 0x08-0x0C selects the RTC registers instead.
+        let mut cartridge = cartridge_with_ram(0x10, 3);
+        cartridge.write(0x0000, 0x0A);
+        cartridge.write(0x4000, 0);
+        cartridge.write(0xA000, 0x11);
+        cartridge.write(0x4000, 0x08);
+        cartridge.write(0xA000, 0x22);
+        assert_eq!(cartridge.read(0xA000), 0x22);
+        cartridge.write(0x4000, 0);
+        assert_eq!(cartridge.read(0xA000), 0x11);
+    }
+}
@@ -2,11 +2,16 @@ use std::fmt;
 
 use crate::save_state::{LoadStateError, SaveState, SaveStateContext};
 
+/// The execution state of the CPU.
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[repr(u8)]
 pub enum CpuState {
+    /// The CPU is executing instructions.
     Running = 0,
+    /// The CPU executed a HALT, and is waiting for a interrupt to be requested (in IE & IF). The
+    /// HALT bug does not put the CPU in this state, it is tracked by `Cpu::halt_bug` instead.
     Halt = 1,
+    /// The CPU executed a STOP. Currently this is handled in the same way as `Halt`.
     Stopped = 2,
 }
 impl SaveState for CpuState {
@@ -84,7 +89,7 @@ impl Default for ImeState {
 // Each pair of registers is in the reverse order to allow addressing them as a single 16 bit
 // registers in little-endian machines.
 #[repr(C, align(2))]
-#[derive(Default, Debug, PartialEq, Eq)]
+#[derive(Default, Debug, PartialEq, Eq, Clone)]
 pub struct Cpu {
     pub f: Flags,
     pub a: u8,
@@ -98,6 +103,8 @@ pub struct Cpu {
     pub pc: u16,
     pub ime: ImeState,
     pub state: CpuState,
+    /// A HALT was executed while a interrupt was pending with IME disabled, so the next opcode
+    /// byte will be read twice.
     pub halt_bug: bool,
 }
 impl fmt::Display for Cpu {
@@ -172,7 +179,7 @@ impl Cpu {
 /// 5 - H: Half Carry flag (BCD)
 /// 4 - C: Carry flag
 /// Remaning bits are read/writeable, but are not flags.
-#[derive(Default, Debug, PartialEq, Eq)]
+#[derive(Default, Debug, PartialEq, Eq, Clone)]
 #[repr(transparent)]
 pub struct Flags(pub u8);
 impl Flags {
@@ -1,12 +1,15 @@
 use crate::{
     consts::{FRAME_CYCLES, SCANLINE_CYCLES, SCANLINE_PER_FRAME, SCREEN_HEIGHT, SCREEN_WIDTH},
-    gameboy::GameBoy,
-    save_state::{LoadStateError, SaveState, SaveStateContext},
+    gameboy::{render_threads::RenderThreads, GameBoy, Model},
+    save_state::{check_clock_count, LoadStateError, SaveState, SaveStateContext},
 };
 
 #[derive(PartialEq, Eq, Default, Clone, Debug)]
 pub struct PixelFifo {
     queue: [u8; 16],
+    /// The OAM index of the sprite that draw each pixel in `queue`. Only used by the sprite FIFO
+    /// on CGB, where the sprite priority is given by the OAM index.
+    oam_index: [u8; 16],
     /// next position to push
     head: u8,
     /// next position to pop
@@ -21,6 +24,7 @@ impl SaveState for PixelFifo {
         self.queue.save_state(ctx, data)?;
         self.head.save_state(ctx, data)?;
         self.tail.save_state(ctx, data)?;
+        self.oam_index.save_state(ctx, data)?;
 
         Ok(())
     }
@@ -33,6 +37,11 @@ impl SaveState for PixelFifo {
         self.queue.load_state(ctx, data)?;
         self.head.load_state(ctx, data)?;
         self.tail.load_state(ctx, data)?;
+        if ctx.version >= 4 {
+            self.oam_index.load_state(ctx, data)?;
+        } else {
+            self.oam_index = [0; 16];
+        }
 
         Ok(())
     }
@@ -86,12 +95,16 @@ impl PixelFifo {
         }
     }
 
+    /// Mix the 8 pixels of a sprite into the fifo. A sprite partially off the left edge of the
+    /// screen is pushed entirely, and its hidden pixels are discarded by `output_pixel`, with the
+    /// background pixels, while `scanline_x` is negative.
     fn push_sprite(
         &mut self,
         tile_low: u8,
         tile_hight: u8,
         palette: bool,
         background_priority: bool,
+        oam_index: Option<u8>,
     ) {
         let pixel = |x| {
             let color: u8 = (((tile_hight >> x) & 0x01) << 1) | ((tile_low >> x) & 0x01);
@@ -102,18 +115,24 @@ impl PixelFifo {
 
         let mut cursor = self.tail;
         let mut x = 8u8;
-        // overwrite pixels in fifo, but only if 0
+        // overwrite pixels in fifo, but only if 0, or if the sprite has a lower OAM index (CGB)
         while cursor != self.head && x != 0 {
             x -= 1;
             let color = self.queue[cursor as usize] & 0b11;
-            if color == 0 {
+            let over = match oam_index {
+                Some(i) => i < self.oam_index[cursor as usize] && pixel(x) & 0b11 != 0,
+                None => false,
+            };
+            if color == 0 || over {
                 self.queue[cursor as usize] = pixel(x);
+                self.oam_index[cursor as usize] = oam_index.unwrap_or(0);
             }
             cursor = (cursor + 1) % self.queue.len() as u8;
         }
         // write remained
         for x in (0..x).rev() {
             self.queue[self.head as usize] = pixel(x);
+            self.oam_index[self.head as usize] = oam_index.unwrap_or(0);
             self.head = (self.head + 1) % self.queue.len() as u8;
             debug_assert_ne!(self.head, self.tail);
         }
@@ -129,12 +148,95 @@ impl PixelFifo {
     }
 }
 
+/// A snapshot of the background pixel fetcher, returned by `Ppu::fetcher_state`.
+#[derive(PartialEq, Eq, Default, Clone, Copy, Debug)]
+pub struct FetcherState {
+    /// The current step of the fetcher.
+    pub step: u8,
+    /// The tile x position that the fetcher is in.
+    pub x: u8,
+    /// The last fetched tile number.
+    pub tile_number: u8,
+    /// The last fetched low and high bytes of the tile data.
+    pub tile_data: (u8, u8),
+    /// If the fetcher is fetching the window, instead of the background.
+    pub window: bool,
+    /// If a sprite is being fetched, which stalls the background fetcher.
+    pub sprite_fetching: bool,
+    /// The remaining extra delay caused by a sprite at x = 0.
+    pub sprite_at_0_penalty: u8,
+}
+
+/// The background or window tile under a screen pixel, returned by `Ppu::bg_tile_at_screen`.
+#[derive(PartialEq, Eq, Default, Clone, Copy, Debug)]
+pub struct ScreenTile {
+    /// If the pixel is in the window, instead of the background.
+    pub window: bool,
+    /// The tile number in the tile map.
+    pub tile_index: u8,
+    /// The address of the tile data, from 0x8000 to 0x97FF.
+    pub tile_address: u16,
+    /// The address of the tile map entry, from 0x9800 to 0x9FFF.
+    pub map_address: u16,
+}
+
+/// A rectangle in screen coordinates, at (`x`, `y`) and of size `w` x `h`.
+#[derive(PartialEq, Eq, Default, Clone, Copy, Debug)]
+pub struct Rect {
+    pub x: u8,
+    pub y: u8,
+    pub w: u8,
+    pub h: u8,
+}
+impl Rect {
+    /// The entire screen.
+    pub const SCREEN: Rect = Rect {
+        x: 0,
+        y: 0,
+        w: SCREEN_WIDTH as u8,
+        h: SCREEN_HEIGHT as u8,
+    };
+}
+
 #[derive(PartialEq, Eq, Default, Clone, Copy, Debug)]
 pub struct Sprite {
     pub sx: u8,
     pub sy: u8,
     pub tile: u8,
     pub flags: u8,
+    /// The index of the sprite in the OAM, from 0 to 39.
+    pub oam_index: u8,
+}
+impl Sprite {
+    /// Decode a sprite from its 4 bytes OAM entry.
+    pub fn from_oam_entry(data: [u8; 4]) -> Self {
+        let [sy, sx, tile, flags] = data;
+        Self {
+            sx,
+            sy,
+            tile,
+            flags,
+            oam_index: 0,
+        }
+    }
+
+    /// If true, the background and window colors 1-3 are drawn over this sprite.
+    pub fn background_priority(&self) -> bool {
+        self.flags & 0x80 != 0
+    }
+
+    pub fn y_flip(&self) -> bool {
+        self.flags & 0x40 != 0
+    }
+
+    pub fn x_flip(&self) -> bool {
+        self.flags & 0x20 != 0
+    }
+
+    /// The palette used by this sprite: 0 for OBP0, and 1 for OBP1.
+    pub fn palette(&self) -> u8 {
+        (self.flags >> 4) & 1
+    }
 }
 impl SaveState for Sprite {
     fn save_state(
@@ -142,7 +244,8 @@ impl SaveState for Sprite {
         ctx: &mut SaveStateContext,
         data: &mut impl std::io::Write,
     ) -> Result<(), std::io::Error> {
-        [self.sx, self.sy, self.tile, self.flags].save_state(ctx, data)
+        [self.sx, self.sy, self.tile, self.flags].save_state(ctx, data)?;
+        self.oam_index.save_state(ctx, data)
     }
 
     fn load_state(
@@ -153,16 +256,37 @@ impl SaveState for Sprite {
         let mut t = [0u8; 4];
         t.load_state(ctx, data)?;
         let [sx, sy, t, flags] = t;
+        let mut oam_index = 0;
+        if ctx.version >= 4 {
+            oam_index.load_state(ctx, data)?;
+        }
         *self = Self {
             sx,
             sy,
             tile: t,
             flags,
+            oam_index,
         };
         Ok(())
     }
 }
 
+/// The layer that produced a pixel of the `Screen`, which selects the palette that was applied to
+/// it. Used for colorizing DMG games with a different set of colors for each layer.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Layer {
+    /// The background or the window, with BGP.
+    Background = 0,
+    /// A sprite with OBP0.
+    Obp0 = 1,
+    /// A sprite with OBP1.
+    Obp1 = 2,
+}
+
+/// The pixels drawn by the PPU. Each pixel holds the final shade, from 0 (lightest) to 3
+/// (darkest), with the BGP, OBP0 or OBP1 palette already applied, both for the lines drawn by the
+/// fast path (`draw_scan_line`) and by the pixel FIFO. The bits 2-3 of each pixel hold its `Layer`.
+///
 /// In some games, more than 30% of the entire CPU time is spent solely on the draw_scan_line
 /// function. So it is important to optimize this function as much as possible.
 ///
@@ -189,6 +313,11 @@ impl Screen {
     /// The number of extra bytes at the start of a scanline.
     const LEFT_PAD: usize = 8;
 
+    /// The scanline `ly`, including the paddings.
+    pub(crate) fn line_mut(&mut self, ly: u8) -> &mut [u8] {
+        &mut self.screen[ly as usize * Self::STRIDE..][..Self::STRIDE]
+    }
+
     fn set(&mut self, lx: u8, ly: u8, color: u8) {
         let lx = lx as usize;
         let ly = ly as usize;
@@ -197,15 +326,61 @@ impl Screen {
         self.screen[ly * Self::STRIDE + Self::LEFT_PAD + lx] = color;
     }
 
+    /// The shade of each visible pixel, from 0 to 3, row by row.
     pub fn packed(&self) -> [u8; SCREEN_WIDTH * SCREEN_HEIGHT] {
+        let mut packed = self.packed_with_layers();
+        for x in packed.iter_mut() {
+            *x &= 0b11;
+        }
+        packed
+    }
+
+    /// Like `packed`, but with the `Layer` of each pixel in its bits 2-3.
+    pub fn packed_with_layers(&self) -> [u8; SCREEN_WIDTH * SCREEN_HEIGHT] {
         let mut packed = [0; SCREEN_WIDTH * SCREEN_HEIGHT];
         for y in 0..SCREEN_HEIGHT {
-            for x in 0..SCREEN_WIDTH {
-                packed[y * SCREEN_WIDTH + x] = self.screen[y * Self::STRIDE + Self::LEFT_PAD + x];
-            }
+            packed[y * SCREEN_WIDTH..][..SCREEN_WIDTH]
+                .copy_from_slice(&self.screen[y * Self::STRIDE + Self::LEFT_PAD..][..SCREEN_WIDTH]);
         }
         packed
     }
+
+    /// A FNV-1a hash of the visible pixels, the same as hashing `packed()`.
+    pub fn hash(&self) -> u64 {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for y in 0..SCREEN_HEIGHT {
+            for &c in &self.screen[y * Self::STRIDE + Self::LEFT_PAD..][..SCREEN_WIDTH] {
+                hash ^= (c & 0b11) as u64;
+                hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+            }
+        }
+        hash
+    }
+
+    /// The bounding box of the pixels whose shade differs from `other`, or None if all are equal.
+    pub fn changed_region(&self, other: &Screen) -> Option<Rect> {
+        let (mut x0, mut x1) = (SCREEN_WIDTH, 0);
+        let (mut y0, mut y1) = (SCREEN_HEIGHT, 0);
+        for y in 0..SCREEN_HEIGHT {
+            let a = &self.screen[y * Self::STRIDE + Self::LEFT_PAD..][..SCREEN_WIDTH];
+            let b = &other.screen[y * Self::STRIDE + Self::LEFT_PAD..][..SCREEN_WIDTH];
+            let differs = |x: &usize| (a[*x] ^ b[*x]) & 0b11 != 0;
+            let Some(first) = (0..SCREEN_WIDTH).find(differs) else {
+                continue;
+            };
+            let last = (0..SCREEN_WIDTH).rev().find(differs).unwrap();
+            x0 = x0.min(first);
+            x1 = x1.max(last + 1);
+            y0 = y0.min(y);
+            y1 = y + 1;
+        }
+        (y0 < y1).then(|| Rect {
+            x: x0 as u8,
+            y: y0 as u8,
+            w: (x1 - x0) as u8,
+            h: (y1 - y0) as u8,
+        })
+    }
 }
 impl SaveState for Screen {
     fn save_state(
@@ -233,8 +408,11 @@ impl SaveState for Screen {
 
 #[derive(PartialEq, Eq, Clone)]
 pub struct Ppu {
-    /// 8000-9FFF: Video RAM
+    /// 8000-9FFF: Video RAM, the bank 0 in the CGB. This is the only bank used for rendering.
     pub vram: [u8; 0x2000],
+    /// 8000-9FFF: The CGB Video RAM bank 1, mapped when `vbk` is 1. The tile attributes it holds
+    /// are not emulated, so it is only read and written by the CPU.
+    pub cgb_vram: [u8; 0x2000],
     /// FE00-FE9F: Sprite Attribute table
     pub oam: [u8; 0xA0],
 
@@ -251,8 +429,9 @@ pub struct Ppu {
     vram_write_block: bool,
 
     /// The current screen been render.
-    /// Each pixel is a shade of gray, from 0 to 3
-    pub screen: Screen,
+    /// Each pixel is a shade of gray, from 0 to 3. Read through `Ppu::screen`, because some lines
+    /// may still be rendering in the `render_threads`.
+    screen: Screen,
     /// sprites that will be rendered in the next mode 3 scanline
     pub sprite_buffer: [Sprite; 10],
     /// the length of the `sprite_buffer`
@@ -298,6 +477,8 @@ pub struct Ppu {
     pub wy: u8,
     /// FF4B: Window X Position
     pub wx: u8,
+    /// FF4F: The CGB VRAM bank, 0 or 1. Always 0 in the DMG, where writes to VBK are ignored.
+    pub vbk: u8,
 
     pub state: u8,
     /// When making the LY==LYC comparison, uses this value instead of ly to control the comparison
@@ -326,6 +507,22 @@ pub struct Ppu {
     /// The estimated time where the next interrupt may happen.
     pub next_interrupt: u64,
 
+    /// The clock count in which the last frame has started, at the start of the last VBlank. It is
+    /// u64::MAX if no frame was completed yet.
+    last_frame_clock_count: u64,
+    /// The number of cycles between the start of the last two frames. 0 if there was none yet.
+    pub last_frame_cycles: u64,
+    /// The `Screen::hash` of the last complete frame, computed at the start of VBlank if
+    /// `GameBoy::hash_frames` is enabled. 0 if there was none yet. It is not saved, like the
+    /// previous frame for `changed_region`.
+    pub frame_hash: u64,
+    /// The region of the last complete frame that differs from the frame before it, computed at
+    /// the start of VBlank if `GameBoy::track_screen_changes` is enabled. Otherwise, or if there is
+    /// no previous frame, this is the entire screen. None if the frames are equal.
+    pub changed_region: Option<Rect>,
+    /// A copy of the last complete frame, kept for computing `changed_region`.
+    previous_screen: Option<Box<Screen>>,
+
     pub background_fifo: PixelFifo,
     pub sprite_fifo: PixelFifo,
 
@@ -349,6 +546,11 @@ pub struct Ppu {
 
     /// Sprites at 0 cause a extra delay in the sprite fetching.
     sprite_at_0_penalty: u8,
+    /// If a sprite is being fetched, in the states 31 to 34.
+    sprite_fetching: bool,
+    /// Set when the sprites are disabled in the middle of a sprite fetch, on DMG. The fetch is
+    /// aborted, and the sprite is not pushed to the sprite_fifo.
+    abort_sprite_fetching: bool,
     wx_just_changed: bool,
 
     /// The x position of the next screen pixel to be draw in the current scanline
@@ -357,12 +559,18 @@ pub struct Ppu {
     /// (represented by positives between 241 and 255) are use for detecting sprites that starts
     /// to the left of the screen, and for discarding pixels for scrolling.
     scanline_x: u8,
+
+    /// Worker threads for rendering the scanlines of the fast path. While they are rendering,
+    /// some lines of `screen` may be outdated, until `finish_rendering` is called, which happens
+    /// at each VBlank.
+    render_threads: RenderThreads,
 }
 
 impl std::fmt::Debug for Ppu {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Ppu")
             .field("vram", &"[...]")
+            .field("cgb_vram", &"[...]")
             .field("oam", &"[...]")
             .field("screen", &"[...]")
             // .field("vram", &self.vram)
@@ -398,6 +606,10 @@ impl std::fmt::Debug for Ppu {
             .field("next_clock_count", &self.next_clock_count)
             .field("line_start_clock_count", &self.line_start_clock_count)
             .field("next_interrupt", &self.next_interrupt)
+            .field("last_frame_clock_count", &self.last_frame_clock_count)
+            .field("last_frame_cycles", &self.last_frame_cycles)
+            .field("frame_hash", &self.frame_hash)
+            .field("changed_region", &self.changed_region)
             .field("background_fifo", &self.background_fifo)
             .field("sprite_fifo", &self.sprite_fifo)
             .field("fetcher_step", &self.fetcher_step)
@@ -413,9 +625,12 @@ impl std::fmt::Debug for Ppu {
             .field("is_window_being_fetched", &self.is_window_being_fetched)
             .field("insert_background_pixel", &self.insert_background_pixel)
             .field("sprite_at_0_penalty", &self.sprite_at_0_penalty)
+            .field("sprite_fetching", &self.sprite_fetching)
+            .field("abort_sprite_fetching", &self.abort_sprite_fetching)
             .field("wx_just_changed", &self.wx_just_changed)
             .field("screen_x", &self.screen_x)
             .field("scanline_x", &self.scanline_x)
+            .field("render_threads", &self.render_threads.threads())
             .finish()
     }
 }
@@ -448,7 +663,7 @@ crate::save_state!(Ppu, self, ctx, data {
 
     self.stat_mode_for_interrupt;
 
-    on_save debug_assert_eq!(self.last_clock_count, ctx.clock_count.unwrap());
+    on_save check_clock_count(ctx, "ppu", self.last_clock_count)?;
     on_load self.last_clock_count = ctx.clock_count.unwrap();
 
     self.next_clock_count;
@@ -488,6 +703,32 @@ crate::save_state!(Ppu, self, ctx, data {
         self.insert_background_pixel
     ];
 
+    if ctx.version >= 4 => {
+        self.last_frame_clock_count;
+        self.last_frame_cycles;
+        bitset [self.sprite_fetching, self.abort_sprite_fetching];
+    }
+    if ctx.version < 4 => {
+        on_load self.last_frame_clock_count = u64::MAX;
+        on_load self.last_frame_cycles = 0;
+        on_load self.sprite_fetching = false;
+        on_load self.abort_sprite_fetching = false;
+    }
+
+    // the previous frame is not saved, so the next frame is entirely changed
+    on_load self.changed_region = Some(Rect::SCREEN);
+    on_load self.previous_screen = None;
+    on_load self.frame_hash = 0;
+
+    if ctx.version >= 7 => {
+        self.cgb_vram;
+        self.vbk;
+    }
+    if ctx.version < 7 => {
+        on_load self.cgb_vram = [0; 0x2000];
+        on_load self.vbk = 0;
+    }
+
     on_load self.next_interrupt = self.estimate_next_interrupt();
 });
 
@@ -495,6 +736,7 @@ impl Default for Ppu {
     fn default() -> Self {
         Self {
             vram: [0; 0x2000],
+            cgb_vram: [0; 0x2000],
             oam: [0; 0xA0],
             dma_started: 0x7fff_ffff_ffff_ffff,
             dma_running: false,
@@ -518,6 +760,7 @@ impl Default for Ppu {
             obp1: Default::default(),
             wy: Default::default(),
             wx: Default::default(),
+            vbk: 0,
             ly_for_compare: 0,
             state: 0,
             stat_signal: false,
@@ -527,6 +770,11 @@ impl Default for Ppu {
             next_clock_count: 0,
             line_start_clock_count: 0,
             next_interrupt: 0,
+            last_frame_clock_count: u64::MAX,
+            last_frame_cycles: 0,
+            frame_hash: 0,
+            changed_region: Some(Rect::SCREEN),
+            previous_screen: None,
             background_fifo: Default::default(),
             sprite_fifo: Default::default(),
             fetcher_step: 0,
@@ -542,15 +790,54 @@ impl Default for Ppu {
             is_window_being_fetched: false,
             insert_background_pixel: false,
             sprite_at_0_penalty: 0,
+            sprite_fetching: false,
+            abort_sprite_fetching: false,
             wx_just_changed: false,
             screen_x: 0,
             scanline_x: 0,
+            render_threads: RenderThreads::default(),
         }
     }
 }
 
 impl Ppu {
+    /// Render the scanlines of the fast path in `threads` worker threads, or synchronously if
+    /// `threads` is 0. The rendered frames are the same in both cases. Panics if threads cannot
+    /// be spawned, like on wasm32.
+    pub fn set_render_threads(&mut self, threads: usize) {
+        self.finish_rendering();
+        self.render_threads = RenderThreads::new(threads);
+    }
+
+    /// The number of render threads, see `set_render_threads`.
+    pub fn render_threads(&self) -> usize {
+        self.render_threads.threads()
+    }
+
+    /// Wait for the scanlines being rendered by the worker threads, and write them to `screen`.
+    pub fn finish_rendering(&mut self) {
+        self.render_threads.finish(&mut self.screen);
+    }
+
+    /// The current screen, with every scanline drawn up to the last update of the PPU. Waits for
+    /// the scanlines being rendered by the worker threads, if any.
+    pub fn screen(&mut self) -> &Screen {
+        self.finish_rendering();
+        &self.screen
+    }
+
+    /// Reset the PPU to its power on state, like `Ppu::default`, but keeping the render threads.
+    pub fn reset(&mut self) {
+        self.finish_rendering();
+        let render_threads = std::mem::take(&mut self.render_threads);
+        *self = Self {
+            render_threads,
+            ..Self::default()
+        };
+    }
+
     pub fn reset_after_boot(&mut self) {
+        self.finish_rendering();
         let mut ppu_state = &include_bytes!("../../after_boot/ppu.sav")[..];
         let ctx = &mut SaveStateContext::default();
         *self = Self {
@@ -561,6 +848,7 @@ impl Ppu {
                 vram
 
             },
+            cgb_vram: [0; 0x2000],
             oam: {
                 let mut oam = [0; 0xA0];
                 oam.load_state(ctx, &mut ppu_state).unwrap();
@@ -592,6 +880,7 @@ impl Ppu {
             obp1: 0,
             wy: 0,
             wx: 0,
+            vbk: 0,
             state: 23,
             ly_for_compare: 0,
 
@@ -599,6 +888,11 @@ impl Ppu {
             next_clock_count: 23_440_377,
             line_start_clock_count: 23_435_361,
             next_interrupt: 23_440_324,
+            last_frame_clock_count: u64::MAX,
+            last_frame_cycles: 0,
+            frame_hash: 0,
+            changed_region: Some(Rect::SCREEN),
+            previous_screen: None,
 
             background_fifo: PixelFifo::default(),
             sprite_fifo: PixelFifo::default(),
@@ -623,13 +917,19 @@ impl Ppu {
             stat_mode_for_interrupt: 1,
 
             sprite_at_0_penalty: 0,
+            sprite_fetching: false,
+            abort_sprite_fetching: false,
             wx_just_changed: false,
 
             screen_x: 0xa0,
             scanline_x: 0x00,
+            render_threads: std::mem::take(&mut self.render_threads),
         }
     }
     pub fn write(gb: &mut GameBoy, address: u8, value: u8) {
+        if let Some(callback) = &mut gb.ppu_write_callback {
+            callback(0xFF00 | address as u16, value, gb.clock_count);
+        }
         match address {
             0x40 => {
                 debug_assert!(
@@ -659,7 +959,7 @@ impl Ppu {
                     // The BG and Windows enable bit is always ON on the first cycle.
                     this.lcdc |= value & BG_EN;
 
-                    update_lcdc(this, old_value, gb.clock_count);
+                    update_lcdc(this, old_value, gb.clock_count, gb.model);
                     old_value = this.lcdc;
                 }
 
@@ -668,7 +968,7 @@ impl Ppu {
                 {
                     let this = &mut *gb.ppu.get_mut();
                     this.lcdc = value;
-                    update_lcdc(this, old_value, gb.clock_count);
+                    update_lcdc(this, old_value, gb.clock_count, gb.model);
                 }
 
                 gb.clock_count += 1;
@@ -699,7 +999,8 @@ impl Ppu {
                 }
                 Ppu::update_interrupt_prediction(gb);
             }
-            0x44 => {} // ly is read only
+            // ly is read only, in all models
+            0x44 => {}
             0x45 => {
                 gb.update_ppu();
                 {
@@ -708,9 +1009,9 @@ impl Ppu {
                 }
                 Ppu::update_interrupt_prediction(gb);
             }
-            0x47 => write_pallete_conflict(gb, value, |x| &mut x.bgp),
-            0x48 => write_pallete_conflict(gb, value, |x| &mut x.obp0),
-            0x49 => write_pallete_conflict(gb, value, |x| &mut x.obp1),
+            0x47 => write_pallete(gb, value, |x| &mut x.bgp),
+            0x48 => write_pallete(gb, value, |x| &mut x.obp0),
+            0x49 => write_pallete(gb, value, |x| &mut x.obp1),
             0x4A => {
                 gb.update_ppu();
                 {
@@ -767,24 +1068,150 @@ impl Ppu {
         }
     }
 
-    fn search_objects(&mut self) {
+    /// The current step of the background pixel fetcher.
+    pub fn fetcher_step(&self) -> u8 {
+        self.fetcher_step
+    }
+
+    /// The window internal line counter: the row of the window drawn in the current line, or in
+    /// the last line that drew the window. It is 0xFF in a frame where the window was not drawn
+    /// yet, and is reset to it at the start of each frame.
+    pub fn window_line_counter(&self) -> u8 {
+        self.wyc
+    }
+
+    /// Set the window internal line counter, see `window_line_counter`. The next line that draws
+    /// the window draws its row `value + 1`.
+    pub fn set_window_line_counter(&mut self, value: u8) {
+        self.wyc = value;
+    }
+
+    /// The x position in the current scanline. See the field `scanline_x`.
+    pub fn scanline_x(&self) -> u8 {
+        self.scanline_x
+    }
+
+    /// The state of the background pixel fetcher.
+    pub fn fetcher_state(&self) -> FetcherState {
+        FetcherState {
+            step: self.fetcher_step,
+            x: self.fetcher_x,
+            tile_number: self.fetch_tile_number,
+            tile_data: (self.fetch_tile_data_low, self.fetch_tile_data_hight),
+            window: self.is_window_being_fetched,
+            sprite_fetching: self.sprite_fetching,
+            sprite_at_0_penalty: self.sprite_at_0_penalty,
+        }
+    }
+
+    /// The pixels in the background FIFO, from first to last to be popped. Each pixel is a color
+    /// index, from 0 to 3, before the palette is applied.
+    pub fn background_fifo_contents(&self) -> Vec<u8> {
+        self.background_fifo.iter().collect()
+    }
+
+    /// The pixels in the sprite FIFO, from first to last to be popped. Bits 0-1 are the color
+    /// index, bit 3 the background priority and bit 4 the palette.
+    pub fn sprite_fifo_contents(&self) -> Vec<u8> {
+        self.sprite_fifo.iter().collect()
+    }
+
+    /// Compare the frame just completed with the previous one, see `changed_region`.
+    fn update_changed_region(&mut self, track_changes: bool) {
+        if !track_changes {
+            self.previous_screen = None;
+            self.changed_region = Some(Rect::SCREEN);
+            return;
+        }
+        match &mut self.previous_screen {
+            Some(previous) => {
+                self.changed_region = self.screen.changed_region(previous);
+                **previous = self.screen;
+            }
+            None => {
+                self.changed_region = Some(Rect::SCREEN);
+                self.previous_screen = Some(Box::new(self.screen));
+            }
+        }
+    }
+
+    /// The background or window tile that produces the pixel at (`sx`, `sy`) of the screen, with
+    /// the current values of the registers. Intended for debugging tools.
+    ///
+    /// The line counter of the window is assumed to be `sy - WY`, which is only true if the window
+    /// was visible in all lines since WY.
+    pub fn bg_tile_at_screen(&self, sx: u8, sy: u8) -> ScreenTile {
+        debug_assert!((sx as usize) < SCREEN_WIDTH && (sy as usize) < SCREEN_HEIGHT);
+        let window =
+            self.lcdc & 0x21 == 0x21 && self.wx < 166 && sy >= self.wy && sx + 7 >= self.wx;
+        let (map, x, y) = if window {
+            let map = if self.lcdc & 0x40 != 0 {
+                0x9C00
+            } else {
+                0x9800
+            };
+            (map, sx + 7 - self.wx, sy - self.wy)
+        } else {
+            let map = if self.lcdc & 0x08 != 0 {
+                0x9C00
+            } else {
+                0x9800
+            };
+            (map, sx.wrapping_add(self.scx), sy.wrapping_add(self.scy))
+        };
+        let map_address = map + (y as u16 / 8) * 32 + x as u16 / 8;
+        let tile_index = self.vram[map_address as usize - 0x8000];
+        ScreenTile {
+            window,
+            tile_index,
+            tile_address: 0x8000 + tile_data_index(self.lcdc, tile_index) as u16 * 0x10,
+            map_address,
+        }
+    }
+
+    /// Return the sprite at the given index in the OAM, from 0 to 39.
+    pub fn sprite(&self, index: usize) -> Sprite {
+        Sprite {
+            oam_index: index as u8,
+            ..Sprite::from_oam_entry(self.oam[index * 4..][..4].try_into().unwrap())
+        }
+    }
+
+    /// Return all the 40 sprites in the OAM, in order.
+    pub fn sprites(&self) -> [Sprite; 40] {
+        std::array::from_fn(|i| self.sprite(i))
+    }
+
+    /// The OAM indices of the sprites whose area on the screen intersects the rectangle at
+    /// (`x`, `y`) of size `w` x `h`, in screen coordinates, in order. The height of the sprites
+    /// depends on the current LCDC. Sprites, or parts of them, outside the screen are ignored.
+    pub fn sprites_in_rect(&self, x: u8, y: u8, w: u8, h: u8) -> Vec<usize> {
+        let sprite_height = if self.lcdc & 0x04 != 0 { 16 } else { 8 };
+        // the intersection of the rectangle with the screen
+        let (x0, y0) = (x as i32, y as i32);
+        let x1 = (x0 + w as i32).min(SCREEN_WIDTH as i32);
+        let y1 = (y0 + h as i32).min(SCREEN_HEIGHT as i32);
+        self.sprites()
+            .iter()
+            .filter(|sprite| {
+                let (sx, sy) = (sprite.sx as i32 - 8, sprite.sy as i32 - 16);
+                sx < x1 && sx + 8 > x0 && sy < y1 && sy + sprite_height > y0
+            })
+            .map(|sprite| sprite.oam_index as usize)
+            .collect()
+    }
+
+    /// Fill the `sprite_buffer` with the sprites of the current line, sorted in increasing order of
+    /// priority. On DMG the sprite with lower x position has greater priority, and on CGB the one
+    /// with lower OAM index.
+    fn search_objects(&mut self, model: Model) {
         self.sprite_buffer_len = 0;
         let sprite_height = if self.lcdc & 0x04 != 0 { 16 } else { 8 };
         for i in 0..40 {
-            let i = i as usize * 4;
-            let data = &self.oam[i..i + 4];
-            let sy = data[0];
-            let sx = data[1];
-            let t = data[2];
-            let flags = data[3];
-
+            // only the Y position is checked, so only the sprites in the line are decoded
+            let sy = self.oam[i * 4];
             if self.ly as u16 + 16 >= sy as u16 && self.ly as u16 + 16 < sy as u16 + sprite_height {
-                self.sprite_buffer[self.sprite_buffer_len as usize] = Sprite {
-                    sy,
-                    sx,
-                    tile: t,
-                    flags,
-                };
+                self.sprite_buffer[self.sprite_buffer_len as usize] = self.sprite(i);
                 self.sprite_buffer_len += 1;
             }
             if self.sprite_buffer_len == 10 {
@@ -792,9 +1219,11 @@ impl Ppu {
             }
         }
         // sort buffer by priority, in increasing order
-        // lower x position, has greater priority
         self.sprite_buffer[0..self.sprite_buffer_len as usize].reverse();
-        self.sprite_buffer[0..self.sprite_buffer_len as usize].sort_by_key(|x| !x.sx);
+        if model == Model::Dmg {
+            // lower x position, has greater priority
+            self.sprite_buffer[0..self.sprite_buffer_len as usize].sort_by_key(|x| !x.sx);
+        }
     }
 
     fn update_dma(gb: &GameBoy, ppu: &mut Ppu, clock_count: u64) {
@@ -808,28 +1237,44 @@ impl Ppu {
                 // Finish running
                 ppu.dma_block_oam = false;
                 ppu.dma_running = false;
-
-                // copy memory
-                let mut value = gb.dma;
-                if value >= 0xFE {
-                    value -= 0x20;
-                }
-                let start = (value as u16) << 8;
-                for (i, j) in (0x00..=0x9F).zip(start..=start + 0x9F) {
-                    // avoid borrowing the ppu twice
-                    let value = match j {
-                        0x8000..=0x9FFF => ppu.vram[j as usize - 0x8000],
-                        j => gb.read(j),
-                    };
-                    ppu.oam[i] = value;
-                }
+                Self::copy_dma(gb, ppu);
             }
         }
     }
 
+    /// Copy the 160 bytes pointed by the DMA register to the OAM.
+    fn copy_dma(gb: &GameBoy, ppu: &mut Ppu) {
+        let start = Self::dma_source(gb.dma);
+        for (i, j) in (0x00..=0x9F).zip(start..=start + 0x9F) {
+            // avoid borrowing the ppu twice
+            let value = match j {
+                0x8000..=0x9FFF => ppu.vram[j as usize - 0x8000],
+                j => gb.read_no_conflict(j),
+            };
+            ppu.oam[i] = value;
+        }
+    }
+
+    /// The address of the first of the 160 bytes copied by a DMA written with `value`. Above
+    /// DFFF, the source would be the echo RAM, the OAM and the I/O registers, but the DMA always
+    /// reads from the WRAM there: E000-FDFF is read as the echo of C000-DDFF, like by the CPU,
+    /// and the sources FE00 and FF00 are replaced by DE00 and DF00.
+    pub fn dma_source(value: u8) -> u16 {
+        let value = if value >= 0xFE { value - 0x20 } else { value };
+        (value as u16) << 8
+    }
+
     pub fn start_dma(gb: &mut GameBoy, value: u8) {
         gb.update_ppu();
         gb.dma = value;
+        if gb.instant_dma {
+            let ppu = &mut *gb.ppu.borrow_mut();
+            // this also cancels any running DMA
+            ppu.dma_running = false;
+            ppu.dma_block_oam = false;
+            Self::copy_dma(gb, ppu);
+            return;
+        }
         let ppu = &mut *gb.ppu.get_mut();
         ppu.dma_started = gb.clock_count;
         if ppu.dma_running {
@@ -842,6 +1287,32 @@ impl Ppu {
         ppu.dma_running = true;
     }
 
+    /// If a DMA is transferring, return the byte currently being transferred, which is the value
+    /// in the bus that CPU reads conflict with.
+    pub fn dma_bus_value(gb: &GameBoy) -> Option<u8> {
+        gb.update_ppu();
+        let address = {
+            let ppu = &*gb.ppu.borrow();
+            if !ppu.dma_block_oam {
+                return None;
+            }
+            let elapsed = gb.clock_count.wrapping_sub(ppu.dma_started);
+            let i = (elapsed.saturating_sub(8) / 4).min(0x9F) as u16;
+            let address = Self::dma_source(gb.dma) + i;
+            if let 0x8000..=0x9FFF = address {
+                return Some(ppu.vram[address as usize - 0x8000]);
+            }
+            address
+        };
+        Some(gb.read_no_conflict(address))
+    }
+
+    /// Return true if the OAM is currently inaccessible to the CPU, either because of a running
+    /// DMA or because of the current PPU mode.
+    pub fn is_oam_blocked(&self) -> bool {
+        self.dma_block_oam || self.oam_read_block
+    }
+
     pub fn read_oam(gb: &GameBoy, address: u16) -> u8 {
         gb.update_ppu();
         let ppu = &mut *gb.ppu.borrow_mut();
@@ -860,13 +1331,49 @@ impl Ppu {
         }
     }
 
+    /// The VRAM bank selected by VBK.
+    fn selected_vram(&mut self) -> &mut [u8; 0x2000] {
+        match self.vbk {
+            0 => &mut self.vram,
+            _ => &mut self.cgb_vram,
+        }
+    }
+
+    /// Read the VRAM at `address`, in the given `bank`, without the access blocking of
+    /// `read_vram`. Return None if `address` is outside 8000-9FFF, or if the bank is not 0 or 1.
+    /// The bank 1 only exists in the CGB, but can be accessed here independently of the model.
+    pub fn vram_read(&self, address: u16, bank: u8) -> Option<u8> {
+        match (address, bank) {
+            (0x8000..=0x9FFF, 0) => Some(self.vram[address as usize - 0x8000]),
+            (0x8000..=0x9FFF, 1) => Some(self.cgb_vram[address as usize - 0x8000]),
+            _ => None,
+        }
+    }
+
+    /// Write to the VRAM at `address`, in the given `bank`, without the access blocking of
+    /// `write_vram`. The write is ignored for the same addresses and banks for which `vram_read`
+    /// returns None.
+    pub fn vram_write(&mut self, address: u16, bank: u8, value: u8) {
+        match (address, bank) {
+            (0x8000..=0x9FFF, 0) => self.vram[address as usize - 0x8000] = value,
+            (0x8000..=0x9FFF, 1) => self.cgb_vram[address as usize - 0x8000] = value,
+            _ => {}
+        }
+    }
+
+    /// Overwrite the entire OAM, ignoring any access blocking and running DMA. This is meant for
+    /// setting up tests, and don't emulate anything that the hardware could do.
+    pub fn force_oam(&mut self, data: &[u8; 0xA0]) {
+        self.oam = *data;
+    }
+
     pub fn read_vram(gb: &GameBoy, address: u16) -> u8 {
         gb.update_ppu();
         let ppu = &mut *gb.ppu.borrow_mut();
         if ppu.vram_read_block {
             0xff
         } else {
-            ppu.vram[address as usize - 0x8000]
+            ppu.selected_vram()[address as usize - 0x8000]
         }
     }
 
@@ -874,11 +1381,17 @@ impl Ppu {
         gb.update_ppu();
         let ppu = &mut *gb.ppu.get_mut();
         if !ppu.vram_write_block {
-            ppu.vram[address as usize - 0x8000] = value;
+            ppu.selected_vram()[address as usize - 0x8000] = value;
         }
     }
 
-    pub fn update(gb: &GameBoy) -> (bool, bool) {
+    /// Update the PPU up to the current clock_count, returning if the VBlank and STAT interrupts
+    /// were requested. If `stat_mode_changes` is given, the STAT mode changes are pushed to it as
+    /// `(ly, mode, clock_count)`, to be reported after the PPU is no longer borrowed.
+    pub fn update(
+        gb: &GameBoy,
+        mut stat_mode_changes: Option<&mut Vec<(u8, u8, u64)>>,
+    ) -> (bool, bool) {
         // Most of the ppu behaviour is based on the LIJI32/SameBoy including all of the timing,
         // and most of the implementation.
 
@@ -908,6 +1421,8 @@ impl Ppu {
 
         while ppu.next_clock_count < gb.clock_count {
             Self::update_dma(gb, ppu, ppu.next_clock_count);
+            let clock_count = ppu.next_clock_count;
+            let mode = ppu.stat & 0b11;
             // println!("state: {}", state);
             match ppu.state {
                 // turn on
@@ -963,15 +1478,25 @@ impl Ppu {
                 }
                 // 84
                 5 => {
-                    // goto mode_3_start
-                    ppu.state = 10;
+                    if gb.audio_only {
+                        // goto exit_mode_3
+                        ppu.state = 11;
+                    } else {
+                        // goto mode_3_start
+                        ppu.state = 10;
+                    }
                 }
 
                 // start_line
                 6 => {
                     ppu.line_start_clock_count = ppu.next_clock_count;
                     ppu.screen_x = 0;
-                    if gb.clock_count > ppu.next_clock_count + 456 {
+                    // The fast path skips the STAT mode changes, so it is only used for lines that
+                    // already ended, and not when the mode changes are being observed. In audio
+                    // only mode, it is used even then, but without drawing the line.
+                    if gb.clock_count > ppu.next_clock_count + 456
+                        && (gb.audio_only || stat_mode_changes.is_none())
+                    {
                         if ppu.wy == ppu.ly {
                             ppu.reach_window = true;
                         }
@@ -982,11 +1507,28 @@ impl Ppu {
                         if ppu.reach_window && window_enabled && ppu.wx < 166 {
                             ppu.is_in_window = true;
                             ppu.wyc = ppu.wyc.wrapping_add(1);
+                        } else if ppu.reach_window && window_enabled && ppu.wx == 166 {
+                            // the window is not drawn, but its line counter still advances, like
+                            // in the pixel FIFO.
+                            ppu.wyc = ppu.wyc.wrapping_add(1);
                         }
 
-                        Self::update_dma(gb, ppu, ppu.next_clock_count + 4);
-                        ppu.search_objects();
-                        draw_scan_line(ppu);
+                        Self::update_dma(gb, ppu, (ppu.next_clock_count + 4).min(gb.clock_count));
+                        if !gb.audio_only {
+                            #[cfg(feature = "timing_profile")]
+                            let start = std::time::Instant::now();
+                            ppu.search_objects(gb.model);
+                            let state = ScanlineState::new(ppu);
+                            let line = ppu.screen.line_mut(ppu.ly);
+                            if !ppu.render_threads.queue(state, &ppu.vram, line) {
+                                render_scan_line(line, &state, &ppu.vram);
+                            }
+                            #[cfg(feature = "timing_profile")]
+                            {
+                                gb.profile.borrow_mut().ppu_draw_scan_line +=
+                                    start.elapsed().as_nanos() as u64;
+                            }
+                        }
 
                         // TODO: I think only LY=LYC flag is observable here? So don't need all this
                         // code.
@@ -1022,7 +1564,7 @@ impl Ppu {
                             ppu.set_stat_mode(0);
 
                             // the draw_scan_line optimizations relies that interrupts don't happen
-                            debug_assert!(!stat_interrupt);
+                            debug_assert!(!stat_interrupt || gb.audio_only);
                         }
 
                         ppu.next_clock_count += 456;
@@ -1064,7 +1606,10 @@ impl Ppu {
                     ppu.stat_mode_for_interrupt = 0xff;
                     ppu.update_stat(&mut stat_interrupt);
 
-                    ppu.search_objects();
+                    ppu.search_objects(gb.model);
+                    // The sprites are fetched in order of x position. On CGB, their priority is
+                    // handled when mixing them in the sprite_fifo.
+                    ppu.sprite_buffer[0..ppu.sprite_buffer_len as usize].sort_by_key(|x| !x.sx);
 
                     ppu.next_clock_count += 76;
                     ppu.state = 39;
@@ -1092,7 +1637,12 @@ impl Ppu {
                     ppu.vram_write_block = true;
 
                     ppu.next_clock_count += 5;
-                    ppu.state = 10;
+                    if gb.audio_only {
+                        // goto exit_mode_3
+                        ppu.state = 11;
+                    } else {
+                        ppu.state = 10;
+                    }
                 }
                 // mode_3_start
                 10 => {
@@ -1109,8 +1659,9 @@ impl Ppu {
                     }
                     ppu.is_in_window = false;
                     ppu.scanline_x = -((ppu.scx % 8 + 8) as i8) as u8;
+                    // A sprite at x 0 always costs 11 dots. The fetcher only waits 5 - SCX % 8
+                    // dots (if positive) for it, so the rest is waited in state 32.
                     ppu.sprite_at_0_penalty = (ppu.scx % 8).min(5);
-                    // ppu.sprite_at_0_penalty = 4;
 
                     ppu.state = 27;
                 }
@@ -1188,7 +1739,6 @@ impl Ppu {
                     }
 
                     // fetch sprites
-                    // ppu.sprite_fetching = true;
                     ppu.state = 30;
                 }
                 // while there are sprites to be fetch...
@@ -1200,6 +1750,7 @@ impl Ppu {
                             == ppu.scanline_x.wrapping_add(8)
                     {
                         // continue loop
+                        ppu.sprite_fetching = true;
                         ppu.state = 31;
                     } else {
                         // exit loop
@@ -1208,17 +1759,19 @@ impl Ppu {
                 }
                 // while there are background pixels or don't reach a fetcher step...
                 31 => {
+                    if ppu.abort_sprite_fetching {
+                        ppu.state = 41;
+                        continue;
+                    }
                     if ppu.background_fifo.is_empty() || ppu.fetcher_step < 5 {
                         tick_pixel_fetcher(ppu, ppu.ly);
                         // wait 1
                         ppu.next_clock_count += 1;
-                        // if abort_sprite_feching { goto aborted }
                     } else {
                         ppu.state = 32;
                     }
                 }
                 32 => {
-                    // TODO: handle extra penalty sprite at 0
                     if ppu.sprite_at_0_penalty != 0
                         && ppu.sprite_buffer[ppu.sprite_buffer_len as usize - 1].sx == 0
                     {
@@ -1232,7 +1785,10 @@ impl Ppu {
                     ppu.state = 38;
                 }
                 37 => {
-                    // if abort_sprite_feching { goto aborted }
+                    if ppu.abort_sprite_fetching {
+                        ppu.state = 41;
+                        continue;
+                    }
 
                     ppu.state = 38;
                 }
@@ -1243,7 +1799,10 @@ impl Ppu {
                     ppu.state = 36;
                 }
                 36 => {
-                    // if abort_sprite_feching { goto aborted }
+                    if ppu.abort_sprite_fetching {
+                        ppu.state = 41;
+                        continue;
+                    }
 
                     tick_pixel_fetcher(ppu, ppu.ly);
                     ppu.sprite_tile_address = {
@@ -1266,7 +1825,10 @@ impl Ppu {
                     ppu.state = 33;
                 }
                 33 => {
-                    // if abort_sprite_feching { goto aborted }
+                    if ppu.abort_sprite_fetching {
+                        ppu.state = 41;
+                        continue;
+                    }
 
                     ppu.sprite_tile_data_low = ppu.vram[ppu.sprite_tile_address as usize];
 
@@ -1275,11 +1837,14 @@ impl Ppu {
                     ppu.state = 34;
                 }
                 34 => {
-                    // if abort_sprite_feching { goto aborted }
+                    if ppu.abort_sprite_fetching {
+                        ppu.state = 41;
+                        continue;
+                    }
 
                     ppu.sprite_tile_data_hight = ppu.vram[ppu.sprite_tile_address as usize + 1];
 
-                    // ppu.sprite_fetching = false;
+                    ppu.sprite_fetching = false;
 
                     // wait 1
                     ppu.next_clock_count += 1;
@@ -1303,12 +1868,21 @@ impl Ppu {
                         tile_hight,
                         sprite.flags & 0x10 != 0,
                         sprite.flags & 0x80 != 0,
+                        (gb.model == Model::Cgb).then_some(sprite.oam_index),
                     );
                     ppu.sprite_buffer_len -= 1;
 
                     // loop again
                     ppu.state = 30;
                 }
+                // aborted
+                41 => {
+                    // The sprite is left in the sprite_buffer, and discarded after the next pixel
+                    // is outputted.
+                    ppu.sprite_fetching = false;
+                    ppu.abort_sprite_fetching = false;
+                    ppu.state = 24;
+                }
                 24 => {
                     output_pixel(ppu);
                     tick_pixel_fetcher(ppu, ppu.ly);
@@ -1398,6 +1972,22 @@ impl Ppu {
                     if ppu.ly == 144 {
                         ppu.set_stat_mode(1);
                         vblank_interrupt = true;
+
+                        if ppu.last_frame_clock_count != u64::MAX {
+                            ppu.last_frame_cycles =
+                                ppu.next_clock_count - ppu.last_frame_clock_count;
+                        }
+                        ppu.last_frame_clock_count = ppu.next_clock_count;
+                        ppu.finish_rendering();
+                        #[cfg(feature = "timing_profile")]
+                        {
+                            gb.profile.borrow_mut().frames += 1;
+                        }
+                        if gb.hash_frames {
+                            ppu.frame_hash = ppu.screen.hash();
+                        }
+                        ppu.update_changed_region(gb.track_screen_changes);
+
                         if !ppu.stat_signal && ppu.stat & 0x20 != 0 {
                             stat_interrupt = true;
                         }
@@ -1463,6 +2053,11 @@ impl Ppu {
                 }
                 _ => unreachable!(),
             }
+            if let Some(changes) = stat_mode_changes.as_mut() {
+                if ppu.stat & 0b11 != mode {
+                    changes.push((ppu.ly, ppu.stat & 0b11, clock_count));
+                }
+            }
         }
 
         Self::update_dma(gb, ppu, gb.clock_count);
@@ -1533,21 +2128,53 @@ impl Ppu {
         gb.update_next_interrupt();
     }
 
-    pub fn estimate_next_interrupt(&self) -> u64 {
-        // if it is off, a interrupt will never happen
-        if self.lcdc & 0x80 == 0 {
-            return u64::MAX;
-        }
-
+    /// The scanline being drawn, which differs from `ly` in the end of scanline 153.
+    fn current_line(&self) -> u8 {
         // The ly becomes 0, 6 cycles after becoming 153
-        let ly = if self.ly == 0 {
+        if self.ly == 0 {
             match self.state {
                 19 | 20 | 21 | 22 | 23 => 153,
                 _ => 0,
             }
         } else {
             self.ly
+        }
+    }
+
+    /// The clock_count at which the scanline 0 of the next frame starts, when at the scanline `ly`.
+    fn next_frame_start(&self, ly: u8) -> u64 {
+        let lines_until_next_frame = if ly <= 143 {
+            SCANLINE_PER_FRAME - ly
+        } else {
+            // the value of line_start_clock_count is not updated while in vblank.
+            SCANLINE_PER_FRAME - 143
         };
+        self.line_start_clock_count + SCANLINE_CYCLES * lines_until_next_frame as u64
+    }
+
+    /// The predicted clock_count at which the next frame starts, at the start of its scanline 0.
+    /// The `ly` becomes 0 a scanline before that, early in the scanline 153.
+    ///
+    /// Returns None if the LCD is off, because no frame will start until it is turned on. The
+    /// prediction is based on the last update of the ppu, see `GameBoy::next_frame_clock`.
+    pub fn next_frame_clock(&self) -> Option<u64> {
+        if self.lcdc & 0x80 == 0 {
+            return None;
+        }
+        if self.line_start_clock_count == 0 {
+            // the first scanline was not started yet.
+            return Some(self.last_clock_count + FRAME_CYCLES);
+        }
+        Some(self.next_frame_start(self.current_line()))
+    }
+
+    pub fn estimate_next_interrupt(&self) -> u64 {
+        // if it is off, a interrupt will never happen
+        if self.lcdc & 0x80 == 0 {
+            return u64::MAX;
+        }
+
+        let ly = self.current_line();
 
         if self.line_start_clock_count == 0 {
             // wait a little, until self.start_clock_count is not 0.
@@ -1579,15 +2206,7 @@ impl Ppu {
             self.line_start_clock_count + SCANLINE_CYCLES * lines_until_vblank as u64 + 4
         };
 
-        let next_frame = {
-            let lines_until_next_frame = if ly <= 143 {
-                SCANLINE_PER_FRAME - ly
-            } else {
-                // the value of line_start_clock_count is not updated while in vblank.
-                SCANLINE_PER_FRAME - 143
-            };
-            self.line_start_clock_count + SCANLINE_CYCLES * lines_until_next_frame as u64
-        };
+        let next_frame = self.next_frame_start(ly);
 
         let next_lyc = if self.lyc < SCANLINE_PER_FRAME {
             let ly = if ly < 143 { ly } else { 143 };
@@ -1722,7 +2341,12 @@ impl Ppu {
     }
 }
 
-fn update_lcdc(ppu: &mut Ppu, old_value: u8, clock_count: u64) {
+fn update_lcdc(ppu: &mut Ppu, old_value: u8, clock_count: u64, model: Model) {
+    // On DMG, disabling the sprites aborts the sprite being fetched.
+    if model == Model::Dmg && ppu.sprite_fetching && ppu.lcdc & 0x02 == 0 {
+        ppu.abort_sprite_fetching = true;
+    }
+
     if ppu.lcdc & 0x80 != old_value & 0x80 {
         if ppu.lcdc & 0x80 == 0 {
             ppu.oam_read_block = false;
@@ -1731,6 +2355,9 @@ fn update_lcdc(ppu: &mut Ppu, old_value: u8, clock_count: u64) {
             ppu.vram_write_block = false;
 
             // disable ppu
+            ppu.finish_rendering();
+            ppu.sprite_fetching = false;
+            ppu.abort_sprite_fetching = false;
             ppu.ly = 0;
             ppu.line_start_clock_count = 0;
             // set to mode 0
@@ -1753,6 +2380,19 @@ fn update_lcdc(ppu: &mut Ppu, old_value: u8, clock_count: u64) {
     }
 }
 
+/// Write to a pallete, with the write conflict of the DMG if `GameBoy::palette_conflict` is set.
+fn write_pallete<F: Fn(&mut Ppu) -> &mut u8>(gb: &mut GameBoy, value: u8, field: F) {
+    if gb.model == Model::Dmg && gb.palette_conflict {
+        return write_pallete_conflict(gb, value, field);
+    }
+    gb.update_ppu();
+    {
+        let this = &mut *gb.ppu.get_mut();
+        *field(this) = value
+    }
+    Ppu::update_interrupt_prediction(gb);
+}
+
 /// When writing to a pallete, its value in the first cycle is OR'ed with the current value, and it
 /// is properly updated in the following cycle.
 ///
@@ -1900,6 +2540,7 @@ fn output_pixel(ppu: &mut Ppu) {
         // background color, with pallete applied
         let palette = ppu.bgp;
         let mut color = (palette >> (bcolor * 2)) & 0b11;
+        let mut layer = Layer::Background;
 
         if let Some(sprite_pixel) = sprite_pixel {
             let scolor = sprite_pixel & 0b11;
@@ -1909,12 +2550,14 @@ fn output_pixel(ppu: &mut Ppu) {
             } else {
                 // use sprite color
                 let palette = (sprite_pixel >> 4) & 0x1;
+                layer = [Layer::Obp0, Layer::Obp1][palette as usize];
                 let palette = [ppu.obp0, ppu.obp1][palette as usize];
                 color = (palette >> (scolor * 2)) & 0b11;
             }
         }
         debug_assert!(color < 4);
-        ppu.screen.set(ppu.screen_x, ppu.ly, color);
+        ppu.screen
+            .set(ppu.screen_x, ppu.ly, color | ((layer as u8) << 2));
         ppu.screen_x += 1;
         ppu.scanline_x += 1;
         ppu.is_window_being_fetched = false;
@@ -1955,31 +2598,36 @@ pub fn draw_tiles(ppu: &Ppu, draw_pixel: &mut impl FnMut(i32, i32, u8), palette:
 }
 
 pub fn draw_background(ppu: &Ppu, draw_pixel: &mut impl FnMut(i32, i32, u8)) {
-    for i in 0..(32 * 32) {
-        let tx = 8 * (i % 32);
-        let ty = 8 * (i / 32);
-        // BG Tile Map Select
-        let address = if ppu.lcdc & 0x08 != 0 { 0x9C00 } else { 0x9800 };
-        let mut tile = ppu.vram[address - 0x8000 + i as usize] as usize;
+    draw_map(ppu, draw_pixel, MapLayer::Background, ppu.bgp);
+}
 
-        // if is using 8800 method
-        if ppu.lcdc & 0x10 == 0 {
-            tile += 0x100;
-            if tile >= 0x180 {
-                tile -= 0x100;
-            }
-        }
+pub fn draw_window(ppu: &Ppu, draw_pixel: &mut impl FnMut(i32, i32, u8)) {
+    draw_map(ppu, draw_pixel, MapLayer::Window, ppu.bgp);
+}
 
-        draw_tile(ppu, draw_pixel, tx, ty, tile, ppu.bgp, false);
-    }
+/// One of the two layers drawn from a 32x32 tile map.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MapLayer {
+    /// The tile map selected by the LCDC bit 3.
+    Background,
+    /// The tile map selected by the LCDC bit 6.
+    Window,
 }
 
-pub fn draw_window(ppu: &Ppu, draw_pixel: &mut impl FnMut(i32, i32, u8)) {
+fn draw_map(ppu: &Ppu, draw_pixel: &mut impl FnMut(i32, i32, u8), layer: MapLayer, palette: u8) {
+    let map_select = match layer {
+        MapLayer::Background => 0x08,
+        MapLayer::Window => 0x40,
+    };
     for i in 0..(32 * 32) {
         let tx = 8 * (i % 32);
         let ty = 8 * (i / 32);
         // BG Tile Map Select
-        let address = if ppu.lcdc & 0x40 != 0 { 0x9C00 } else { 0x9800 };
+        let address = if ppu.lcdc & map_select != 0 {
+            0x9C00
+        } else {
+            0x9800
+        };
         let mut tile = ppu.vram[address - 0x8000 + i as usize] as usize;
 
         // if is using 8800 method
@@ -1990,20 +2638,63 @@ pub fn draw_window(ppu: &Ppu, draw_pixel: &mut impl FnMut(i32, i32, u8)) {
             }
         }
 
-        draw_tile(ppu, draw_pixel, tx, ty, tile, ppu.bgp, false);
+        draw_tile(ppu, draw_pixel, tx, ty, tile, palette, false);
     }
 }
 
+/// Render the entire 256x256 image of the tile map of `layer`, with the current tiles, as one
+/// byte per pixel. Each pixel is a shade from BGP if `apply_palette` is true, or the color index
+/// in the tile otherwise, both from 0 to 3.
+pub fn render_map(ppu: &Ppu, layer: MapLayer, apply_palette: bool) -> Vec<u8> {
+    let palette = if apply_palette {
+        ppu.bgp
+    } else {
+        0b11_10_01_00
+    };
+    let mut image = vec![0; 256 * 256];
+    draw_map(
+        ppu,
+        &mut |x, y, c| image[(x + y * 256) as usize] = c,
+        layer,
+        palette,
+    );
+    image
+}
+
+/// Encode `render_map` as a grayscale PNG, where 0 is white and 3 is black.
+#[cfg(feature = "png")]
+pub fn map_png(
+    ppu: &Ppu,
+    layer: MapLayer,
+    apply_palette: bool,
+) -> Result<Vec<u8>, image::ImageError> {
+    use image::ImageEncoder;
+
+    let pixels: Vec<u8> = render_map(ppu, layer, apply_palette)
+        .into_iter()
+        .map(|c| 255 - c * 85)
+        .collect();
+    let mut png = Vec::new();
+    image::codecs::png::PngEncoder::new(&mut png).write_image(
+        &pixels,
+        256,
+        256,
+        image::ColorType::L8,
+    )?;
+    Ok(png)
+}
+
 pub fn draw_sprites(ppu: &Ppu, draw_pixel: &mut impl FnMut(i32, i32, u8)) {
-    for i in 0..40 {
-        let i = i as usize * 4;
-        let data = &ppu.oam[i..i + 4];
-        let sy = data[0] as i32 - 16;
-        let sx = data[1] as i32 - 8;
-        let t = data[2];
-        let f = data[3];
+    for sprite in ppu.sprites() {
+        let sy = sprite.sy as i32 - 16;
+        let sx = sprite.sx as i32 - 8;
+        let t = sprite.tile;
 
-        let palette = if f & 0x10 != 0 { ppu.obp1 } else { ppu.obp0 };
+        let palette = if sprite.palette() == 1 {
+            ppu.obp1
+        } else {
+            ppu.obp0
+        };
 
         if sy < 0 || sx < 0 {
             continue;
@@ -2076,10 +2767,63 @@ pub fn draw_screen(ppu: &Ppu, draw_pixel: &mut impl FnMut(i32, i32, u8)) {
 }
 
 pub fn draw_scan_line(ppu: &mut Ppu) {
-    let scanline = &mut ppu.screen.screen[ppu.ly as usize * Screen::STRIDE..][..Screen::STRIDE];
+    let state = ScanlineState::new(ppu);
+    render_scan_line(ppu.screen.line_mut(ppu.ly), &state, &ppu.vram);
+}
 
-    let window_enabled = ppu.is_in_window && ppu.lcdc & 0x01 != 0;
-    let dx = if ppu.wx != 0 {
+/// The state read when drawing a scanline in the fast path, captured at the start of the line.
+#[derive(Clone, Copy)]
+pub(crate) struct ScanlineState {
+    pub ly: u8,
+    lcdc: u8,
+    scy: u8,
+    scx: u8,
+    wx: u8,
+    wyc: u8,
+    is_in_window: bool,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    sprite_buffer: [Sprite; 10],
+    sprite_buffer_len: u8,
+}
+impl ScanlineState {
+    fn new(ppu: &Ppu) -> Self {
+        Self {
+            ly: ppu.ly,
+            lcdc: ppu.lcdc,
+            scy: ppu.scy,
+            scx: ppu.scx,
+            wx: ppu.wx,
+            wyc: ppu.wyc,
+            is_in_window: ppu.is_in_window,
+            bgp: ppu.bgp,
+            obp0: ppu.obp0,
+            obp1: ppu.obp1,
+            sprite_buffer: ppu.sprite_buffer,
+            sprite_buffer_len: ppu.sprite_buffer_len,
+        }
+    }
+}
+
+/// The index of the data of a background or window tile, from 0 to 0x17F, for the given tile
+/// number, taking into account the addressing method selected in LCDC.
+#[inline]
+fn tile_data_index(lcdc: u8, tile: u8) -> usize {
+    // if is using 8800 method
+    if lcdc & 0x10 == 0 && tile < 0x80 {
+        tile as usize + 0x100
+    } else {
+        tile as usize
+    }
+}
+
+/// Draw a scanline, including its paddings, with the given state and VRAM.
+pub(crate) fn render_scan_line(scanline: &mut [u8], ppu: &ScanlineState, vram: &[u8; 0x2000]) {
+    debug_assert_eq!(scanline.len(), Screen::STRIDE);
+
+    let window_enabled = ppu.is_in_window && ppu.lcdc & 0x01 != 0;
+    let dx = if ppu.wx != 0 {
         7
     } else {
         // Similar array is show in state 27 of the PPU.
@@ -2104,16 +2848,11 @@ pub fn draw_scan_line(ppu: &mut Ppu) {
 
         let mut lx = Screen::LEFT_PAD as u8 - ppu.scx % 8;
         while lx < end {
-            let mut tile = ppu.vram[offset_y + offset_x as usize] as usize;
-
-            // if is using 8800 method
-            if ppu.lcdc & 0x10 == 0 && tile < 0x80 {
-                tile += 0x100;
-            }
+            let tile = tile_data_index(ppu.lcdc, vram[offset_y + offset_x as usize]);
 
             let i = tile * 0x10;
-            let a = ppu.vram[i + y as usize * 2] as usize;
-            let b = (ppu.vram[i + y as usize * 2 + 1] as usize) << 1;
+            let a = vram[i + y as usize * 2] as usize;
+            let b = (vram[i + y as usize * 2 + 1] as usize) << 1;
 
             // bound-check just once.
             let _ = &scanline[..lx as usize + 8];
@@ -2143,16 +2882,11 @@ pub fn draw_scan_line(ppu: &mut Ppu) {
 
         let mut lx = Screen::LEFT_PAD as u8 + wxs - scx % 8;
         while lx < end {
-            let mut tile = ppu.vram[offset_y + offset_x as usize] as usize;
-
-            // if is using 8800 method
-            if ppu.lcdc & 0x10 == 0 && tile < 0x80 {
-                tile += 0x100;
-            }
+            let tile = tile_data_index(ppu.lcdc, vram[offset_y + offset_x as usize]);
 
             let i = tile * 0x10;
-            let a = ppu.vram[i + y as usize * 2] as usize;
-            let b = (ppu.vram[i + y as usize * 2 + 1] as usize) << 1;
+            let a = vram[i + y as usize * 2] as usize;
+            let b = (vram[i + y as usize * 2 + 1] as usize) << 1;
 
             // bound-check just once.
             let _ = &scanline[..lx as usize + 8];
@@ -2167,8 +2901,9 @@ pub fn draw_scan_line(ppu: &mut Ppu) {
         }
     }
 
-    const BACKGROUND_PRIORITY_FLAG: u8 = 0b01_0000;
-    const SPRITE_DRAW_FLAG: u8 = 0b10_0000;
+    const BACKGROUND_PRIORITY_FLAG: u8 = 0b001_0000;
+    const SPRITE_DRAW_FLAG: u8 = 0b010_0000;
+    const SPRITE_OBP1_FLAG: u8 = 0b100_0000;
 
     // Draw Sprites, if enabled
     if ppu.lcdc & 0x02 != 0 && ppu.sprite_buffer_len != 0 {
@@ -2178,6 +2913,7 @@ pub fn draw_scan_line(ppu: &mut Ppu) {
             sx,
             tile,
             flags,
+            ..
         } in sprites.iter()
         {
             // Sprite is outside the screen
@@ -2206,16 +2942,16 @@ pub fn draw_scan_line(ppu: &mut Ppu) {
                 tile
             };
 
-            let palette = if flags & 0x10 != 0 {
-                ppu.obp1
+            let (palette, palette_flag) = if flags & 0x10 != 0 {
+                (ppu.obp1, SPRITE_OBP1_FLAG)
             } else {
-                ppu.obp0
+                (ppu.obp0, 0)
             };
 
             let y = py as usize % 8;
             let i = t as usize * 0x10;
-            let a = ppu.vram[i + y * 2];
-            let b = ppu.vram[i + y * 2 + 1];
+            let a = vram[i + y * 2];
+            let b = vram[i + y * 2 + 1];
 
             for x in 0..8 {
                 let lx = Screen::LEFT_PAD as u8 + sx + x - 8;
@@ -2223,11 +2959,12 @@ pub fn draw_scan_line(ppu: &mut Ppu) {
                 // X-Flip
                 let x = if flags & 0x20 != 0 { x } else { 7 - x };
                 let color = (((b >> x) << 1) & 0b10) | ((a >> x) & 0b1);
-                let c = (palette >> (color * 2)) & 0b11;
 
+                // transparency is given by the color index, not by the palette.
                 if color == 0 {
                     continue;
                 }
+                let c = (palette >> (color * 2)) & 0b11;
 
                 // The sprite cannot be directly draw to the screen, because it would overwritte
                 // the value of the background, that still could be used for next overlapping
@@ -2248,6 +2985,7 @@ pub fn draw_scan_line(ppu: &mut Ppu) {
                 *p = (*p & 0b11)
                     | (c << 2)
                     | ((flags & 0x80 != 0) as u8 * BACKGROUND_PRIORITY_FLAG)
+                    | palette_flag
                     | SPRITE_DRAW_FLAG;
             }
         }
@@ -2257,7 +2995,12 @@ pub fn draw_scan_line(ppu: &mut Ppu) {
             if *x & SPRITE_DRAW_FLAG != 0
                 && !(*x & BACKGROUND_PRIORITY_FLAG != 0 && background_color != 0)
             {
-                *x = (*x >> 2) & 0b11;
+                let layer = if *x & SPRITE_OBP1_FLAG != 0 {
+                    Layer::Obp1
+                } else {
+                    Layer::Obp0
+                };
+                *x = ((*x >> 2) & 0b11) | ((layer as u8) << 2);
             } else {
                 *x = (ppu.bgp >> ((background_color) * 2)) & 0b11;
             }
@@ -2274,6 +3017,7 @@ pub fn draw_scan_line(ppu: &mut Ppu) {
 #[cfg(test)]
 mod test {
     use crate::gameboy::cartridge::Cartridge;
+    use crate::{gameboy::cpu::CpuState, interpreter::Interpreter};
 
     use super::*;
     use rand::Rng;
@@ -2290,6 +3034,1508 @@ mod test {
     const WY: u16 = 0xff4a;
     const WX: u16 = 0xff4b;
 
+    /// Observe the STAT mode changes, which disables the fast path (`draw_scan_line`), so every
+    /// scanline is drawn by the pixel FIFO.
+    fn force_fifo(gb: &mut GameBoy) {
+        *gb.stat_mode_callback.get_mut() = Some(Box::new(|_, _, _| {}));
+    }
+
+    /// Run two frames, drawn by the pixel FIFO if `fifo` is true, or by the fast path otherwise,
+    /// and return the screen.
+    fn render_frames(gb: &mut GameBoy, fifo: bool) -> [u8; SCREEN_WIDTH * SCREEN_HEIGHT] {
+        if fifo {
+            force_fifo(gb);
+        }
+        gb.tick(2 * FRAME_CYCLES);
+        gb.update_all();
+        gb.ppu.get_mut().screen.packed()
+    }
+
+    #[test]
+    fn frame_hash() {
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        gb.hash_frames = true;
+        assert_eq!(gb.frame_hash(), None);
+
+        let next_frame = |gb: &mut GameBoy| {
+            gb.tick(FRAME_CYCLES);
+            gb.update_all();
+            gb.frame_hash().unwrap()
+        };
+
+        next_frame(&mut gb);
+        let hash = next_frame(&mut gb);
+        assert_eq!(next_frame(&mut gb), hash);
+        assert_eq!(hash, gb.ppu.borrow().screen.hash());
+
+        // tile 0 fills the background, so this changes some pixels.
+        gb.write(0x8000, 0x80);
+        next_frame(&mut gb);
+        assert_ne!(next_frame(&mut gb), hash);
+
+        // the hash is not saved
+        let mut state = Vec::new();
+        gb.save_state(None, &mut state).unwrap();
+        gb.load_state(&mut state.as_slice()).unwrap();
+        assert_eq!(gb.frame_hash(), None);
+        next_frame(&mut gb);
+        gb.hash_frames = false;
+        assert_eq!(gb.frame_hash(), None);
+
+        let mut screen = Screen::default();
+        let hash = screen.hash();
+        screen.set(159, 143, 1);
+        assert_ne!(screen.hash(), hash);
+    }
+
+    #[test]
+    fn changed_region() {
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        gb.track_screen_changes = true;
+
+        let next_frame = |gb: &mut GameBoy| {
+            gb.tick(FRAME_CYCLES);
+            gb.update_all();
+            gb.changed_region()
+        };
+
+        // there is no previous frame to compare to
+        assert_eq!(next_frame(&mut gb), Some(Rect::SCREEN));
+        // a static scene
+        next_frame(&mut gb);
+        assert_eq!(next_frame(&mut gb), None);
+
+        // a sprite whose tile only has its first row set, so it changes a single line
+        {
+            let ppu = gb.ppu.get_mut();
+            ppu.oam[..4].copy_from_slice(&[16 + 20, 8 + 20, 0x80, 0x00]);
+            ppu.vram[0x800..0x802].copy_from_slice(&[0xFF, 0x00]);
+        }
+        gb.tick(4);
+        gb.write(OBP0, 0xE4);
+        gb.tick(4);
+        let lcdc = gb.read(LCDC);
+        gb.write(LCDC, lcdc | 0x02);
+        let region = Rect {
+            x: 20,
+            y: 20,
+            w: 8,
+            h: 1,
+        };
+        assert_eq!(next_frame(&mut gb), Some(region));
+        assert_eq!(next_frame(&mut gb), None);
+
+        let mut screen = Screen::default();
+        let other = screen;
+        screen.set(10, 20, 1);
+        screen.set(30, 25, 2);
+        assert_eq!(
+            screen.changed_region(&other),
+            Some(Rect {
+                x: 10,
+                y: 20,
+                w: 21,
+                h: 6
+            })
+        );
+        assert_eq!(screen.changed_region(&screen), None);
+
+        gb.track_screen_changes = false;
+        assert_eq!(next_frame(&mut gb), Some(Rect::SCREEN));
+    }
+
+    #[test]
+    fn force_oam() {
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        let line = 100;
+        {
+            let ppu = gb.ppu.get_mut();
+            // tile 1 is filled with color 3, and background is blank
+            ppu.vram[0x10..0x20].fill(0xff);
+            ppu.vram[0x1800..0x1C00].fill(0x00);
+
+            let mut oam = [0; 0xA0];
+            oam[..8].copy_from_slice(&[line + 16, 8 + 20, 1, 0, line + 9, 8 + 100, 1, 0]);
+            ppu.force_oam(&oam);
+        }
+        gb.tick(4);
+        gb.write(LCDC, 0x93);
+        gb.tick(4);
+        gb.write(OBP0, 0xE4);
+
+        // the OAM is written even while the PPU is blocking it
+        while !(gb.read(LY) == line && gb.read(STAT) & 0b11 == 3) {
+            gb.tick(4);
+        }
+        gb.update_all();
+        let mut oam = gb.ppu.borrow().oam;
+        oam[8..12].copy_from_slice(&[line + 16, 8 + 140, 1, 0]);
+        gb.ppu.get_mut().force_oam(&oam);
+        assert_eq!(gb.read(0xFE08), 0xFF);
+        assert_eq!(gb.ppu.borrow().oam[8], line + 16);
+
+        while gb.read(LY) == line {
+            gb.tick(4);
+        }
+        let start = line as usize * SCREEN_WIDTH;
+        let colors = gb.ppu.get_mut().screen.packed()[start..][..SCREEN_WIDTH].to_vec();
+        let mut expected = [0; SCREEN_WIDTH];
+        expected[20..28].fill(3);
+        expected[100..108].fill(3);
+        assert_eq!(colors, expected);
+    }
+
+    #[test]
+    fn window_line_counter() {
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        {
+            let ppu = gb.ppu.get_mut();
+            // the window uses tile 1, where the row y has color y % 4
+            for y in 0..8 {
+                let color = y as u8 % 4;
+                ppu.vram[0x10 + 2 * y] = 0xff * (color & 1);
+                ppu.vram[0x10 + 2 * y + 1] = 0xff * (color >> 1);
+            }
+            ppu.vram[0x1800..0x1C00].fill(0x00);
+            ppu.vram[0x1C00..0x2000].fill(0x01);
+        }
+        gb.write_registers(&[(WY, 0), (WX, 7), (BGP, 0xe4), (LCDC, 0xf1)]);
+
+        // wait for the mode 0 of `line`
+        let wait_line = |gb: &mut GameBoy, line: u8| {
+            while !(gb.read(LY) == line && gb.read(STAT) & 0b11 == 3) {
+                gb.tick(4);
+            }
+            while gb.read(STAT) & 0b11 != 0 {
+                gb.tick(4);
+            }
+            gb.update_all();
+        };
+        let line_colors = |gb: &GameBoy, line: usize| {
+            gb.ppu.borrow().screen.packed()[line * SCREEN_WIDTH..][..SCREEN_WIDTH].to_vec()
+        };
+
+        // wait for the next frame
+        wait_line(&mut gb, 20);
+        wait_line(&mut gb, 10);
+        assert_eq!(gb.ppu.borrow().window_line_counter(), 10);
+        assert_eq!(line_colors(&gb, 10), [10 % 4; SCREEN_WIDTH]);
+
+        // the next line draws the row 5
+        gb.ppu.get_mut().set_window_line_counter(4);
+        wait_line(&mut gb, 12);
+        assert_eq!(gb.ppu.borrow().window_line_counter(), 6);
+        assert_eq!(line_colors(&gb, 11), [5 % 4; SCREEN_WIDTH]);
+        assert_eq!(line_colors(&gb, 12), [6 % 4; SCREEN_WIDTH]);
+
+        // it is reset at the start of the frame
+        wait_line(&mut gb, 0);
+        assert_eq!(gb.ppu.borrow().window_line_counter(), 0);
+    }
+
+    #[test]
+    fn pallete_conflict() {
+        // Write to BGP in the middle of a scanline, and return the colors drawn on it.
+        fn draw_line(model: Model, palette_conflict: bool) -> Vec<u8> {
+            let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+            gb.model = model;
+            gb.palette_conflict = palette_conflict;
+            {
+                let ppu = gb.ppu.get_mut();
+                // tile 0 is filled with color 3, and fills the background.
+                ppu.vram[..0x10].fill(0xff);
+                ppu.vram[0x1800..0x1C00].fill(0x00);
+            }
+            gb.tick(4);
+            gb.write(LCDC, 0x91);
+            gb.tick(4);
+            gb.write(BGP, 0b01 << 6);
+
+            let line = 100;
+            while !(gb.read(LY) == line && gb.read(STAT) & 0b11 == 3) {
+                gb.tick(4);
+            }
+            gb.tick(40);
+            gb.write(BGP, 0b10 << 6);
+            while gb.read(LY) == line {
+                gb.tick(4);
+            }
+
+            let start = line as usize * SCREEN_WIDTH;
+            let screen = gb.ppu.get_mut().screen.packed();
+            screen[start..start + SCREEN_WIDTH].to_vec()
+        }
+
+        // On DMG, the palette is ORed with the new value for one cycle.
+        let colors = draw_line(Model::Dmg, true);
+        assert!(colors.contains(&3));
+        assert_eq!(colors[0], 1);
+        assert_eq!(colors[SCREEN_WIDTH - 1], 2);
+
+        for colors in [draw_line(Model::Cgb, true), draw_line(Model::Dmg, false)] {
+            assert!(!colors.contains(&3));
+            assert_eq!(colors[0], 1);
+            assert_eq!(colors[SCREEN_WIDTH - 1], 2);
+        }
+    }
+
+    #[test]
+    fn background_disable_mid_line() {
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        gb.model = Model::Dmg;
+        let line = 100;
+        {
+            let ppu = gb.ppu.get_mut();
+            // tile 0 is filled with color 3, and fills the background.
+            ppu.vram[..0x10].fill(0xff);
+            ppu.vram[0x1800..0x1C00].fill(0x00);
+            // tile 1 is filled with color 1, and is used by a sprite on each side of the line.
+            ppu.vram[0x10..0x20].copy_from_slice(&[0xff, 0x00].repeat(8));
+            ppu.oam.fill(0);
+            ppu.oam[..8].copy_from_slice(&[line + 16, 8 + 8, 1, 0, line + 16, 8 + 150, 1, 0]);
+        }
+        gb.tick(4);
+        gb.write(LCDC, 0x93);
+        gb.tick(4);
+        gb.write(BGP, 0xE4);
+        gb.tick(4);
+        gb.write(OBP0, 0xE4);
+
+        while !(gb.read(LY) == line && gb.read(STAT) & 0b11 == 3) {
+            gb.tick(4);
+        }
+        gb.tick(80);
+        gb.write(LCDC, 0x92);
+        while gb.read(LY) == line {
+            gb.tick(4);
+        }
+
+        let start = line as usize * SCREEN_WIDTH;
+        let colors = gb.ppu.get_mut().screen.packed()[start..][..SCREEN_WIDTH].to_vec();
+        assert_eq!(colors[..8], [3; 8]);
+        assert_eq!(colors[8..16], [1; 8]);
+        assert_eq!(colors[150..158], [1; 8]);
+        assert_eq!(colors[158..], [0; 2]);
+
+        // the background is drawn up to the write, and is blank after it
+        let split = (16..150).find(|&x| colors[x] == 0).unwrap();
+        assert!((24..140).contains(&split), "{}", split);
+        assert!(colors[16..split].iter().all(|&x| x == 3));
+        assert!(colors[split..150].iter().all(|&x| x == 0));
+    }
+
+    /// Return the clocks, relative to `origin`, in which the CPU access to VRAM and OAM changes,
+    /// in the next `cycles` cycles. The access is in the format [VRAM read, VRAM write, OAM read,
+    /// OAM write], where true means accessible.
+    fn access_changes(gb: &mut GameBoy, origin: u64, cycles: u64) -> Vec<(i64, [bool; 4])> {
+        gb.ppu.get_mut().vram[0] = 0x12;
+        gb.ppu.get_mut().oam[0] = 0x34;
+
+        let mut changes = Vec::new();
+        let mut last = None;
+        for _ in 0..cycles {
+            gb.tick(1);
+            gb.ppu.get_mut().vram[1] = 0;
+            gb.ppu.get_mut().oam[1] = 0;
+            gb.write(0x8001, 0x56);
+            gb.write(0xFE01, 0x78);
+            let vram_read = gb.read(0x8000) == 0x12;
+            let oam_read = gb.read(0xFE00) == 0x34;
+            let ppu = gb.ppu.get_mut();
+            let access = [vram_read, ppu.vram[1] == 0x56, oam_read, ppu.oam[1] == 0x78];
+            if last != Some(access) {
+                changes.push((gb.clock_count as i64 - origin as i64, access));
+                last = Some(access);
+            }
+        }
+        changes
+    }
+
+    #[test]
+    fn vram_oam_access_boundaries() {
+        const ALL: [bool; 4] = [true; 4];
+        const NONE: [bool; 4] = [false; 4];
+
+        // The clocks are the first ones in which the change is observable.
+        for line in [1, 10, 143] {
+            let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+            // wait for the mode 0 of the previous line
+            while gb.read(LY) != line - 1 || gb.read(STAT) & 0b11 != 3 {
+                gb.tick(4);
+            }
+            while gb.read(STAT) & 0b11 != 0 {
+                gb.tick(4);
+            }
+            gb.update_all();
+            let line_start = gb.ppu.borrow().line_start_clock_count + 456;
+            let cycles = line_start + 456 - gb.clock_count;
+
+            let changes = access_changes(&mut gb, line_start, cycles);
+            assert_eq!(
+                changes[1..],
+                [
+                    // OAM read is blocked 1 cycle before mode 2
+                    (4, [true, true, false, true]),
+                    // mode 2
+                    (5, [true, true, false, false]),
+                    // VRAM read is blocked 4 cycles before mode 3, and OAM write is briefly
+                    // unblocked.
+                    (81, [false, true, false, true]),
+                    // mode 3
+                    (85, NONE),
+                    // mode 0
+                    (257, ALL),
+                ],
+                "line {}",
+                line
+            );
+        }
+
+        // line 0 after turning the LCD on don't have a mode 2.
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        gb.tick(4);
+        gb.write(LCDC, 0x11);
+        gb.tick(4);
+        gb.write(LCDC, 0x91);
+        let turn_on = gb.clock_count;
+        let changes = access_changes(&mut gb, turn_on, 460);
+        assert_eq!(
+            changes,
+            [
+                (1, ALL),
+                (77, [true, true, true, false]),
+                // mode 3
+                (79, NONE),
+                // mode 0
+                (251, ALL),
+                // line 1
+                (452, [true, true, false, true]),
+                (453, [true, true, false, false]),
+            ]
+        );
+    }
+
+    #[test]
+    fn vram_oam_access_mode_3_length() {
+        const NONE: [bool; 4] = [false; 4];
+
+        // The end of mode 3, and of the access blocking, is delayed by SCX % 8, and by 6 to 11
+        // cycles for each sprite in the line, depending on its alignment to the background tiles.
+        let cases = [
+            (0, None, 257),
+            (1, None, 258),
+            (5, None, 262),
+            (7, None, 264),
+            (0, Some(8), 268),
+            (0, Some(9), 267),
+            (0, Some(50), 266),
+            (3, Some(50), 266),
+            (0, Some(167), 263),
+            // outside the screen
+            (0, Some(168), 257),
+        ];
+        for (scx, sprite_x, mode_0) in cases {
+            let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+            let line = 10;
+            gb.tick(4);
+            gb.write(SCX, scx);
+            if let Some(x) = sprite_x {
+                // `access_changes` overwrites the first sprite.
+                let ppu = gb.ppu.get_mut();
+                ppu.oam.fill(0);
+                ppu.oam[4..8].copy_from_slice(&[line + 16, x, 0, 0]);
+                gb.tick(4);
+                let lcdc = gb.read(LCDC);
+                gb.write(LCDC, lcdc | 0x02);
+            }
+            // wait for the mode 0 of the previous line
+            while gb.read(LY) != line - 1 || gb.read(STAT) & 0b11 != 3 {
+                gb.tick(4);
+            }
+            while gb.read(STAT) & 0b11 != 0 {
+                gb.tick(4);
+            }
+            gb.update_all();
+            let line_start = gb.ppu.borrow().line_start_clock_count + 456;
+            let cycles = line_start + 456 - gb.clock_count;
+
+            let changes = access_changes(&mut gb, line_start, cycles);
+            assert_eq!(
+                changes[1..],
+                [
+                    (4, [true, true, false, true]),
+                    (5, [true, true, false, false]),
+                    (81, [false, true, false, true]),
+                    // the start of mode 3 is not affected
+                    (85, NONE),
+                    (mode_0, [true; 4]),
+                ],
+                "scx {}, sprite {:?}",
+                scx,
+                sprite_x
+            );
+        }
+    }
+
+    #[test]
+    fn sprites() {
+        let mut ppu = Ppu::default();
+        ppu.oam[0..4].copy_from_slice(&[16, 8, 0x12, 0x00]);
+        ppu.oam[4..8].copy_from_slice(&[50, 60, 0x34, 0xF0]);
+        ppu.oam[39 * 4..].copy_from_slice(&[1, 2, 3, 0x30]);
+
+        let sprites = ppu.sprites();
+
+        assert_eq!(
+            (sprites[0].sy, sprites[0].sx, sprites[0].tile),
+            (16, 8, 0x12)
+        );
+        assert!(!sprites[0].background_priority());
+        assert!(!sprites[0].y_flip());
+        assert!(!sprites[0].x_flip());
+        assert_eq!(sprites[0].palette(), 0);
+
+        assert_eq!(
+            (sprites[1].sy, sprites[1].sx, sprites[1].tile),
+            (50, 60, 0x34)
+        );
+        assert!(sprites[1].background_priority());
+        assert!(sprites[1].y_flip());
+        assert!(sprites[1].x_flip());
+        assert_eq!(sprites[1].palette(), 1);
+
+        assert_eq!(
+            (sprites[39].sy, sprites[39].sx, sprites[39].tile),
+            (1, 2, 3)
+        );
+        assert!(!sprites[39].background_priority());
+        assert!(!sprites[39].y_flip());
+        assert!(sprites[39].x_flip());
+        assert_eq!(sprites[39].palette(), 1);
+        assert_eq!(ppu.sprite(39), sprites[39]);
+
+        assert_eq!(
+            sprites[2],
+            Sprite {
+                oam_index: 2,
+                ..Sprite::default()
+            }
+        );
+        assert_eq!(sprites[39].oam_index, 39);
+    }
+
+    #[test]
+    fn fetcher_state() {
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        // tile 0 has a pattern of colors 0 to 3, and tile 1 is used by the sprite.
+        gb.ppu.get_mut().vram[..2].copy_from_slice(&[0b0101_0101, 0b0011_0011]);
+        gb.ppu.get_mut().vram[0x10..0x12].copy_from_slice(&[0xFF, 0x00]);
+        {
+            let ppu = gb.ppu.get_mut();
+            // a sprite at x = 20, in line 0
+            ppu.oam[0..4].copy_from_slice(&[16, 28, 0x01, 0x00]);
+        }
+        gb.tick(4);
+        gb.write(LCDC, 0x93);
+
+        let mut mode_3_dots = 0;
+        let mut sprite_fetching = false;
+        loop {
+            let dot = gb.step_ppu_dot();
+            if dot.ly == 1 {
+                break;
+            }
+            if dot.mode != 3 {
+                continue;
+            }
+            mode_3_dots += 1;
+
+            let ppu = gb.ppu.borrow();
+            let background = ppu.background_fifo_contents();
+            assert_eq!(background.len(), ppu.background_fifo.len());
+            assert_eq!(background, ppu.background_fifo.iter().collect::<Vec<_>>());
+            assert_eq!(background, dot.background_fifo);
+            assert!(background.iter().all(|&x| x < 4));
+
+            let sprites = ppu.sprite_fifo_contents();
+            assert_eq!(sprites.len(), ppu.sprite_fifo.len());
+            assert_eq!(sprites, dot.sprite_fifo);
+
+            let fetcher = ppu.fetcher_state();
+            assert_eq!(fetcher.step, ppu.fetcher_step());
+            assert_eq!(fetcher.step, dot.fetcher_step);
+            assert!(!fetcher.window);
+            assert!(fetcher.x <= 21, "{:?}", fetcher);
+            sprite_fetching |= fetcher.sprite_fetching;
+        }
+        assert!(mode_3_dots > 172);
+        assert!(sprite_fetching);
+    }
+
+    #[test]
+    fn sprite_priority() {
+        // Draw two overlapping sprites, and return the colors drawn on line 100. If `fast` is true,
+        // the line is draw all at once by `draw_scan_line`, instead of by the pixel FIFO.
+        fn draw_line(model: Model, fast: bool) -> Vec<u8> {
+            let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+            gb.model = model;
+            {
+                let ppu = gb.ppu.get_mut();
+                // tile 1 is filled with color 3, and the background uses tile 0, of color 0.
+                ppu.vram[0x10..0x20].fill(0xff);
+                ppu.vram[0x1800..0x1C00].fill(0x00);
+                // sprite 0, at screen x 12..20, with OBP0.
+                ppu.oam[0..4].copy_from_slice(&[100 + 16, 20, 1, 0x00]);
+                // sprite 1, at screen x 8..16, with OBP1.
+                ppu.oam[4..8].copy_from_slice(&[100 + 16, 16, 1, 0x10]);
+            }
+            gb.write_registers(&[
+                (BGP, 0x00),
+                (OBP0, 0b11 << 6),
+                (OBP1, 0b01 << 6),
+                (LCDC, 0x93),
+            ]);
+
+            if fast {
+                gb.tick(456 * 102);
+                gb.read(LY);
+            } else {
+                while gb.read(LY) <= 100 {
+                    gb.tick(4);
+                }
+            }
+
+            let start = 100 * SCREEN_WIDTH;
+            let screen = gb.ppu.get_mut().screen.packed();
+            screen[start..start + SCREEN_WIDTH].to_vec()
+        }
+
+        for fast in [false, true] {
+            // On DMG, the sprite with lower x is on top.
+            let colors = draw_line(Model::Dmg, fast);
+            assert_eq!(colors[6..8], [0; 2], "{fast}");
+            assert_eq!(colors[8..16], [1; 8], "{fast}");
+            assert_eq!(colors[16..20], [3; 4], "{fast}");
+            assert_eq!(colors[20..22], [0; 2], "{fast}");
+
+            // On CGB, the sprite with lower OAM index is on top.
+            let colors = draw_line(Model::Cgb, fast);
+            assert_eq!(colors[6..8], [0; 2], "{fast}");
+            assert_eq!(colors[8..12], [1; 4], "{fast}");
+            assert_eq!(colors[12..20], [3; 8], "{fast}");
+            assert_eq!(colors[20..22], [0; 2], "{fast}");
+        }
+    }
+
+    #[test]
+    fn sprite_transparency() {
+        fn render(fifo: bool) -> [u8; SCREEN_WIDTH * SCREEN_HEIGHT] {
+            let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+            {
+                let ppu = gb.ppu.get_mut();
+                // tile 1 has the colors 0, 1, 2, 3, 0, 1, 2, 3 in every row
+                for y in 0..8 {
+                    ppu.vram[0x10 + 2 * y..][..2].copy_from_slice(&[0b0101_0101, 0b0011_0011]);
+                }
+                // a sprite with OBP0 at x = 16, and one with OBP1 at x = 40, in lines 8 to 15
+                ppu.oam[0..8].copy_from_slice(&[24, 24, 0x01, 0x00, 24, 48, 0x01, 0x10]);
+            }
+            gb.write_registers(&[
+                // the color 0 of the background is shade 1
+                (BGP, 0b11_10_01_01),
+                // the color 0 of both sprite palettes is a visible shade
+                (OBP0, 0b11_10_01_11),
+                (OBP1, 0b01_00_11_10),
+                (LCDC, 0x93),
+            ]);
+            render_frames(&mut gb, fifo)
+        }
+
+        for fifo in [false, true] {
+            let frame = render(fifo);
+            let line = &frame[10 * SCREEN_WIDTH..][..SCREEN_WIDTH];
+            assert_eq!(line[16..24], [1, 1, 2, 3, 1, 1, 2, 3], "fifo: {}", fifo);
+            assert_eq!(line[40..48], [1, 3, 0, 1, 1, 3, 0, 1], "fifo: {}", fifo);
+            assert!(line[..16].iter().all(|&x| x == 1));
+            assert!(frame[..8 * SCREEN_WIDTH].iter().all(|&x| x == 1));
+        }
+    }
+
+    #[test]
+    fn sprite_off_screen_left() {
+        // Return the line 10, with a sprite at `sx` in lines 8 to 15
+        fn render(fifo: bool, sx: u8, flags: u8, scx: u8) -> Vec<u8> {
+            let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+            {
+                let ppu = gb.ppu.get_mut();
+                // tile 1 has the colors 1, 2, 3, 1, 2, 3, 1, 2 in every row
+                for y in 0..8 {
+                    ppu.vram[0x10 + 2 * y..][..2].copy_from_slice(&[0b1011_0110, 0b0110_1101]);
+                }
+                ppu.oam[0..4].copy_from_slice(&[24, sx, 0x01, flags]);
+            }
+            gb.write_registers(&[(BGP, 0x00), (OBP0, 0b11_10_01_00), (SCX, scx), (LCDC, 0x93)]);
+            render_frames(&mut gb, fifo)[10 * SCREEN_WIDTH..][..SCREEN_WIDTH].to_vec()
+        }
+
+        let tile = [1, 2, 3, 1, 2, 3, 1, 2];
+        for fifo in [false, true] {
+            for scx in [0, 5] {
+                // at x = 3, only the 3 rightmost pixels are on screen
+                let line = render(fifo, 3, 0x00, scx);
+                assert_eq!(
+                    line[..8],
+                    [3, 1, 2, 0, 0, 0, 0, 0],
+                    "fifo {}, scx {}",
+                    fifo,
+                    scx
+                );
+                assert!(line[8..].iter().all(|&x| x == 0));
+
+                // flipped, the leftmost pixels of the tile are the visible ones
+                let line = render(fifo, 3, 0x20, scx);
+                assert_eq!(
+                    line[..8],
+                    [3, 2, 1, 0, 0, 0, 0, 0],
+                    "fifo {}, scx {}",
+                    fifo,
+                    scx
+                );
+
+                for sx in 0..8 {
+                    let line = render(fifo, sx, 0x00, scx);
+                    let visible = sx as usize;
+                    assert_eq!(
+                        line[..visible],
+                        tile[8 - visible..],
+                        "fifo {}, sx {}",
+                        fifo,
+                        sx
+                    );
+                    assert!(
+                        line[visible..].iter().all(|&x| x == 0),
+                        "fifo {}, sx {}",
+                        fifo,
+                        sx
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn screen_holds_shades() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        // Render a random scene, with the same palette for the background and the sprites.
+        fn render(fifo: bool, palette: u8) -> [u8; SCREEN_WIDTH * SCREEN_HEIGHT] {
+            let mut rng = StdRng::seed_from_u64(7);
+            let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+            {
+                let ppu = gb.ppu.get_mut();
+                rng.fill(&mut ppu.vram[..]);
+                rng.fill(&mut ppu.oam[..]);
+            }
+            gb.write_registers(&[
+                (SCX, 5),
+                (SCY, 30),
+                (WY, 60),
+                (WX, 90),
+                (BGP, palette),
+                (OBP0, palette),
+                (OBP1, palette),
+                (LCDC, 0xf3),
+            ]);
+            render_frames(&mut gb, fifo)
+        }
+
+        let identity = render(false, 0b11_10_01_00);
+        assert!((0..4).all(|c| identity.contains(&c)));
+        assert_eq!(render(true, 0b11_10_01_00), identity);
+
+        // the shades, with palette 0b01_00_11_10, of each color
+        let shades = [2, 3, 0, 1];
+        let expected = identity.map(|x| shades[x as usize]);
+        assert_eq!(render(false, 0b01_00_11_10), expected);
+        assert_eq!(render(true, 0b01_00_11_10), expected);
+    }
+
+    #[test]
+    fn layer_palettes() {
+        use crate::scale::expand_colors_layered;
+
+        // The background filled with color 1, and two sprites with color 1, one with each palette.
+        fn render(fifo: bool) -> [u8; SCREEN_WIDTH * SCREEN_HEIGHT] {
+            let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+            {
+                let ppu = gb.ppu.get_mut();
+                ppu.vram.fill(0);
+                for i in 0..16 {
+                    ppu.vram[2 * i] = 0xFF;
+                }
+                ppu.oam.fill(0);
+                ppu.oam[0..8].copy_from_slice(&[16 + 8, 8 + 8, 1, 0x00, 16 + 8, 8 + 24, 1, 0x10]);
+            }
+            gb.write_registers(&[
+                (BGP, 0b11_10_01_00),
+                (OBP0, 0b11_10_01_00),
+                (OBP1, 0b11_10_01_00),
+                (LCDC, 0x93),
+            ]);
+            assert!(render_frames(&mut gb, fifo).iter().all(|&x| x == 1));
+            gb.ppu.get_mut().screen.packed_with_layers()
+        }
+
+        let frame = render(false);
+        assert_eq!(render(true), frame);
+        let at = |x: usize, y: usize| frame[y * SCREEN_WIDTH + x];
+        assert_eq!(at(0, 0), 1 | (Layer::Background as u8) << 2);
+        assert_eq!(at(8, 8), 1 | (Layer::Obp0 as u8) << 2);
+        assert_eq!(at(24, 8), 1 | (Layer::Obp1 as u8) << 2);
+
+        let gray = |x: u8| [x, x, x, 255];
+        let palettes = [
+            [gray(255), gray(170), gray(85), gray(0)],
+            [gray(255), [255, 0, 0, 255], gray(85), gray(0)],
+            [gray(255), [0, 255, 0, 255], gray(85), gray(0)],
+        ];
+        let colors = expand_colors_layered(&frame, &palettes);
+        let at = |x: usize, y: usize| colors[y * SCREEN_WIDTH + x];
+        assert_eq!(at(0, 0), gray(170));
+        assert_eq!(at(15, 15), [255, 0, 0, 255]);
+        assert_eq!(at(24, 8), [0, 255, 0, 255]);
+        assert_eq!(at(32, 8), gray(170));
+    }
+
+    #[test]
+    fn lcd_off_reads() {
+        // disable the LCD in each of the modes, and in the middle of VBlank
+        for (ly, mode) in [(50, 2), (50, 3), (50, 0), (144, 1), (150, 1)] {
+            let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+            gb.write_registers(&[(LYC, 0), (STAT, 0x78)]);
+            let mut dot = gb.step_ppu_dot();
+            while !(dot.ly == ly && dot.mode == mode) {
+                dot = gb.step_ppu_dot();
+            }
+            gb.tick(4);
+            gb.write(LCDC, 0x11);
+
+            for _ in 0..2 * SCANLINE_CYCLES / 4 {
+                gb.tick(4);
+                assert_eq!(gb.read(LY), 0, "ly {} mode {}", ly, mode);
+                let stat = gb.read(STAT);
+                assert_eq!(stat & 0b11, 0, "ly {} mode {}", ly, mode);
+                // the unused bit 7 reads as 1, and the interrupt selection is kept
+                assert_eq!(stat & 0xF8, 0xF8, "ly {} mode {}", ly, mode);
+            }
+
+            // writing to STAT does not change the mode
+            gb.tick(4);
+            gb.write(STAT, 0x0F);
+            assert_eq!(gb.read(STAT) & 0b11, 0);
+            assert_eq!(gb.read(STAT) & 0xF8, 0x88);
+        }
+    }
+
+    #[test]
+    fn window_at_wx_166() {
+        // Run 73 lines with the given WX, then 40 lines with WX = 7. Return the window line
+        // counter after each part, and the screen.
+        fn run(fifo: bool, wx: u8) -> (u8, u8, [u8; SCREEN_WIDTH * SCREEN_HEIGHT]) {
+            let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+            if fifo {
+                force_fifo(&mut gb);
+            }
+            {
+                let ppu = gb.ppu.get_mut();
+                // the background uses tile 0, of color 0, and the window uses tile 1, where the
+                // even rows have color 1, and the odd rows color 2.
+                ppu.vram[0x00..0x10].fill(0x00);
+                for y in 0..8 {
+                    ppu.vram[0x10 + 2 * y..][..2]
+                        .copy_from_slice(&[0xff * (y % 2 == 0) as u8, 0xff * (y % 2 == 1) as u8]);
+                }
+                ppu.vram[0x1800..0x1C00].fill(0x00);
+                ppu.vram[0x1C00..0x2000].fill(0x01);
+            }
+            gb.write_registers(&[(WY, 0), (WX, wx), (BGP, 0xe4), (LCDC, 0xf1)]);
+
+            let mut dot = gb.step_ppu_dot();
+            while !(dot.ly == 0 && dot.mode == 2) {
+                dot = gb.step_ppu_dot();
+            }
+            gb.tick(73 * SCANLINE_CYCLES);
+            gb.update_all();
+            let first = gb.ppu.borrow().wyc;
+
+            gb.tick(4);
+            gb.write(WX, 7);
+            gb.tick(40 * SCANLINE_CYCLES);
+            gb.update_all();
+            let second = gb.ppu.borrow().wyc;
+
+            let packed = gb.ppu.borrow().screen.packed();
+            (first, second, packed)
+        }
+
+        for fifo in [false, true] {
+            // With WX = 166 the window is never visible, but the line counter still advances in
+            // every line, so the window continues from line 73 when it becomes visible. With
+            // WX = 167, the window starts from line 0.
+            for (wx, first, second, row_offset) in [(166, 72, 112, 0), (167, 0xff, 39, 73)] {
+                let (wyc_first, wyc_second, frame) = run(fifo, wx);
+                assert_eq!(wyc_first, first, "fifo {}, wx {}", fifo, wx);
+                assert_eq!(wyc_second, second, "fifo {}, wx {}", fifo, wx);
+
+                // no window pixels
+                assert!(frame[..73 * SCREEN_WIDTH].iter().all(|&x| x == 0));
+                for ly in 80..110 {
+                    let window_y = ly - row_offset;
+                    let color = if window_y % 2 == 0 { 1 } else { 2 };
+                    let line = &frame[ly * SCREEN_WIDTH..][..SCREEN_WIDTH];
+                    assert!(
+                        line.iter().all(|&x| x == color),
+                        "fifo {}, wx {}, ly {}",
+                        fifo,
+                        wx,
+                        ly
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sprites_in_rect() {
+        let mut ppu = Ppu::default();
+        // (y, x) of the top-left corner of each sprite, in screen coordinates
+        for (i, (y, x)) in [(10, 10), (20, 30), (12, 40), (0, 20), (12, -8)]
+            .into_iter()
+            .enumerate()
+        {
+            ppu.oam[4 * i..4 * i + 4].copy_from_slice(&[(y + 16) as u8, (x + 8) as u8, 0, 0]);
+        }
+
+        ppu.lcdc = 0x93;
+        assert_eq!(ppu.sprites_in_rect(12, 12, 20, 10), [0, 1]);
+        assert_eq!(ppu.sprites_in_rect(0, 0, 160, 144), [0, 1, 2, 3]);
+        assert_eq!(ppu.sprites_in_rect(17, 17, 1, 1), [0]);
+        assert!(ppu.sprites_in_rect(12, 18, 4, 2).is_empty());
+        // the sprite at x -8 is outside the screen
+        assert!(ppu.sprites_in_rect(0, 12, 8, 4).is_empty());
+
+        // 8x16 sprites
+        ppu.lcdc = 0x97;
+        assert_eq!(ppu.sprites_in_rect(12, 12, 20, 10), [0, 1, 3]);
+        assert_eq!(ppu.sprites_in_rect(12, 18, 4, 2), [0]);
+    }
+
+    #[test]
+    fn vram_read_write() {
+        let mut ppu = Ppu::default();
+        ppu.vram_write(0x8000, 0, 0x12);
+        ppu.vram_write(0x9FFF, 0, 0x34);
+        assert_eq!(ppu.vram[0], 0x12);
+        assert_eq!(ppu.vram_read(0x8000, 0), Some(0x12));
+        assert_eq!(ppu.vram_read(0x9FFF, 0), Some(0x34));
+
+        // out of range
+        assert_eq!(ppu.vram_read(0x7FFF, 0), None);
+        assert_eq!(ppu.vram_read(0xA000, 0), None);
+        assert_eq!(ppu.vram_read(0x0000, 0), None);
+        ppu.vram_write(0xA000, 0, 0x56);
+        ppu.vram_write(0x7FFF, 0, 0x56);
+
+        // the banks are independent, and there is no bank 2
+        assert_eq!(ppu.vram_read(0x8000, 1), Some(0x00));
+        ppu.vram_write(0x8000, 1, 0x56);
+        assert_eq!(ppu.vram_read(0x8000, 1), Some(0x56));
+        assert_eq!(ppu.vram_read(0x8000, 0), Some(0x12));
+        assert_eq!(ppu.vram_read(0x8000, 2), None);
+        ppu.vram_write(0x8000, 2, 0x78);
+        assert_eq!(ppu, {
+            let mut other = Ppu::default();
+            other.vram[0] = 0x12;
+            other.vram[0x1FFF] = 0x34;
+            other.cgb_vram[0] = 0x56;
+            other
+        });
+    }
+
+    #[test]
+    fn bg_tile_at_screen() {
+        let mut ppu = Ppu::default();
+        for (i, x) in ppu.vram[0x1800..0x2000].iter_mut().enumerate() {
+            *x = (i * 7) as u8;
+        }
+        ppu.lcdc = 0x91;
+        ppu.scx = 13;
+        ppu.scy = 250;
+
+        // (13 + 20, 250 + 10) is the pixel (33, 4) of the background, in the tile (4, 0)
+        let tile = ppu.bg_tile_at_screen(20, 10);
+        assert!(!tile.window);
+        assert_eq!(tile.map_address, 0x9804);
+        assert_eq!(tile.tile_index, ppu.vram[0x1804]);
+        assert_eq!(tile.tile_index, 28);
+        assert_eq!(tile.tile_address, 0x8000 + 28 * 0x10);
+
+        // 8800 addressing, and the 9C00 tile map
+        ppu.lcdc = 0x89;
+        let tile = ppu.bg_tile_at_screen(159, 143);
+        // (13 + 159, (250 + 143) % 256) is in the tile (21, 17)
+        let map_address = 0x9C00 + 17 * 32 + 21;
+        assert_eq!(tile.map_address, map_address);
+        let index = ppu.vram[map_address as usize - 0x8000];
+        assert_eq!(tile.tile_index, index);
+        assert!(index < 0x80);
+        assert_eq!(tile.tile_address, 0x9000 + index as u16 * 0x10);
+
+        // the window, using the 9800 tile map
+        ppu.lcdc = 0xb1;
+        ppu.wx = 87;
+        ppu.wy = 40;
+        assert!(!ppu.bg_tile_at_screen(79, 100).window);
+        assert!(!ppu.bg_tile_at_screen(100, 39).window);
+        let tile = ppu.bg_tile_at_screen(100, 60);
+        assert!(tile.window);
+        // (100 + 7 - 87, 60 - 40) is in the tile (2, 2)
+        assert_eq!(tile.map_address, 0x9800 + 2 * 32 + 2);
+        assert_eq!(tile.tile_index, ppu.vram[0x1800 + 2 * 32 + 2]);
+
+        // the window is not visible with the background disabled
+        ppu.lcdc = 0xb0;
+        assert!(!ppu.bg_tile_at_screen(100, 60).window);
+    }
+
+    /// A Ppu where tile 1 is all color 3, and tile 2 has the colors 0 to 3 in its first row. The
+    /// background map has tile 1 at (2, 1) and tile 2 at (0, 0), and the window map has tile 1 at
+    /// (0, 0).
+    fn map_ppu() -> Ppu {
+        let mut ppu = Ppu::default();
+        ppu.vram[0x10..0x20].fill(0xff);
+        ppu.vram[0x20..0x22].copy_from_slice(&[0b0101_0101, 0b0011_0011]);
+        ppu.vram[0x1800 + 32 + 2] = 1;
+        ppu.vram[0x1800] = 2;
+        ppu.vram[0x1c00] = 1;
+        // window map at 9C00, 8000 addressing
+        ppu.lcdc = 0xd1;
+        ppu.bgp = 0b00_01_10_11;
+        ppu
+    }
+
+    #[test]
+    fn render_map() {
+        let ppu = map_ppu();
+
+        let map = super::render_map(&ppu, MapLayer::Background, false);
+        assert_eq!(map.len(), 256 * 256);
+        assert_eq!(map[..8], [0, 1, 2, 3, 0, 1, 2, 3]);
+        assert_eq!(map[256 + 8], 0);
+        for y in 8..16 {
+            assert_eq!(map[y * 256 + 15..][..10], [0, 3, 3, 3, 3, 3, 3, 3, 3, 0]);
+        }
+
+        // with the palette applied
+        let map = super::render_map(&ppu, MapLayer::Background, true);
+        assert_eq!(map[..8], [3, 2, 1, 0, 3, 2, 1, 0]);
+        assert_eq!(map[8 * 256 + 16], 0);
+
+        let map = super::render_map(&ppu, MapLayer::Window, false);
+        assert_eq!(map[..9], [3, 3, 3, 3, 3, 3, 3, 3, 0]);
+        assert_eq!(map[8 * 256 + 16], 0);
+    }
+
+    #[cfg(feature = "png")]
+    #[test]
+    fn map_png() {
+        let ppu = map_ppu();
+        let png = super::map_png(&ppu, MapLayer::Background, false).unwrap();
+
+        let image = image::load_from_memory(&png).unwrap().to_luma8();
+        assert_eq!(image.dimensions(), (256, 256));
+        assert_eq!(image.get_pixel(0, 0).0, [255]);
+        assert_eq!(image.get_pixel(3, 0).0, [0]);
+        assert_eq!(image.get_pixel(1, 0).0, [170]);
+        assert_eq!(image.get_pixel(20, 12).0, [0]);
+        assert_eq!(image.get_pixel(20, 20).0, [255]);
+    }
+
+    #[test]
+    fn sprite_fetch_abort() {
+        // Draw a sprite at line 100, and disable the sprites for a few cycles, `abort_at` cycles
+        // after its fetch starts. Return the length of mode 3 and the colors drawn on the line.
+        fn draw_line(model: Model, abort_at: Option<u64>) -> (u64, Vec<u8>) {
+            let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+            gb.model = model;
+            {
+                let ppu = gb.ppu.get_mut();
+                // tile 1 is filled with color 3, and the background uses tile 0, of color 0.
+                ppu.vram[0x10..0x20].fill(0xff);
+                ppu.vram[0x1800..0x1C00].fill(0x00);
+                // a sprite at screen x 80..88
+                ppu.oam[0..4].copy_from_slice(&[100 + 16, 88, 1, 0x00]);
+            }
+            gb.write_registers(&[(BGP, 0xe4), (OBP0, 0xe4), (LCDC, 0x93)]);
+
+            let mut dot = gb.step_ppu_dot();
+            while !(dot.ly == 100 && dot.mode == 3) {
+                dot = gb.step_ppu_dot();
+            }
+            let mode_3_start = gb.clock_count;
+            let mut fetch_start = None;
+            while dot.mode == 3 {
+                if fetch_start.is_none() && gb.ppu.borrow().sprite_fetching {
+                    fetch_start = Some(gb.clock_count);
+                }
+                match (fetch_start, abort_at) {
+                    (Some(start), Some(at)) if gb.clock_count == start + at => {
+                        gb.tick(2);
+                        gb.write(LCDC, 0x91);
+                        gb.tick(2);
+                        gb.write(LCDC, 0x93);
+                    }
+                    _ => {}
+                }
+                dot = gb.step_ppu_dot();
+            }
+            let mode_3_length = gb.clock_count - 1 - mode_3_start;
+
+            let start = 100 * SCREEN_WIDTH;
+            let screen = gb.ppu.get_mut().screen.packed();
+            (mode_3_length, screen[start..start + SCREEN_WIDTH].to_vec())
+        }
+
+        let no_sprite = [0; 16];
+        let mut sprite = [0; 16];
+        sprite[4..12].fill(3);
+
+        for model in [Model::Dmg, Model::Cgb] {
+            // the sprite fetch takes 10 cycles
+            let (length, colors) = draw_line(model, None);
+            assert_eq!(length, 182, "{:?}", model);
+            assert_eq!(colors[76..92], sprite, "{:?}", model);
+        }
+
+        // On DMG, the fetch is aborted, and the sprite is not drawn, even if the sprites are
+        // enabled again.
+        for (at, expected_length) in [(0, 173), (2, 175), (4, 177), (7, 181)] {
+            let (length, colors) = draw_line(Model::Dmg, Some(at));
+            assert_eq!(length, expected_length, "abort at {}", at);
+            assert_eq!(colors[76..92], no_sprite, "abort at {}", at);
+        }
+        // after the fetch is completed, there is nothing to abort.
+        let (length, colors) = draw_line(Model::Dmg, Some(9));
+        assert_eq!(length, 182);
+        assert_eq!(colors[76..92], sprite);
+
+        // On CGB, the fetch is never aborted.
+        for at in 0..10 {
+            let (length, colors) = draw_line(Model::Cgb, Some(at));
+            assert_eq!(length, 182, "abort at {}", at);
+            assert_eq!(colors[76..92], sprite, "abort at {}", at);
+        }
+    }
+
+    #[test]
+    fn sprite_scroll_penalty() {
+        // Return the length of mode 3 at line 100, with the given SCX and optionally a sprite at
+        // the given OAM x position.
+        fn mode_3_length(scx: u8, sprite_x: Option<u8>) -> u64 {
+            let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+            if let Some(x) = sprite_x {
+                gb.ppu.get_mut().oam[0..4].copy_from_slice(&[100 + 16, x, 1, 0x00]);
+            }
+            gb.write_registers(&[(SCX, scx), (LCDC, 0x93)]);
+
+            let mut dot = gb.step_ppu_dot();
+            while !(dot.ly == 100 && dot.mode == 3) {
+                dot = gb.step_ppu_dot();
+            }
+            let mode_3_start = gb.clock_count;
+            while dot.mode == 3 {
+                dot = gb.step_ppu_dot();
+            }
+            gb.clock_count - mode_3_start
+        }
+
+        // The reference values from Pan Docs: mode 3 takes 172 + SCX % 8 dots, and each sprite
+        // adds 6 dots, plus the number of pixels of its background tile to the right of the
+        // sprite's leftmost pixel, minus 2 (if positive). A sprite at OAM x 0 always adds 11 dots.
+        for scx in 0..8u64 {
+            let base = 172 + scx;
+            let penalty = |x: u64| {
+                // pixels of the tile to the right of the sprite's leftmost pixel
+                let right = 7 - (x + scx) % 8;
+                6 + right.saturating_sub(2)
+            };
+            assert_eq!(mode_3_length(scx as u8, None), base, "scx {}", scx);
+            assert_eq!(mode_3_length(scx as u8, Some(0)), base + 11, "scx {}", scx);
+            for x in [1, 4, 8, 13] {
+                assert_eq!(
+                    mode_3_length(scx as u8, Some(x as u8)),
+                    base + penalty(x),
+                    "scx {}, x {}",
+                    scx,
+                    x
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn oam_mode_2_blocking() {
+        // Return, for each dot of the first 90 dots of the line, if a read of OAM returns 0xFF and
+        // if a write is ignored. A access at a given dot sees the changes made in the previous dots.
+        fn probe(line: u8) -> Vec<(bool, bool)> {
+            let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+            // use the pixel FIFO, so each line goes through all the mode 2 states
+            force_fifo(&mut gb);
+            gb.tick(FRAME_CYCLES);
+            gb.update_all();
+
+            while gb.step_ppu_dot().ly != line {}
+            if line == 0 {
+                // LY already reads 0 in the end of line 153
+                let last_start = gb.ppu.borrow().line_start_clock_count;
+                while gb.ppu.borrow().line_start_clock_count == last_start {
+                    gb.step_ppu_dot();
+                }
+            }
+            let ppu = gb.ppu.borrow();
+            let start = ppu.line_start_clock_count;
+            assert_eq!(ppu.ly, line);
+            assert_eq!(gb.clock_count, start + 1);
+            drop(ppu);
+
+            (1..90)
+                .map(|dot| {
+                    gb.clock_count = start + dot;
+                    let read_blocked = Ppu::read_oam(&gb, 0xFE00) == 0xFF;
+                    Ppu::write_oam(&mut gb, 0xFE00, dot as u8);
+                    let write_blocked = gb.ppu.borrow().oam[0] != dot as u8;
+                    (read_blocked, write_blocked)
+                })
+                .collect()
+        }
+
+        // The reads are blocked from dot 3 and the writes from dot 4, until mode 3. Between the
+        // end of the OAM scan at dot 80 and the start of mode 3 at dot 84, the writes are allowed
+        // again. Line 0 behaves like the other lines, only its STAT interrupt is late.
+        let expected: Vec<_> = (1..90)
+            .map(|dot| match dot {
+                1..=3 => (false, false),
+                4 => (true, false),
+                5..=80 => (true, true),
+                81..=84 => (true, false),
+                _ => (true, true),
+            })
+            .collect();
+        for line in [0, 1, 100, 143] {
+            assert_eq!(probe(line), expected, "line {}", line);
+        }
+    }
+
+    #[test]
+    fn search_objects_off_screen_left() {
+        // Return the sprite buffer at the start of mode 3 of line 100, and the length of mode 3.
+        fn search(sprite_xs: &[u8]) -> (Vec<u8>, u64) {
+            let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+            let oam = &mut gb.ppu.get_mut().oam;
+            for (i, &x) in sprite_xs.iter().enumerate() {
+                oam[4 * i..4 * i + 4].copy_from_slice(&[100 + 16, x, 1, 0x00]);
+            }
+            // a sprite in another line
+            oam[4 * 39..4 * 40].copy_from_slice(&[50 + 16, 0, 1, 0x00]);
+            gb.tick(4);
+            gb.write(LCDC, 0x93);
+
+            let mut dot = gb.step_ppu_dot();
+            while !(dot.ly == 100 && dot.mode == 3) {
+                dot = gb.step_ppu_dot();
+            }
+            let ppu = gb.ppu.borrow();
+            let buffer = ppu.sprite_buffer[..ppu.sprite_buffer_len as usize]
+                .iter()
+                .map(|x| x.sx)
+                .collect();
+            drop(ppu);
+
+            let mode_3_start = gb.clock_count;
+            while dot.mode == 3 {
+                dot = gb.step_ppu_dot();
+            }
+            (buffer, gb.clock_count - mode_3_start)
+        }
+
+        let (buffer, base) = search(&[]);
+        assert!(buffer.is_empty());
+
+        // sprites are searched regardless of their x position, and are sorted by decreasing x,
+        // so the next one to be fetched is the last
+        let (buffer, length) = search(&[4, 0, 200, 7]);
+        assert_eq!(buffer, [200, 7, 4, 0]);
+        assert!(length > base, "{} {}", length, base);
+
+        // the ones fully off screen to the left still cost their penalty
+        for x in [0, 4, 7] {
+            let (buffer, length) = search(&[x]);
+            assert_eq!(buffer, [x]);
+            assert!(length > base, "x {}: {} {}", x, length, base);
+        }
+    }
+
+    #[test]
+    fn render_threads() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        // Run 5 frames of a random scene, with random writes to the registers and VRAM, and return
+        // the frames, and the final state.
+        fn run(threads: usize) -> (Vec<(u64, [u8; SCREEN_WIDTH * SCREEN_HEIGHT])>, Ppu) {
+            let mut rng = StdRng::seed_from_u64(42);
+            let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+            gb.hash_frames = true;
+            gb.ppu.get_mut().set_render_threads(threads);
+            {
+                let ppu = gb.ppu.get_mut();
+                rng.fill(&mut ppu.vram[..]);
+                rng.fill(&mut ppu.oam[..]);
+            }
+            gb.write_registers(&[
+                (LCDC, 0xf3),
+                (SCX, 3),
+                (SCY, 70),
+                (WY, 40),
+                (WX, 50),
+                (BGP, 0xe4),
+                (OBP0, 0xd2),
+                (OBP1, 0x1b),
+            ]);
+
+            let mut frames = Vec::new();
+            for _ in 0..5 {
+                gb.v_blank_trigger.set(false);
+                while !gb.v_blank_trigger.get() {
+                    // run many lines at once, so they are drawn by the fast path
+                    gb.tick(rng.gen_range(4..456 * 20));
+                    match rng.gen_range(0..4) {
+                        0 => gb.write(SCX, rng.gen()),
+                        1 => gb.write(LCDC, 0xf3 ^ (rng.gen::<u8>() & 0x5e)),
+                        2 => gb.write(0x8000 + rng.gen_range(0..0x1800), rng.gen()),
+                        _ => gb.write(0x9800 + rng.gen_range(0..0x800), rng.gen()),
+                    }
+                    gb.update_all();
+                }
+                // the lazy update may have already started the next frame
+                let ppu = gb.ppu.get_mut();
+                ppu.finish_rendering();
+                frames.push((ppu.frame_hash, ppu.screen.packed()));
+            }
+            // in the middle of a frame, reading the screen waits for the queued lines
+            gb.tick(456 * 70);
+            gb.update_all();
+            let ppu = gb.ppu.get_mut();
+            assert!(ppu.ly > 0 && ppu.ly < 144);
+            frames.push((0, ppu.screen().packed()));
+            (frames, ppu.clone())
+        }
+
+        let (frames, ppu) = run(0);
+        // the frames are not blank
+        assert!(frames.iter().all(|(_, x)| x.iter().any(|&c| c != x[0])));
+        for threads in [1, 3] {
+            let (threaded_frames, threaded_ppu) = run(threads);
+            assert!(frames == threaded_frames, "threads = {}", threads);
+            assert!(ppu == threaded_ppu, "threads = {}", threads);
+            assert_eq!(threaded_ppu.render_threads(), threads);
+        }
+    }
+
+    /// Run 3 frames ticking one cycle at time, with the LY=LYC STAT interrupt enabled, and return
+    /// the line and the clock, relative to the start of the line, of every STAT interrupt.
+    fn lyc_interrupts(lyc: u8) -> Vec<(u8, u64)> {
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        gb.predict_interrupt = false;
+        gb.write_registers(&[(LYC, lyc), (STAT, 0x40), (LCDC, 0x91)]);
+        gb.interrupt_flag.set(0);
+
+        let mut interrupts = Vec::new();
+        let (mut line, mut line_start) = (gb.read(LY), gb.clock_count);
+        for _ in 0..3 * 70224 {
+            gb.tick(1);
+            let ly = gb.read(LY);
+            // LY changes from 153 to 0 in the middle of the line 153.
+            if ly != line && !(line == 153 && ly == 0) {
+                (line, line_start) = (ly, gb.clock_count);
+            }
+            if gb.interrupt_flag.get() & 0b10 != 0 {
+                gb.interrupt_flag.set(0);
+                interrupts.push((line, gb.clock_count - line_start));
+            }
+        }
+        interrupts
+    }
+
+    #[test]
+    fn lyc_interrupt_timing() {
+        // LY=LYC is checked 4 cycles after the start of the line.
+        for lyc in [1u8, 100, 143, 144, 152] {
+            assert_eq!(lyc_interrupts(lyc), [(lyc, 4); 3], "lyc = {}", lyc);
+        }
+        // In line 153, LY only reads 153 for the first 6 cycles, but the comparison with LYC=153
+        // happens in the 6 cycles after that. Then LY=LYC=0 starts 12 cycles in line 153.
+        assert_eq!(lyc_interrupts(153), [(153, 6); 3]);
+        assert_eq!(lyc_interrupts(0), [(153, 12); 3]);
+    }
+
+    #[test]
+    fn next_frame_clock() {
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        gb.write_registers(&[(LCDC, 0x91)]);
+
+        // the clock_count and prediction of each cycle since the last frame start
+        let mut predictions = Vec::new();
+        let (mut ly, mut mode) = (gb.read(LY), gb.read(STAT) & 3);
+        let mut ly_wrap = None;
+        let mut frames = 0;
+        for _ in 0..3 * 70224 {
+            predictions.push((gb.clock_count, gb.next_frame_clock().unwrap()));
+            gb.tick(1);
+            let (new_ly, new_mode) = (gb.read(LY), gb.read(STAT) & 3);
+            if ly == 153 && new_ly == 0 {
+                ly_wrap = Some(gb.clock_count);
+            }
+            // the frame starts with the mode 2 of the line 0.
+            if new_ly == 0 && mode != 2 && new_mode == 2 {
+                let start = gb.clock_count;
+                // the first frame start may be missed, if the LCD was already in the line 153.
+                if let Some(ly_wrap) = ly_wrap.take() {
+                    // STAT only reads mode 2 a few cycles after the line starts.
+                    for &(clock, prediction) in &predictions {
+                        if clock + 8 < start {
+                            assert!(
+                                prediction.abs_diff(start) <= 8,
+                                "{} != {}",
+                                prediction,
+                                start
+                            );
+                        }
+                    }
+                    // ly wraps to 0 in the start of the line 153
+                    assert!(start - ly_wrap <= SCANLINE_CYCLES, "{} {}", ly_wrap, start);
+                    frames += 1;
+                }
+                predictions.clear();
+            }
+            (ly, mode) = (new_ly, new_mode);
+        }
+        assert!(frames >= 2);
+
+        gb.write_registers(&[(LCDC, 0x11)]);
+        assert_eq!(gb.next_frame_clock(), None);
+    }
+
+    #[test]
+    fn ly_in_line_153() {
+        let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+        gb.write_registers(&[(LCDC, 0x91)]);
+
+        // wait for the start of line 152
+        while gb.read(LY) == 152 {
+            gb.tick(1);
+        }
+        while gb.read(LY) != 152 {
+            gb.tick(1);
+        }
+        let line_152 = gb.clock_count;
+
+        // poll LY every cycle, for the lines 152, 153 and 0
+        let mut lys = Vec::new();
+        while gb.clock_count < line_152 + 3 * SCANLINE_CYCLES {
+            lys.push(gb.read(LY));
+            gb.tick(1);
+        }
+        let (line_152, rest) = lys.split_at(SCANLINE_CYCLES as usize);
+        let (line_153, line_0) = rest.split_at(SCANLINE_CYCLES as usize);
+        assert!(line_152.iter().all(|&ly| ly == 152));
+        // LY reads 153 for the first 6 cycles of the line 153, and then 0.
+        assert_eq!(line_153[..6], [153; 6]);
+        assert!(line_153[6..].iter().all(|&ly| ly == 0), "{:?}", line_153);
+        assert!(line_0.iter().all(|&ly| ly == 0));
+        assert_eq!(gb.read(LY), 1);
+    }
+
+    #[test]
+    fn ly_is_read_only() {
+        let new = || {
+            let mut gb = GameBoy::new(None, Cartridge::halt_filled());
+            gb.write_registers(&[(LCDC, 0x91)]);
+            gb.tick(50 * SCANLINE_CYCLES + 100);
+            gb
+        };
+        let (mut a, mut b) = (new(), new());
+
+        // writing to LY mid-frame is ignored
+        let ly = b.read(LY);
+        let line_start = b.ppu.borrow().line_start_clock_count;
+        for value in [0x00, 0x99, ly + 1] {
+            b.write(LY, value);
+        }
+        assert_eq!(b.read(LY), ly);
+        assert_eq!(b.ppu.borrow().line_start_clock_count, line_start);
+
+        // and the timing of the following frames is unaffected
+        for _ in 0..2 * FRAME_CYCLES / 100 {
+            a.tick(100);
+            b.tick(100);
+            assert_eq!((a.read(LY), a.read(STAT)), (b.read(LY), b.read(STAT)));
+        }
+        assert_eq!(a.diff(&b), []);
+    }
+
+    #[test]
+    fn lyc_interrupt_prediction() {
+        // Wait for the LY=LYC STAT interrupt with HALT, and return the clock_count after each wake
+        // up, relative to the LCD being turned on.
+        fn halt_wake_ups(lyc: u8, predict_interrupt: bool) -> Vec<u64> {
+            #[rustfmt::skip]
+            let code = [
+                0x3e, lyc, 0xe0, 0x45, // LD A, lyc; LDH (LYC), A
+                0x3e, 0x40, 0xe0, 0x41, // LD A, $40; LDH (STAT), A
+                0x3e, 0x02, 0xe0, 0xff, // LD A, $02; LDH (IE), A
+                0x3e, 0x91, 0xe0, 0x40, // LD A, $91; LDH (LCDC), A
+                // 0x160:
+                0xaf, 0xe0, 0x0f, // XOR A; LDH (IF), A
+                0x76, // HALT
+                0x00, // NOP
+                0x18, 0xf9, // JR $0160
+            ];
+            let mut gb = GameBoy::new(None, Cartridge::with_entry_code(&code));
+            gb.predict_interrupt = predict_interrupt;
+            while gb.cpu.pc != 0x160 {
+                Interpreter(&mut gb).interpret_op();
+            }
+            let origin = gb.clock_count;
+
+            let mut wake_ups = Vec::new();
+            while wake_ups.len() < 3 {
+                let halted = gb.cpu.state == CpuState::Halt;
+                Interpreter(&mut gb).interpret_op();
+                if halted && gb.cpu.state == CpuState::Running {
+                    wake_ups.push(gb.clock_count - origin);
+                }
+            }
+            wake_ups
+        }
+
+        for lyc in [0, 1, 100, 144, 152, 153] {
+            let expected = halt_wake_ups(lyc, false);
+            // one interrupt per frame
+            assert_eq!(expected[1] - expected[0], 70224, "lyc = {}", lyc);
+            assert_eq!(halt_wake_ups(lyc, true), expected, "lyc = {}", lyc);
+        }
+    }
+
     #[test]
     fn fuzz() {
         let start_time = std::time::Instant::now();
@@ -2401,6 +4647,8 @@ mod test {
             is_window_being_fetched: false,
             insert_background_pixel: false,
             sprite_at_0_penalty: 0,
+            sprite_fetching: false,
+            abort_sprite_fetching: false,
             wx_just_changed: false,
             screen_x: 160,
             scanline_x: 160,
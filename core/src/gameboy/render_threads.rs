@@ -0,0 +1,194 @@
+//! Offload the rendering of the scanlines drawn by the PPU fast path (`draw_scan_line`) to worker
+//! threads, while the emulation continues.
+//!
+//! When a scanline is drawn by the fast path, everything that its rendering depends on is captured
+//! in a `ScanlineState`, together with a snapshot of the VRAM. The VRAM snapshot is shared between
+//! lines while it is not modified, so it is only copied when the game writes to it, and its
+//! allocation is reused once the lines that used it are done. The rendered lines are written back
+//! to the screen in the order they were queued, at the start of VBlank or when the screen is read,
+//! so the result is the same as rendering them synchronously.
+
+use std::{
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
+    thread::JoinHandle,
+};
+
+use super::ppu::{render_scan_line, ScanlineState, Screen};
+
+struct Job {
+    seq: u64,
+    state: ScanlineState,
+    vram: Arc<[u8; 0x2000]>,
+    /// The previous content of the scanline, that is overwritten by the rendering.
+    line: Box<[u8]>,
+}
+
+struct Workers {
+    senders: Vec<Sender<Job>>,
+    handles: Vec<JoinHandle<()>>,
+    results: Receiver<(u64, u8, Box<[u8]>)>,
+    /// The VRAM snapshot of the last queued line.
+    vram: Arc<[u8; 0x2000]>,
+    /// The buffers of the lines already written back to the screen, for reuse.
+    free_lines: Vec<Box<[u8]>>,
+    next_seq: u64,
+    pending: usize,
+}
+
+impl Drop for Workers {
+    fn drop(&mut self) {
+        // dropping the senders makes the workers exit.
+        self.senders.clear();
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Workers {
+    fn spawn(threads: usize) -> Self {
+        let (result_sender, results) = channel();
+        let mut senders = Vec::with_capacity(threads);
+        let mut handles = Vec::with_capacity(threads);
+        for i in 0..threads {
+            let (sender, jobs) = channel::<Job>();
+            let result_sender = result_sender.clone();
+            let handle = std::thread::Builder::new()
+                .name(format!("render-{}", i))
+                .spawn(move || {
+                    for mut job in jobs {
+                        render_scan_line(&mut job.line, &job.state, &job.vram);
+                        if result_sender
+                            .send((job.seq, job.state.ly, job.line))
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                })
+                .expect("failed to spawn render thread");
+            senders.push(sender);
+            handles.push(handle);
+        }
+
+        Self {
+            senders,
+            handles,
+            results,
+            vram: Arc::new([0; 0x2000]),
+            free_lines: Vec::new(),
+            next_seq: 0,
+            pending: 0,
+        }
+    }
+}
+
+/// A pool of threads that render scanlines. It is not part of the emulation state: it is not
+/// saved, and it is ignored when comparing. The threads are only spawned when the first line is
+/// queued, so a clone has the same number of threads, without spawning them until it is used.
+#[derive(Default)]
+pub struct RenderThreads {
+    threads: usize,
+    workers: Option<Workers>,
+}
+
+impl RenderThreads {
+    /// A pool of `threads` worker threads. With 0 threads, the lines are rendered synchronously.
+    pub fn new(threads: usize) -> Self {
+        Self {
+            threads,
+            workers: None,
+        }
+    }
+
+    /// The number of worker threads.
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// The number of queued lines not yet written back to the screen.
+    fn pending(&self) -> usize {
+        self.workers.as_ref().map_or(0, |x| x.pending)
+    }
+
+    /// Queue the rendering of a scanline. Return false if there are no worker threads, and the
+    /// line must be rendered synchronously.
+    pub(crate) fn queue(&mut self, state: ScanlineState, vram: &[u8; 0x2000], line: &[u8]) -> bool {
+        if self.threads == 0 {
+            return false;
+        }
+        let threads = self.threads;
+        let workers = self.workers.get_or_insert_with(|| Workers::spawn(threads));
+        if *workers.vram != *vram {
+            match Arc::get_mut(&mut workers.vram) {
+                Some(snapshot) => snapshot.copy_from_slice(vram),
+                None => workers.vram = Arc::new(*vram),
+            }
+        }
+        let mut buffer = workers.free_lines.pop().unwrap_or_default();
+        if buffer.len() == line.len() {
+            buffer.copy_from_slice(line);
+        } else {
+            buffer = line.into();
+        }
+        let seq = workers.next_seq;
+        let job = Job {
+            seq,
+            state,
+            vram: workers.vram.clone(),
+            line: buffer,
+        };
+        workers.senders[seq as usize % workers.senders.len()]
+            .send(job)
+            .expect("render thread exited");
+        workers.next_seq += 1;
+        workers.pending += 1;
+        true
+    }
+
+    /// Wait for all queued lines to be rendered, and write them to the screen.
+    pub(crate) fn finish(&mut self, screen: &mut Screen) {
+        let Some(workers) = &mut self.workers else {
+            return;
+        };
+        if workers.pending == 0 {
+            return;
+        }
+        let mut lines: Vec<_> = (0..workers.pending)
+            .map(|_| workers.results.recv().expect("render thread panicked"))
+            .collect();
+        workers.pending = 0;
+        // write them in the order they were queued
+        lines.sort_unstable_by_key(|x| x.0);
+        for (_, ly, line) in lines {
+            screen.line_mut(ly).copy_from_slice(&line);
+            workers.free_lines.push(line);
+        }
+    }
+}
+
+/// The queued lines are not cloned, so `Ppu::finish_rendering` must be called before cloning the
+/// `Ppu`, or the screen of the clone would miss them.
+impl Clone for RenderThreads {
+    fn clone(&self) -> Self {
+        debug_assert_eq!(self.pending(), 0, "cloned with lines still being rendered");
+        Self::new(self.threads)
+    }
+}
+
+/// The threads don't change the emulation, so any two pools are equal. Like for `Clone`, the
+/// queued lines must be written back to the screen before comparing.
+impl PartialEq for RenderThreads {
+    fn eq(&self, other: &Self) -> bool {
+        debug_assert_eq!(
+            self.pending() + other.pending(),
+            0,
+            "compared with lines still being rendered"
+        );
+        true
+    }
+}
+impl Eq for RenderThreads {}
@@ -112,7 +112,8 @@ impl Serial {
             0x01 => gb.serial.borrow().serial_data,
             0x02 => {
                 gb.update_serial();
-                gb.serial.borrow().serial_control
+                // bits 1-6 are unused
+                gb.serial.borrow().serial_control | 0x7E
             }
             _ => unreachable!(),
         }
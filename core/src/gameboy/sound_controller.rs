@@ -66,9 +66,10 @@ pub struct SoundController {
 
     /// The current step of the frame sequencer
     frame_sequencer_step: u8,
-    // From SameBoy source code: "When turning the APU on while DIV's bit 4 (or 5 in
-    // double speed mode) is on, the first DIV/APU event is skipped."
-    // frame_sequencer_skip: u8,
+    /// If the next step of the frame sequencer is skipped. From SameBoy source code: "When turning
+    /// the APU on while DIV's bit 4 (or 5 in double speed mode) is on, the first DIV/APU event is
+    /// skipped."
+    frame_sequencer_skip: bool,
     ch1_channel_enable: bool,
     ch1_length_timer: u8,
     ch1_sweep_enabled: bool,
@@ -107,8 +108,26 @@ pub struct SoundController {
     /// Clock count at the last sound update
     pub last_clock_count: u64,
     /// The frequency in Hertz at which the sound controller is sampled. Default value is 0, which
-    /// means that there will be no sampling.
+    /// means that there will be no sampling. Samples are collected at even clocks, so this must be
+    /// at most `CLOCK_SPEED / 2`.
     pub sample_frequency: u64,
+    /// If true, no samples are generated, as if `sample_frequency` was 0, but the channels are
+    /// still emulated. This is different from muting the audio, where silent samples are still
+    /// generated. Useful for benchmarking the emulation without the cost of audio sampling.
+    pub sampling_disabled: bool,
+    /// The total time spent in `update`, in nanoseconds.
+    #[cfg(feature = "timing_profile")]
+    pub(crate) update_time: u64,
+    /// If true, no samples are generated while the APU is off (NR52 bit 7 is 0), instead of silent
+    /// samples. By default the silent samples are generated, to keep the audio stream contiguous,
+    /// which some audio backends depend on.
+    pub skip_samples_while_off: bool,
+    /// If true, the left and right channels are downmixed to a single one, and `get_output` returns
+    /// one value per sample, the average of left and right, rounded down. By default the output is
+    /// stereo.
+    pub mono: bool,
+    /// Set by `pause`. While paused, updates skip the elapsed clocks instead of emulating them.
+    paused: bool,
 
     /// The remainder of `curr_clock * sample_frequency / CLOCK_SPEED`, used for timing the samples.
     sample_mod: u64,
@@ -139,6 +158,7 @@ impl PartialEq for SoundController {
             && self.nr51 == other.nr51
             && self.on == other.on
             && self.frame_sequencer_step == other.frame_sequencer_step
+            && self.frame_sequencer_skip == other.frame_sequencer_skip
             && self.ch1_channel_enable == other.ch1_channel_enable
             && self.ch1_length_timer == other.ch1_length_timer
             && self.ch1_sweep_enabled == other.ch1_sweep_enabled
@@ -170,10 +190,14 @@ impl PartialEq for SoundController {
             // && self.output == other.output
             && self.last_clock_count == other.last_clock_count
         // && self.sample_frequency == other.sample_frequency
+        // && self.sampling_disabled == other.sampling_disabled
+        // && self.skip_samples_while_off == other.skip_samples_while_off
+        // && self.mono == other.mono
+        // && self.paused == other.paused
         // && self.sample_mod == other.sample_mod
     }
 }
-crate::save_state!(SoundController, self, data {
+crate::save_state!(SoundController, self, ctx, data {
     self.nr10;
     self.nr11;
     self.nr12;
@@ -212,6 +236,12 @@ crate::save_state!(SoundController, self, data {
     ];
 
     self.frame_sequencer_step;
+    if ctx.version >= 5 => {
+        bitset [self.frame_sequencer_skip];
+    }
+    if ctx.version < 5 => {
+        on_load self.frame_sequencer_skip = false;
+    }
     self.ch1_length_timer;
     self.ch1_shadow_freq;
     self.ch1_sweep_timer;
@@ -239,7 +269,18 @@ crate::save_state!(SoundController, self, data {
 
     // self.output;
     self.last_clock_count;
+    // the sound controller is updated lazily, but can't be ahead of the GameBoy
+    on_load if ctx.strict && ctx.clock_count.is_some_and(|x| x < self.last_clock_count) {
+        return Err(LoadStateError::SoundControllerDesync(
+            self.last_clock_count,
+            ctx.clock_count.unwrap(),
+        ));
+    };
     // self.sample_frequency;
+    // self.sampling_disabled;
+    // self.skip_samples_while_off;
+    // self.mono;
+    // self.paused;
     // self.sample_mod;
 });
 impl Default for SoundController {
@@ -271,6 +312,7 @@ impl Default for SoundController {
             nr51: 0,
             on: false,
             frame_sequencer_step: 0,
+            frame_sequencer_skip: false,
             ch1_channel_enable: false,
             ch1_length_timer: 0,
             ch1_sweep_enabled: false,
@@ -302,6 +344,12 @@ impl Default for SoundController {
             output: Vec::default(),
             last_clock_count: 0,
             sample_frequency: 0,
+            sampling_disabled: false,
+            #[cfg(feature = "timing_profile")]
+            update_time: 0,
+            skip_samples_while_off: false,
+            mono: false,
+            paused: false,
             sample_mod: 0,
         }
     }
@@ -309,46 +357,275 @@ impl Default for SoundController {
 
 const WAVE_DUTY_TABLE: [u8; 4] = [0b0000_0001, 0b0000_0011, 0b0000_1111, 0b1111_1100];
 
+/// The internal state of a sound channel, returned by `SoundController::channel_state`. A channel
+/// is silent if it is disabled, which happens when its length timer expires or its DAC is turned
+/// off, or if its volume is 0.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct ChannelState {
+    /// If the channel is enabled, as read in NR52.
+    pub enabled: bool,
+    /// If the DAC of the channel is on, in the upper 5 bits of NRx2, or in bit 7 of NR30 for the
+    /// channel 3. Turning it off disables the channel.
+    pub dac_enabled: bool,
+    /// If the length timer is enabled, in bit 6 of NRx4.
+    pub length_enabled: bool,
+    /// The length timer. While enabled, it counts down at 256 Hz, and the channel is disabled when
+    /// it reaches 0. It is at most 64, or 256 for the channel 3.
+    pub length_timer: u16,
+    /// The current volume of the envelope, from 0 to 15. For the channel 3, which has no envelope,
+    /// this is the output level of NR32: 0 is muted, and 1, 2 and 3 are 100%, 50% and 25%.
+    pub volume: u8,
+    /// The period of the envelope, in the lower 3 bits of NRx2. 0 for the channel 3.
+    pub envelope_period: u8,
+    /// The timer of the envelope, counting down at 64 Hz to the next volume change. 0 for the
+    /// channel 3.
+    pub envelope_timer: u8,
+}
+
+/// The number of samples at `sample_frequency` between the clocks `l` (inclusive) and `r`
+/// (exclusive).
+fn samples_between(l: u64, r: u64, sample_frequency: u64) -> u64 {
+    // compute the number of samples (multiples of k) beetween l (inclusive) and r (exclusive)
+    //  k = fc/fs
+    //  n = r/k - l/k + (l%k == 0) <- for r, l and k integers
+    //  => n = r*fs/fc - l*fs/fc + (l*fs % fc < fs) <- for r, l, fs and fc integers
+
+    // map clock_count to a smaller value, to avoid multiplication overflows in the
+    // distance future
+    let anchor = l - (l % CLOCK_SPEED);
+    let l = l - anchor;
+    let r = r - anchor;
+
+    r * sample_frequency / CLOCK_SPEED - l * sample_frequency / CLOCK_SPEED
+        + ((l * sample_frequency) % CLOCK_SPEED < sample_frequency) as u64
+}
+
+/// Advance `sample_mod` by `elapsed` clocks. The elapsed clocks are reduced modulo `CLOCK_SPEED`
+/// first, so the multiplication don't overflow for large values.
+fn advance_sample_mod(sample_mod: u64, elapsed: u64, sample_frequency: u64) -> u64 {
+    (sample_mod + (elapsed % CLOCK_SPEED) * sample_frequency) % CLOCK_SPEED
+}
+
 impl SoundController {
     /// Updates itself and return the currently generated audio output. The buffer is cleared.
     pub fn get_output(&mut self, clock_count: u64) -> Vec<u16> {
         self.update(clock_count);
-        std::mem::take(&mut self.output)
+        let output = std::mem::take(&mut self.output);
+        if self.mono {
+            return output.chunks_exact(2).map(|x| (x[0] + x[1]) / 2).collect();
+        }
+        output
+    }
+
+    /// The number of values that `get_output` would return for the clocks from `from_clock` up to
+    /// `to_clock`, at the current `sample_frequency`, two for each sample (left and right
+    /// channels), or one if `mono` is set. Useful for sizing buffers before running the emulation.
+    /// This does not update the state.
+    ///
+    /// The estimate is exact if the sound controller is not turned on or off in the meantime.
+    pub fn estimate_samples(&self, from_clock: u64, to_clock: u64) -> usize {
+        let channels = if self.mono { 1 } else { 2 };
+        if self.paused || self.sampling_disabled || to_clock <= from_clock {
+            return 0;
+        }
+        let fs = self.sample_frequency;
+        if !self.on {
+            if self.skip_samples_while_off {
+                return 0;
+            }
+            return channels * samples_between(from_clock, to_clock, fs) as usize;
+        }
+
+        // While on, the clocks are rounded up to even, and a sample is collected each time
+        // `sample_mod` wraps around. Find its value at `from_clock`, and count the wraps.
+        let even = |c: u64| c + c % 2;
+        let (l, r) = (even(from_clock), even(to_clock));
+        let offset = l as i128 - even(self.last_clock_count) as i128;
+        let sample_mod =
+            (self.sample_mod as i128 + offset * fs as i128).rem_euclid(CLOCK_SPEED as i128) as u64;
+        channels * ((sample_mod + (r - l) * fs) / CLOCK_SPEED) as usize
+    }
+
+    /// The current waveform of the channel 1 or 2. Return the duty pattern from `WAVE_DUTY_TABLE`,
+    /// where each bit is a sample, and the position of the current sample in it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `channel` is not 1 or 2.
+    pub fn channel_duty(&mut self, clock_count: u64, channel: u8) -> (u8, u8) {
+        self.update(clock_count);
+        let (nrx1, position) = match channel {
+            1 => (self.nr11, self.ch1_wave_duty_position),
+            2 => (self.nr21, self.ch2_wave_duty_position),
+            _ => panic!("channel {} don't have a duty cycle", channel),
+        };
+        (WAVE_DUTY_TABLE[(nrx1 >> 6) as usize], position)
+    }
+
+    /// The frequency in Hz of the given channel, from 1 to 4, computed from its registers, for
+    /// displaying the note being played. For the channels 1 and 2 this is the frequency of the duty
+    /// cycle, `131072 / (2048 - x)`, and for the channel 3 of the entire wave pattern,
+    /// `65536 / (2048 - x)`, where `x` is the 11-bit frequency value. For the channel 4 this is
+    /// the rate at which the LFSR is clocked, `262144 / (r * 2^s)`, where `r` is the divisor code
+    /// (0 is treated as 0.5) and `s` the shift.
+    ///
+    /// Return None if the channel is disabled, or if the sound controller is off.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `channel` is not between 1 and 4.
+    pub fn channel_frequency(&mut self, clock_count: u64, channel: u8) -> Option<f32> {
+        self.update(clock_count);
+        let (enabled, nrx3, nrx4) = match channel {
+            1 => (self.ch1_channel_enable, self.nr13, self.nr14),
+            2 => (self.ch2_channel_enable, self.nr23, self.nr24),
+            3 => (self.ch3_channel_enable, self.nr33, self.nr34),
+            4 => (self.ch4_channel_enable, 0, 0),
+            _ => panic!("there is no channel {}", channel),
+        };
+        if !self.on || !enabled {
+            return None;
+        }
+        let freq = (u16::from_be_bytes([nrx4, nrx3]) & 0x07FF) as f32;
+        Some(match channel {
+            1 | 2 => 131072.0 / (2048.0 - freq),
+            3 => 65536.0 / (2048.0 - freq),
+            _ => {
+                let divisor = [0.5, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0][self.nr43 as usize & 0x07];
+                let shift = (self.nr43 >> 4) as i32;
+                262144.0 / (divisor * 2f32.powi(shift))
+            }
+        })
+    }
+
+    /// The internal state of the given channel, from 1 to 4, like its length timer and envelope.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `channel` is not between 1 and 4.
+    pub fn channel_state(&mut self, clock_count: u64, channel: u8) -> ChannelState {
+        self.update(clock_count);
+        let envelope =
+            |enabled, length_timer: u8, nrx2: u8, nrx4: u8, volume, timer| ChannelState {
+                enabled,
+                dac_enabled: nrx2 & 0xF8 != 0,
+                length_enabled: nrx4 & 0x40 != 0,
+                length_timer: length_timer as u16,
+                volume,
+                envelope_period: nrx2 & 0x07,
+                envelope_timer: timer,
+            };
+        match channel {
+            1 => envelope(
+                self.ch1_channel_enable,
+                self.ch1_length_timer,
+                self.nr12,
+                self.nr14,
+                self.ch1_current_volume,
+                self.ch1_env_period_timer,
+            ),
+            2 => envelope(
+                self.ch2_channel_enable,
+                self.ch2_length_timer,
+                self.nr22,
+                self.nr24,
+                self.ch2_current_volume,
+                self.ch2_env_period_timer,
+            ),
+            3 => ChannelState {
+                enabled: self.ch3_channel_enable,
+                dac_enabled: self.nr30 & 0x80 != 0,
+                length_enabled: self.nr34 & 0x40 != 0,
+                length_timer: self.ch3_length_timer,
+                volume: (self.nr32 >> 5) & 0x03,
+                envelope_period: 0,
+                envelope_timer: 0,
+            },
+            4 => envelope(
+                self.ch4_channel_enable,
+                self.ch4_length_timer,
+                self.nr42,
+                self.nr44,
+                self.ch4_current_volume,
+                self.ch4_env_period_timer,
+            ),
+            _ => panic!("there is no channel {}", channel),
+        }
+    }
+
+    /// The current waveform of the channel 3. Return the 32 samples of the wave pattern, one
+    /// nibble per byte, and the position of the current sample.
+    pub fn channel3_wave(&mut self, clock_count: u64) -> ([u8; 32], u8) {
+        self.update(clock_count);
+        let wave = std::array::from_fn(|i| (self.ch3_wave_pattern[i / 2] >> [4, 0][i % 2]) & 0xF);
+        (wave, self.ch3_wave_position)
+    }
+
+    /// Pause the sound controller at `clock_count`, for frontends where the clock can jump forward,
+    /// like when the emulation is paused. The sound is emulated up to `clock_count`, and the
+    /// generated samples are kept in the output.
+    ///
+    /// Until `resume` is called, any update only sets `last_clock_count` to the new clock_count,
+    /// without emulating the elapsed time or generating samples for it.
+    pub fn pause(&mut self, clock_count: u64) {
+        self.update(clock_count);
+        self.paused = true;
+    }
+
+    /// Resume the sound controller paused by `pause`, skipping the clocks since the last update.
+    /// The phase of the sampling is kept, so the samples continue as if the skipped interval never
+    /// existed.
+    pub fn resume(&mut self, clock_count: u64) {
+        self.update(clock_count);
+        self.paused = false;
+    }
+
+    /// If the sound controller is paused. See `pause`.
+    pub fn is_paused(&self) -> bool {
+        self.paused
     }
 
     /// Emulator the sound controller until to the currently `clock_count`, since the `clock_count` of
     /// the last update.
     pub fn update(&mut self, clock_count: u64) {
+        #[cfg(feature = "timing_profile")]
+        let start = std::time::Instant::now();
+
+        self.update_to(clock_count);
+
+        #[cfg(feature = "timing_profile")]
+        {
+            self.update_time += start.elapsed().as_nanos() as u64;
+        }
+    }
+
+    fn update_to(&mut self, clock_count: u64) {
         debug_assert!(clock_count >= self.last_clock_count);
         if clock_count <= self.last_clock_count {
             return;
         }
+        if self.paused {
+            self.last_clock_count = clock_count;
+            return;
+        }
+
+        let sample_frequency = if self.sampling_disabled {
+            0
+        } else {
+            self.sample_frequency
+        };
 
         // if it is off, there is no need for audio generation
         if !self.on {
-            if self.sample_frequency != 0 {
-                // compute the number of samples (multiples of k) beetween l (inclusive) and r (exclusive)
-                //  k = fc/fs
-                //  n = r/k - l/k + (l%k == 0) <- for r, l and k integers
-                //  => n = r*fs/fc - l*fs/fc + (l*fs % fc < fs) <- for r, l, fs and fc integers
-
-                // map clock_count to a smaller value, to avoid multiplication overflows in the
-                // distance future
-                let anchor = self.last_clock_count - (self.last_clock_count % CLOCK_SPEED);
-                let l = self.last_clock_count - anchor;
-                let r = clock_count - anchor;
-
-                let n = r * self.sample_frequency / CLOCK_SPEED
-                    - l * self.sample_frequency / CLOCK_SPEED
-                    + ((l * self.sample_frequency) % CLOCK_SPEED < self.sample_frequency) as u64;
+            if sample_frequency != 0 && !self.skip_samples_while_off {
+                let n = samples_between(self.last_clock_count, clock_count, sample_frequency);
                 // for each sample, there is two values (left and right channels)
                 self.output.extend((0..2 * n).map(|_| 0));
             }
 
-            self.last_clock_count = clock_count;
             let elapsed_clock = clock_count - self.last_clock_count;
-            self.sample_mod =
-                (self.sample_mod + elapsed_clock * self.sample_frequency) % CLOCK_SPEED;
+            self.last_clock_count = clock_count;
+            self.sample_mod = advance_sample_mod(self.sample_mod, elapsed_clock, sample_frequency);
             return;
         }
 
@@ -390,6 +667,12 @@ impl SoundController {
         let ch3_right = (self.nr51 & 0x04) != 0;
         let ch4_right = (self.nr51 & 0x08) != 0;
 
+        debug_assert!(sample_frequency <= CLOCK_SPEED / 2);
+
+        // The loop below advances `sample_mod` by at most a step period at a time, so its
+        // multiplications are bounded by `step_period * sample_frequency` and don't overflow, no
+        // matter how many clocks this update spans. Only the clocks themselves are absolute.
+
         // round up, to nearest even, including self
         let l = self.last_clock_count + (self.last_clock_count % 2 != 0) as u64;
         let r = clock_count + (clock_count % 2 != 0) as u64;
@@ -402,10 +685,10 @@ impl SoundController {
             let step_period = CLOCK_SPEED / 512;
             let next_step = step_period * (1 + clock / step_period);
 
-            let next_sample = if self.sample_frequency == 0 {
+            let next_sample = if sample_frequency == 0 {
                 u64::MAX
             } else {
-                let fs = self.sample_frequency;
+                let fs = sample_frequency;
                 let next_sample = clock + (((CLOCK_SPEED - self.sample_mod) + fs - 1) / fs);
                 // round up
                 next_sample + (next_sample % 2 != 0) as u64
@@ -418,12 +701,12 @@ impl SoundController {
 
             if clock >= clock_count {
                 let delta = (r - 2) - previous_clock;
-                self.sample_mod = (self.sample_mod + delta * self.sample_frequency) % CLOCK_SPEED;
+                self.sample_mod = (self.sample_mod + delta * sample_frequency) % CLOCK_SPEED;
                 break;
             }
 
             let delta = clock - previous_clock;
-            self.sample_mod = (self.sample_mod + delta * self.sample_frequency) % CLOCK_SPEED;
+            self.sample_mod = (self.sample_mod + delta * sample_frequency) % CLOCK_SPEED;
 
             if next_step == clock {
                 // TODO: a step should happens in a falling edge of the bit 13 of the Timer's DIV
@@ -441,10 +724,13 @@ impl SoundController {
                     last_run = clock;
 
                     // step
-                    let lenght_ctr = self.frame_sequencer_step % 2 == 0;
-                    let volume_env = self.frame_sequencer_step % 8 == 7;
-                    let sweep = self.frame_sequencer_step % 4 == 2;
-                    self.frame_sequencer_step = (self.frame_sequencer_step + 1) % 8;
+                    let skip = std::mem::take(&mut self.frame_sequencer_skip);
+                    let lenght_ctr = !skip && self.frame_sequencer_step % 2 == 0;
+                    let volume_env = !skip && self.frame_sequencer_step % 8 == 7;
+                    let sweep = !skip && self.frame_sequencer_step % 4 == 2;
+                    if !skip {
+                        self.frame_sequencer_step = (self.frame_sequencer_step + 1) % 8;
+                    }
 
                     if lenght_ctr {
                         if self.nr14 & 0x40 != 0 && self.ch1_length_timer != 0 {
@@ -700,31 +986,22 @@ impl SoundController {
 
     /// Reference implementation for the more optimized Self::update.
     pub fn update_ref(&mut self, clock_count: u64) {
+        if self.paused {
+            self.last_clock_count = self.last_clock_count.max(clock_count);
+            return;
+        }
         // if it is off, there is no need for audio generation
         if !self.on {
-            if self.sample_frequency != 0 {
-                // compute the number of samples (multiples of k) beetween l (inclusive) and r (exclusive)
-                //  k = fc/fs
-                //  n = r/k - l/k + (l%k == 0) <- for r, l and k integers
-                //  => n = r*fs/fc - l*fs/fc + (l*fs % fc < fs) <- for r, l, fs and fc integers
-
-                // map clock_count to a smaller value, to avoid multiplication overflows in the
-                // distance future
-                let anchor = self.last_clock_count - (self.last_clock_count % CLOCK_SPEED);
-                let l = self.last_clock_count - anchor;
-                let r = clock_count - anchor;
-
-                let n = r * self.sample_frequency / CLOCK_SPEED
-                    - l * self.sample_frequency / CLOCK_SPEED
-                    + ((l * self.sample_frequency) % CLOCK_SPEED < self.sample_frequency) as u64;
+            if self.sample_frequency != 0 && !self.skip_samples_while_off {
+                let n = samples_between(self.last_clock_count, clock_count, self.sample_frequency);
                 // for each sample, there is two values (left and right channels)
                 self.output.extend((0..2 * n).map(|_| 0));
             }
 
-            self.last_clock_count = clock_count;
             let elapsed_clock = clock_count - self.last_clock_count;
+            self.last_clock_count = clock_count;
             self.sample_mod =
-                (self.sample_mod + elapsed_clock * self.sample_frequency) % CLOCK_SPEED;
+                advance_sample_mod(self.sample_mod, elapsed_clock, self.sample_frequency);
             return;
         }
 
@@ -822,10 +1099,13 @@ impl SoundController {
             // TODO: a step should happens in a falling edge of the bit 5 of the DIV timer.
             if clock % (CLOCK_SPEED / 512) == 0 {
                 // step
-                let lenght_ctr = self.frame_sequencer_step % 2 == 0;
-                let volume_env = self.frame_sequencer_step % 8 == 7;
-                let sweep = self.frame_sequencer_step % 4 == 2;
-                self.frame_sequencer_step = (self.frame_sequencer_step + 1) % 8;
+                let skip = std::mem::take(&mut self.frame_sequencer_skip);
+                let lenght_ctr = !skip && self.frame_sequencer_step % 2 == 0;
+                let volume_env = !skip && self.frame_sequencer_step % 8 == 7;
+                let sweep = !skip && self.frame_sequencer_step % 4 == 2;
+                if !skip {
+                    self.frame_sequencer_step = (self.frame_sequencer_step + 1) % 8;
+                }
 
                 if lenght_ctr {
                     if self.nr14 & 0x40 != 0 && self.ch1_length_timer != 0 {
@@ -1182,6 +1462,9 @@ impl SoundController {
             }
             0x1A => {
                 self.nr30 = value;
+                // Turning the DAC off disables the channel, but turning it back on doesn't enable
+                // it again, only a trigger does. The sample buffer is kept, and is output after
+                // the trigger until the next wave sample is read.
                 if self.nr30 & 0x80 == 0 {
                     self.ch3_channel_enable = false;
                 }
@@ -1218,6 +1501,9 @@ impl SoundController {
                 if value & 0x80 != 0 {
                     // Trigger event
 
+                    // On DMG, triggering the channel at the moment it reads the wave RAM
+                    // overwrites the start of the wave RAM with the byte being read, or with its
+                    // aligned block of 4 bytes if it is not in the first 4.
                     if self.ch3_channel_enable
                         && self.nr30 & 0x80 != 0
                         && self.ch3_frequency_timer == 0
@@ -1330,11 +1616,21 @@ impl SoundController {
                         output: std::mem::take(&mut self.output),
                         last_clock_count: self.last_clock_count,
                         sample_frequency: self.sample_frequency,
+                        sampling_disabled: self.sampling_disabled,
+                        #[cfg(feature = "timing_profile")]
+                        update_time: self.update_time,
+                        skip_samples_while_off: self.skip_samples_while_off,
+                        mono: self.mono,
+                        paused: self.paused,
                         sample_mod: self.sample_mod,
                         ..Self::default()
                     };
                 } else if value & 0x80 != 0 && !self.on {
                     self.on = true;
+                    // The frame sequencer steps on the falling edges of DIV's bit 4, that is
+                    // modeled by the clock_count (see the TODO in `update_to`).
+                    let step_period = CLOCK_SPEED / 512;
+                    self.frame_sequencer_skip = clock_count % step_period >= step_period / 2;
                 }
             }
             0x30..=0x3F => {
@@ -1400,6 +1696,10 @@ impl SoundController {
                 _ => unreachable!(),
             }
         } else {
+            // While off, the registers read as if they were zeroed, even the length counters that
+            // can be written on DMG. The wave RAM is still accessible. The sound controller does
+            // not know the model, so the CGB, which ignores the writes to the length counters, is
+            // not emulated.
             match address {
                 0x10 => 0x80,
                 0x11 => 0x3F,
@@ -1474,6 +1774,558 @@ mod test {
         }
     }
 
+    #[test]
+    fn channel_duty() {
+        let mut sound = SoundController::default();
+        let mut clock_count = 4;
+        sound.write(clock_count, 0x26, 0x80);
+        // duty 2, max volume and max frequency
+        sound.write(clock_count, 0x11, 0b10 << 6);
+        sound.write(clock_count, 0x12, 0xF0);
+        sound.write(clock_count, 0x13, 0x00);
+        sound.write(clock_count, 0x14, 0x87);
+
+        let (pattern, mut position) = sound.channel_duty(clock_count, 1);
+        assert_eq!(pattern, WAVE_DUTY_TABLE[2]);
+
+        // find the cycles between each sample, and check that the position advances by one.
+        let mut last_change = None;
+        let mut period = None;
+        while clock_count < 10_000 {
+            clock_count += 1;
+            let (pattern, curr) = sound.channel_duty(clock_count, 1);
+            assert_eq!(pattern, WAVE_DUTY_TABLE[2]);
+            if curr == position {
+                continue;
+            }
+            assert_eq!(curr, (position + 1) % 8);
+            position = curr;
+            if let Some(last) = last_change {
+                let elapsed = clock_count - last;
+                assert_eq!(*period.get_or_insert(elapsed), elapsed);
+            }
+            last_change = Some(clock_count);
+        }
+        assert!(period.is_some());
+
+        sound.write(clock_count, 0x11, 0b01 << 6);
+        assert_eq!(sound.channel_duty(clock_count, 1).0, WAVE_DUTY_TABLE[1]);
+        assert_eq!(sound.channel_duty(clock_count, 2).0, WAVE_DUTY_TABLE[0]);
+    }
+
+    #[test]
+    fn channel_state() {
+        let mut sound = SoundController::default();
+        let mut clock_count = 4;
+        sound.write(clock_count, 0x26, 0x80);
+        // channel 2 at max volume, with a length of 4, and a slow decreasing envelope
+        sound.write(clock_count, 0x16, 64 - 4);
+        sound.write(clock_count, 0x17, 0xF7);
+        sound.write(clock_count, 0x19, 0xC0);
+
+        let state = sound.channel_state(clock_count, 2);
+        assert_eq!(
+            state,
+            ChannelState {
+                enabled: true,
+                dac_enabled: true,
+                length_enabled: true,
+                length_timer: 4,
+                volume: 15,
+                envelope_period: 7,
+                envelope_timer: 7,
+            }
+        );
+
+        // the length timer counts down at 256 Hz, and disables the channel at 0
+        let mut timers = vec![state.length_timer];
+        while clock_count < CLOCK_SPEED / 16 {
+            clock_count += 4;
+            let state = sound.channel_state(clock_count, 2);
+            if state.length_timer != *timers.last().unwrap() {
+                timers.push(state.length_timer);
+            }
+            assert_eq!(state.enabled, state.length_timer != 0);
+        }
+        assert_eq!(timers, [4, 3, 2, 1, 0]);
+        // the envelope was not clocked yet
+        assert_eq!(sound.channel_state(clock_count, 2).volume, 15);
+
+        // turning off the DAC also disables the channel
+        sound.write(clock_count, 0x19, 0x80);
+        assert!(sound.channel_state(clock_count, 2).enabled);
+        sound.write(clock_count, 0x17, 0x00);
+        let state = sound.channel_state(clock_count, 2);
+        assert!(!state.enabled && !state.dac_enabled);
+
+        // the channel 3 has no envelope
+        sound.write(clock_count, 0x1C, 0b10 << 5);
+        assert_eq!(sound.channel_state(clock_count, 3).volume, 2);
+        assert_eq!(sound.channel_state(clock_count, 3).envelope_period, 0);
+    }
+
+    #[test]
+    fn channel_frequency() {
+        let mut sound = SoundController::default();
+        let mut clock_count = 4;
+        assert_eq!(sound.channel_frequency(clock_count, 1), None);
+
+        sound.write(clock_count, 0x26, 0x80);
+        // max volume and a frequency value of 1750
+        sound.write(clock_count, 0x12, 0xF0);
+        sound.write(clock_count, 0x13, 0xD6);
+        sound.write(clock_count, 0x14, 0x86);
+        let freq = sound.channel_frequency(clock_count, 1).unwrap();
+        assert_eq!(freq, 131072.0 / (2048.0 - 1750.0));
+        assert!((439.0..441.0).contains(&freq));
+
+        // the emulated duty cycle has about the same frequency
+        let (_, start) = sound.channel_duty(clock_count, 1);
+        let mut changes = Vec::new();
+        while changes.len() < 9 {
+            clock_count += 1;
+            let (_, position) = sound.channel_duty(clock_count, 1);
+            if position != (start + changes.len() as u8) % 8 {
+                changes.push(clock_count);
+            }
+        }
+        let cycle = changes[8] - changes[0];
+        let emulated = CLOCK_SPEED as f32 / cycle as f32;
+        assert!((emulated - freq).abs() < freq / 100.0, "{}", emulated);
+
+        assert_eq!(sound.channel_frequency(clock_count, 2), None);
+        assert_eq!(sound.channel_frequency(clock_count, 3), None);
+        assert_eq!(sound.channel_frequency(clock_count, 4), None);
+
+        // channel 3, with the DAC on
+        sound.write(clock_count, 0x1A, 0x80);
+        sound.write(clock_count, 0x1D, 0x00);
+        sound.write(clock_count, 0x1E, 0x87);
+        assert_eq!(
+            sound.channel_frequency(clock_count, 3),
+            Some(65536.0 / 256.0)
+        );
+
+        // channel 4, with divisor code 0 and a shift of 2
+        sound.write(clock_count, 0x21, 0xF0);
+        sound.write(clock_count, 0x22, 0x20);
+        sound.write(clock_count, 0x23, 0x80);
+        assert_eq!(
+            sound.channel_frequency(clock_count, 4),
+            Some(262144.0 / 2.0)
+        );
+
+        sound.write(clock_count, 0x26, 0x00);
+        assert_eq!(sound.channel_frequency(clock_count, 1), None);
+    }
+
+    #[test]
+    fn channel3_wave() {
+        let mut sound = SoundController::default();
+        for i in 0..16 {
+            let (a, b) = ((2 * i) & 0xF, (2 * i + 1) & 0xF);
+            sound.write(4, 0x30 + i, (a << 4) | b);
+        }
+        let (wave, _) = sound.channel3_wave(4);
+        let expected: [u8; 32] = std::array::from_fn(|i| i as u8 & 0xF);
+        assert_eq!(wave, expected);
+    }
+
+    #[test]
+    fn channel3_trigger_corruption() {
+        // Trigger channel 3 while it is playing, and return the wave RAM after it.
+        fn trigger(wave_position: u8, frequency_timer: u16) -> [u8; 16] {
+            let mut sound = SoundController {
+                on: true,
+                nr30: 0x80,
+                ch3_channel_enable: true,
+                ch3_frequency_timer: frequency_timer,
+                ch3_wave_position: wave_position,
+                ch3_wave_pattern: std::array::from_fn(|i| i as u8 * 0x11),
+                ..SoundController::default()
+            };
+            sound.write(0, 0x1E, 0x80);
+            sound.ch3_wave_pattern
+        }
+        let wave: [u8; 16] = std::array::from_fn(|i| i as u8 * 0x11);
+        let with_start = |start: &[u8]| {
+            let mut wave = wave;
+            wave[..start.len()].copy_from_slice(start);
+            wave
+        };
+
+        // The byte with the next sample is the one being read. If it is one of the first 4, it is
+        // copied to the first byte.
+        assert_eq!(trigger(0, 0), wave);
+        assert_eq!(trigger(1, 0), with_start(&[0x11]));
+        assert_eq!(trigger(6, 0), with_start(&[0x33]));
+        // Otherwise, its aligned block of 4 bytes is copied to the first 4 bytes.
+        assert_eq!(trigger(7, 0), with_start(&[0x44, 0x55, 0x66, 0x77]));
+        assert_eq!(trigger(16, 0), with_start(&[0x88, 0x99, 0xaa, 0xbb]));
+        assert_eq!(trigger(30, 0), with_start(&[0xcc, 0xdd, 0xee, 0xff]));
+        // the position wraps around to the first byte
+        assert_eq!(trigger(31, 0), wave);
+
+        for wave_position in 0..32 {
+            let byte = (wave_position as usize + 1) % 32 / 2;
+            let expected = if byte < 4 {
+                with_start(&wave[byte..byte + 1])
+            } else {
+                with_start(&wave[byte & !3..][..4])
+            };
+            assert_eq!(trigger(wave_position, 0), expected, "{}", wave_position);
+
+            // the wave RAM is only corrupted if the channel was reading it
+            for frequency_timer in [1, 2, 0x7ff] {
+                assert_eq!(trigger(wave_position, frequency_timer), wave);
+            }
+        }
+    }
+
+    #[test]
+    fn estimate_samples() {
+        for sample_frequency in [0, 8000, 44100, 48000, 97408] {
+            for start in [4, 5, 777, CLOCK_SPEED - 3, 3 * CLOCK_SPEED + 12345] {
+                for on in [false, true] {
+                    let mut sound = SoundController {
+                        sample_frequency,
+                        last_clock_count: start,
+                        ..SoundController::default()
+                    };
+                    if on {
+                        sound.write(start, 0x26, 0x80);
+                    }
+                    sound.get_output(start);
+                    let mut clock = start;
+                    for delta in [1, 2, 95, 70224, CLOCK_SPEED / 3, 1] {
+                        let estimate = sound.estimate_samples(clock, clock + delta);
+                        clock += delta;
+                        assert_eq!(
+                            estimate,
+                            sound.get_output(clock).len(),
+                            "fs {} start {} on {} delta {}",
+                            sample_frequency,
+                            start,
+                            on,
+                            delta
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn large_update() {
+        let start = (1 << 40) + 3;
+        for (sample_frequency, seconds) in [(192_000, 10), (CLOCK_SPEED / 2, 1)] {
+            let sound = || {
+                let mut sound = SoundController {
+                    sample_frequency,
+                    last_clock_count: start,
+                    ..SoundController::default()
+                };
+                sound.write(start, 0x26, 0x80);
+                for (address, value) in [(0x24, 0x77), (0x25, 0xFF), (0x12, 0xF0), (0x14, 0x87)] {
+                    sound.write(start, address, value);
+                }
+                sound
+            };
+            let end = start + seconds * CLOCK_SPEED;
+
+            // a single update
+            let mut once = sound();
+            let estimate = once.estimate_samples(start, end);
+            let output = once.get_output(end);
+            assert_eq!(output.len(), estimate);
+            let expected = 2 * seconds * sample_frequency;
+            assert!(output.len().abs_diff(expected as usize) <= 2);
+
+            // the same as many small updates
+            let mut chunked = sound();
+            let mut chunked_output = Vec::new();
+            let mut clock = start;
+            while clock < end {
+                clock = (clock + 70224).min(end);
+                chunked_output.extend(chunked.get_output(clock));
+            }
+            assert!(output == chunked_output, "fs {}", sample_frequency);
+        }
+
+        // while off, the phase of the samples is kept over a large update
+        let fs = 48000;
+        let mut sound = SoundController {
+            sample_frequency: fs,
+            skip_samples_while_off: true,
+            ..SoundController::default()
+        };
+        let end = 1 << 50;
+        sound.update(end);
+        assert!(sound.get_output(end).is_empty());
+        assert_eq!(
+            sound.sample_mod as u128,
+            (end as u128 * fs as u128) % CLOCK_SPEED as u128
+        );
+    }
+
+    #[test]
+    fn mono_downmix() {
+        let sound = || {
+            let mut sound = SoundController {
+                sample_frequency: 48000,
+                last_clock_count: 4,
+                ..SoundController::default()
+            };
+            sound.write(4, 0x26, 0x80);
+            // channel 1 and 2 with different volumes, channel 2 only on the left
+            for (address, value) in [
+                (0x24, 0x73),
+                (0x25, 0x13),
+                (0x11, 0x80),
+                (0x12, 0xF0),
+                (0x14, 0x87),
+                (0x16, 0x40),
+                (0x17, 0x70),
+                (0x19, 0x86),
+            ] {
+                sound.write(4, address, value);
+            }
+            sound
+        };
+
+        let mut stereo = sound();
+        let mut mono = sound();
+        mono.mono = true;
+
+        let mut clock = 4;
+        let mut differ = false;
+        for delta in [1, 95, 70224, CLOCK_SPEED / 3] {
+            assert_eq!(
+                mono.estimate_samples(clock, clock + delta),
+                stereo.estimate_samples(clock, clock + delta) / 2
+            );
+            clock += delta;
+            let stereo = stereo.get_output(clock);
+            let mono = mono.get_output(clock);
+            assert_eq!(mono.len(), stereo.len() / 2);
+            for (m, s) in mono.iter().zip(stereo.chunks_exact(2)) {
+                assert_eq!(*m, (s[0] + s[1]) / 2);
+                differ |= s[0] != s[1];
+            }
+        }
+        assert!(differ);
+        assert!(stereo.get_output(clock).is_empty());
+    }
+
+    #[test]
+    fn frame_sequencer_skip() {
+        // Return the clock when the channel 1 is disabled by its length counter, after turning the
+        // APU on at the given phase of the frame sequencer.
+        fn length_expire(phase: u64) -> u64 {
+            let step_period = CLOCK_SPEED / 512;
+            let mut sound = SoundController::default();
+            let start = 10 * step_period + phase;
+            sound.write(start - 4, 0x26, 0x00);
+            sound.write(start, 0x26, 0x80);
+            // a length of 1, with the length counter enabled
+            for (address, value) in [(0x11, 0x3F), (0x12, 0xF0), (0x14, 0xC0)] {
+                sound.write(start, address, value);
+            }
+            assert_eq!(sound.read(start, 0x26) & 0x01, 0x01);
+
+            let mut clock = start;
+            while sound.read(clock, 0x26) & 0x01 != 0 {
+                clock += 4;
+            }
+            clock - start
+        }
+
+        let step_period = CLOCK_SPEED / 512;
+        // in the first half of the period, the first step clocks the length counter
+        assert!(length_expire(1000) <= step_period - 1000 + 4);
+        // in the second half, the first step is skipped, and the length counter is only clocked
+        // in the second one.
+        let expire = length_expire(5000);
+        assert!(expire > step_period - 5000 + 4, "{}", expire);
+        assert!(expire <= 2 * step_period - 5000 + 4, "{}", expire);
+    }
+
+    #[test]
+    fn channel3_dac() {
+        let mut sound = SoundController {
+            sample_frequency: 44100,
+            ..SoundController::default()
+        };
+        let clock_count = 4;
+        for address in 0x30..0x40 {
+            sound.write(clock_count, address, 0xFF);
+        }
+        // channel 3 playing at full volume
+        for (address, value) in [
+            (0x26, 0x80),
+            (0x24, 0x77),
+            (0x25, 0x44),
+            (0x1A, 0x80),
+            (0x1C, 0x20),
+            (0x1E, 0x87),
+        ] {
+            sound.write(clock_count, address, value);
+        }
+        sound.get_output(clock_count);
+
+        let play = |sound: &mut SoundController| {
+            let output = sound.get_output(sound.last_clock_count + CLOCK_SPEED / 100);
+            assert!(!output.is_empty());
+            output.iter().any(|&x| x != 0)
+        };
+        let ch3_status =
+            |sound: &mut SoundController| sound.read(sound.last_clock_count, 0x26) & 0x04 != 0;
+
+        assert!(play(&mut sound));
+        assert!(ch3_status(&mut sound));
+
+        // turning the DAC off silences the channel immediately
+        sound.write(sound.last_clock_count, 0x1A, 0x00);
+        assert!(!ch3_status(&mut sound));
+        assert!(!play(&mut sound));
+        assert_eq!(sound.ch3_sample_buffer, 0xF);
+
+        // turning it on again is not enough
+        sound.write(sound.last_clock_count, 0x1A, 0x80);
+        assert!(!ch3_status(&mut sound));
+        assert!(!play(&mut sound));
+
+        // a trigger with the DAC off doesn't enable the channel
+        sound.write(sound.last_clock_count, 0x1A, 0x00);
+        sound.write(sound.last_clock_count, 0x1E, 0x87);
+        assert!(!ch3_status(&mut sound));
+        assert!(!play(&mut sound));
+
+        sound.write(sound.last_clock_count, 0x1A, 0x80);
+        sound.write(sound.last_clock_count, 0x1E, 0x87);
+        assert!(ch3_status(&mut sound));
+        assert!(play(&mut sound));
+    }
+
+    #[test]
+    fn sampling_disabled() {
+        let mut sound = SoundController {
+            sample_frequency: 44100,
+            ..SoundController::default()
+        };
+        let writes = [
+            (0x26, 0x80),
+            // channel 1, with length 1 and length enabled
+            (0x11, 0x3F),
+            (0x12, 0xF0),
+            (0x14, 0xC7),
+            // channel 2, 3 and 4
+            (0x16, 0x80),
+            (0x17, 0xF0),
+            (0x19, 0x85),
+            (0x1A, 0x80),
+            (0x1C, 0x20),
+            (0x1E, 0x83),
+            (0x21, 0xF0),
+            (0x22, 0x21),
+            (0x23, 0x80),
+            (0x24, 0x77),
+            (0x25, 0xFF),
+        ];
+        for (address, value) in writes {
+            sound.write(4, address, value);
+        }
+        sound.get_output(4);
+        let mut disabled = sound.clone();
+        disabled.sampling_disabled = true;
+        assert!(sound.ch1_channel_enable);
+
+        let mut clock_count = 4;
+        for i in 0..200 {
+            clock_count += 97 + i * 31;
+            let output = sound.get_output(clock_count);
+            assert!(disabled.get_output(clock_count).is_empty());
+            if i > 0 {
+                assert!(!output.is_empty());
+            }
+            assert_eq!(sound, disabled, "at clock {}", clock_count);
+        }
+        assert!(!disabled.ch1_channel_enable);
+        assert!(disabled.ch2_channel_enable);
+    }
+
+    #[test]
+    fn skip_samples_while_off() {
+        let mut sound = SoundController {
+            sample_frequency: 44100,
+            ..SoundController::default()
+        };
+        let mut skip = sound.clone();
+        skip.skip_samples_while_off = true;
+
+        // the APU starts off
+        let output = sound.get_output(CLOCK_SPEED / 10);
+        assert_eq!(output.len(), 2 * 4410);
+        assert!(output.iter().all(|&x| x == 0));
+        assert!(skip.get_output(CLOCK_SPEED / 10).is_empty());
+
+        // while on, the samples are generated
+        for sound in [&mut sound, &mut skip] {
+            sound.write(CLOCK_SPEED / 10, 0x26, 0x80);
+            let len = sound.get_output(CLOCK_SPEED / 5).len();
+            assert!(len.abs_diff(2 * 4410) <= 2, "{}", len);
+            sound.write(CLOCK_SPEED / 5, 0x26, 0x00);
+        }
+
+        assert_eq!(sound.get_output(3 * CLOCK_SPEED / 10).len(), 2 * 4410);
+        assert!(skip.get_output(3 * CLOCK_SPEED / 10).is_empty());
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let mut sound = SoundController {
+            sample_frequency: 44100,
+            ..SoundController::default()
+        };
+        // channel 2 playing, with a constant volume
+        for (address, value) in [
+            (0x26, 0x80),
+            (0x24, 0x77),
+            (0x25, 0xFF),
+            (0x16, 0x80),
+            (0x17, 0xF0),
+            (0x19, 0x87),
+        ] {
+            sound.write(4, address, value);
+        }
+        sound.get_output(4);
+        let mut continuous = sound.clone();
+
+        let pause = 3 * CLOCK_SPEED / 10;
+        let gap = 7 * CLOCK_SPEED + 1234;
+        let mut output = sound.get_output(CLOCK_SPEED / 10);
+        sound.pause(pause);
+        assert!(sound.is_paused());
+        output.extend(sound.get_output(pause));
+
+        // no samples are generated for the gap
+        for clock_count in [pause + 100, pause + CLOCK_SPEED, pause + gap] {
+            assert!(sound.get_output(clock_count).is_empty());
+        }
+        sound.resume(pause + gap);
+        assert!(!sound.is_paused());
+        assert!(sound.get_output(pause + gap).is_empty());
+
+        let resumed = sound.get_output(pause + gap + CLOCK_SPEED / 10);
+        assert!(resumed.iter().any(|&x| x != 0));
+        output.extend(resumed);
+
+        // the same samples are generated as if the gap never existed
+        let expected = continuous.get_output(pause + CLOCK_SPEED / 10);
+        assert_eq!(output.len(), expected.len());
+        assert_eq!(output, expected);
+    }
+
     pub fn check_with_ref(sound_start: &SoundController, sound: &mut SoundController) {
         let mut sound_ref = sound_start.clone();
         sound_ref.update_ref(sound.last_clock_count);
@@ -1502,7 +2354,7 @@ mod test {
     #[test]
     fn case1() {
         #[rustfmt::skip]
-        let mut sound = SoundController { nr10: 0, nr11: 5, nr12: 0, nr13: 0, nr14: 0, nr21: 0, nr22: 0, nr23: 0, nr24: 0, nr30: 0, nr31: 99, nr32: 0, nr33: 0, nr34: 0, ch3_wave_pattern: [240, 214, 67, 163, 199, 10, 6, 197, 14, 228, 70, 146, 52, 77, 129, 74], nr41: 2, nr42: 0, nr43: 0, nr44: 0, nr50: 0, nr51: 0, on: true, frame_sequencer_step: 0, ch1_channel_enable: false, ch1_length_timer: 59, ch1_sweep_enabled: false, ch1_shadow_freq: 0, ch1_sweep_timer: 0, ch1_has_done_sweep_calculation: false, ch1_frequency_timer: 0, ch1_wave_duty_position: 0, ch1_current_volume: 0, ch1_env_period_timer: 0, ch2_channel_enable: false, ch2_length_timer: 0, ch2_frequency_timer: 0, ch2_wave_duty_position: 0, ch2_current_volume: 0, ch2_env_period_timer: 0, ch3_channel_enable: false, ch3_length_timer: 157, ch3_frequency_timer: 0, ch3_wave_position: 0, ch3_sample_buffer: 0, ch3_wave_just_read: false, ch4_channel_enable: false, ch4_length_timer: 62, ch4_current_volume: 0, ch4_env_period_timer: 0, ch4_lfsr: 0, ch4_frequency_timer: 0, output: [0, 0].to_vec(), last_clock_count: 100, sample_frequency: 10843, sample_mod: 21686, };
+        let mut sound = SoundController { nr10: 0, nr11: 5, nr12: 0, nr13: 0, nr14: 0, nr21: 0, nr22: 0, nr23: 0, nr24: 0, nr30: 0, nr31: 99, nr32: 0, nr33: 0, nr34: 0, ch3_wave_pattern: [240, 214, 67, 163, 199, 10, 6, 197, 14, 228, 70, 146, 52, 77, 129, 74], nr41: 2, nr42: 0, nr43: 0, nr44: 0, nr50: 0, nr51: 0, on: true, frame_sequencer_step: 0, frame_sequencer_skip: false, ch1_channel_enable: false, ch1_length_timer: 59, ch1_sweep_enabled: false, ch1_shadow_freq: 0, ch1_sweep_timer: 0, ch1_has_done_sweep_calculation: false, ch1_frequency_timer: 0, ch1_wave_duty_position: 0, ch1_current_volume: 0, ch1_env_period_timer: 0, ch2_channel_enable: false, ch2_length_timer: 0, ch2_frequency_timer: 0, ch2_wave_duty_position: 0, ch2_current_volume: 0, ch2_env_period_timer: 0, ch3_channel_enable: false, ch3_length_timer: 157, ch3_frequency_timer: 0, ch3_wave_position: 0, ch3_sample_buffer: 0, ch3_wave_just_read: false, ch4_channel_enable: false, ch4_length_timer: 62, ch4_current_volume: 0, ch4_env_period_timer: 0, ch4_lfsr: 0, ch4_frequency_timer: 0, output: [0, 0].to_vec(), last_clock_count: 100, sample_frequency: 10843, sampling_disabled: false, #[cfg(feature = "timing_profile")] update_time: 0, skip_samples_while_off: false, mono: false, paused: false, sample_mod: 21686, };
         let mut clock_count = sound.last_clock_count;
 
         let timer_start = sound.clone();
@@ -1518,7 +2370,7 @@ mod test {
     #[test]
     fn case2() {
         #[rustfmt::skip]
-        let mut sound = SoundController { nr10: 0, nr11: 0, nr12: 0, nr13: 0, nr14: 0, nr21: 0, nr22: 0, nr23: 0, nr24: 0, nr30: 0, nr31: 0, nr32: 0, nr33: 0, nr34: 0, ch3_wave_pattern: [65, 64, 67, 170, 45, 120, 208, 60, 225, 11, 239, 176, 52, 184, 46, 74], nr41: 0, nr42: 0, nr43: 0, nr44: 0, nr50: 0, nr51: 0, on: true, frame_sequencer_step: 0, ch1_channel_enable: false, ch1_length_timer: 0, ch1_sweep_enabled: false, ch1_shadow_freq: 0, ch1_sweep_timer: 0, ch1_has_done_sweep_calculation: false, ch1_frequency_timer: 0, ch1_wave_duty_position: 0, ch1_current_volume: 0, ch1_env_period_timer: 0, ch2_channel_enable: false, ch2_length_timer: 0, ch2_frequency_timer: 0, ch2_wave_duty_position: 0, ch2_current_volume: 0, ch2_env_period_timer: 0, ch3_channel_enable: false, ch3_length_timer: 0, ch3_frequency_timer: 0, ch3_wave_position: 0, ch3_sample_buffer: 0, ch3_wave_just_read: false, ch4_channel_enable: false, ch4_length_timer: 0, ch4_current_volume: 0, ch4_env_period_timer: 0, ch4_lfsr: 0, ch4_frequency_timer: 0, output: [0, 0, 0, 0].to_vec(), last_clock_count: 100, sample_frequency: 97408, sample_mod: 0 };
+        let mut sound = SoundController { nr10: 0, nr11: 0, nr12: 0, nr13: 0, nr14: 0, nr21: 0, nr22: 0, nr23: 0, nr24: 0, nr30: 0, nr31: 0, nr32: 0, nr33: 0, nr34: 0, ch3_wave_pattern: [65, 64, 67, 170, 45, 120, 208, 60, 225, 11, 239, 176, 52, 184, 46, 74], nr41: 0, nr42: 0, nr43: 0, nr44: 0, nr50: 0, nr51: 0, on: true, frame_sequencer_step: 0, frame_sequencer_skip: false, ch1_channel_enable: false, ch1_length_timer: 0, ch1_sweep_enabled: false, ch1_shadow_freq: 0, ch1_sweep_timer: 0, ch1_has_done_sweep_calculation: false, ch1_frequency_timer: 0, ch1_wave_duty_position: 0, ch1_current_volume: 0, ch1_env_period_timer: 0, ch2_channel_enable: false, ch2_length_timer: 0, ch2_frequency_timer: 0, ch2_wave_duty_position: 0, ch2_current_volume: 0, ch2_env_period_timer: 0, ch3_channel_enable: false, ch3_length_timer: 0, ch3_frequency_timer: 0, ch3_wave_position: 0, ch3_sample_buffer: 0, ch3_wave_just_read: false, ch4_channel_enable: false, ch4_length_timer: 0, ch4_current_volume: 0, ch4_env_period_timer: 0, ch4_lfsr: 0, ch4_frequency_timer: 0, output: [0, 0, 0, 0].to_vec(), last_clock_count: 100, sample_frequency: 97408, sampling_disabled: false, #[cfg(feature = "timing_profile")] update_time: 0, skip_samples_while_off: false, mono: false, paused: false, sample_mod: 0 };
         let mut clock_count = sound.last_clock_count;
 
         let timer_start = sound.clone();
@@ -1534,7 +2386,7 @@ mod test {
     #[test]
     fn case3() {
         #[rustfmt::skip]
-           let mut sound = SoundController { nr10: 0, nr11: 37, nr12: 0, nr13: 40, nr14: 0, nr21: 6, nr22: 0, nr23: 0, nr24: 0, nr30: 184, nr31: 148, nr32: 0, nr33: 91, nr34: 0, ch3_wave_pattern: [187, 26, 80, 4, 215, 120, 80, 50, 7, 255, 7, 52, 52, 67, 13, 15], nr41: 10, nr42: 0, nr43: 0, nr44: 0, nr50: 0, nr51: 0, on: true, frame_sequencer_step: 0, ch1_channel_enable: false, ch1_length_timer: 27, ch1_sweep_enabled: false, ch1_shadow_freq: 0, ch1_sweep_timer: 0, ch1_has_done_sweep_calculation: false, ch1_frequency_timer: 0, ch1_wave_duty_position: 0, ch1_current_volume: 0, ch1_env_period_timer: 0, ch2_channel_enable: false, ch2_length_timer: 58, ch2_frequency_timer: 0, ch2_wave_duty_position: 0, ch2_current_volume: 0, ch2_env_period_timer: 0, ch3_channel_enable: false, ch3_length_timer: 108, ch3_frequency_timer: 0, ch3_wave_position: 0, ch3_sample_buffer: 0, ch3_wave_just_read: false, ch4_channel_enable: false, ch4_length_timer: 54, ch4_current_volume: 0, ch4_env_period_timer: 0, ch4_lfsr: 0, ch4_frequency_timer: 0, output: Vec::new(), last_clock_count: 65536, sample_frequency: 111537, sample_mod: 80512 };
+           let mut sound = SoundController { nr10: 0, nr11: 37, nr12: 0, nr13: 40, nr14: 0, nr21: 6, nr22: 0, nr23: 0, nr24: 0, nr30: 184, nr31: 148, nr32: 0, nr33: 91, nr34: 0, ch3_wave_pattern: [187, 26, 80, 4, 215, 120, 80, 50, 7, 255, 7, 52, 52, 67, 13, 15], nr41: 10, nr42: 0, nr43: 0, nr44: 0, nr50: 0, nr51: 0, on: true, frame_sequencer_step: 0, frame_sequencer_skip: false, ch1_channel_enable: false, ch1_length_timer: 27, ch1_sweep_enabled: false, ch1_shadow_freq: 0, ch1_sweep_timer: 0, ch1_has_done_sweep_calculation: false, ch1_frequency_timer: 0, ch1_wave_duty_position: 0, ch1_current_volume: 0, ch1_env_period_timer: 0, ch2_channel_enable: false, ch2_length_timer: 58, ch2_frequency_timer: 0, ch2_wave_duty_position: 0, ch2_current_volume: 0, ch2_env_period_timer: 0, ch3_channel_enable: false, ch3_length_timer: 108, ch3_frequency_timer: 0, ch3_wave_position: 0, ch3_sample_buffer: 0, ch3_wave_just_read: false, ch4_channel_enable: false, ch4_length_timer: 54, ch4_current_volume: 0, ch4_env_period_timer: 0, ch4_lfsr: 0, ch4_frequency_timer: 0, output: Vec::new(), last_clock_count: 65536, sample_frequency: 111537, sampling_disabled: false, #[cfg(feature = "timing_profile")] update_time: 0, skip_samples_while_off: false, mono: false, paused: false, sample_mod: 80512 };
         let mut clock_count = sound.last_clock_count;
 
         let timer_start = sound.clone();
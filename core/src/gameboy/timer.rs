@@ -1,4 +1,4 @@
-use crate::save_state::{LoadStateError, SaveState};
+use crate::save_state::{check_clock_count, LoadStateError, SaveState};
 
 #[derive(Default, Debug, Eq, Clone)]
 pub struct Timer {
@@ -50,7 +50,7 @@ impl PartialEq for Timer {
     }
 }
 crate::save_state!(Timer, self, ctx, data {
-    on_save debug_assert_eq!(self.last_clock_count, ctx.clock_count.unwrap());
+    on_save check_clock_count(ctx, "timer", self.last_clock_count)?;
     on_load self.next_interrupt = 0;
 
     self.div;
@@ -9,7 +9,7 @@ use crate::{
     },
 };
 
-#[derive(PartialEq, Eq, Clone, Copy)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum Condition {
     None,
     Z,
@@ -68,8 +68,32 @@ fn add(a: u8, b: u8) -> u8 {
 pub struct Interpreter<'a>(pub &'a mut GameBoy);
 impl Interpreter<'_> {
     pub fn interpret_op(&mut self) {
-        if let ControlFlow::Break(_) = self.handle_interrupt() {
-            return;
+        let _ = self.interpret_op_or_interrupt();
+    }
+
+    /// Same as `interpret_op`, but return `Break` if a interrupt was serviced, instead of
+    /// executing a opcode.
+    #[inline(always)]
+    pub fn interpret_op_or_interrupt(&mut self) -> ControlFlow<()> {
+        #[cfg(feature = "timing_profile")]
+        let start = std::time::Instant::now();
+
+        let result = self.execute_op_or_interrupt();
+
+        #[cfg(feature = "timing_profile")]
+        {
+            self.0.profile.borrow_mut().interpreter += start.elapsed().as_nanos() as u64;
+        }
+
+        result
+    }
+
+    #[inline(always)]
+    fn execute_op_or_interrupt(&mut self) -> ControlFlow<()> {
+        self.handle_interrupt()?;
+
+        if self.0.capture_trigger.is_some() {
+            self.0.check_capture_pc();
         }
 
         if self.0.cpu.ime == ImeState::ToBeEnable {
@@ -77,28 +101,20 @@ impl Interpreter<'_> {
         }
 
         if self.0.cpu.state != CpuState::Running {
-            return;
+            return ControlFlow::Continue(());
+        }
+
+        if self.0.cpu_trace.is_some() {
+            self.0.write_cpu_trace();
+        }
+
+        // code in RAM is not traced by `jump_to`, so it is recorded as it is executed.
+        if self.0.trace_ram_code && self.0.cpu.pc >= 0x8000 {
+            self.0.trace_ram_opcode();
         }
 
         use Condition::*;
         let op = self.read_next_pc();
-        let trace = false;
-        if trace {
-            println!(
-                "{:04x}: {:02x} {:04x} {:02x} {:02x} {:02x} {:02x} {:02x} {:02x} {:02x} {:02x}",
-                self.0.cpu.pc,
-                op,
-                self.0.cpu.sp,
-                self.0.cpu.a,
-                self.0.cpu.f.0,
-                self.0.cpu.b,
-                self.0.cpu.c,
-                self.0.cpu.d,
-                self.0.cpu.e,
-                self.0.cpu.h,
-                self.0.cpu.l,
-            );
-        }
         match op {
             // NOP 1:4 - - - -
             0x00 => self.nop(),
@@ -613,6 +629,7 @@ impl Interpreter<'_> {
             // RST 38H 1:16 - - - -
             0xff => self.rst(0x38),
         }
+        ControlFlow::Continue(())
     }
 
     #[inline(always)]
@@ -1,8 +1,16 @@
+pub mod apu_dump;
+pub mod audio_ring;
 pub mod consts;
 pub mod debugger;
 pub mod diff_stack;
 pub mod disassembler;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod frame_stepper;
 pub mod gameboy;
 pub mod interpreter;
+pub mod log;
 pub mod parser;
+pub mod save_slots;
 pub mod save_state;
+pub mod scale;
@@ -0,0 +1,99 @@
+//! A lightweight logging hook.
+//!
+//! Debug output of the emulator (like the JIT compilation events, or the debugger actions) is
+//! routed to a `Logger`, instead of being printed to stdout. By default no sink is installed, and
+//! all messages are discarded, so a frontend that wants to show them must install one, like the
+//! one of `Logger::stderr`.
+
+use std::fmt;
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+/// A single log message.
+pub struct Record<'a> {
+    pub level: Level,
+    /// The component that emitted this message, like "jit" or "debugger".
+    pub target: &'static str,
+    pub args: fmt::Arguments<'a>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub type LogSink = Box<dyn FnMut(&Record) + Send>;
+#[cfg(target_arch = "wasm32")]
+pub type LogSink = Box<dyn FnMut(&Record)>;
+
+pub struct Logger {
+    /// The sink where records are sent to. If None, all records are discarded.
+    pub sink: Option<LogSink>,
+    /// Records less severe than this level are discarded.
+    pub max_level: Level,
+}
+impl Default for Logger {
+    fn default() -> Self {
+        Self {
+            sink: None,
+            max_level: Level::Trace,
+        }
+    }
+}
+impl fmt::Debug for Logger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Logger")
+            .field("sink", &self.sink.is_some())
+            .field("max_level", &self.max_level)
+            .finish()
+    }
+}
+impl Logger {
+    pub fn new(sink: LogSink) -> Self {
+        Self {
+            sink: Some(sink),
+            ..Self::default()
+        }
+    }
+
+    /// A logger that prints the records up to `max_level` to stderr.
+    pub fn stderr(max_level: Level) -> Self {
+        Self {
+            sink: Some(Box::new(|record: &Record| {
+                eprintln!("[{:?} {}] {}", record.level, record.target, record.args)
+            })),
+            max_level,
+        }
+    }
+
+    /// Return true if a record of the given level would be send to the sink.
+    pub fn enabled(&self, level: Level) -> bool {
+        self.sink.is_some() && level <= self.max_level
+    }
+
+    pub fn log(&mut self, level: Level, target: &'static str, args: fmt::Arguments) {
+        if level > self.max_level {
+            return;
+        }
+        if let Some(sink) = &mut self.sink {
+            sink(&Record {
+                level,
+                target,
+                args,
+            });
+        }
+    }
+}
+
+/// Log a message to a `Logger`, in the format `log!(logger, Level, target, "fmt", args...)`.
+#[macro_export]
+macro_rules! log {
+    ($logger:expr, $level:ident, $target:expr, $($arg:tt)+) => {
+        if $logger.enabled($crate::log::Level::$level) {
+            $logger.log($crate::log::Level::$level, $target, format_args!($($arg)+))
+        }
+    };
+}
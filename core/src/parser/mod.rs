@@ -1,4 +1,6 @@
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{BufRead, Read, Seek, SeekFrom};
+
+use crate::scale::Rgba;
 
 fn read_u32(file: &mut impl Read) -> Result<u32, std::io::Error> {
     let mut value = [0; 4];
@@ -243,3 +245,86 @@ pub fn vbm(file: &mut (impl Read + Seek)) -> Result<Vbm, VbmParseError> {
         controller_data,
     })
 }
+
+#[derive(Debug)]
+pub enum PalParseError {
+    InvalidHeader,
+    InvalidVersion(String),
+    /// The number of colors is not 4.
+    InvalidColorCount(String),
+    /// The color in the given line, counting from 1, is not three numbers from 0 to 255.
+    InvalidColor(usize),
+    IoError(std::io::Error),
+}
+impl From<std::io::Error> for PalParseError {
+    fn from(v: std::io::Error) -> Self {
+        Self::IoError(v)
+    }
+}
+
+#[test]
+fn parse_pal() {
+    let file = "JASC-PAL\r\n0100\r\n4\r\n224 248 208\r\n136 192 112\r\n52 104 86\r\n8 24 32\r\n";
+    assert_eq!(
+        pal(file.as_bytes()).unwrap(),
+        [
+            [224, 248, 208, 255],
+            [136, 192, 112, 255],
+            [52, 104, 86, 255],
+            [8, 24, 32, 255],
+        ]
+    );
+
+    let invalid = [
+        "RIFF\n0100\n4\n0 0 0\n0 0 0\n0 0 0\n0 0 0\n",
+        "JASC-PAL\n0200\n4\n0 0 0\n0 0 0\n0 0 0\n0 0 0\n",
+        "JASC-PAL\n0100\n16\n0 0 0\n0 0 0\n0 0 0\n0 0 0\n",
+        "JASC-PAL\n0100\n4\n0 0 0\n0 0 256\n0 0 0\n0 0 0\n",
+        "JASC-PAL\n0100\n4\n0 0 0\n0 0\n0 0 0\n0 0 0\n",
+        "JASC-PAL\n0100\n4\n0 0 0\n0 0 0\n",
+    ];
+    for file in invalid {
+        assert!(pal(file.as_bytes()).is_err(), "{:?}", file);
+    }
+    assert!(matches!(
+        pal(invalid[3].as_bytes()),
+        Err(PalParseError::InvalidColor(5))
+    ));
+}
+
+/// Parse a palette in the JASC-PAL format, returning its 4 colors, from lightest to darkest, for
+/// `scale::expand_colors`. The alpha is always 255.
+pub fn pal(file: impl BufRead) -> Result<[Rgba; 4], PalParseError> {
+    // The format is a text file, with a line for each of: the "JASC-PAL" signature, the version
+    // "0100", the number of colors, and then each color as its red, green and blue components.
+    let mut lines = file.lines();
+    let mut next_line = || -> Result<Option<String>, std::io::Error> {
+        Ok(lines.next().transpose()?.map(|x| x.trim().to_string()))
+    };
+
+    if next_line()?.as_deref() != Some("JASC-PAL") {
+        return Err(PalParseError::InvalidHeader);
+    }
+    let version = next_line()?.unwrap_or_default();
+    if version != "0100" {
+        return Err(PalParseError::InvalidVersion(version));
+    }
+    let count = next_line()?.unwrap_or_default();
+    if count != "4" {
+        return Err(PalParseError::InvalidColorCount(count));
+    }
+
+    let mut colors = [[0, 0, 0, 255]; 4];
+    for (i, color) in colors.iter_mut().enumerate() {
+        let line = next_line()?.unwrap_or_default();
+        let components = line
+            .split_whitespace()
+            .map(|x| x.parse::<u8>())
+            .collect::<Result<Vec<u8>, _>>();
+        match components.as_deref() {
+            Ok(&[r, g, b]) => *color = [r, g, b, 255],
+            _ => return Err(PalParseError::InvalidColor(i + 4)),
+        }
+    }
+    Ok(colors)
+}
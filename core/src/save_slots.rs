@@ -0,0 +1,228 @@
+//! Numbered save state slots, like the ones offered by most frontends.
+//!
+//! Each slot is keyed by the `Cartridge::header_hash` of the ROM it was saved from, so slots of
+//! different games don't mix, and stores some metadata that can be displayed in a slot list.
+
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+};
+
+use crate::{
+    consts::FRAME_CYCLES,
+    gameboy::GameBoy,
+    save_state::{LoadStateError, SaveState, SaveStateContext},
+};
+
+/// The metadata of a save state slot.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct SlotInfo {
+    /// The instant that the state was saved, in number of milliseconds since the UNIX_EPOCH.
+    pub timestamp: Option<u64>,
+    /// The clock_count of the GameBoy when saved.
+    pub clock_count: u64,
+    /// The screen when saved, one shade (from 0 to 3) per pixel, like `Screen::packed`.
+    pub thumbnail: Vec<u8>,
+}
+impl SlotInfo {
+    /// The number of frames since the GameBoy was powered on, until this state was saved.
+    pub fn frames(&self) -> u64 {
+        self.clock_count / FRAME_CYCLES
+    }
+}
+
+struct Slot {
+    info: SlotInfo,
+    state: Vec<u8>,
+}
+
+/// Stores save states in numbered slots, in memory. The slots can be persisted with `write` and
+/// `read`.
+#[derive(Default)]
+pub struct SaveStateManager {
+    /// The slots, keyed by the rom header hash and the slot number.
+    slots: BTreeMap<(u64, u32), Slot>,
+}
+
+impl SaveStateManager {
+    /// "GameRoy Save Slots" magic constant.
+    const MAGIC_CONST: [u8; 4] = *b"GRSS";
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Save the state of `gb` in the given slot, replacing the state that was there.
+    pub fn save(
+        &mut self,
+        gb: &GameBoy,
+        slot: u32,
+        timestamp: Option<u64>,
+    ) -> Result<(), std::io::Error> {
+        let mut state = Vec::new();
+        gb.save_state(timestamp, &mut state)?;
+        let info = SlotInfo {
+            timestamp,
+            clock_count: gb.clock_count,
+            thumbnail: gb.ppu.borrow_mut().screen().packed().to_vec(),
+        };
+        self.slots
+            .insert((gb.cartridge.header_hash(), slot), Slot { info, state });
+        Ok(())
+    }
+
+    /// Load the state in the given slot, saved from the same rom of `gb`. Return Ok(false) if the
+    /// slot is empty.
+    pub fn load(&self, gb: &mut GameBoy, slot: u32) -> Result<bool, LoadStateError> {
+        let Some(slot) = self.slots.get(&(gb.cartridge.header_hash(), slot)) else {
+            return Ok(false);
+        };
+        gb.load_state(&mut slot.state.as_slice())?;
+        Ok(true)
+    }
+
+    /// The metadata of the slot, if not empty.
+    pub fn info(&self, header_hash: u64, slot: u32) -> Option<&SlotInfo> {
+        self.slots.get(&(header_hash, slot)).map(|x| &x.info)
+    }
+
+    /// List the non empty slots of the rom with the given header hash, in order of slot number.
+    pub fn list(&self, header_hash: u64) -> impl Iterator<Item = (u32, &SlotInfo)> + '_ {
+        self.slots
+            .range((header_hash, 0)..=(header_hash, u32::MAX))
+            .map(|(&(_, slot), x)| (slot, &x.info))
+    }
+
+    /// Delete the slot. Return false if it was already empty.
+    pub fn delete(&mut self, header_hash: u64, slot: u32) -> bool {
+        self.slots.remove(&(header_hash, slot)).is_some()
+    }
+
+    /// Write all slots, of all roms.
+    pub fn write(&self, data: &mut impl Write) -> Result<(), std::io::Error> {
+        let ctx = &mut SaveStateContext::default();
+        Self::MAGIC_CONST.save_state(ctx, data)?;
+        (self.slots.len() as u32).save_state(ctx, data)?;
+        for (&(header_hash, slot), Slot { info, state }) in &self.slots {
+            header_hash.save_state(ctx, data)?;
+            slot.save_state(ctx, data)?;
+            info.timestamp.unwrap_or(u64::MAX).save_state(ctx, data)?;
+            info.clock_count.save_state(ctx, data)?;
+            info.thumbnail.save_state(ctx, data)?;
+            state.save_state(ctx, data)?;
+        }
+        Ok(())
+    }
+
+    /// Read the slots written by `write`.
+    pub fn read(data: &mut impl Read) -> Result<Self, LoadStateError> {
+        let ctx = &mut SaveStateContext::default();
+        let mut magic = [0u8; 4];
+        magic.load_state(ctx, data)?;
+        if magic != Self::MAGIC_CONST {
+            return Err(LoadStateError::InvalidMagicConst(magic));
+        }
+
+        let mut len = 0u32;
+        len.load_state(ctx, data)?;
+        let mut this = Self::new();
+        for _ in 0..len {
+            let (mut header_hash, mut slot, mut timestamp) = (0u64, 0u32, 0u64);
+            header_hash.load_state(ctx, data)?;
+            slot.load_state(ctx, data)?;
+            timestamp.load_state(ctx, data)?;
+            let mut info = SlotInfo {
+                timestamp: (timestamp != u64::MAX).then_some(timestamp),
+                clock_count: 0,
+                thumbnail: Vec::new(),
+            };
+            info.clock_count.load_state(ctx, data)?;
+            info.thumbnail.load_state(ctx, data)?;
+            let mut state = Vec::new();
+            state.load_state(ctx, data)?;
+            this.slots.insert((header_hash, slot), Slot { info, state });
+        }
+        Ok(this)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        consts::{SCREEN_HEIGHT, SCREEN_WIDTH},
+        gameboy::cartridge::Cartridge,
+        interpreter::Interpreter,
+    };
+
+    fn cartridge(title: &[u8]) -> Cartridge {
+        let mut rom = Cartridge::nop_rom();
+        rom[0x134..0x134 + title.len()].copy_from_slice(title);
+        rom[0x100..0x104].copy_from_slice(&[0x04, 0x18, 0xfd, 0x00]); // INC B; JR -3
+        Cartridge::new(rom).unwrap()
+    }
+
+    fn run_frames(gb: &mut GameBoy, frames: u64) {
+        let target = gb.clock_count + frames * FRAME_CYCLES;
+        while gb.clock_count < target {
+            Interpreter(gb).interpret_op();
+        }
+    }
+
+    #[test]
+    fn save_and_load_slots() {
+        let mut gb = GameBoy::new(None, cartridge(b"GAME A"));
+        let rom = gb.cartridge.header_hash();
+        let mut manager = SaveStateManager::new();
+
+        run_frames(&mut gb, 2);
+        manager.save(&gb, 1, Some(1000)).unwrap();
+        let first = (
+            gb.state_hash(),
+            gb.clock_count,
+            gb.ppu.borrow_mut().screen().packed(),
+        );
+
+        run_frames(&mut gb, 3);
+        manager.save(&gb, 4, None).unwrap();
+        let second = gb.state_hash();
+
+        let slots: Vec<_> = manager.list(rom).collect();
+        assert_eq!(slots.len(), 2);
+        assert_eq!(slots[0].0, 1);
+        assert_eq!(slots[0].1.timestamp, Some(1000));
+        assert_eq!(slots[0].1.clock_count, first.1);
+        assert_eq!(slots[0].1.frames(), first.1 / FRAME_CYCLES);
+        assert_eq!(slots[0].1.thumbnail.len(), SCREEN_WIDTH * SCREEN_HEIGHT);
+        assert_eq!(slots[0].1.thumbnail, first.2);
+        assert_eq!(slots[1].0, 4);
+        assert_eq!(slots[1].1.timestamp, None);
+        assert_eq!(slots[1].1.frames() - slots[0].1.frames(), 3);
+
+        // persist the slots
+        let mut data = Vec::new();
+        manager.write(&mut data).unwrap();
+        let mut manager = SaveStateManager::read(&mut data.as_slice()).unwrap();
+        assert_eq!(manager.list(rom).count(), 2);
+
+        let mut other = GameBoy::new(None, cartridge(b"GAME A"));
+        assert!(!manager.load(&mut other, 2).unwrap());
+        assert!(manager.load(&mut other, 1).unwrap());
+        assert_eq!(other.state_hash(), first.0);
+        assert!(manager.load(&mut other, 4).unwrap());
+        assert_eq!(other.state_hash(), second);
+
+        // the slots of other roms are kept apart
+        let mut game_b = GameBoy::new(None, cartridge(b"GAME B"));
+        assert_ne!(game_b.cartridge.header_hash(), rom);
+        assert!(!manager.load(&mut game_b, 1).unwrap());
+        manager.save(&game_b, 1, None).unwrap();
+        assert_eq!(manager.list(rom).count(), 2);
+        assert_eq!(manager.info(rom, 1).unwrap().timestamp, Some(1000));
+
+        assert!(manager.delete(rom, 1));
+        assert!(!manager.delete(rom, 1));
+        assert_eq!(manager.list(rom).map(|x| x.0).collect::<Vec<_>>(), [4]);
+        assert!(manager.load(&mut game_b, 1).unwrap());
+    }
+}
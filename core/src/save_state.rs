@@ -1,11 +1,13 @@
 use std::cell::Cell;
 use std::io::{Read, Write};
+use std::rc::Rc;
 
 #[derive(Debug)]
 pub enum LoadStateError {
     InvalidImeState(u8),
     InvalidState(u8),
     InvalidPpuMode(u8),
+    InvalidModel(u8),
     InvalidBool(u8),
     InvalidBoolBitArray(u8, u8),
     SoundControllerDesync(u64, u64),
@@ -14,6 +16,37 @@ pub enum LoadStateError {
     InvalidMagicConst([u8; 4]),
     UnknownVersion(u32),
 }
+impl std::fmt::Display for LoadStateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidImeState(x) => write!(f, "invalid IME state '{:02x}'", x),
+            Self::InvalidState(x) => write!(f, "invalid CPU state '{:02x}'", x),
+            Self::InvalidPpuMode(x) => write!(f, "invalid PPU mode '{:02x}'", x),
+            Self::InvalidModel(x) => write!(f, "invalid model '{:02x}'", x),
+            Self::InvalidBool(x) => write!(f, "invalid bool value '{:02x}'", x),
+            Self::InvalidBoolBitArray(x, mask) => {
+                write!(
+                    f,
+                    "invalid bool bit array '{:02x}' (mask '{:02x}')",
+                    x, mask
+                )
+            }
+            Self::SoundControllerDesync(a, b) => {
+                write!(f, "sound controller desync: clock {} != {}", a, b)
+            }
+            Self::ConstMismatch(loaded, expected) => {
+                write!(f, "expected constant {}, found {}", expected, loaded)
+            }
+            Self::IoError(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                write!(f, "save state is truncated")
+            }
+            Self::IoError(err) => write!(f, "io error: {}", err),
+            Self::InvalidMagicConst(x) => write!(f, "not a save state, magic constant is {:?}", x),
+            Self::UnknownVersion(x) => write!(f, "unknown save state version {}", x),
+        }
+    }
+}
+impl std::error::Error for LoadStateError {}
 impl From<std::io::Error> for LoadStateError {
     fn from(error: std::io::Error) -> Self {
         Self::IoError(error)
@@ -29,6 +62,12 @@ pub struct SaveStateContext {
     pub time: Option<u64>,
     /// The clock_count of the GameBoy.
     pub clock_count: Option<u64>,
+    /// If set, the fields being saved are recorded in it. See `StateLayout`.
+    pub layout: Option<StateLayout>,
+    /// Check the consistency of the clocks of the components in release builds too, returning a
+    /// error instead of saving or loading a inconsistent state. Otherwise, they are only asserted
+    /// in debug builds.
+    pub strict: bool,
 }
 
 impl SaveStateContext {
@@ -37,6 +76,8 @@ impl SaveStateContext {
             version: SaveStateHeader::SAVE_STATE_VERSION,
             time,
             clock_count: Some(clock_count),
+            layout: None,
+            strict: false,
         }
     }
 }
@@ -47,8 +88,96 @@ impl Default for SaveStateContext {
             version: SaveStateHeader::SAVE_STATE_VERSION,
             time: None,
             clock_count: None,
+            layout: None,
+            strict: false,
+        }
+    }
+}
+
+/// Describes what each byte of a save state represents.
+///
+/// While saving with a `StateLayout` in the context, each field saved by the `save_state!` macro
+/// (or between `begin_field` and `end_field`) is recorded, with its path (like `ppu.ly`), offset and
+/// length. Only the innermost fields are listed, so they cover the entire save state, in order.
+#[derive(Clone, Default)]
+pub struct StateLayout {
+    /// The number of bytes written so far.
+    position: Rc<Cell<usize>>,
+    path: Vec<String>,
+    entries: Vec<(String, usize, usize)>,
+}
+impl StateLayout {
+    /// Save `value`, and return the path, offset and length of each field written.
+    pub fn of(value: &impl SaveState, mut ctx: SaveStateContext) -> Vec<(String, usize, usize)> {
+        struct Counter(Rc<Cell<usize>>);
+        impl Write for Counter {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.set(self.0.get() + buf.len());
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
         }
+
+        let layout = Self::default();
+        let mut counter = Counter(layout.position.clone());
+        ctx.layout = Some(layout);
+        value
+            .save_state(&mut ctx, &mut counter)
+            .expect("counting bytes never fails");
+        ctx.layout.unwrap().entries
+    }
+}
+
+/// Mark the start of the field `name` in the save state, if its layout is being recorded. Must be
+/// paired with a `end_field`, receiving the returned value.
+pub fn begin_field(ctx: &mut SaveStateContext, name: &str) -> Option<(usize, usize)> {
+    let layout = ctx.layout.as_mut()?;
+    let name = name.replace("self.", "");
+    let name = name.trim_end_matches(".borrow_mut()");
+    layout
+        .path
+        .push(name.split_whitespace().collect::<Vec<_>>().join(" "));
+    Some((layout.position.get(), layout.entries.len()))
+}
+
+/// Mark the end of the field started by `begin_field`.
+pub fn end_field(ctx: &mut SaveStateContext, start: Option<(usize, usize)>) {
+    let (Some(layout), Some((offset, entries))) = (ctx.layout.as_mut(), start) else {
+        return;
+    };
+    let path = layout.path.join(".");
+    layout.path.pop();
+    let len = layout.position.get() - offset;
+    // only record the innermost fields
+    if layout.entries.len() == entries && len != 0 {
+        layout.entries.push((path, offset, len));
+    }
+}
+
+/// Check that a lazily updated component, being saved, was updated up to the clock_count of `ctx`.
+/// A mismatch is a bug, that panics in debug builds, or is returned as a error if `ctx.strict`.
+pub fn check_clock_count(
+    ctx: &SaveStateContext,
+    component: &str,
+    last_clock_count: u64,
+) -> Result<(), std::io::Error> {
+    let Some(clock_count) = ctx.clock_count else {
+        return Ok(());
+    };
+    if ctx.strict && last_clock_count != clock_count {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "{} is at clock {}, but the GameBoy is at {}",
+                component, last_clock_count, clock_count
+            ),
+        ));
     }
+    debug_assert_eq!(last_clock_count, clock_count, "{}", component);
+    Ok(())
 }
 
 /// The Header of a save state. Contains some metadata like version and time of save.
@@ -56,7 +185,7 @@ impl Default for SaveStateContext {
 pub struct SaveStateHeader;
 impl SaveStateHeader {
     /// The current version of the save state format
-    const SAVE_STATE_VERSION: u32 = 3;
+    const SAVE_STATE_VERSION: u32 = 7;
 
     /// "GameRoy Save State" magic contant.
     const MAGIC_CONST: [u8; 4] = *b"GRST";
@@ -110,6 +239,10 @@ impl SaveState for SaveStateHeader {
     }
 }
 
+/// A type that can be written to and read from a save state.
+///
+/// Integers are always written in big-endian order, independently of the host, so save states are
+/// portable between platforms. `usize` is never written directly: lengths are written as `u32`.
 pub trait SaveState {
     fn save_state(
         &self,
@@ -173,7 +306,11 @@ macro_rules! save_state {
     (@accum ($n:ident, $s:ident, $ctx:ident, $d:ident, const $e:expr; $($f:tt)* ) -> ($($save:tt)*) -> ($($load:tt)*)) => {
         $crate::save_state!(
             @accum ($n, $s, $ctx, $d, $($f)* )
-            -> ($($save)* ($e).save_state($ctx, $d)?; )
+            -> ($($save)* {
+                let field = $crate::save_state::begin_field($ctx, stringify!($e));
+                ($e).save_state($ctx, $d)?;
+                $crate::save_state::end_field($ctx, field);
+            })
             -> ($($load)* {
                 let expected = $e;
                 let mut loaded = expected;
@@ -188,7 +325,12 @@ macro_rules! save_state {
     (@accum ($n:ident, $s:ident, $ctx:ident, $d:ident, bitset [ $($e:expr),* ]; $($f:tt)* ) -> ($($save:tt)*) -> ($($load:tt)*)) => {
         $crate::save_state!(
             @accum ($n, $s, $ctx, $d, $($f)* )
-            -> ($($save)* { use $crate::save_state::BoolExt; [ $( &   $e.get()),* ].save_state($ctx, $d)?; } )
+            -> ($($save)* {
+                use $crate::save_state::BoolExt;
+                let field = $crate::save_state::begin_field($ctx, stringify!($($e)|*));
+                [ $( &   $e.get()),* ].save_state($ctx, $d)?;
+                $crate::save_state::end_field($ctx, field);
+            })
             -> ($($load)* { use $crate::save_state::BoolExt; [ $( $e.get_mut()),* ].load_state($ctx, $d)?; } )
         );
     };
@@ -220,7 +362,11 @@ macro_rules! save_state {
     (@accum ($n:ident, $s:ident, $ctx:ident, $d:ident, $e:expr; $($f:tt)* ) -> ($($save:tt)*) -> ($($load:tt)*)) => {
         $crate::save_state!(
             @accum ($n, $s, $ctx, $d, $($f)* )
-            -> ($($save)* ($e).save_state($ctx, $d)?; )
+            -> ($($save)* {
+                let field = $crate::save_state::begin_field($ctx, stringify!($e));
+                ($e).save_state($ctx, $d)?;
+                $crate::save_state::end_field($ctx, field);
+            })
             -> ($($load)* ($e).load_state($ctx, $d)?; )
         );
     };
@@ -451,3 +597,48 @@ impl<T: SaveState + Default + Copy> SaveState for Cell<T> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integer_byte_order() {
+        let ctx = &mut SaveStateContext::default();
+        let mut data = Vec::new();
+        0x12u8.save_state(ctx, &mut data).unwrap();
+        0x1234u16.save_state(ctx, &mut data).unwrap();
+        0x1234_5678u32.save_state(ctx, &mut data).unwrap();
+        0x0123_4567_89ab_cdefu64.save_state(ctx, &mut data).unwrap();
+        vec![0xAAu8, 0xBB].save_state(ctx, &mut data).unwrap();
+        assert_eq!(
+            data,
+            [
+                0x12, // u8
+                0x12, 0x34, // u16
+                0x12, 0x34, 0x56, 0x78, // u32
+                0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, // u64
+                0x00, 0x00, 0x00, 0x02, 0xAA, 0xBB, // Vec<u8>, with a u32 length
+            ]
+        );
+
+        let (mut a, mut b, mut c, mut d, mut e) = (0u8, 0u16, 0u32, 0u64, Vec::new());
+        let data = &mut data.as_slice();
+        a.load_state(ctx, data).unwrap();
+        b.load_state(ctx, data).unwrap();
+        c.load_state(ctx, data).unwrap();
+        d.load_state(ctx, data).unwrap();
+        e.load_state(ctx, data).unwrap();
+        assert_eq!(
+            (a, b, c, d, e),
+            (
+                0x12,
+                0x1234,
+                0x1234_5678,
+                0x0123_4567_89ab_cdef,
+                vec![0xAA, 0xBB]
+            )
+        );
+        assert!(data.is_empty());
+    }
+}
@@ -0,0 +1,244 @@
+//! CPU upscaling of the LCD frame, for frontends that does not render through a GPU, and for
+//! screenshots.
+//!
+//! This is kept out of the emulation path: it operates on a frame that was already expanded to
+//! RGBA colors, like the one returned by [`expand_colors`].
+//!
+//! The expansion can optionally apply a [`ColorCorrection`], approximating the colors of a real
+//! LCD.
+
+use crate::consts::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/// A pixel in RGBA format.
+pub type Rgba = [u8; 4];
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum Filter {
+    /// Each pixel becomes a `factor`x`factor` block of the same color.
+    #[default]
+    Nearest,
+    /// Like `Nearest`, but the last row of each block is darkened.
+    Scanline,
+    /// Like `Nearest`, but the last row and the last column of each block is darkened, emulating
+    /// the gaps between the pixels of the LCD.
+    LcdGrid,
+}
+
+/// Darken a color in the grid lines of the `Scanline` and `LcdGrid` filters. The RGB channels are
+/// multiplied by 3/4, and the alpha is kept the same.
+pub fn darken(color: Rgba) -> Rgba {
+    let [r, g, b, a] = color;
+    let d = |c: u8| (c as u16 * 3 / 4) as u8;
+    [d(r), d(g), d(b), a]
+}
+
+/// A correction of the colors, approximating the non-linear response of a real LCD.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum ColorCorrection {
+    /// The colors are kept as they are.
+    #[default]
+    None,
+    /// The DMG LCD: the luminance of the color goes through a gamma curve, and is mapped to the
+    /// green shades between `DMG_DARKEST` and `DMG_LIGHTEST`.
+    Dmg,
+    /// The CGB LCD: the color is reduced to 15-bit, and the channels are mixed and dimmed, like
+    /// the color correction of higan and SameBoy.
+    Cgb,
+}
+
+/// The darkest color of the DMG LCD, used by `ColorCorrection::Dmg`.
+pub const DMG_DARKEST: Rgba = [0x0F, 0x38, 0x0F, 0xFF];
+/// The lightest color of the DMG LCD, used by `ColorCorrection::Dmg`.
+pub const DMG_LIGHTEST: Rgba = [0x9B, 0xBC, 0x0F, 0xFF];
+/// The gamma of the DMG LCD response, used by `ColorCorrection::Dmg`.
+const DMG_GAMMA: f32 = 1.5;
+
+/// Apply the color correction to a single color. The alpha is kept the same.
+pub fn correct_color(color: Rgba, correction: ColorCorrection) -> Rgba {
+    let [r, g, b, a] = color;
+    match correction {
+        ColorCorrection::None => color,
+        ColorCorrection::Dmg => {
+            let luma = (r as u32 * 299 + g as u32 * 587 + b as u32 * 114) as f32 / (1000.0 * 255.0);
+            let level = luma.powf(DMG_GAMMA);
+            let mix = |i: usize| {
+                let (dark, light) = (DMG_DARKEST[i] as f32, DMG_LIGHTEST[i] as f32);
+                (dark + (light - dark) * level).round() as u8
+            };
+            [mix(0), mix(1), mix(2), a]
+        }
+        ColorCorrection::Cgb => {
+            let (r, g, b) = (r as u16 >> 3, g as u16 >> 3, b as u16 >> 3);
+            let c = |x: u16| (x.min(960) >> 2) as u8;
+            [
+                c(r * 26 + g * 4 + b * 2),
+                c(g * 24 + b * 8),
+                c(r * 6 + g * 4 + b * 22),
+                a,
+            ]
+        }
+    }
+}
+
+/// Expand a packed frame, like the one returned by `Screen::packed`, to RGBA, using `palette` to
+/// map each of the 4 shades.
+pub fn expand_colors(frame: &[u8; SCREEN_WIDTH * SCREEN_HEIGHT], palette: &[Rgba; 4]) -> Vec<Rgba> {
+    frame.iter().map(|&c| palette[c as usize & 0b11]).collect()
+}
+
+/// Expand a frame with the layer of each pixel, like the one returned by
+/// `Screen::packed_with_layers`, to RGBA, using a different palette for each layer. `palettes` is
+/// indexed by `Layer`: the background and window, then the sprites with OBP0 and with OBP1.
+pub fn expand_colors_layered(
+    frame: &[u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+    palettes: &[[Rgba; 4]; 3],
+) -> Vec<Rgba> {
+    frame
+        .iter()
+        .map(|&c| {
+            let layer = (c as usize >> 2).min(2);
+            palettes[layer][c as usize & 0b11]
+        })
+        .collect()
+}
+
+/// Like `expand_colors`, but with the given color correction applied to the palette.
+pub fn expand_colors_corrected(
+    frame: &[u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+    palette: &[Rgba; 4],
+    correction: ColorCorrection,
+) -> Vec<Rgba> {
+    expand_colors(frame, &palette.map(|x| correct_color(x, correction)))
+}
+
+/// Scale a `width`x`height` frame by an integer `factor`, applying the given filter. The returned
+/// buffer have `width * factor` by `height * factor` pixels.
+///
+/// With a factor of 1 the grid lines are not drawn, and all filters are equivalent to `Nearest`.
+pub fn scale(
+    frame: &[Rgba],
+    width: usize,
+    height: usize,
+    factor: usize,
+    filter: Filter,
+) -> Vec<Rgba> {
+    assert_eq!(frame.len(), width * height);
+    assert!(factor > 0);
+
+    let out_width = width * factor;
+    let mut out = vec![[0; 4]; out_width * height * factor];
+    for y in 0..height * factor {
+        let grid_row = factor > 1 && y % factor == factor - 1;
+        for x in 0..out_width {
+            let grid_col = factor > 1 && x % factor == factor - 1;
+            let color = frame[(y / factor) * width + x / factor];
+            let grid = match filter {
+                Filter::Nearest => false,
+                Filter::Scanline => grid_row,
+                Filter::LcdGrid => grid_row || grid_col,
+            };
+            out[y * out_width + x] = if grid { darken(color) } else { color };
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: Rgba = [255, 255, 255, 255];
+    const B: Rgba = [0, 0, 0, 255];
+    const C: Rgba = [200, 100, 40, 255];
+    const D: Rgba = [8, 16, 32, 128];
+
+    #[test]
+    fn nearest_2x() {
+        let frame = [A, B, C, D, B, A];
+        let scaled = scale(&frame, 3, 2, 2, Filter::Nearest);
+
+        assert_eq!(scaled.len(), 6 * 4);
+        for y in 0..4 {
+            for x in 0..6 {
+                assert_eq!(
+                    scaled[y * 6 + x],
+                    frame[(y / 2) * 3 + x / 2],
+                    "at {} {}",
+                    x,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn lcd_grid() {
+        let frame = [C, D];
+        let scaled = scale(&frame, 2, 1, 3, Filter::LcdGrid);
+
+        assert_eq!(darken(C), [150, 75, 30, 255]);
+        assert_eq!(darken(D), [6, 12, 24, 128]);
+
+        let (c, d) = (darken(C), darken(D));
+        #[rustfmt::skip]
+        let expected = [
+            C, C, c, D, D, d,
+            C, C, c, D, D, d,
+            c, c, c, d, d, d,
+        ];
+        assert_eq!(scaled, expected);
+
+        let scanline = scale(&frame, 2, 1, 3, Filter::Scanline);
+        assert_eq!(&scanline[..6], &[C, C, C, D, D, D]);
+        assert_eq!(&scanline[12..], &[c, c, c, d, d, d]);
+
+        // with a factor of 1, there are no grid lines.
+        assert_eq!(scale(&frame, 2, 1, 1, Filter::LcdGrid), frame);
+    }
+
+    #[test]
+    fn expand() {
+        let mut frame = [0; SCREEN_WIDTH * SCREEN_HEIGHT];
+        frame[1] = 1;
+        frame[2] = 3;
+        let palette = [A, C, D, B];
+        let expanded = expand_colors(&frame, &palette);
+        assert_eq!(expanded.len(), SCREEN_WIDTH * SCREEN_HEIGHT);
+        assert_eq!(&expanded[..4], &[A, C, B, A]);
+    }
+
+    #[test]
+    fn color_correction() {
+        let mut frame = [0; SCREEN_WIDTH * SCREEN_HEIGHT];
+        for (i, x) in frame.iter_mut().enumerate() {
+            *x = (i % 4) as u8;
+        }
+        let gray = |x: u8| [x, x, x, 255];
+        let palette = [gray(255), gray(170), gray(85), gray(0)];
+
+        let linear = expand_colors(&frame, &palette);
+        assert_eq!(
+            expand_colors_corrected(&frame, &palette, ColorCorrection::None),
+            linear
+        );
+
+        let dmg = expand_colors_corrected(&frame, &palette, ColorCorrection::Dmg);
+        assert_eq!(dmg[0], DMG_LIGHTEST);
+        assert_eq!(dmg[1], [91, 128, 15, 255]);
+        assert_eq!(dmg[2], [42, 81, 15, 255]);
+        assert_eq!(dmg[3], DMG_DARKEST);
+        assert_eq!(&dmg[4..8], &dmg[..4]);
+
+        // the alpha is kept
+        assert_eq!(correct_color(D, ColorCorrection::Dmg)[3], 128);
+        assert_eq!(correct_color(D, ColorCorrection::Cgb)[3], 128);
+
+        // white is dimmed, and pure colors are mixed with the other channels
+        assert_eq!(correct_color(A, ColorCorrection::Cgb), [240, 240, 240, 255]);
+        assert_eq!(
+            correct_color([255, 0, 0, 255], ColorCorrection::Cgb),
+            [201, 0, 46, 255]
+        );
+        assert_eq!(correct_color(B, ColorCorrection::Cgb), B);
+    }
+}
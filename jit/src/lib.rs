@@ -1,31 +1,52 @@
-use dynasmrt::ExecutableBuffer;
+//! A JIT compiler for the Game Boy CPU, that compiles blocks of ROM code to x64.
+//!
+//! The compiler is behind the default `compiler` feature. Without it, `JitCompiler` is a stub
+//! with the same interface, that always runs the interpreter, for builds that can't or must not
+//! generate code at runtime.
+
+#[cfg(feature = "compiler")]
+use dynasmrt::{mmap::MutableBuffer, ExecutableBuffer};
 use gameroy::{
-    consts::{self, CB_CLOCK, CLOCK, CLOCK_SPEED, LEN},
-    disassembler::{Address, Cursor},
+    consts::CLOCK_SPEED,
     gameboy::{cpu::CpuState, GameBoy},
     interpreter::Interpreter,
 };
-use std::{
-    collections::HashMap,
-    hash::{BuildHasher, Hasher},
+#[cfg(feature = "compiler")]
+use gameroy::{
+    consts::{self, CB_CLOCK, CLOCK, LEN},
+    disassembler::{Address, Cursor},
+    log::Logger,
 };
+#[cfg(feature = "compiler")]
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
 
+#[cfg(feature = "compiler")]
 use self::x64::BlockCompiler;
 
-#[cfg(target_os = "linux")]
+#[cfg(all(feature = "compiler", target_os = "linux"))]
 pub mod linux;
-#[cfg(target_os = "windows")]
+#[cfg(all(feature = "compiler", target_os = "windows"))]
 pub mod windows;
 
+#[cfg(feature = "compiler")]
+pub mod lockstep;
+#[cfg(not(feature = "compiler"))]
+mod stub;
+#[cfg(feature = "compiler")]
 mod x64;
 
+#[cfg(not(feature = "compiler"))]
+pub use stub::JitCompiler;
+
+#[cfg(feature = "compiler")]
 pub struct Block {
     _start_address: u16,
     _length: u16,
     initial_block_clock_cycles: u32,
-    _max_clock_cycles: u32,
+    max_clock_cycles: u32,
     fn_ptr: unsafe extern "sysv64" fn(&mut GameBoy),
-    pub _compiled_code: ExecutableBuffer,
+    pub _compiled_code: Box<dyn ExecutableCode>,
     _bytes: usize,
 
     #[cfg(feature = "statistics")]
@@ -36,6 +57,7 @@ pub struct Block {
     non_cleared_flags: usize,
 }
 
+#[cfg(feature = "compiler")]
 impl Block {
     #[inline(never)]
     fn call(&self, gb: &mut GameBoy) {
@@ -46,7 +68,52 @@ impl Block {
     }
 }
 
+/// Memory holding the machine code of a compiled block, returned by a `CodeAllocator`.
+#[cfg(feature = "compiler")]
+pub trait ExecutableCode: Send {
+    /// The compiled code. It must be executable, and must not be modified while `self` is alive.
+    fn code(&self) -> &[u8];
+}
+#[cfg(feature = "compiler")]
+impl ExecutableCode for ExecutableBuffer {
+    fn code(&self) -> &[u8] {
+        self
+    }
+}
+
+/// Provides the executable memory where the compiled blocks are written, so frontends can use a
+/// implementation appropriated for their platform, like one that uses `MAP_JIT` on macOS, or dual
+/// mapping where memory cannot be both writable and executable.
+///
+/// When the `emit_debug_info` feature is enabled on windows, the allocator is not used, because
+/// the unwind information needs to be registered together with the code.
+///
+/// # Safety
+///
+/// The `ExecutableCode` returned by `allocate` must contain exactly the given code, in memory that
+/// can be executed, because the compiled blocks are called directly.
+#[cfg(feature = "compiler")]
+pub unsafe trait CodeAllocator: Send {
+    fn allocate(&mut self, code: Vec<u8>) -> std::io::Result<Box<dyn ExecutableCode>>;
+}
+
+/// The default `CodeAllocator`. Maps a new writable region for each block, and makes it
+/// executable after the code is copied to it.
+#[cfg(feature = "compiler")]
+#[derive(Default)]
+pub struct MmapAllocator;
+#[cfg(feature = "compiler")]
+unsafe impl CodeAllocator for MmapAllocator {
+    fn allocate(&mut self, code: Vec<u8>) -> std::io::Result<Box<dyn ExecutableCode>> {
+        let mut buffer = MutableBuffer::new(code.len())?;
+        buffer.set_len(code.len());
+        buffer[..].copy_from_slice(&code);
+        Ok(Box::new(buffer.make_exec()?))
+    }
+}
+
 /// A block of instruction to be compiled.
+#[cfg(feature = "compiler")]
 struct BlockTrace {
     instrs: Vec<Instr>,
     length: u16,
@@ -55,6 +122,7 @@ struct BlockTrace {
     interrupt_checks: Vec<(u16, u32)>,
 }
 
+#[cfg(feature = "compiler")]
 #[derive(Clone)]
 pub struct CompilerOpts {
     pub flag_optimization: bool,
@@ -64,6 +132,7 @@ pub struct CompilerOpts {
     pub emit_perf_map: bool,
 }
 
+#[cfg(feature = "compiler")]
 struct Instr {
     op: [u8; 3],
     pc: u16,
@@ -71,6 +140,7 @@ struct Instr {
     curr_clock_count: u32,
 }
 
+#[cfg(feature = "compiler")]
 fn trace_a_block(gb: &GameBoy) -> BlockTrace {
     let bank = gb.cartridge.curr_bank();
 
@@ -158,14 +228,20 @@ fn trace_a_block(gb: &GameBoy) -> BlockTrace {
     }
 }
 
+/// A identity hasher, for hashing `Address`es.
+///
+/// Each write is shifted into the state, so the hash of a `Address` is `bank << 16 | address`,
+/// which is unique for any key of up to 64 bits.
 pub struct NoHashHasher(u64);
 impl Hasher for NoHashHasher {
     fn finish(&self) -> u64 {
         self.0
     }
 
-    fn write(&mut self, _: &[u8]) {
-        panic!("should only be hashing u16")
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = (self.0 << 8) | b as u64;
+        }
     }
 
     fn write_u16(&mut self, i: u16) {
@@ -180,6 +256,7 @@ impl BuildHasher for NoHashHasher {
     }
 }
 
+#[cfg(feature = "compiler")]
 macro_rules! stat {
     ($stats:expr) => {
         #[cfg(feature = "statistics")]
@@ -189,17 +266,43 @@ macro_rules! stat {
     };
 }
 
-#[derive(Default)]
-#[cfg(feature = "statistics")]
-struct Stats {
-    cycles_compiled: u64,
-    cycles_interpreted: u64,
-    cycles_on_ram: u64,
-    blocks_runned: u64,
-    fallbacks_on_ram: u64,
-    fallbacks_on_halt: u64,
-    fallbacks_on_interrupt: u64,
-    fallbacks_other: u64,
+/// A block that ran within this many clock cycles of its `max_clock_cycles` is counted as a near
+/// miss in `Stats::near_misses`. This is the slack of a conditional branch.
+#[cfg(feature = "compiler")]
+const NEAR_MISS_CYCLES: u64 = 12;
+
+/// Statistics of the execution of the blocks, returned by `JitCompiler::stats`, and printed when
+/// the `JitCompiler` is dropped if the `statistics` feature is enabled.
+///
+/// The counters of how often the clock bounds of the compiled blocks are hit are always collected,
+/// to tell if the bounds are too conservative. The others are only collected with the
+/// `statistics` feature.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Stats {
+    /// The number of times that a block was not run, and the interpreter was used instead,
+    /// because the next interrupt may happen before the first interrupt check of the block.
+    pub fallbacks_on_interrupt: u64,
+    /// The number of times that a block ran past the next interrupt, meaning that its clock
+    /// bounds were wrong. Each one is also logged as a warning.
+    pub overruns: u64,
+    /// The number of times that a block ran within `NEAR_MISS_CYCLES` of its maximum clock
+    /// cycles. If this is rare, the bounds are too conservative.
+    pub near_misses: u64,
+
+    #[cfg(feature = "statistics")]
+    pub cycles_compiled: u64,
+    #[cfg(feature = "statistics")]
+    pub cycles_interpreted: u64,
+    #[cfg(feature = "statistics")]
+    pub cycles_on_ram: u64,
+    #[cfg(feature = "statistics")]
+    pub blocks_runned: u64,
+    #[cfg(feature = "statistics")]
+    pub fallbacks_on_ram: u64,
+    #[cfg(feature = "statistics")]
+    pub fallbacks_on_halt: u64,
+    #[cfg(feature = "statistics")]
+    pub fallbacks_other: u64,
 }
 #[cfg(feature = "statistics")]
 impl Drop for JitCompiler {
@@ -247,6 +350,8 @@ impl Drop for JitCompiler {
     fallbacks on halt:      {:10} ({:.2}%)
     fallbacks on interrupt: {:10} ({:.2}%)
     fallbacks on other:     {:10} ({:.2}%)
+    overruns:               {:10} ({:.2}%)
+    near misses:            {:10} ({:.2}%)
     compiled bytes:         {:10} bytes
     cleared flags:          {:10} ({:.2}%)
     partially cleared flags:{:10} ({:.2}%)
@@ -267,6 +372,10 @@ impl Drop for JitCompiler {
             100.0 * self.stats.fallbacks_on_interrupt as f64 / total_queries as f64,
             self.stats.fallbacks_other,
             100.0 * self.stats.fallbacks_other as f64 / total_queries as f64,
+            self.stats.overruns,
+            100.0 * self.stats.overruns as f64 / self.stats.blocks_runned as f64,
+            self.stats.near_misses,
+            100.0 * self.stats.near_misses as f64 / self.stats.blocks_runned as f64,
             compiled_bytes,
             cleared_flags,
             100.0 * cleared_flags as f64 / total_flags as f64,
@@ -278,26 +387,35 @@ impl Drop for JitCompiler {
     }
 }
 
+#[cfg(feature = "compiler")]
 pub struct JitCompiler {
     pub blocks: HashMap<Address, Block, NoHashHasher>,
-    #[cfg(feature = "statistics")]
     stats: Stats,
     pub opts: CompilerOpts,
     /// A VecAssembler, reused for each block compilation
     assembler: x64::Assembler,
+    /// Where compilation and block execution events are logged to.
+    pub logger: Logger,
+    /// Where the compiled blocks are written to. A `MmapAllocator` by default.
+    pub allocator: Box<dyn CodeAllocator>,
+    /// The maximum number of instructions that `interpret_block` runs in the interpreter in a
+    /// single call, before returning, even in the middle of a block. Code that can't be compiled,
+    /// like code in RAM, is only interrupted at a jump otherwise. None by default, for no limit.
+    pub max_interpreted_instructions: Option<u32>,
 }
 
+#[cfg(feature = "compiler")]
 impl Default for JitCompiler {
     fn default() -> Self {
         Self::new()
     }
 }
 
+#[cfg(feature = "compiler")]
 impl JitCompiler {
     pub fn new() -> Self {
         Self {
             blocks: HashMap::with_hasher(NoHashHasher(0)),
-            #[cfg(feature = "statistics")]
             stats: Stats::default(),
             opts: CompilerOpts {
                 flag_optimization: true,
@@ -305,6 +423,9 @@ impl JitCompiler {
                 emit_perf_map: false,
             },
             assembler: x64::Assembler::new(0),
+            logger: Logger::default(),
+            allocator: Box::new(MmapAllocator),
+            max_interpreted_instructions: None,
         }
     }
 
@@ -335,14 +456,24 @@ impl JitCompiler {
 
         let address = Address::from_pc(bank, pc)?;
         Some(self.blocks.entry(address).or_insert_with(|| {
-            BlockCompiler::new(gb).compile_block(&self.opts, &mut self.assembler)
+            BlockCompiler::new(gb).compile_block(
+                &self.opts,
+                &mut self.assembler,
+                &mut *self.allocator,
+                &mut self.logger,
+            )
         }))
     }
 
+    /// See `Stats`.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
     pub fn interpret_block(&mut self, gb: &mut GameBoy) {
         let on_ram = gb.cpu.pc >= 0x8000;
 
-        #[cfg(feature = "statistics")]
+        // taken, to not borrow self with the block
         let mut stats = std::mem::take(&mut self.stats);
 
         let block = self.get_block(gb);
@@ -358,7 +489,7 @@ impl JitCompiler {
 
                 let next_check = gb.clock_count + block.initial_block_clock_cycles as u64 + 4;
                 if next_interrupt <= next_check {
-                    stat!(stats.fallbacks_on_interrupt += 1);
+                    stats.fallbacks_on_interrupt += 1;
                     break 'block None;
                 }
 
@@ -377,79 +508,287 @@ impl JitCompiler {
 
         match block {
             Some(block) => {
-                // let bank = gb.cartridge.curr_bank();
-                // println!(
-                //     "running {:02x} {:04x} ({})",
-                //     if block._start_address <= 0x3FFF {
-                //         bank.0
-                //     } else {
-                //         bank.1
-                //     },
-                //     block._start_address,
-                //     gb.clock_count,
-                // );
+                let start_address = block._start_address;
+                let max_clock_cycles = block.max_clock_cycles as u64;
+                #[cfg(feature = "timing_profile")]
+                let start = std::time::Instant::now();
                 block.call(gb);
+                #[cfg(feature = "timing_profile")]
+                {
+                    gb.profile.borrow_mut().jit += start.elapsed().as_nanos() as u64;
+                }
                 debug_assert!(gb.clock_count != start_clock);
 
-                stat!(stats.cycles_compiled += gb.clock_count - start_clock);
+                gameroy::log!(
+                    self.logger,
+                    Trace,
+                    "jit",
+                    "ran block {:04x} ({})",
+                    start_address,
+                    start_clock,
+                );
+
+                let elapsed = gb.clock_count - start_clock;
+                stat!(stats.cycles_compiled += elapsed);
+                if elapsed + NEAR_MISS_CYCLES >= max_clock_cycles {
+                    stats.near_misses += 1;
+                }
 
-                // assert that no interrupt happened inside the block (unless it happend in a write
+                // check that no interrupt happened inside the block (unless it happend in a write
                 // of the last instruction).
-                debug_assert!(
-                    gb.clock_count - 24 < gb.next_interrupt.get(),
-                    "{} < {}",
-                    gb.clock_count,
-                    next_interrupt
-                );
+                let overrun = gb.clock_count - 24 >= gb.next_interrupt.get();
+                if overrun {
+                    stats.overruns += 1;
+                    gameroy::log!(
+                        self.logger,
+                        Warn,
+                        "jit",
+                        "block {:04x} ran past the next interrupt ({} >= {})",
+                        start_address,
+                        gb.clock_count - 24,
+                        gb.next_interrupt.get(),
+                    );
+                }
+                debug_assert!(!overrun, "{} < {}", gb.clock_count, next_interrupt);
             }
             _ => {
-                // println!("interpr {:04x} ({})", gb.cpu.pc, gb.clock_count);
-
-                // avoid being stuck here for to long
-                let timeout = gb.clock_count + CLOCK_SPEED / 60;
-
-                let mut _on_halt = 0;
-
-                let mut inter = Interpreter(gb);
-                loop {
-                    let op = inter.0.read(inter.0.cpu.pc);
-
-                    let now = inter.0.clock_count;
-                    let is_halt = inter.0.cpu.state == CpuState::Halt;
-                    inter.interpret_op();
-                    let elapsed = inter.0.clock_count - now;
-                    if is_halt {
-                        _on_halt += elapsed;
-                    }
-
-                    let is_jump = [
-                        0xc2, 0xc3, 0xca, 0xd2, 0xda, 0xe9, 0x18, 0x20, 0x28, 0x30, 0x38, 0xc4,
-                        0xcc, 0xcd, 0xd4, 0xdc, 0xc0, 0xc8, 0xc9, 0xd0, 0xd8, 0xd9, 0xc7, 0xcf,
-                        0xd7, 0xdf, 0xe7, 0xef, 0xf7, 0xff,
-                    ]
-                    .contains(&op);
-
-                    let is_interrupt = [0x40, 0x48, 0x50, 0x58, 0x60].contains(&inter.0.cpu.pc);
-
-                    if is_interrupt
-                        || is_jump && inter.0.cpu.pc < 0x8000
-                        || inter.0.clock_count > timeout
-                    {
-                        stat!(
-                            stats.cycles_interpreted +=
-                                inter.0.clock_count - start_clock - _on_halt
-                        );
-                        if on_ram {
-                            stat!(
-                                stats.cycles_on_ram += inter.0.clock_count - start_clock - _on_halt
-                            );
-                        }
-                        break;
-                    }
+                gameroy::log!(
+                    self.logger,
+                    Trace,
+                    "jit",
+                    "interpreting {:04x} ({})",
+                    gb.cpu.pc,
+                    gb.clock_count,
+                );
+
+                let _on_halt = interpret_until_jump(gb, self.max_interpreted_instructions);
+                stat!(stats.cycles_interpreted += gb.clock_count - start_clock - _on_halt);
+                if on_ram {
+                    stat!(stats.cycles_on_ram += gb.clock_count - start_clock - _on_halt);
                 }
             }
         }
+        self.stats = stats;
+    }
+}
+
+/// Run the interpreter until it jumps to ROM code, where a compiled block may start, until an
+/// interrupt is dispatched, or until `max_instructions` are run. Returns the number of clock cycles
+/// spent halted.
+fn interpret_until_jump(gb: &mut GameBoy, max_instructions: Option<u32>) -> u64 {
+    // avoid being stuck here for to long
+    let timeout = gb.clock_count + CLOCK_SPEED / 60;
+
+    let mut on_halt = 0;
+    let mut instructions = 0;
+
+    let mut inter = Interpreter(gb);
+    loop {
+        let op = inter.0.read(inter.0.cpu.pc);
+
+        let now = inter.0.clock_count;
+        let is_halt = inter.0.cpu.state == CpuState::Halt;
+        inter.interpret_op();
+        instructions += 1;
+        let elapsed = inter.0.clock_count - now;
+        if is_halt {
+            on_halt += elapsed;
+        }
+
+        let is_jump = [
+            0xc2, 0xc3, 0xca, 0xd2, 0xda, 0xe9, 0x18, 0x20, 0x28, 0x30, 0x38, 0xc4, 0xcc, 0xcd,
+            0xd4, 0xdc, 0xc0, 0xc8, 0xc9, 0xd0, 0xd8, 0xd9, 0xc7, 0xcf, 0xd7, 0xdf, 0xe7, 0xef,
+            0xf7, 0xff,
+        ]
+        .contains(&op);
+
+        let is_interrupt = [0x40, 0x48, 0x50, 0x58, 0x60].contains(&inter.0.cpu.pc);
+
+        let limit_reached = max_instructions.is_some_and(|max| instructions >= max);
+
+        if is_interrupt
+            || is_jump && inter.0.cpu.pc < 0x8000
+            || inter.0.clock_count > timeout
+            || limit_reached
+        {
+            return on_halt;
+        }
+    }
+}
+
+#[cfg(all(test, feature = "compiler"))]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use gameroy::{
+        disassembler::Address,
+        gameboy::{cartridge::Cartridge, GameBoy},
+        log::Logger,
+    };
+
+    use super::{CodeAllocator, ExecutableCode, JitCompiler, MmapAllocator, NoHashHasher};
+
+    #[test]
+    fn address_hash() {
+        use std::hash::BuildHasher;
+
+        let hash = |address: Address| NoHashHasher(0).hash_one(address);
+
+        assert_ne!(hash(Address::new(1, 0x0123)), hash(Address::new(2, 0x0123)));
+        assert_eq!(hash(Address::new(0x12, 0x0345)), 0x0012_0345);
+    }
+
+    #[test]
+    fn same_address_different_banks() {
+        // a MBC1 rom with 4 banks, with a infinite loop at 0x4000 in banks 1 and 2.
+        let mut rom = vec![0x00; 4 * 0x4000];
+        rom[0x147] = 0x01;
+        rom[0x148] = 0x01;
+        for bank in [1, 2] {
+            rom[bank * 0x4000..][..3].copy_from_slice(&[0xc3, 0x00, 0x40]);
+        }
+        let cartridge = Cartridge::new(rom).unwrap();
+
+        let mut gb = GameBoy::new(None, cartridge);
+        gb.cpu.pc = 0x4000;
+
+        let mut jit = JitCompiler::new();
+        assert!(jit.get_block(&gb).is_some());
+
+        gb.write(0x2000, 2);
+        assert_eq!(gb.cartridge.curr_bank(), (0, 2));
+        assert!(jit.get_block(&gb).is_some());
+
+        assert_eq!(jit.blocks.len(), 2);
+        assert!(jit.blocks.contains_key(&Address::new(1, 0x0000)));
+        assert!(jit.blocks.contains_key(&Address::new(2, 0x0000)));
+    }
+
+    #[test]
+    fn interrupt_fallbacks() {
+        // a rom filled with NOPs, with a infinite loop at 0x150.
+        let cartridge = Cartridge::with_code(0x150, &[0xc3, 0x50, 0x01]);
+
+        let mut gb = GameBoy::new(None, cartridge);
+        gb.cpu.pc = 0x150;
+        let mut jit = JitCompiler::new();
+
+        // with a interrupt pending, but IME disabled, the next interrupt is always now.
+        gb.interrupt_enabled = 0x01;
+        gb.interrupt_flag.set(0x01);
+        gb.update_next_interrupt();
+        for i in 1..=3 {
+            jit.interpret_block(&mut gb);
+            assert_eq!(jit.stats().fallbacks_on_interrupt, i);
+        }
+        assert_eq!(gb.cpu.pc, 0x150);
+
+        // without interrupts, the block is run most of the time
+        gb.interrupt_enabled = 0x00;
+        gb.interrupt_flag.set(0x00);
+        gb.update_next_interrupt();
+        for _ in 0..1000 {
+            jit.interpret_block(&mut gb);
+        }
+        let stats = jit.stats();
+        assert!(stats.fallbacks_on_interrupt < 100, "{:?}", stats);
+        assert_eq!(stats.overruns, 0);
+        // the block is a single jump, so it always takes its maximum clock cycles
+        assert_eq!(stats.near_misses, 1003 - stats.fallbacks_on_interrupt);
+    }
+
+    #[test]
+    fn max_interpreted_instructions() {
+        // a NOP sled in WRAM, which is never compiled.
+        let mut gb = GameBoy::new(None, Cartridge::with_code(0x150, &[0x18, 0xfe]));
+        for address in 0xc000..0xe000 {
+            gb.write(address, 0x00);
+        }
+        gb.cpu.pc = 0xc000;
+
+        let mut jit = JitCompiler::new();
+        jit.max_interpreted_instructions = Some(100);
+        jit.interpret_block(&mut gb);
+        assert_eq!(gb.cpu.pc, 0xc000 + 100);
+
+        // resuming continues from where it stopped
+        let clock_count = gb.clock_count;
+        jit.interpret_block(&mut gb);
+        assert_eq!(gb.cpu.pc, 0xc000 + 200);
+        assert_eq!(gb.clock_count - clock_count, 100 * 4);
+
+        // without the limit, the rest of the sled is run
+        jit.max_interpreted_instructions = None;
+        let clock_count = gb.clock_count;
+        jit.interpret_block(&mut gb);
+        assert!(gb.clock_count - clock_count >= (0x2000 - 200) * 4);
+    }
+
+    #[test]
+    fn custom_allocator() {
+        struct CountingAllocator(Arc<Mutex<Vec<usize>>>);
+        unsafe impl CodeAllocator for CountingAllocator {
+            fn allocate(&mut self, code: Vec<u8>) -> std::io::Result<Box<dyn ExecutableCode>> {
+                self.0.lock().unwrap().push(code.len());
+                MmapAllocator.allocate(code)
+            }
+        }
+
+        // INC B; JR -3
+        let cartridge = Cartridge::with_code(0x150, &[0x04, 0x18, 0xfd]);
 
-        stat!(self.stats = stats);
+        let mut gb = GameBoy::new(None, cartridge);
+        gb.cpu.pc = 0x150;
+        gb.cpu.b = 0;
+
+        let allocations = Arc::new(Mutex::new(Vec::new()));
+        let mut jit = JitCompiler::new();
+        jit.allocator = Box::new(CountingAllocator(allocations.clone()));
+
+        for _ in 0..10 {
+            jit.interpret_block(&mut gb);
+        }
+
+        // a single block was compiled, and it runs the loop
+        let block = &jit.blocks[&Address::new(0, 0x150)];
+        assert_eq!(
+            *allocations.lock().unwrap(),
+            [block._compiled_code.code().len()]
+        );
+        assert_eq!(gb.cpu.pc, 0x150);
+        assert!(gb.cpu.b >= 10, "{}", gb.cpu.b);
+    }
+
+    #[test]
+    fn log_compile_event() {
+        // a rom filled with NOPs, with a infinite loop at 0x150.
+        let cartridge = Cartridge::with_code(0x150, &[0xc3, 0x50, 0x01]);
+
+        let mut gb = GameBoy::new(None, cartridge);
+        gb.cpu.pc = 0x150;
+
+        let records = Arc::new(Mutex::new(Vec::new()));
+
+        let mut jit = JitCompiler::new();
+        jit.logger = Logger::new(Box::new({
+            let records = records.clone();
+            move |record| {
+                records
+                    .lock()
+                    .unwrap()
+                    .push((record.level, record.target, record.args.to_string()))
+            }
+        }));
+
+        jit.interpret_block(&mut gb);
+
+        let records = records.lock().unwrap();
+        assert!(
+            records
+                .iter()
+                .any(|(_, target, msg)| *target == "jit" && msg == "compiling 00_0150 (len: 3)"),
+            "{:?}",
+            records
+        );
     }
 }
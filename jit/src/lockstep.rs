@@ -0,0 +1,181 @@
+//! Run the JIT and the interpreter in lockstep, for catching miscompiled blocks.
+//!
+//! [`Lockstep`] keeps a copy of the GameBoy that is only run by the interpreter. After each block
+//! run by the JIT, the copy is run up to the same clock count, and both are compared. This is very
+//! slow, but reports a divergence right after the block that caused it.
+
+use std::fmt;
+
+use gameroy::{
+    gameboy::{cpu::Cpu, GameBoy},
+    interpreter::Interpreter,
+};
+
+use crate::JitCompiler;
+
+/// The first difference found between the GameBoy run by the JIT and the one run by the
+/// interpreter.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Divergence {
+    /// The PC at the start of the block after which the states diverged.
+    pub pc: u16,
+    pub jit_clock_count: u64,
+    pub interpreter_clock_count: u64,
+    pub jit: Cpu,
+    pub interpreter: Cpu,
+    /// The CPU and the clock count are equal, but the rest of the state, compared through
+    /// `GameBoy::state_hash`, is not.
+    pub state_differs: bool,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "divergence after the block at {:04x}", self.pc)?;
+        writeln!(f, "{:>15} jit: {:x?}", self.jit_clock_count, self.jit)?;
+        write!(
+            f,
+            "{:>15} int: {:x?}",
+            self.interpreter_clock_count, self.interpreter
+        )?;
+        if self.state_differs {
+            write!(f, "\nthe CPUs are equal, but the rest of the state is not")?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks the JIT against the interpreter, see the module documentation.
+pub struct Lockstep {
+    pub jit: JitCompiler,
+    /// The GameBoy run by the interpreter.
+    reference: GameBoy,
+}
+
+impl Lockstep {
+    /// Start checking from the current state of `gb`, which is copied through a save state.
+    pub fn new(jit: JitCompiler, gb: &GameBoy) -> Self {
+        let mut reference = GameBoy::new(gb.boot_rom, gb.cartridge.clone());
+        reference.cgb_boot_rom = gb.cgb_boot_rom.clone();
+        reference.predict_interrupt = gb.predict_interrupt;
+        let mut state = Vec::new();
+        gb.save_state(None, &mut state)
+            .expect("writing to a Vec never fails");
+        reference
+            .load_state(&mut state.as_slice())
+            .expect("the state was just saved");
+        Self { jit, reference }
+    }
+
+    /// The GameBoy run by the interpreter.
+    pub fn reference(&self) -> &GameBoy {
+        &self.reference
+    }
+
+    /// Run a block in `gb` with the JIT, and the reference up to the same clock count, and compare
+    /// them.
+    pub fn step(&mut self, gb: &mut GameBoy) -> Result<(), Box<Divergence>> {
+        let pc = gb.cpu.pc;
+        self.jit.interpret_block(gb);
+        while self.reference.clock_count < gb.clock_count {
+            Interpreter(&mut self.reference).interpret_op();
+        }
+
+        let equal_cpu =
+            gb.cpu == self.reference.cpu && gb.clock_count == self.reference.clock_count;
+        let state_differs = equal_cpu && {
+            gb.update_all();
+            self.reference.update_all();
+            gb.state_hash() != self.reference.state_hash()
+        };
+        if equal_cpu && !state_differs {
+            return Ok(());
+        }
+        Err(Box::new(Divergence {
+            pc,
+            jit_clock_count: gb.clock_count,
+            interpreter_clock_count: self.reference.clock_count,
+            jit: gb.cpu.clone(),
+            interpreter: self.reference.cpu.clone(),
+            state_differs,
+        }))
+    }
+
+    /// Call `step` until the clock count of `gb` reaches `clock_count`, or a divergence is found.
+    pub fn run_until(&mut self, gb: &mut GameBoy, clock_count: u64) -> Result<(), Box<Divergence>> {
+        while gb.clock_count < clock_count {
+            self.step(gb)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gameroy::gameboy::cartridge::Cartridge;
+
+    use super::*;
+
+    fn cartridge() -> Cartridge {
+        Cartridge::with_code(
+            0x150,
+            &[
+                0x21, 0x00, 0xc0, // LD HL, $C000
+                0x3e, 0x10, // LD A, $10
+                0x22, // LD (HL+), A
+                0x3c, // INC A
+                0x18, 0x02, // JR +2
+                0x00, 0x00, // NOP; NOP
+                0x04, // INC B
+                0x18, 0xf7, // JR -9
+            ],
+        )
+    }
+
+    #[test]
+    fn no_divergence() {
+        let mut gb = GameBoy::new(None, cartridge());
+        gb.cpu.pc = 0x150;
+        let mut lockstep = Lockstep::new(JitCompiler::new(), &gb);
+        let target = gb.clock_count + 200_000;
+        lockstep.run_until(&mut gb, target).unwrap();
+        assert!(!lockstep.jit.blocks.is_empty());
+        assert_eq!(lockstep.reference().cpu, gb.cpu);
+    }
+
+    #[test]
+    fn broken_opcode() {
+        let mut gb = GameBoy::new(None, cartridge());
+        gb.cpu.pc = 0x150;
+        gb.cpu.b = 0;
+        let mut lockstep = Lockstep::new(JitCompiler::new(), &gb);
+
+        // INC B is compiled as DEC B
+        let target = gb.clock_count + 200_000;
+        crate::x64::BROKEN_OPCODE.with(|x| x.set(Some(0x04)));
+        let divergence = lockstep.run_until(&mut gb, target).unwrap_err();
+        crate::x64::BROKEN_OPCODE.with(|x| x.set(None));
+
+        assert_eq!(divergence.pc, 0x15b);
+        assert!(!divergence.state_differs);
+        assert_eq!(
+            divergence.jit_clock_count,
+            divergence.interpreter_clock_count
+        );
+        assert_eq!(divergence.jit.b.wrapping_neg(), divergence.interpreter.b);
+        assert!(divergence.to_string().contains("block at 015b"));
+    }
+
+    #[test]
+    fn state_divergence() {
+        let mut gb = GameBoy::new(None, cartridge());
+        gb.cpu.pc = 0x150;
+        let mut lockstep = Lockstep::new(JitCompiler::new(), &gb);
+        lockstep.step(&mut gb).unwrap();
+
+        // a difference outside the CPU
+        gb.write(0xC100, 0x12);
+        let divergence = lockstep.step(&mut gb).unwrap_err();
+        assert!(divergence.state_differs);
+        assert_eq!(divergence.jit, divergence.interpreter);
+    }
+}
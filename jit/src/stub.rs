@@ -0,0 +1,40 @@
+//! The `JitCompiler` used when the `compiler` feature is disabled.
+
+use gameroy::{gameboy::GameBoy, log::Logger};
+
+use crate::{interpret_until_jump, Stats};
+
+/// A `JitCompiler` that never compiles anything, and runs all the code in the interpreter.
+#[derive(Default)]
+pub struct JitCompiler {
+    /// Where execution events are logged to.
+    pub logger: Logger,
+    /// The maximum number of instructions that `interpret_block` runs in a single call. None by
+    /// default, for no limit.
+    pub max_interpreted_instructions: Option<u32>,
+    stats: Stats,
+}
+
+impl JitCompiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Always zero, because there are no compiled blocks.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Run the interpreter up to the next jump, like the fallback of the compiler.
+    pub fn interpret_block(&mut self, gb: &mut GameBoy) {
+        gameroy::log!(
+            self.logger,
+            Trace,
+            "jit",
+            "interpreting {:04x} ({})",
+            gb.cpu.pc,
+            gb.clock_count,
+        );
+        interpret_until_jump(gb, self.max_interpreted_instructions);
+    }
+}
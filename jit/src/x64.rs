@@ -1,7 +1,4 @@
-use dynasmrt::{
-    dynasm, mmap::MutableBuffer, x64::X64Relocation, DynamicLabel, DynasmApi, DynasmLabelApi,
-    VecAssembler,
-};
+use dynasmrt::{dynasm, x64::X64Relocation, DynamicLabel, DynasmApi, DynasmLabelApi, VecAssembler};
 
 pub type Assembler = VecAssembler<X64Relocation>;
 
@@ -13,10 +10,17 @@ use gameroy::{
         GameBoy,
     },
     interpreter::{Condition, Interpreter, Reg, Reg16},
+    log::Logger,
 };
 
 use crate::{trace_a_block, Block, BlockTrace};
 
+#[cfg(test)]
+thread_local! {
+    /// For testing `lockstep`: this opcode is compiled as the opcode with the lowest bit flipped.
+    pub(crate) static BROKEN_OPCODE: std::cell::Cell<Option<u8>> = const { std::cell::Cell::new(None) };
+}
+
 macro_rules! offset {
     (@ $parent:path, $field:tt) => {
         memoffset::offset_of!($parent, $field)
@@ -137,15 +141,22 @@ impl<'a> BlockCompiler<'a> {
         }
     }
 
-    pub fn compile_block(mut self, opts: &super::CompilerOpts, ops: &mut Assembler) -> Block {
-        // let bank = self.gb.cartridge.curr_bank();
-        // println!(
-        //     "compiling {:02x}_{:04x} (len: {})",
-        //     if self.pc <= 0x3FFF { bank.0 } else { bank.1 },
-        //     self.pc,
-        //     self.block_trace.length,
-        //     // self.max_clock_cycles,
-        // );
+    pub fn compile_block(
+        mut self,
+        opts: &super::CompilerOpts,
+        ops: &mut Assembler,
+        allocator: &mut dyn super::CodeAllocator,
+        logger: &mut Logger,
+    ) -> Block {
+        gameroy::log!(
+            logger,
+            Trace,
+            "jit",
+            "compiling {:02x}_{:04x} (len: {})",
+            self.block_trace.instrs[0].bank,
+            self.pc,
+            self.block_trace.length,
+        );
 
         self.instrs = std::mem::take(&mut self.block_trace.instrs)
             .into_iter()
@@ -296,6 +307,7 @@ impl<'a> BlockCompiler<'a> {
 
         cfg_if::cfg_if! {
             if #[cfg(all(target_os = "windows", feature = "emit_debug_info"))] {
+                let _ = allocator;
                 let buffer = crate::windows::to_mutable_buffer_with_unwin_info(
                     code,
                     prolog_len,
@@ -303,27 +315,29 @@ impl<'a> BlockCompiler<'a> {
                     push_rbx_offset,
                     push_rbp_offset,
                 );
+                let compiled_code: Box<dyn super::ExecutableCode> =
+                    Box::new(buffer.make_exec().unwrap());
             } else {
                 let _ = (prolog_len, push_r12_offset, push_rbx_offset, push_rbp_offset);
-                let buffer = to_mutable_buffer(code);
+                let compiled_code = allocator
+                    .allocate(code)
+                    .expect("failed to allocate executable memory");
             }
         }
 
-        let compiled_code = buffer.make_exec().unwrap();
-
         #[cfg(target_os = "linux")]
         if opts.emit_perf_map {
             let bank = self.instrs[0].bank;
             let symbol = format!("{:2x}_{:04x}", bank, start_address);
             let res = crate::linux::write_to_perf_map(
                 &symbol,
-                compiled_code.as_ptr() as usize,
-                compiled_code.len(),
+                compiled_code.code().as_ptr() as usize,
+                compiled_code.code().len(),
             );
             match res {
                 Ok(_) => {}
                 Err(err) => {
-                    println!("error writing to perf map: {}", err)
+                    gameroy::log!(logger, Warn, "jit", "error writing to perf map: {}", err)
                 }
             }
         }
@@ -332,8 +346,8 @@ impl<'a> BlockCompiler<'a> {
             _start_address: start_address,
             _length: self.block_trace.length,
             initial_block_clock_cycles: self.block_trace.interrupt_checks[0].1,
-            _max_clock_cycles: self.block_trace.interrupt_checks.iter().map(|x| x.1).sum(),
-            fn_ptr: unsafe { std::mem::transmute(compiled_code.as_ptr()) },
+            max_clock_cycles: self.block_trace.interrupt_checks.iter().map(|x| x.1).sum(),
+            fn_ptr: unsafe { std::mem::transmute(compiled_code.code().as_ptr()) },
             _compiled_code: compiled_code,
             _bytes: bytes,
             #[cfg(feature = "statistics")]
@@ -541,6 +555,11 @@ impl<'a> BlockCompiler<'a> {
     /// Compile a Opcode. Return false if the compiled fallbacks to the interpreter (which means
     /// that clock_count were already updated).
     fn compile_opcode(&mut self, ops: &mut Assembler, op: u8) -> bool {
+        #[cfg(test)]
+        let op = match BROKEN_OPCODE.with(|x| x.get()) {
+            Some(broken) if broken == op => op ^ 1,
+            _ => op,
+        };
         self.tick(4);
         self.did_write = false;
         use Condition::*;
@@ -1746,11 +1765,11 @@ impl<'a> BlockCompiler<'a> {
             0x8000..=0x9FFF => {}
             // Cartridge RAM
             0xA000..=0xBFFF => {}
-            // Work RAM
-            0xC000..=0xDFFF => {
+            // Work RAM, bank 0
+            0xC000..=0xCFFF => {
                 let wram = offset!(GameBoy, wram);
                 let offset = wram + (address as usize - 0xC000);
-                debug_assert!(offset < wram + 0x2000);
+                debug_assert!(offset < wram + 0x1000);
                 dynasm!(ops
                     ; .arch x64
                     ; movzx	eax, BYTE [rbx + src as i32]
@@ -1763,6 +1782,8 @@ impl<'a> BlockCompiler<'a> {
                 self.tick(4);
                 return;
             }
+            // Work RAM, switchable bank in CGB
+            0xD000..=0xDFFF => {}
             // ECHO RAM
             0xE000..=0xFDFF => unreachable!(),
             // Sprite Attribute table
@@ -1917,17 +1938,13 @@ impl<'a> BlockCompiler<'a> {
             0x8000..=0x9FFF => {}
             // Cartridge RAM
             0xA000..=0xBFFF => {}
-            // Work RAM or ECHO RAM
-            0xC000..=0xDFFF | 0xE000..=0xFDFF => {
-                let addr = if (0xE000..=0xFDFF).contains(&address) {
-                    address - 0x2000
-                } else {
-                    address
-                };
+            // Work RAM bank 0, or its ECHO RAM
+            0xC000..=0xCFFF | 0xE000..=0xEFFF => {
+                let addr = address & 0x0FFF;
 
                 let wram = offset!(GameBoy, wram);
-                let offset = wram + (addr as usize - 0xC000);
-                debug_assert!(offset < wram + 0x2000);
+                let offset = wram + addr as usize;
+                debug_assert!(offset < wram + 0x1000);
                 dynasm!(ops
                     ; .arch x64
                     ; movzx	eax, BYTE [rbx + offset as i32]
@@ -1940,6 +1957,8 @@ impl<'a> BlockCompiler<'a> {
                 self.tick(4);
                 return;
             }
+            // Work RAM, switchable bank in CGB, or its ECHO RAM
+            0xD000..=0xDFFF | 0xF000..=0xFDFF => {}
             // Sprite Attribute table
             0xFE00..=0xFE9F => {}
             // Not Usable
@@ -4297,14 +4316,6 @@ fn reg_offset(reg: Reg) -> usize {
     }
 }
 
-#[allow(dead_code)]
-fn to_mutable_buffer(code: Vec<u8>) -> MutableBuffer {
-    let mut buffer = MutableBuffer::new(code.len()).unwrap();
-    buffer.set_len(code.len());
-    buffer[..].copy_from_slice(code.as_slice());
-    buffer
-}
-
 /// Returns the offsets of the fields `ptr`, `length` and `capacity`.
 /// TODO: this is too hacky, replace the Vec with a struct with accessable fields instead.
 fn get_vec_u8_fields_offset() -> (usize, usize, usize) {
@@ -1,3 +1,6 @@
+// Dumps the compiled blocks on failures.
+#![cfg(feature = "compiler")]
+
 use std::{
     panic::catch_unwind,
     path::PathBuf,
@@ -180,7 +183,7 @@ fn test_interrupt_prediction(rom: &str, timeout: u64) -> bool {
             // press the start button, repeatedly
             gb.joypad ^= 0x80;
             let mut vblank = vblank.lock().unwrap();
-            vblank.screen_a = Some(gb.ppu.borrow().screen.packed());
+            vblank.screen_a = Some(gb.ppu.borrow_mut().screen().packed());
             vblank.clock_count = Some(gb.clock_count);
         }
     }));
@@ -204,7 +207,7 @@ fn test_interrupt_prediction(rom: &str, timeout: u64) -> bool {
                 ),
             }
 
-            vblank.screen_b = Some(gb.ppu.borrow().screen.packed());
+            vblank.screen_b = Some(gb.ppu.borrow_mut().screen().packed());
         }
     }));
     game_boy_b.serial.borrow_mut().serial_transfer_callback = None;
@@ -249,7 +252,7 @@ fn test_interrupt_prediction(rom: &str, timeout: u64) -> bool {
                         "failed_test/{}/{:02x}_{:04x}.bin",
                         name, address.bank, address.address
                     ),
-                    &*block._compiled_code,
+                    block._compiled_code.code(),
                 )
                 .unwrap();
             }
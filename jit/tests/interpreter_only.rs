@@ -0,0 +1,54 @@
+//! Run a scene with the `JitCompiler` and with the interpreter alone, and compare the frames. With
+//! the `compiler` feature disabled, this checks that the stub `JitCompiler` runs the code
+//! correctly.
+
+use gameroy::{
+    consts::FRAME_CYCLES,
+    gameboy::{cartridge::Cartridge, GameBoy},
+    interpreter::Interpreter,
+};
+use gameroy_jit::JitCompiler;
+
+#[test]
+fn same_frames_as_interpreter() {
+    #[rustfmt::skip]
+    let code = [
+        0x21, 0x00, 0x80, // LD HL, $8000
+        // 0x153:
+        0x34, // INC (HL)
+        0x23, // INC HL
+        0x7c, // LD A, H
+        0xe0, 0x43, // LDH (SCX), A
+        0xfe, 0xa0, // CP $A0
+        0x20, 0xf7, // JR NZ, $0153
+        0x26, 0x80, // LD H, $80
+        0x18, 0xf3, // JR $0153
+    ];
+    let cartridge = Cartridge::with_code(0x150, &code);
+
+    let mut jitted = GameBoy::new(None, cartridge.clone());
+    jitted.cpu.pc = 0x150;
+    let mut interpreted = GameBoy::new(None, cartridge);
+    interpreted.cpu.pc = 0x150;
+
+    let mut jit = JitCompiler::new();
+    let mut frames = Vec::new();
+    let start = jitted.clock_count;
+    for frame in 1..=10 {
+        while jitted.clock_count < start + frame * FRAME_CYCLES {
+            jit.interpret_block(&mut jitted);
+        }
+        while interpreted.clock_count < jitted.clock_count {
+            Interpreter(&mut interpreted).interpret_op();
+        }
+        assert_eq!(jitted.clock_count, interpreted.clock_count);
+        assert_eq!(jitted.cpu, interpreted.cpu);
+
+        let screen = jitted.ppu.borrow_mut().screen().packed();
+        assert_eq!(screen, interpreted.ppu.borrow_mut().screen().packed());
+        frames.push(screen);
+    }
+
+    // the scene changes
+    assert!(frames.iter().any(|frame| *frame != frames[0]));
+}
@@ -188,7 +188,7 @@ extern "C" fn retro_load_game(info: Option<&retro_game_info>) -> bool {
     let mut gb = GameBoy::new(None, cartridge);
     gb.sound.get_mut().sample_frequency = SAMPLE_RATE;
     gb.v_blank = Some(Box::new(|gb| {
-        core().screen_buffer = gb.ppu.get_mut().screen.packed();
+        core().screen_buffer = gb.ppu.get_mut().screen().packed();
     }));
 
     core().state = Some(gb);
@@ -204,7 +204,7 @@ pub extern "C" fn retro_run() {
         return;
     };
 
-    state.joypad = 0xff;
+    let mut joypad = 0xff;
     if let (Some(input_poll), Some(input_state)) =
         (core.input_poll_callback, core.input_state_callback)
     {
@@ -225,10 +225,11 @@ pub extern "C" fn retro_run() {
         for (i, id) in key_map.iter().copied().enumerate() {
             let value = unsafe { input_state(0, RETRO_DEVICE_JOYPAD, 0, id) };
             if value != 0 {
-                state.joypad &= !(1 << i);
+                joypad &= !(1 << i);
             }
         }
     }
+    state.set_joypad(joypad);
 
     let target = state.clock_count + gameroy::consts::FRAME_CYCLES;
     while state.clock_count < target {
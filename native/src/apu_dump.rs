@@ -0,0 +1,44 @@
+use std::io::Write;
+
+use gameroy_lib::gameroy::{
+    apu_dump::dump_apu_registers,
+    gameboy::{cartridge::Cartridge, GameBoy},
+};
+
+use crate::ApuDump;
+
+pub fn apu_dump(
+    ApuDump {
+        rom_path: ref path,
+        frames,
+        output,
+    }: ApuDump,
+) {
+    let rom = match std::fs::read(path) {
+        Ok(x) => x,
+        Err(e) => return eprintln!("failed to load '{}': {}", path, e),
+    };
+
+    let cartridge = match Cartridge::new(rom) {
+        Ok(x) => x,
+        Err(e) => return eprintln!("failed to load rom: {}", e),
+    };
+    let mut game_boy = GameBoy::new(None, cartridge);
+
+    // remove serial transfer console output
+    game_boy.serial.get_mut().serial_transfer_callback = None;
+
+    let mut writer: Box<dyn Write> = match output {
+        Some(output) => match std::fs::File::create(&output) {
+            Ok(x) => Box::new(std::io::BufWriter::new(x)),
+            Err(e) => return eprintln!("failed to create '{}': {}", output, e),
+        },
+        None => Box::new(std::io::stdout().lock()),
+    };
+
+    if let Err(e) =
+        dump_apu_registers(&mut game_boy, frames, &mut writer).and_then(|_| writer.flush())
+    {
+        eprintln!("failed to write the dump: {}", e);
+    }
+}
@@ -73,6 +73,8 @@ pub fn benchmark(
     let cartridge = Cartridge::new(rom).unwrap();
     let mut game_boy = GameBoy::new(None, cartridge);
     game_boy.predict_interrupt = predict_interrupt;
+    // only measure the emulation, without the audio sampling
+    game_boy.sound.get_mut().sampling_disabled = true;
 
     // remove serial transfer console output
     game_boy.serial.get_mut().serial_transfer_callback = None;
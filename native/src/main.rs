@@ -13,6 +13,7 @@ use clap::{ArgAction, Args, Parser, Subcommand};
 use gameroy_lib::config::parse_screen_size;
 use gameroy_lib::{config, gameroy, rom_loading::load_gameboy, RomFile};
 
+mod apu_dump;
 mod bench;
 
 // this struct is a mirror of gameroy_lib::Config.
@@ -82,6 +83,22 @@ pub struct Cli {
 enum Commands {
     // Emulate a given rom for some ammount of frames, and give back the time runned.
     Bench(Bench),
+    // Emulate a given rom, and dump the APU registers at each frame as CSV.
+    ApuDump(ApuDump),
+}
+
+#[derive(Args)]
+pub struct ApuDump {
+    /// path to the game rom to be emulated
+    rom_path: String,
+
+    /// the number of frames to run
+    #[arg(short, long, default_value_t = 600)]
+    frames: u64,
+
+    /// the file to write the CSV to. If not given, it is written to stdout
+    #[arg(short, long, value_name = "PATH")]
+    output: Option<String>,
 }
 
 #[derive(Args)]
@@ -139,8 +156,10 @@ pub fn main() {
 
     let args: Cli = Cli::parse();
 
-    if let Some(Commands::Bench(bench)) = args.command {
-        return bench::benchmark(bench);
+    match args.command {
+        Some(Commands::Bench(bench)) => return bench::benchmark(bench),
+        Some(Commands::ApuDump(apu_dump)) => return apu_dump::apu_dump(apu_dump),
+        None => {}
     }
 
     {
@@ -100,6 +100,7 @@ pub struct Config {
     pub start_in_debug: bool,
     pub rom_folder: Option<String>,
     pub boot_rom: Option<String>,
+    pub fast_boot: bool,
     pub sort_list: Option<String>,
     pub rewinding: bool,
     pub interrupt_prediction: bool,
@@ -266,6 +267,7 @@ const DEFAULT_CONFIG: Config = Config {
     start_in_debug: false,
     rom_folder: None,
     boot_rom: None,
+    fast_boot: false,
     sort_list: None,
     rewinding: true,
     interrupt_prediction: true,
@@ -459,7 +459,8 @@ impl Emulator {
                 }
                 let joypad = &mut *joypad.lock();
                 if !joypad.rewinding {
-                    gb.joypad = joypad.next_frame(gb);
+                    let next = joypad.next_frame(gb);
+                    gb.set_joypad(next);
                 }
             }));
         }
@@ -568,8 +569,8 @@ impl Emulator {
                             Ok(_) => {
                                 log::info!("load state")
                             }
-                            Err(_) => {
-                                log::error!("error loading save state: save state is malformatted");
+                            Err(err) => {
+                                log::error!("error loading save state: {}", err);
                                 // restore current state
                                 gb.load_state(&mut old_state.as_slice()).unwrap();
                             }
@@ -627,6 +628,8 @@ impl Emulator {
                     return false;
                 }
                 self.debug = value;
+                // only list the code executed from RAM while debugging
+                self.gb.lock().trace_ram_code = value;
                 if self.debug {
                     self.debugger.lock().last_op_clock = None;
                     self.set_state(EmulatorState::Idle);
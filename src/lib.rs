@@ -29,6 +29,7 @@ use gameroy::{
     consts::{SCREEN_HEIGHT, SCREEN_WIDTH, VERSION},
     debugger::{Debugger, DebuggerEvent},
     gameboy::GameBoy,
+    log::{Level, Logger, Record},
     parser::Vbm,
 };
 use parking_lot::Mutex;
@@ -428,7 +429,7 @@ impl EmulatorApp {
             move |gb| {
                 {
                     let img_data = &mut lcd_screen.lock();
-                    img_data.copy_from_slice(&gb.ppu.borrow().screen.packed());
+                    img_data.copy_from_slice(&gb.ppu.borrow_mut().screen().packed());
                 }
                 let _ = proxy.send_event(UserEvent::FrameUpdated);
             }
@@ -441,6 +442,17 @@ impl EmulatorApp {
             emu_channel.send(EmulatorEvent::RunFrame).unwrap();
         }
         let debugger = Arc::new(Mutex::new(Debugger::default()));
+        // forward the debugger output, like the one of 'echo', to the log
+        debugger.lock().logger = Logger::new(Box::new(|record: &Record| {
+            let level = match record.level {
+                Level::Error => log::Level::Error,
+                Level::Warn => log::Level::Warn,
+                Level::Info => log::Level::Info,
+                Level::Debug => log::Level::Debug,
+                Level::Trace => log::Level::Trace,
+            };
+            log::log!(target: record.target, level, "{}", record.args);
+        }));
         {
             let proxy = proxy.clone();
             let emu_channel = emu_channel.clone();
@@ -25,12 +25,34 @@ pub fn load_gameboy(rom: Vec<u8>, ram: Option<Vec<u8>>) -> Result<Box<GameBoy>,
     let mut cartridge = Cartridge::new(rom)?;
     log::info!("Cartridge type: {}", cartridge.kind_name());
 
-    if let Some(ram) = ram {
-        cartridge.ram = ram;
+    if let Some(mut ram) = ram {
+        // Save files of other emulators may have a different size, like the ones with a RTC
+        // footer after the RAM of MBC3 cartridges, so the RAM is truncated or zero padded instead.
+        let size = cartridge.export_ram().len();
+        if ram.len() != size {
+            log::warn!(
+                "save file has {} bytes, but the cartridge has {} bytes of RAM, {} it",
+                ram.len(),
+                size,
+                if ram.len() > size {
+                    "truncating"
+                } else {
+                    "zero padding"
+                }
+            );
+            ram.resize(size, 0);
+        }
+        cartridge.import_ram(ram)?;
     }
 
     let mut game_boy = GameBoy::new(boot_rom, cartridge);
     game_boy.predict_interrupt = config().interrupt_prediction;
+    if config().fast_boot {
+        game_boy.fast_boot();
+        if game_boy.boot_rom_active {
+            log::warn!("the boot ROM locked up, the cartridge may have a invalid header");
+        }
+    }
     {
         let mut trace = game_boy.trace.borrow_mut();
 
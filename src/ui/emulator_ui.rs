@@ -274,7 +274,7 @@ fn open_debug_panel(
     let tab_group = ButtonGroup::new(|_, _| ());
 
     let disas_page = ctx.create_control().parent(tab_page).build(ctx);
-    disassembler_viewer::build(disas_page, ctx, event_table, style, cpu_id, ppu_id);
+    disassembler_viewer::build(disas_page, ctx, event_table, style, cpu_id, ppu_id, None);
     let _disas_tab = ctx
         .create_control()
         .parent(tab_header)
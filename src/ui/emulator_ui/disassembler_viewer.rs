@@ -2,7 +2,7 @@ use std::{any::Any, ops::Range, sync::Arc, usize};
 
 use gameroy::{
     debugger::{break_flags, Debugger},
-    disassembler::{Address, Directive},
+    disassembler::{Address, Directive, LabelProvider, Trace},
     gameboy::GameBoy,
 };
 use giui::{
@@ -136,48 +136,67 @@ struct DissasemblerList {
     pc: Option<Address>,
     directives: Vec<Directive>,
     items_are_dirty: bool,
+    /// A external source of labels and comments, in addition to the ones in `Trace`.
+    label_provider: Option<Box<dyn LabelProvider>>,
     _emulator_updated_event: Handle<EmulatorUpdated>,
 }
+
+/// The text of a line of the disassembly: the address, its label and the instruction, followed by
+/// the comment of the `label_provider`. The label of a jump target is surrounded by `<l></l>`, and
+/// a address operand by `<a></a>`.
+fn directive_text(
+    direc: &Directive,
+    trace: &Trace,
+    label_provider: Option<&dyn LabelProvider>,
+) -> String {
+    let label_name = |address: &Address| {
+        label_provider
+            .and_then(|x| x.label(*address))
+            .or_else(|| trace.labels.get(address).map(|x| x.name.clone()))
+    };
+
+    let curr = direc.address;
+    let mut text = format!(
+        "{:04x} {:16} ",
+        {
+            let mut address = curr.address;
+            if address < 0x4000 && curr.bank != 0 {
+                address += 0x4000;
+            }
+            address
+        },
+        label_name(&curr).unwrap_or_default()
+    );
+    let label = |pc, x| {
+        if let Some(name) = trace.jumps.get(&pc).and_then(&label_name) {
+            return format!("<l>{}</l>", name);
+        }
+        format!("<a>${:04x}</a>", x)
+    };
+    gameroy::disassembler::disassembly_opcode(
+        direc.address.address,
+        &direc.op[0..direc.len as usize],
+        |x| label(curr, x),
+        &mut text,
+    )
+    .unwrap();
+    if let Some(comment) = label_provider.and_then(|x| x.comment(curr)) {
+        text += "; ";
+        text += &comment;
+    }
+    text
+}
+
 impl DissasemblerList {
     fn graphic(
         &mut self,
         style: TextStyle,
         direc: Directive,
-        trace: std::cell::Ref<gameroy::disassembler::Trace>,
+        trace: std::cell::Ref<Trace>,
         pc: Option<Address>,
     ) -> (Graphic, Option<Range<usize>>) {
         let curr = direc.address;
-        let mut text = format!(
-            "{:04x} {:16} ",
-            {
-                let mut address = curr.address;
-                if address < 0x4000 && curr.bank != 0 {
-                    address += 0x4000;
-                }
-                address
-            },
-            trace
-                .labels
-                .get(&curr)
-                .map(|x| x.name.as_str())
-                .unwrap_or("")
-        );
-        let label = |pc, x| {
-            if let Some(address) = trace.jumps.get(&pc) {
-                let mut name = trace.labels.get(address).unwrap().name.clone();
-                name.insert_str(0, "<l>");
-                name += "</l>";
-                return name;
-            }
-            format!("<a>${:04x}</a>", x)
-        };
-        gameroy::disassembler::disassembly_opcode(
-            direc.address.address,
-            &direc.op[0..direc.len as usize],
-            |x| label(curr, x),
-            &mut text,
-        )
-        .unwrap();
+        let mut text = directive_text(&direc, &trace, self.label_provider.as_deref());
         let label_range = if let Some(start) = text.find("<l>") {
             let end = text.find("</l>").unwrap() - 3;
             text.replace_range(start..start + 3, "");
@@ -195,6 +214,7 @@ impl DissasemblerList {
             None
         };
         let op_len = text[22..].find(' ').unwrap();
+        let comment_start = text.find("; ");
 
         let mut text = Text::new(text, (-1, 0), style);
 
@@ -210,6 +230,9 @@ impl DissasemblerList {
             .map(|r| text.add_span(r.clone(), Span::Color(label)));
         text.add_span(22..22 + op_len, Span::Color(op));
         address_range.map(|r| text.add_span(r, Span::Color(number)));
+        if let Some(start) = comment_start {
+            text.add_span(start..text.len(), Span::Color(address));
+        }
         if Some(curr) == pc {
             text.add_span(
                 0..text.len(),
@@ -323,6 +346,23 @@ impl ListBuilder for DissasemblerList {
             self.items_are_dirty = true;
             self.directives.clear();
             self.directives.extend(trace.directives.iter().cloned());
+            // The code executed from RAM is listed in the bank 0xFF, like the PC below. This bends
+            // the rule that a `Address` is only in the ROM, but it is only used for display.
+            self.directives
+                .extend(
+                    trace
+                        .ram_code_regions()
+                        .into_iter()
+                        .map(|(address, len, op)| Directive {
+                            address: Address {
+                                bank: 0xFF,
+                                address,
+                            },
+                            len: len as u16,
+                            op,
+                        }),
+                );
+            self.directives.sort_unstable();
             debug_assert!(self.directives.windows(2).all(|x| x[0] <= x[1]));
 
             let pc = cpu.pc;
@@ -401,7 +441,7 @@ impl ListBuilder for DissasemblerList {
                             _ if mouse.click() => ctx.send_event_to(
                                 _list_id,
                                 JumpToAddress {
-                                    from_address: dbg!(directive.address),
+                                    from_address: directive.address,
                                 },
                             ),
                             _ => {}
@@ -429,20 +469,18 @@ struct BreakpointList {
 impl BreakpointList {
     fn get_text(ctx: &mut dyn BuilderContext, index: usize) -> String {
         let debugger = ctx.get::<Arc<Mutex<Debugger>>>().lock();
-        let (address, flags) = debugger.breakpoints().iter().nth(index).unwrap();
-        let flags = {
-            let mut flags_str = String::new();
-            let check = |c, flag| if flags & flag != 0 { c } else { '-' };
-
-            use break_flags::*;
-            flags_str.push(check('w', WRITE));
-            flags_str.push(check('r', READ));
-            flags_str.push(check('j', JUMP));
-            flags_str.push(check('x', EXECUTE));
-
-            flags_str
-        };
-        let text = format!("{} {:04x}", flags, address);
+        let len = debugger.breakpoints().len();
+        if index >= len {
+            let (address, &flags) = debugger.bank_breakpoints().iter().nth(index - len).unwrap();
+            return format!(
+                "{} {:02x}:{:04x}",
+                break_flags::to_string(flags),
+                address.bank,
+                address.to_pc()
+            );
+        }
+        let (address, &flags) = debugger.breakpoints().iter().nth(index).unwrap();
+        let text = format!("{} {:04x}", break_flags::to_string(flags), address);
         text
     }
 }
@@ -454,7 +492,8 @@ impl ListBuilder for BreakpointList {
     }
 
     fn item_count(&mut self, ctx: &mut dyn BuilderContext) -> usize {
-        ctx.get::<Arc<Mutex<Debugger>>>().lock().breakpoints().len()
+        let debugger = ctx.get::<Arc<Mutex<Debugger>>>().lock();
+        debugger.breakpoints().len() + debugger.bank_breakpoints().len()
     }
 
     fn create_item<'a>(
@@ -467,6 +506,12 @@ impl ListBuilder for BreakpointList {
         let text = Self::get_text(ctx, index);
         list_item(ctx, cb, text, move |_, ctx| {
             let mut debugger = ctx.get::<Arc<Mutex<Debugger>>>().lock();
+            let len = debugger.breakpoints().len();
+            if index >= len {
+                let &address = debugger.bank_breakpoints().keys().nth(index - len).unwrap();
+                debugger.remove_bank_break(address);
+                return;
+            }
             let &address = debugger.breakpoints().keys().nth(index).unwrap();
             debugger.remove_break(address);
         })
@@ -561,6 +606,8 @@ fn list_item(
         })
 }
 
+/// Build the disassembly list. The labels and comments of `label_provider` are shown in addition to
+/// the ones found while tracing the code.
 pub fn build(
     parent: Id,
     ctx: &mut dyn BuilderContext,
@@ -568,6 +615,7 @@ pub fn build(
     style: &Style,
     cpu_id: Id,
     ppu_id: Id,
+    label_provider: Option<Box<dyn LabelProvider>>,
 ) {
     let list_id = ctx.reserve();
     ui::list(
@@ -582,6 +630,7 @@ pub fn build(
             pc: None,
             directives: Vec::new(),
             items_are_dirty: true,
+            label_provider,
             _emulator_updated_event: event_table.register(list_id),
         },
     )
@@ -694,3 +743,49 @@ pub fn command_field(
         .graphic(Text::new(String::new(), (-1, -1), style.text_style.clone()))
         .build(ctx);
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Symbols;
+    impl LabelProvider for Symbols {
+        fn label(&self, address: Address) -> Option<String> {
+            (address == Address::new(0, 0x0150)).then(|| "main".to_string())
+        }
+
+        fn comment(&self, address: Address) -> Option<String> {
+            (address == Address::new(0, 0x0150)).then(|| "game entry".to_string())
+        }
+    }
+
+    #[test]
+    fn label_provider() {
+        let mut trace = Trace::new();
+        let main = Directive {
+            address: Address::new(0, 0x0150),
+            len: 1,
+            op: [0x00, 0x00, 0x00],
+        };
+        // JP $0150
+        let entry = Directive {
+            address: Address::new(0, 0x0100),
+            len: 3,
+            op: [0xC3, 0x50, 0x01],
+        };
+        trace.jumps.insert(entry.address, main.address);
+
+        let text = directive_text(&main, &trace, Some(&Symbols));
+        assert!(text.starts_with("0150 main "), "{}", text);
+        assert!(text.ends_with("; game entry"), "{}", text);
+        let text = directive_text(&entry, &trace, Some(&Symbols));
+        assert!(text.contains("<l>main</l>"), "{}", text);
+        assert!(!text.contains(';'), "{}", text);
+
+        // without the provider, there are no labels
+        let text = directive_text(&main, &trace, None);
+        assert!(!text.contains("main"), "{}", text);
+        let text = directive_text(&entry, &trace, None);
+        assert!(text.contains("<a>$0150</a>"), "{}", text);
+    }
+}
@@ -83,15 +83,19 @@ impl PpuViewer {
         let proxy = &ctx.get::<EventLoopProxy<UserEvent>>();
 
         // clone into box to avoid stack overflow
-        let ppu = {
+        let mut ppu = {
             let mut ppu: Box<Ppu> = Box::default();
-            (*ppu).clone_from(&*gb.lock().ppu.borrow());
+            let gb = gb.lock();
+            let mut original = gb.ppu.borrow_mut();
+            // the lines being rendered are not cloned
+            original.finish_rendering();
+            (*ppu).clone_from(&*original);
             ppu
         };
 
         if emulator_updated {
             let mut debug_screen = vec![255; 160 * 144 * 4];
-            let screen = &ppu.screen.packed();
+            let screen = &ppu.screen().packed();
             let curr_i = (ppu.ly as usize * 160 + ppu.screen_x.min(160) as usize).min(160 * 144);
 
             for i in 0..curr_i {
@@ -198,6 +202,7 @@ impl PpuViewer {
                 sy,
                 tile,
                 flags,
+                ..
             } = ppu.sprite_buffer[i];
 
             // let palette = if flags & 0x10 != 0 {